@@ -0,0 +1,312 @@
+// HTTP load generator for the tcp server.
+//
+//   cargo run --release --bin loadgen -- [options] 127.0.0.1:7878
+//
+//   -c, --connections N   concurrent connections (default 8)
+//   -d, --duration SECS   run for this long (default 10)
+//   -n, --requests N      stop after N requests instead of after a duration
+//   --no-keep-alive       new connection for every request
+//   --path PATH           request target (default /)
+//   --host NAME           Host header (default: the address)
+//   -t, --timeout SECS    give up on a response after this long (default 5)
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Stop {
+    After(Duration),
+    Count(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Config {
+    addr: String,
+    connections: usize,
+    stop: Stop,
+    keep_alive: bool,
+    path: String,
+    host: String,
+    // Per read and write, so a stalled server can't hold the run past its end.
+    timeout: Duration,
+}
+
+impl Config {
+    fn build(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        args.next();
+
+        let mut addr = None;
+        let mut connections = 8;
+        let mut stop = Stop::After(Duration::from_secs(10));
+        let mut keep_alive = true;
+        let mut path = String::from("/");
+        let mut host = None;
+        let mut timeout = Duration::from_secs(5);
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+            match arg.as_str() {
+                "-c" | "--connections" => {
+                    connections = value(&arg)?.parse().map_err(|_| "bad connection count")?;
+                }
+                "-d" | "--duration" => {
+                    let secs: f64 = value(&arg)?.parse().map_err(|_| "bad duration")?;
+                    stop = Stop::After(Duration::try_from_secs_f64(secs).map_err(|_| "bad duration")?);
+                }
+                "-n" | "--requests" => stop = Stop::Count(value(&arg)?.parse().map_err(|_| "bad request count")?),
+                "--no-keep-alive" => keep_alive = false,
+                "--path" => path = value(&arg)?,
+                "--host" => host = Some(value(&arg)?),
+                "-t" | "--timeout" => {
+                    let secs: f64 = value(&arg)?.parse().map_err(|_| "bad timeout")?;
+                    timeout = Duration::try_from_secs_f64(secs)
+                        .ok()
+                        .filter(|t| !t.is_zero())
+                        .ok_or("bad timeout")?;
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ => addr = Some(arg),
+            }
+        }
+
+        let addr = addr.ok_or("Didn't get an address to load")?;
+        if connections == 0 {
+            return Err("need at least one connection".to_string());
+        }
+        let host = host.unwrap_or_else(|| addr.clone());
+        Ok(Config { addr, connections, stop, keep_alive, path, host, timeout })
+    }
+
+    fn request(&self) -> Vec<u8> {
+        let connection = if self.keep_alive { "keep-alive" } else { "close" };
+        format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: {connection}\r\n\r\n", self.path, self.host).into_bytes()
+    }
+}
+
+// What one connection thread saw.
+#[derive(Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    errors: u64,
+    bytes: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+    }
+}
+
+// Status code, whether the server keeps the connection, and bytes read.
+fn read_response(reader: &mut impl BufRead) -> std::io::Result<(u16, bool, u64)> {
+    let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(invalid("connection closed"));
+    }
+    let mut bytes = line.len() as u64;
+    let status = line.split(' ').nth(1).and_then(|s| s.parse().ok()).ok_or_else(|| invalid("bad status line"))?;
+
+    let mut length = 0;
+    let mut keep_alive = true;
+    loop {
+        line.clear();
+        bytes += reader.read_line(&mut line)? as u64;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().map_err(|_| invalid("bad Content-Length"))?;
+            } else if name.eq_ignore_ascii_case("Connection") {
+                keep_alive = !value.trim().eq_ignore_ascii_case("close");
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok((status, keep_alive, bytes + length as u64))
+}
+
+fn run_connection(config: &Config, running: &AtomicBool, remaining: &AtomicU64) -> Stats {
+    let request = config.request();
+    let mut stats = Stats::default();
+    let mut connection: Option<(TcpStream, BufReader<TcpStream>)> = None;
+
+    while running.load(Ordering::Relaxed) {
+        if let Stop::Count(_) = config.stop {
+            // Take a ticket; none left means the run is over.
+            if remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_err() {
+                break;
+            }
+        }
+
+        let started = Instant::now();
+        let result = (|| {
+            if connection.is_none() {
+                let stream = TcpStream::connect(&config.addr)?;
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(config.timeout))?;
+                stream.set_write_timeout(Some(config.timeout))?;
+                let reader = BufReader::new(stream.try_clone()?);
+                connection = Some((stream, reader));
+            }
+            let (stream, reader) = connection.as_mut().expect("connected above");
+            stream.write_all(&request)?;
+            read_response(reader)
+        })();
+
+        match result {
+            Ok((status, keep_alive, bytes)) => {
+                stats.latencies.push(started.elapsed());
+                *stats.statuses.entry(status).or_default() += 1;
+                stats.bytes += bytes;
+                if !keep_alive || !config.keep_alive {
+                    connection = None;
+                }
+            }
+            Err(_) => {
+                stats.errors += 1;
+                connection = None;
+            }
+        }
+    }
+    stats
+}
+
+// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn main() {
+    let config = Config::build(std::env::args()).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        process::exit(1);
+    });
+
+    let running = Arc::new(AtomicBool::new(true));
+    let remaining = Arc::new(AtomicU64::new(match config.stop {
+        Stop::Count(n) => n,
+        Stop::After(_) => 0,
+    }));
+
+    println!(
+        "Loading {}{} with {} connection(s), keep-alive {}",
+        config.addr,
+        config.path,
+        config.connections,
+        if config.keep_alive { "on" } else { "off" }
+    );
+
+    let started = Instant::now();
+    let workers: Vec<_> = (0..config.connections)
+        .map(|_| {
+            let (config, running, remaining) = (config.clone(), Arc::clone(&running), Arc::clone(&remaining));
+            thread::spawn(move || run_connection(&config, &running, &remaining))
+        })
+        .collect();
+
+    if let Stop::After(duration) = config.stop {
+        thread::sleep(duration);
+        running.store(false, Ordering::Relaxed);
+    }
+
+    let mut total = Stats::default();
+    for worker in workers {
+        total.merge(worker.join().expect("connection thread panicked"));
+    }
+    let elapsed = started.elapsed();
+
+    total.latencies.sort_unstable();
+    let done = total.latencies.len();
+    let secs = elapsed.as_secs_f64();
+
+    println!("{done} requests in {elapsed:.2?}, {} errors", total.errors);
+    println!("Throughput: {:.0} req/s, {:.2} MB/s", done as f64 / secs, total.bytes as f64 / secs / 1e6);
+    println!(
+        "Latency:    p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        percentile(&total.latencies, 50.0),
+        percentile(&total.latencies, 90.0),
+        percentile(&total.latencies, 99.0),
+        total.latencies.last().copied().unwrap_or_default()
+    );
+    for (status, count) in &total.statuses {
+        println!("  {status}: {count}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> {
+        std::iter::once("loadgen".to_string()).chain(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn builds_config_from_arguments() {
+        let config = Config::build(args("-c 64 -n 1000 --no-keep-alive --path /sleep 127.0.0.1:7878")).unwrap();
+        assert_eq!(config.connections, 64);
+        assert_eq!(config.stop, Stop::Count(1000));
+        assert!(!config.keep_alive);
+        assert_eq!(config.path, "/sleep");
+        assert_eq!(config.host, "127.0.0.1:7878");
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert_eq!(Config::build(args("-t 0.5 127.0.0.1:1")).unwrap().timeout, Duration::from_millis(500));
+        assert!(Config::build(args("-t 0 127.0.0.1:1")).is_err());
+
+        assert!(Config::build(args("-c 4")).is_err());
+        assert!(Config::build(args("--bogus 127.0.0.1:1")).is_err());
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn reads_one_response() {
+        let raw = b"HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 4\r\n\r\nnopeHTTP/1.1";
+        let (status, keep_alive, bytes) = read_response(&mut &raw[..]).unwrap();
+        assert_eq!((status, keep_alive), (404, false));
+        assert_eq!(bytes, raw.len() as u64 - "HTTP/1.1".len() as u64);
+    }
+
+    #[test]
+    fn stalled_servers_time_out() {
+        // Accepts, reads the request and never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let _held: Vec<_> = listener.incoming().collect();
+        });
+        let config = Config::build(args(&format!("-c 1 -n 1 -t 0.1 {addr}"))).unwrap();
+        let started = Instant::now();
+        let stats = run_connection(&config, &AtomicBool::new(true), &AtomicU64::new(1));
+        assert_eq!(stats.errors, 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
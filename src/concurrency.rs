@@ -9,6 +9,47 @@ use std::time::Duration;
 use rand::distr::uniform::SampleBorrow;
 use rayon::prelude::*;
 
+// The modules below are a library the binary barely calls, so outside
+// tests most of it is unused by nature. The test build uses all of it and
+// is held to the usual unused-code warnings.
+#[cfg_attr(test, warn(unused))]
+pub mod actor;
+#[cfg_attr(test, warn(unused))]
+pub mod cancel;
+#[cfg_attr(test, warn(unused))]
+pub mod channel;
+#[cfg_attr(test, warn(unused))]
+pub mod cron;
+#[cfg_attr(test, warn(unused))]
+pub mod handle;
+// Its tests need debug assertions; release builds keep only the wrapper.
+#[cfg_attr(all(test, debug_assertions), warn(unused))]
+pub mod lockorder;
+#[cfg(test)]
+#[warn(unused)]
+pub(crate) mod model;
+#[cfg_attr(test, warn(unused))]
+pub mod pipeline;
+#[cfg_attr(test, warn(unused))]
+pub mod pool;
+#[cfg_attr(test, warn(unused))]
+pub mod queue;
+#[cfg(target_os = "linux")]
+#[cfg_attr(test, warn(unused))]
+pub mod runtime;
+#[cfg_attr(test, warn(unused))]
+pub mod schedule;
+#[cfg_attr(test, warn(unused))]
+pub mod scope;
+#[cfg_attr(test, warn(unused))]
+pub mod sort;
+#[cfg_attr(test, warn(unused))]
+pub mod sync;
+
+pub use cancel::{CancelToken, Cancelled};
+pub use pipeline::Pipeline;
+pub use pool::ThreadPool;
+
 pub fn thread_spawning() {
 
     let handle = thread::spawn(|| {
@@ -84,6 +125,21 @@ pub fn channels() {
     }
 }
 
+// The same producers and consumer as a pipeline: the messages are parsed on
+// two workers, bad ones stop the lot, and they come out in batches.
+pub fn pipeline() {
+    let lines = ["1", "2", "3", "4", "5", "six", "7"];
+    let result = Pipeline::from_iter(lines)
+        .try_map(|line| line.parse::<i32>())
+        .workers(2)
+        .ordered()
+        .batch(2)
+        .for_each(|batch| println!("Got: {:?}", batch));
+    if let Err(e) = result {
+        println!("Pipeline stopped: {}", e);
+    }
+}
+
 pub fn mutexes() {
     // Arc<T> - Atomic Reference Counting
     let counter = Arc::new(Mutex::new(0));
@@ -112,61 +168,45 @@ fn mutate_counter(counter: &Mutex<i32>) {
     *reference += 1;
 }
 
-// Thread pool
-
-trait FnBox {
-    fn call_box(self: Box<Self>);
-}
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        self();
-    }
+// Lock order: two transfers that lock the accounts in opposite orders can
+// deadlock if they ever run at the same time. Run one after the other they
+// never do, yet in a debug build lockorder's Mutex reports the risk anyway.
+pub fn lock_ordering() {
+    let checking = Arc::new(lockorder::Mutex::new(100));
+    let savings = Arc::new(lockorder::Mutex::new(100));
+    let transfer = |from: Arc<lockorder::Mutex<i32>>, to: Arc<lockorder::Mutex<i32>>| {
+        thread::spawn(move || {
+            let mut from = from.lock().unwrap();
+            let mut to = to.lock().unwrap();
+            *from -= 10;
+            *to += 10;
+        })
+    };
+    transfer(Arc::clone(&checking), Arc::clone(&savings)).join().unwrap();
+    transfer(Arc::clone(&savings), Arc::clone(&checking)).join().unwrap();
+    println!("Balances: {} and {}", *checking.lock().unwrap(), *savings.lock().unwrap());
 }
-type Job = Box<dyn FnBox + Send + 'static>;
-pub struct ThreadPool {
-    handles: Vec<thread::JoinHandle<()>>,
-    ch:Sender<Job>,
-}
-impl ThreadPool {
-    pub fn new(n: usize) -> Self {
-        let (cs,cr) = channel::<Job>();
-        let amap = Arc::new(Mutex::new(cr));
-        let mut handles = Vec::with_capacity(n);
-        for _ in 0..n {
-            let amp = amap.clone();
-            handles.push(thread::spawn(move || {
-                loop {
-                    let job = match amp.lock().unwrap().recv() {
-                        Ok(j) => j,
-                        _ => return,
-                    };
-                    job.call_box();
-                }
-            }));
-        }
-        ThreadPool { handles, ch: cs }
-    }
-    
-    pub fn add<F:FnOnce() + 'static + Send>(&self, job:F) {
-        self.ch.send(Box::new(job)).unwrap();
-    }
-    
-    pub fn end(self) {
-        // once dropped, all the receivers will receive an empty channel and end execution
-        drop(self.ch);
 
-        for h in self.handles {
-            h.join();
-        }
-    }
-}
+// Thread pool
 
 pub fn thread_pool() {
     let p = ThreadPool::new(10);
     p.add(|| {
         println!("Hello, world!");
     });
-    
+    let answer = p.submit(|| 6 * 7);
+    println!("The answer is {}", answer.join().unwrap());
+
+    // Scoped jobs can borrow 'v' instead of needing their own copy.
+    let v: Vec<u32> = (1..=100).collect();
+    let mut sums = [0; 4];
+    p.scope(|s| {
+        for (chunk, sum) in v.chunks(25).zip(sums.iter_mut()) {
+            s.spawn(move |_| *sum = chunk.iter().sum());
+        }
+    });
+    println!("Sums: {:?}", sums);
+
     p.end();
     println!("Done");
 }
@@ -196,6 +236,51 @@ pub fn rayon_parallel_vector_fill() {
     });
 }
 
+// Divide and conquer rather than par_iter: both sorts split the vector and
+// sort the halves in parallel, on rayon's pool or on ours.
+pub fn parallel_sorting() {
+    let mut v: Vec<u32> = (0..100_000).map(|i| (i * 7919) % 100_003).collect();
+    let mut w = v.clone();
+    sort::merge_sort(&mut v, &sort::Rayon);
+    let pool = ThreadPool::new(4);
+    sort::quicksort(&mut w, &pool);
+    println!("{:?} {}", &v[..5], v == w);
+
+    let orders = [(1, "tea"), (2, "cake"), (2, "jam"), (4, "bread")];
+    let customers = [(1, "Ann"), (2, "Bob"), (3, "Cy")];
+    for (order, customer) in sort::join_sorted(&orders, &customers, |o| o.0, |c| c.0, &pool) {
+        println!("{} ordered {}", customer.1, order.1);
+    }
+    pool.end();
+}
+
+// The same fill, but one a user can stop: each element checks the token
+// first, and collecting into a Result gives up at the first Err.
+pub fn cancellable_parallel_vector_fill() {
+    let v: Vec<i32> = (0..500).collect();
+    let token = CancelToken::new();
+    let canceller = token.clone();
+    thread::spawn(move || {
+        sleep(Duration::from_millis(500));
+        println!("Cancelling");
+        canceller.cancel();
+    });
+    match parallel_squares(&v, &token) {
+        Ok(v2) => println!("{:?}", &v2[490..500]),
+        Err(e) => println!("Fill stopped: {}", e),
+    }
+}
+
+pub fn parallel_squares(v: &[i32], token: &CancelToken) -> Result<Vec<i32>, Cancelled> {
+    v.par_iter()
+        .map(|x| {
+            token.check()?;
+            sleep(Duration::from_millis(10));
+            Ok(x * x)
+        })
+        .collect()
+}
+
 
 
 
@@ -0,0 +1,446 @@
+// Actors: each owns its state and a mailbox (a bounded channel, see
+// channel.rs), and handles one message at a time on its own thread, so the
+// state needs no locks. Everyone else holds an Addr, which can be cloned
+// freely: send() posts a message, ask() posts one carrying a Reply and
+// waits for the answer.
+//
+// An actor stops when asked to, when it calls ctx.stop(), or when the last
+// Addr is dropped. If handling a message panics, a plain actor is gone for
+// good; one started by a Supervisor is rebuilt from its factory, as its
+// restart policy allows, and carries on with the same mailbox, so the
+// callers' addresses stay valid. The message being handled when it
+// panicked is lost, and an ask() waiting on it gets NoReply; so do the
+// asks still queued when an actor stops or dies for good.
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::channel::{self, Receiver, Sender};
+
+pub const DEFAULT_MAILBOX: usize = 64;
+
+pub trait Actor: Send + 'static {
+    type Message: Send + 'static;
+
+    fn handle(&mut self, message: Self::Message, ctx: &mut Context);
+
+    // Before the first message, and again after every restart.
+    fn started(&mut self, _ctx: &mut Context) {}
+
+    // After a clean stop; not after a panic.
+    fn stopped(&mut self) {}
+}
+
+pub struct Context {
+    stopping: bool,
+    restarts: usize,
+}
+
+impl Context {
+    // Stops after the message being handled.
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorError {
+    // The actor has stopped, or died and won't be restarted.
+    Stopped,
+    // The mailbox is full (try_send only).
+    Full,
+    // The actor dropped the Reply without answering, most likely because
+    // it panicked handling the message.
+    NoReply,
+    Timeout,
+}
+
+impl Display for ActorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActorError::Stopped => write!(f, "actor has stopped"),
+            ActorError::Full => write!(f, "actor mailbox is full"),
+            ActorError::NoReply => write!(f, "actor did not reply"),
+            ActorError::Timeout => write!(f, "timed out waiting for the actor"),
+        }
+    }
+}
+
+impl std::error::Error for ActorError {}
+
+enum Envelope<M> {
+    Message(M),
+    Stop,
+}
+
+pub struct Addr<A: Actor> {
+    mailbox: Sender<Envelope<A::Message>>,
+}
+
+impl<A: Actor> Clone for Addr<A> {
+    fn clone(&self) -> Addr<A> {
+        Addr { mailbox: self.mailbox.clone() }
+    }
+}
+
+// The answer to an ask(). Dropping it unanswered gives the caller NoReply.
+pub struct Reply<R> {
+    sender: Sender<R>,
+}
+
+impl<R> Reply<R> {
+    pub fn send(self, value: R) {
+        // The caller may have timed out and gone.
+        let _ = self.sender.try_send(value);
+    }
+}
+
+impl<A: Actor> Addr<A> {
+    // Waits while the mailbox is full.
+    pub fn send(&self, message: A::Message) -> Result<(), ActorError> {
+        self.mailbox.send(Envelope::Message(message)).map_err(|_| ActorError::Stopped)
+    }
+
+    pub fn try_send(&self, message: A::Message) -> Result<(), ActorError> {
+        self.mailbox.try_send(Envelope::Message(message)).map_err(|e| match e {
+            TrySendError::Full(_) => ActorError::Full,
+            TrySendError::Disconnected(_) => ActorError::Stopped,
+        })
+    }
+
+    // Sends the message 'make' builds around a Reply and waits for the
+    // answer:
+    //
+    //     let total = counter.ask(Counter::Get)?;
+    pub fn ask<R>(&self, make: impl FnOnce(Reply<R>) -> A::Message) -> Result<R, ActorError> {
+        let (sender, answer) = channel::bounded(1);
+        self.send(make(Reply { sender }))?;
+        answer.recv().map_err(|_| ActorError::NoReply)
+    }
+
+    // Like ask, but gives up after 'timeout' (counting the wait for room in
+    // the mailbox).
+    pub fn ask_timeout<R>(&self, make: impl FnOnce(Reply<R>) -> A::Message, timeout: Duration) -> Result<R, ActorError> {
+        let deadline = Instant::now() + timeout;
+        let (sender, answer) = channel::bounded(1);
+        self.mailbox.send_timeout(Envelope::Message(make(Reply { sender })), timeout).map_err(|e| match e {
+            channel::SendTimeoutError::Timeout(_) => ActorError::Timeout,
+            channel::SendTimeoutError::Disconnected(_) => ActorError::Stopped,
+        })?;
+        answer.recv_timeout(deadline.saturating_duration_since(Instant::now())).map_err(|e| match e {
+            RecvTimeoutError::Timeout => ActorError::Timeout,
+            RecvTimeoutError::Disconnected => ActorError::NoReply,
+        })
+    }
+
+    // Stops once the messages already in the mailbox have been handled.
+    pub fn stop(&self) {
+        let _ = self.mailbox.send(Envelope::Stop);
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !self.mailbox.is_disconnected()
+    }
+}
+
+// Starts 'actor' on its own thread. If it panics, it stays dead.
+pub fn spawn<A: Actor>(actor: A) -> Addr<A> {
+    let mut first = Some(actor);
+    start(DEFAULT_MAILBOX, move |_| first.take())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    Never,
+    Always,
+    // Gives up once it has restarted 'max' times within 'within'.
+    Limited { max: usize, within: Duration },
+}
+
+// Starts actors and restarts them after panics, by building a fresh one:
+// whatever state the panic left behind is thrown away.
+pub struct Supervisor {
+    policy: Restart,
+    mailbox: usize,
+    restarts: Arc<AtomicUsize>,
+}
+
+impl Supervisor {
+    pub fn new(policy: Restart) -> Supervisor {
+        Supervisor { policy, mailbox: DEFAULT_MAILBOX, restarts: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn mailbox(mut self, capacity: usize) -> Supervisor {
+        self.mailbox = capacity;
+        self
+    }
+
+    pub fn spawn<A: Actor>(&self, factory: impl Fn() -> A + Send + 'static) -> Addr<A> {
+        let policy = self.policy;
+        let total = Arc::clone(&self.restarts);
+        let mut recent = VecDeque::new();
+        start(self.mailbox, move |restarts| {
+            if restarts > 0 {
+                let now = Instant::now();
+                let allowed = match policy {
+                    Restart::Never => false,
+                    Restart::Always => true,
+                    Restart::Limited { max, within } => {
+                        while recent.front().is_some_and(|&at| now.duration_since(at) > within) {
+                            recent.pop_front();
+                        }
+                        recent.len() < max
+                    }
+                };
+                if !allowed {
+                    return None;
+                }
+                recent.push_back(now);
+                total.fetch_add(1, Ordering::SeqCst);
+            }
+            Some(factory())
+        })
+    }
+
+    // Restarts across every actor this supervisor started.
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::SeqCst)
+    }
+}
+
+// Runs actors from 'make' (called with the number of restarts so far)
+// until one stops cleanly or 'make' has no more to give.
+fn start<A: Actor>(mailbox: usize, mut make: impl FnMut(usize) -> Option<A> + Send + 'static) -> Addr<A> {
+    let (sender, receiver) = channel::bounded(mailbox);
+    let name = std::any::type_name::<A>().rsplit("::").next().unwrap_or("actor");
+    thread::Builder::new()
+        .name(format!("actor-{name}"))
+        .spawn(move || {
+            let mut ctx = Context { stopping: false, restarts: 0 };
+            while let Some(mut actor) = make(ctx.restarts) {
+                let run = panic::catch_unwind(AssertUnwindSafe(|| {
+                    actor.started(&mut ctx);
+                    run(&mut actor, &receiver, &mut ctx);
+                }));
+                if run.is_ok() {
+                    actor.stopped();
+                    break;
+                }
+                ctx.restarts += 1;
+            }
+        })
+        .expect("failed to spawn an actor thread");
+    Addr { mailbox: sender }
+}
+
+fn run<A: Actor>(actor: &mut A, mailbox: &Receiver<Envelope<A::Message>>, ctx: &mut Context) {
+    while !ctx.stopping {
+        match mailbox.recv() {
+            Ok(Envelope::Message(message)) => actor.handle(message, ctx),
+            // Stop was asked for, or nobody can send us anything any more.
+            Ok(Envelope::Stop) | Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumerations::Message;
+    use std::sync::Mutex;
+
+    enum Counter {
+        Add(i64),
+        Get(Reply<i64>),
+        Fail,
+    }
+
+    struct Count(i64);
+
+    impl Actor for Count {
+        type Message = Counter;
+
+        fn handle(&mut self, message: Counter, _: &mut Context) {
+            match message {
+                Counter::Add(n) => self.0 += n,
+                Counter::Get(reply) => reply.send(self.0),
+                Counter::Fail => panic!("counter failed"),
+            }
+        }
+    }
+
+    #[test]
+    fn handles_messages_in_order_and_answers_asks() {
+        let counter = spawn(Count(0));
+        let other = counter.clone();
+        for n in 1..=10 {
+            other.send(Counter::Add(n)).unwrap();
+        }
+        assert_eq!(counter.ask(Counter::Get), Ok(55));
+        assert_eq!(counter.ask_timeout(Counter::Get, Duration::from_secs(5)), Ok(55));
+    }
+
+    // The tutorial's Message enum, handled by an actor.
+    struct Turtle {
+        position: (i32, i32),
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Actor for Turtle {
+        type Message = Message;
+
+        fn handle(&mut self, message: Message, ctx: &mut Context) {
+            match message {
+                Message::Move { x, y } => self.position = (self.position.0 + x, self.position.1 + y),
+                Message::Write(text) => self.log.lock().unwrap().push(format!("{text} at {:?}", self.position)),
+                Message::ChangeColor(r, g, b) => self.log.lock().unwrap().push(format!("color {r},{g},{b}")),
+                Message::Quit => ctx.stop(),
+            }
+        }
+
+        fn stopped(&mut self) {
+            self.log.lock().unwrap().push("stopped".to_string());
+        }
+    }
+
+    fn wait_until_dead<A: Actor>(addr: &Addr<A>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while addr.is_alive() {
+            assert!(Instant::now() < deadline, "the actor should have stopped");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn stops_itself_and_rejects_later_messages() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let turtle = spawn(Turtle { position: (0, 0), log: Arc::clone(&log) });
+        turtle.send(Message::Move { x: 3, y: 4 }).unwrap();
+        turtle.send(Message::Write("hello".to_string())).unwrap();
+        turtle.send(Message::ChangeColor(255, 0, 0)).unwrap();
+        turtle.send(Message::Quit).unwrap();
+        wait_until_dead(&turtle);
+
+        assert_eq!(turtle.send(Message::Move { x: 1, y: 1 }), Err(ActorError::Stopped));
+        assert_eq!(*log.lock().unwrap(), ["hello at (3, 4)", "color 255,0,0", "stopped"]);
+    }
+
+    #[test]
+    fn stop_and_dropping_every_address_end_the_actor() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let turtle = spawn(Turtle { position: (0, 0), log: Arc::clone(&log) });
+        turtle.send(Message::Write("before stop".to_string())).unwrap();
+        turtle.stop();
+        wait_until_dead(&turtle);
+        assert_eq!(*log.lock().unwrap(), ["before stop at (0, 0)", "stopped"]);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        drop(spawn(Turtle { position: (0, 0), log: Arc::clone(&log) }));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while log.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*log.lock().unwrap(), ["stopped"]);
+    }
+
+    #[test]
+    fn unsupervised_actors_die_with_their_panic() {
+        let counter = spawn(Count(0));
+        counter.send(Counter::Fail).unwrap();
+        wait_until_dead(&counter);
+        assert_eq!(counter.ask(Counter::Get), Err(ActorError::Stopped));
+    }
+
+    #[test]
+    fn asks_queued_behind_the_end_get_no_reply() {
+        enum Gated {
+            Wait(Receiver<()>),
+            Fail,
+            Get(Reply<()>),
+        }
+        struct Gate;
+        impl Actor for Gate {
+            type Message = Gated;
+            fn handle(&mut self, message: Gated, _: &mut Context) {
+                match message {
+                    Gated::Wait(open) => open.recv().unwrap(),
+                    Gated::Fail => panic!("gate failed"),
+                    Gated::Get(reply) => reply.send(()),
+                }
+            }
+        }
+
+        let ends: [fn(&Addr<Gate>); 2] = [|gate| gate.send(Gated::Fail).unwrap(), |gate| gate.stop()];
+        for end in ends {
+            let gate = spawn(Gate);
+            let (open, opened) = channel::bounded(1);
+            // Holds the actor up until the end and the ask are both queued.
+            gate.send(Gated::Wait(opened)).unwrap();
+            end(&gate);
+            let asker = {
+                let gate = gate.clone();
+                thread::spawn(move || gate.ask(Gated::Get))
+            };
+            thread::sleep(Duration::from_millis(20));
+            open.send(()).unwrap();
+            assert_eq!(asker.join().unwrap(), Err(ActorError::NoReply));
+        }
+    }
+
+    #[test]
+    fn supervisors_restart_with_fresh_state() {
+        let supervisor = Supervisor::new(Restart::Always);
+        let counter = supervisor.spawn(|| Count(100));
+        counter.send(Counter::Add(5)).unwrap();
+        assert_eq!(counter.ask(Counter::Get), Ok(105));
+        // An ask the panic swallows gets NoReply; the address keeps working.
+        let lost = counter.ask(|reply: Reply<i64>| {
+            drop(reply);
+            Counter::Fail
+        });
+        assert_eq!(lost, Err(ActorError::NoReply));
+        assert_eq!(counter.ask(Counter::Get), Ok(100));
+        assert_eq!(supervisor.restarts(), 1);
+    }
+
+    #[test]
+    fn limited_restarts_give_up() {
+        let supervisor = Supervisor::new(Restart::Limited { max: 2, within: Duration::from_secs(60) });
+        let counter = supervisor.spawn(|| Count(0));
+        for _ in 0..3 {
+            let _ = counter.send(Counter::Fail);
+        }
+        wait_until_dead(&counter);
+        assert_eq!(supervisor.restarts(), 2);
+        assert_eq!(counter.send(Counter::Add(1)), Err(ActorError::Stopped));
+
+        let never = Supervisor::new(Restart::Never);
+        let counter = never.spawn(|| Count(0));
+        counter.send(Counter::Fail).unwrap();
+        wait_until_dead(&counter);
+        assert_eq!(never.restarts(), 0);
+    }
+
+    #[test]
+    fn full_mailboxes_push_back() {
+        struct Slow;
+        impl Actor for Slow {
+            type Message = Duration;
+            fn handle(&mut self, pause: Duration, _: &mut Context) {
+                thread::sleep(pause);
+            }
+        }
+        let slow = Supervisor::new(Restart::Never).mailbox(1).spawn(|| Slow);
+        slow.send(Duration::from_millis(100)).unwrap();
+        // The first is being handled or still queued; one of these won't fit.
+        let results = [slow.try_send(Duration::ZERO), slow.try_send(Duration::ZERO)];
+        assert!(results.contains(&Err(ActorError::Full)));
+    }
+}
@@ -0,0 +1,281 @@
+// Cooperative cancellation. A CancelToken is cloned into jobs, which check
+// is_cancelled() (or token.check()?) between steps and give up early; we
+// can't stop a running job from outside. Jobs added with add_cancellable or
+// submit_cancellable are also skipped outright if their token is cancelled
+// before a worker gets to them.
+//
+// child() and with_deadline() make tokens that are cancelled along with
+// their parent, or on their own; a deadline cancels a token by itself once
+// it passes, so a job can be given one without anyone watching the clock.
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::handle::{self, JobHandle};
+use super::ThreadPool;
+
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    // Never later than the parent's, so a passed deadline needs no passing on.
+    deadline: Option<Instant>,
+    // Also the lock 'changed' waits with.
+    children: Mutex<Vec<Weak<Inner>>>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    // Cancelled when this one is, or by itself.
+    pub fn child(&self) -> CancelToken {
+        self.child_until(self.inner.deadline)
+    }
+
+    // A child that also cancels itself at 'deadline'.
+    pub fn with_deadline(&self, deadline: Instant) -> CancelToken {
+        let deadline = self.inner.deadline.map_or(deadline, |ours| ours.min(deadline));
+        self.child_until(Some(deadline))
+    }
+
+    pub fn with_timeout(&self, timeout: Duration) -> CancelToken {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    fn child_until(&self, deadline: Option<Instant>) -> CancelToken {
+        let child = CancelToken { inner: Arc::new(Inner { deadline, ..Inner::default() }) };
+        let mut children = self.inner.children.lock().unwrap();
+        if self.inner.cancelled.load(Ordering::SeqCst) {
+            child.inner.cancelled.store(true, Ordering::SeqCst);
+        } else {
+            children.retain(|c| c.strong_count() > 0);
+            children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    // Cancels this token and every token made from it.
+    pub fn cancel(&self) {
+        let children = {
+            let mut children = self.inner.children.lock().unwrap();
+            if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            self.inner.changed.notify_all();
+            std::mem::take(&mut *children)
+        };
+        for child in children.iter().filter_map(Weak::upgrade) {
+            CancelToken { inner: child }.cancel();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst) || self.inner.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    // For jobs returning Results: token.check()?
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline
+    }
+
+    // Blocks until cancelled (or the deadline passes).
+    pub fn wait(&self) {
+        self.wait_until(None);
+    }
+
+    // Returns whether it was cancelled within 'timeout'.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&self, until: Option<Instant>) -> bool {
+        let until = match (until, self.inner.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let mut children = self.inner.children.lock().unwrap();
+        while !self.is_cancelled() {
+            children = match until {
+                None => self.inner.changed.wait(children).unwrap(),
+                Some(until) => {
+                    let now = Instant::now();
+                    if now >= until {
+                        break;
+                    }
+                    self.inner.changed.wait_timeout(children, until - now).unwrap().0
+                }
+            };
+        }
+        self.is_cancelled()
+    }
+}
+
+impl ThreadPool {
+    // Runs job(&token) like add, unless the token has been cancelled by the
+    // time a worker takes it from the queue.
+    pub fn add_cancellable<F>(&self, token: &CancelToken, job: F)
+    where
+        F: FnOnce(&CancelToken) + Send + 'static,
+    {
+        let token = token.clone();
+        self.add(move || {
+            if !token.is_cancelled() {
+                job(&token);
+            }
+        });
+    }
+
+    // Like submit; a job skipped because of its token reports
+    // JobError::Cancelled.
+    pub fn submit_cancellable<T, F>(&self, token: &CancelToken, job: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&CancelToken) -> T + Send + 'static,
+    {
+        let (job, handle) = handle::wrap_cancellable(token.clone(), job);
+        self.add(job);
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::handle::JobError;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn cancelling_reaches_clones_and_children_but_not_parents() {
+        let parent = CancelToken::new();
+        let child = parent.child();
+        let grandchild = child.child();
+        let clone = child.clone();
+
+        clone.cancel();
+        assert!(child.is_cancelled() && grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert_eq!(grandchild.check(), Err(Cancelled));
+
+        parent.cancel();
+        assert!(parent.child().is_cancelled());
+        assert_eq!(parent.check(), Err(Cancelled));
+    }
+
+    #[test]
+    fn deadlines_cancel_by_themselves() {
+        let parent = CancelToken::new().with_timeout(Duration::from_millis(30));
+        // A child can't outlive its parent's deadline.
+        let child = parent.with_timeout(Duration::from_secs(60));
+        assert_eq!(child.deadline(), parent.deadline());
+        assert!(!child.is_cancelled());
+
+        let started = Instant::now();
+        child.wait();
+        assert!(started.elapsed() >= Duration::from_millis(25));
+        assert!(parent.is_cancelled() && child.is_cancelled());
+    }
+
+    #[test]
+    fn waiters_wake_when_cancelled() {
+        let token = CancelToken::new();
+        let child = token.child();
+        let waiter = thread::spawn(move || child.wait_timeout(Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+        assert!(waiter.join().unwrap());
+        assert!(!CancelToken::new().wait_timeout(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn queued_jobs_are_skipped_once_cancelled() {
+        let pool = ThreadPool::new(1);
+        let gate = Arc::new(Barrier::new(2));
+        let blocker = Arc::clone(&gate);
+        // Keeps the only worker busy until the others are queued and cancelled.
+        pool.add(move || {
+            blocker.wait();
+        });
+
+        let token = CancelToken::new();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let ran = Arc::clone(&ran);
+            pool.add_cancellable(&token, move |_| {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let skipped = pool.submit_cancellable(&token, |_| 1);
+        let unrelated = pool.submit_cancellable(&CancelToken::new(), |_| 2);
+        token.cancel();
+        gate.wait();
+
+        assert_eq!(skipped.join(), Err(JobError::Cancelled));
+        assert_eq!(unrelated.join(), Ok(2));
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn running_jobs_stop_at_their_next_check() {
+        let pool = ThreadPool::new(2);
+        let token = CancelToken::new();
+        let handle = pool.submit_cancellable(&token.with_timeout(Duration::from_millis(20)), |token| {
+            let mut steps = 0;
+            while token.check().is_ok() {
+                steps += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            steps
+        });
+        let steps = handle.join().unwrap();
+        assert!(steps > 0 && steps < 1000);
+        // The deadline cancelled only the job's own token.
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn cancels_a_parallel_fill() {
+        let values: Vec<i32> = (0..200).collect();
+        assert_eq!(crate::concurrency::parallel_squares(&values[..4], &CancelToken::new()), Ok(vec![0, 1, 4, 9]));
+
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let started = Instant::now();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            canceller.cancel();
+        });
+        // 200 steps of 10ms each would take far longer than we wait.
+        assert_eq!(crate::concurrency::parallel_squares(&values, &token), Err(Cancelled));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
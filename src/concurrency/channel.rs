@@ -0,0 +1,655 @@
+// A bounded multi-producer multi-consumer channel. Both ends can be cloned;
+// every message goes to exactly one receiver. Senders wait while the
+// channel is full, receivers while it is empty. Once every receiver is gone
+// sends fail and the messages still queued are dropped, and once every
+// sender is gone receives fail after the channel has been drained.
+//
+// select! waits on several receivers at once:
+//
+//     select! {
+//         recv(jobs) -> job => ...,
+//         recv(quit) -> _ => ...,
+//         default(Duration::from_secs(1)) => ...,
+//     }
+//
+// The error types are std::sync::mpsc's, apart from SendTimeoutError,
+// which std doesn't have on stable.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a channel needs room for at least one message");
+    let channel = Arc::new(Channel {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receivers: 1,
+            selectors: Vec::new(),
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    });
+    (Sender { channel: Arc::clone(&channel) }, Receiver { channel })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTimeoutError<T> {
+    Timeout(T),
+    Disconnected(T),
+}
+
+impl<T> Display for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => write!(f, "timed out waiting on send operation"),
+            SendTimeoutError::Disconnected(_) => write!(f, "sending on a disconnected channel"),
+        }
+    }
+}
+
+impl<T: Debug> std::error::Error for SendTimeoutError<T> {}
+
+struct Channel<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    // Selects waiting on this channel among others.
+    selectors: Vec<Arc<Signal>>,
+}
+
+impl<T> State<T> {
+    // Something changed that a waiting select should look at.
+    fn signal_selectors(&self) {
+        for signal in &self.selectors {
+            signal.raise();
+        }
+    }
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        match self.send_until(message, None) {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Disconnected(message) | SendTimeoutError::Timeout(message)) => Err(SendError(message)),
+        }
+    }
+
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let state = self.channel.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(message));
+        }
+        if state.queue.len() == state.capacity {
+            return Err(TrySendError::Full(message));
+        }
+        self.push(state, message);
+        Ok(())
+    }
+
+    pub fn send_timeout(&self, message: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(message, Some(Instant::now() + timeout))
+    }
+
+    fn send_until(&self, message: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let mut state = self.channel.state.lock().unwrap();
+        loop {
+            if state.receivers == 0 {
+                return Err(SendTimeoutError::Disconnected(message));
+            }
+            if state.queue.len() < state.capacity {
+                self.push(state, message);
+                return Ok(());
+            }
+            state = match deadline {
+                None => self.channel.not_full.wait(state).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(SendTimeoutError::Timeout(message));
+                    }
+                    self.channel.not_full.wait_timeout(state, left).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn push(&self, mut state: MutexGuard<'_, State<T>>, message: T) {
+        state.queue.push_back(message);
+        state.signal_selectors();
+        drop(state);
+        self.channel.not_empty.notify_one();
+    }
+
+    pub fn len(&self) -> usize {
+        self.channel.state.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Whether every receiver is gone, so sends can only fail.
+    pub fn is_disconnected(&self) -> bool {
+        self.channel.state.lock().unwrap().receivers == 0
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.channel.state.lock().unwrap().senders += 1;
+        Sender { channel: Arc::clone(&self.channel) }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.signal_selectors();
+            drop(state);
+            self.channel.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.channel.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(message) => {
+                self.popped(state);
+                Ok(message)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut state = self.channel.state.lock().unwrap();
+        loop {
+            if let Some(message) = state.queue.pop_front() {
+                self.popped(state);
+                return Ok(message);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match deadline {
+                None => self.channel.not_empty.wait(state).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.channel.not_empty.wait_timeout(state, left).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn popped(&self, state: MutexGuard<'_, State<T>>) {
+        drop(state);
+        self.channel.not_full.notify_one();
+    }
+
+    // Blocks for each message; ends when every sender is gone.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.recv().ok())
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.channel.state.lock().unwrap().receivers += 1;
+        Receiver { channel: Arc::clone(&self.channel) }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Nobody will ever take them, and they may hold things someone
+            // is waiting on, like the reply end of a request. Dropped
+            // outside the lock, since that runs their Drop.
+            let queued = std::mem::take(&mut state.queue);
+            drop(state);
+            self.channel.not_full.notify_all();
+            drop(queued);
+        }
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl<T> Debug for Sender<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Sender { .. }")
+    }
+}
+
+impl<T> Debug for Receiver<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Receiver { .. }")
+    }
+}
+
+// Raised by a channel when a select waiting on it should look again.
+pub struct Signal {
+    raised: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Signal {
+    fn raise(&self) {
+        *self.raised.lock().unwrap() = true;
+        self.condvar.notify_one();
+    }
+
+    // False if the deadline passed first.
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut raised = self.raised.lock().unwrap();
+        while !*raised {
+            raised = match deadline {
+                None => self.condvar.wait(raised).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return false;
+                    }
+                    self.condvar.wait_timeout(raised, left).unwrap().0
+                }
+            };
+        }
+        true
+    }
+}
+
+// A message as select! first sees it, before it knows the arm's type.
+pub type Taken = Result<Box<dyn Any + Send>, RecvError>;
+
+// What select! is built on; use the macro rather than this directly. Each
+// receiver is polled in turn, starting at a different one each time so an
+// always-busy receiver can't starve the others. A message is taken off its
+// channel as soon as it is seen, so another consumer can't snatch it
+// between select noticing it and the arm running.
+pub trait Selectable {
+    fn poll(&self) -> Option<Taken>;
+    fn watch(&self, signal: &Arc<Signal>);
+    fn unwatch(&self, signal: &Arc<Signal>);
+}
+
+impl<T: Send + 'static> Selectable for Receiver<T> {
+    fn poll(&self) -> Option<Taken> {
+        match self.try_recv() {
+            Ok(message) => Some(Ok(Box::new(message))),
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    fn watch(&self, signal: &Arc<Signal>) {
+        self.channel.state.lock().unwrap().selectors.push(Arc::clone(signal));
+    }
+
+    fn unwatch(&self, signal: &Arc<Signal>) {
+        self.channel.state.lock().unwrap().selectors.retain(|s| !Arc::ptr_eq(s, signal));
+    }
+}
+
+impl<T: 'static> Receiver<T> {
+    // Undoes the boxing in poll(), for select!.
+    pub fn unbox(&self, message: Taken) -> Result<T, RecvError> {
+        message.map(|m| *m.downcast::<T>().expect("select! passed a message to the wrong arm"))
+    }
+}
+
+pub struct Select<'a> {
+    receivers: Vec<&'a dyn Selectable>,
+}
+
+static NEXT_START: AtomicUsize = AtomicUsize::new(0);
+
+impl<'a> Select<'a> {
+    pub fn new() -> Select<'a> {
+        Select { receivers: Vec::new() }
+    }
+
+    pub fn recv(&mut self, receiver: &'a dyn Selectable) {
+        self.receivers.push(receiver);
+    }
+
+    // The index of the receiver that had a message (or was disconnected),
+    // and what it had; None if 'timeout' ran out first.
+    pub fn wait(&self, timeout: Option<Duration>) -> Option<(usize, Taken)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let start = NEXT_START.fetch_add(1, Ordering::Relaxed);
+        loop {
+            if let Some(ready) = self.poll_all(start) {
+                return Some(ready);
+            }
+            // Watch before the second look, so a message sent after it
+            // raises the signal instead of going unnoticed.
+            let signal = Arc::new(Signal { raised: Mutex::new(false), condvar: Condvar::new() });
+            self.receivers.iter().for_each(|r| r.watch(&signal));
+            let ready = self.poll_all(start);
+            let raised = ready.is_some() || signal.wait(deadline);
+            self.receivers.iter().for_each(|r| r.unwatch(&signal));
+            if ready.is_some() {
+                return ready;
+            }
+            if !raised {
+                return None;
+            }
+        }
+    }
+
+    // For select!, which numbers its arms as it goes.
+    pub fn is_arm(ready: usize, arm: &mut usize) -> bool {
+        *arm += 1;
+        ready == *arm - 1
+    }
+
+    fn poll_all(&self, start: usize) -> Option<(usize, Taken)> {
+        let n = self.receivers.len();
+        (0..n).map(|k| (start + k) % n).find_map(|i| self.receivers[i].poll().map(|message| (i, message)))
+    }
+}
+
+// Receiver expressions are evaluated more than once, so pass variables or
+// fields rather than calls that make a new receiver each time.
+macro_rules! select {
+    ($(recv($rx:expr) -> $message:pat => $body:expr),+ , default($timeout:expr) => $default:expr $(,)?) => {
+        $crate::concurrency::channel::select!(@run Some($timeout), $default; $(recv($rx) -> $message => $body),+)
+    };
+    ($(recv($rx:expr) -> $message:pat => $body:expr),+ $(,)?) => {
+        $crate::concurrency::channel::select!(@run None, unreachable!("select! without a timeout timed out"); $(recv($rx) -> $message => $body),+)
+    };
+    (@run $timeout:expr, $default:expr; $(recv($rx:expr) -> $message:pat => $body:expr),+) => {{
+        let mut select = $crate::concurrency::channel::Select::new();
+        $( select.recv(&$rx); )+
+        match select.wait($timeout) {
+            Some((ready, message)) => {
+                let mut arm = 0;
+                $(
+                    if $crate::concurrency::channel::Select::is_arm(ready, &mut arm) {
+                        let $message = $rx.unbox(message);
+                        $body
+                    } else
+                )+
+                { unreachable!("select! had no arm {ready}") }
+            }
+            None => $default,
+        }
+    }};
+}
+
+pub(crate) use select;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn sends_and_receives_in_order() {
+        let (sender, receiver) = bounded(4);
+        for n in 0..4 {
+            sender.send(n).unwrap();
+        }
+        assert_eq!(sender.len(), 4);
+        assert_eq!(sender.try_send(4), Err(TrySendError::Full(4)));
+        assert_eq!(sender.send_timeout(4, Duration::from_millis(10)), Err(SendTimeoutError::Timeout(4)));
+        assert_eq!((0..4).map(|_| receiver.recv().unwrap()).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert!(sender.is_empty());
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn full_channels_make_senders_wait() {
+        let (sender, receiver) = bounded(1);
+        sender.send(1).unwrap();
+        let waiting = thread::spawn(move || {
+            sender.send(2).unwrap();
+            "sent"
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiting.is_finished());
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(waiting.join().unwrap(), "sent");
+        assert_eq!(receiver.recv(), Ok(2));
+    }
+
+    #[test]
+    fn detects_disconnection_from_either_end() {
+        let (sender, receiver) = bounded(2);
+        let second = sender.clone();
+        sender.send("last words").unwrap();
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Ok("last words"));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        drop(second);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(receiver.recv(), Err(RecvError));
+
+        let (sender, receiver) = bounded(1);
+        sender.send(1).unwrap();
+        let blocked = thread::spawn(move || sender.send(2));
+        thread::sleep(Duration::from_millis(20));
+        // Dropping the only receiver wakes the waiting sender with its message.
+        drop(receiver);
+        assert_eq!(blocked.join().unwrap(), Err(SendError(2)));
+    }
+
+    #[test]
+    fn the_last_receiver_drops_what_is_queued() {
+        let (sender, receiver) = bounded(2);
+        let queued = Arc::new(());
+        sender.send(Arc::clone(&queued)).unwrap();
+        let other = receiver.clone();
+        drop(receiver);
+        assert_eq!(Arc::strong_count(&queued), 2);
+        drop(other);
+        assert_eq!(Arc::strong_count(&queued), 1);
+    }
+
+    #[test]
+    fn waiting_receivers_see_the_last_sender_leave() {
+        let (sender, receiver) = bounded::<u8>(1);
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || receiver.recv())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        drop(sender);
+        for waiting in waiting {
+            assert_eq!(waiting.join().unwrap(), Err(RecvError));
+        }
+    }
+
+    #[test]
+    fn stress_nothing_lost_nothing_duplicated() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const EACH: usize = 5_000;
+        let (sender, receiver) = bounded(16);
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for n in 0..EACH {
+                        sender.send(p * EACH + n).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(sender);
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || receiver.iter().collect::<Vec<_>>())
+            })
+            .collect();
+        drop(receiver);
+        producers.into_iter().for_each(|p| p.join().unwrap());
+
+        let mut seen = HashSet::new();
+        for consumer in consumers {
+            let received = consumer.join().unwrap();
+            // Each producer's messages arrive in the order it sent them.
+            for p in 0..PRODUCERS {
+                let own: Vec<_> = received.iter().filter(|&&n| n / EACH == p).collect();
+                assert!(own.windows(2).all(|w| w[0] < w[1]));
+            }
+            for n in received {
+                assert!(seen.insert(n), "{n} was delivered twice");
+            }
+        }
+        assert_eq!(seen.len(), PRODUCERS * EACH);
+    }
+
+    #[test]
+    fn select_takes_whichever_is_ready() {
+        let (numbers, number_rx) = bounded(1);
+        let (words, word_rx) = bounded(1);
+        words.send("ready").unwrap();
+        let got = select! {
+            recv(number_rx) -> n => format!("number {}", n.unwrap()),
+            recv(word_rx) -> w => format!("word {}", w.unwrap()),
+        };
+        assert_eq!(got, "word ready");
+
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            numbers.send(7).unwrap();
+            numbers
+        });
+        let got = select! {
+            recv(number_rx) -> n => format!("number {}", n.unwrap()),
+            recv(word_rx) -> w => format!("word {}", w.unwrap()),
+        };
+        assert_eq!(got, "number 7");
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn select_times_out_and_reports_disconnection() {
+        let (sender, receiver) = bounded::<u8>(1);
+        let got = select! {
+            recv(receiver) -> _ => "message",
+            default(Duration::from_millis(20)) => "timed out",
+        };
+        assert_eq!(got, "timed out");
+        drop(sender);
+        let got = select! {
+            recv(receiver) -> message => if message.is_err() { "disconnected" } else { "message" },
+            default(Duration::from_secs(5)) => "timed out",
+        };
+        assert_eq!(got, "disconnected");
+    }
+
+    #[test]
+    fn stress_select_across_channels() {
+        const EACH: usize = 5_000;
+        let (a, a_rx) = bounded(8);
+        let (b, b_rx) = bounded(8);
+        let producers = [(a, 0), (b, EACH)].map(|(sender, base)| {
+            thread::spawn(move || {
+                for n in base..base + EACH {
+                    sender.send(n).unwrap();
+                }
+            })
+        });
+        // Several threads selecting over the same pair of channels.
+        let selectors: Vec<_> = (0..3)
+            .map(|_| {
+                let (a_rx, b_rx) = (a_rx.clone(), b_rx.clone());
+                thread::spawn(move || {
+                    let mut got = Vec::new();
+                    let (mut a_open, mut b_open) = (true, true);
+                    while a_open || b_open {
+                        select! {
+                            recv(a_rx) -> n => match n {
+                                Ok(n) => got.push(n),
+                                // A disconnected channel is always ready;
+                                // stop looking at it.
+                                Err(_) => { a_open = false; if b_open { got.extend(b_rx.iter()); } b_open = false; }
+                            },
+                            recv(b_rx) -> n => match n {
+                                Ok(n) => got.push(n),
+                                Err(_) => { b_open = false; if a_open { got.extend(a_rx.iter()); } a_open = false; }
+                            },
+                        }
+                    }
+                    got
+                })
+            })
+            .collect();
+        drop((a_rx, b_rx));
+        producers.into_iter().for_each(|p| p.join().unwrap());
+
+        let mut seen = HashSet::new();
+        for selector in selectors {
+            for n in selector.join().unwrap() {
+                assert!(seen.insert(n), "{n} was delivered twice");
+            }
+        }
+        assert_eq!(seen.len(), 2 * EACH);
+    }
+}
@@ -0,0 +1,259 @@
+// Cron expressions: "minute hour day-of-month month day-of-week", each field
+// a '*', a number, a range 'a-b', a step '*/n' or 'a-b/n', or a list of
+// those separated by commas. Months and weekdays can also be given by name
+// (JAN-DEC, SUN-SAT); Sunday is 0 or 7. As in Vixie cron, when both day
+// fields are restricted a day matching either one will do, and a field
+// starting with '*' (like '*/2') doesn't count as restricted. Times are UTC.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    // Bit n set means value n matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Whether the day fields started with '*', for the either-day rule.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl Display for CronError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bad {} field: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+const MONTHS: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+// Give up looking for a match after this many years (e.g. "0 0 30 2 *").
+const SEARCH_YEARS: i64 = 8;
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Cron, CronError> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(CronError { field: "expression", message: format!("expected 5 fields, got {}", fields.len()) });
+        };
+        let weekdays = parse_field("day-of-week", weekday, 0, 7, &WEEKDAYS)?;
+        Ok(Cron {
+            minutes: parse_field("minute", minute, 0, 59, &[])?,
+            hours: parse_field("hour", hour, 0, 23, &[])?,
+            days: parse_field("day-of-month", day, 1, 31, &[])?,
+            months: parse_field("month", month, 1, 12, &MONTHS)?,
+            // 7 is another Sunday.
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+
+    // The first matching minute strictly after 'after', or None if there
+    // is none in the next few years.
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let seconds = after.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        let mut minute = seconds / 60 + 1;
+        let limit = minute + SEARCH_YEARS * 366 * 24 * 60;
+        while minute < limit {
+            let (days, in_day) = (minute.div_euclid(24 * 60), minute.rem_euclid(24 * 60));
+            let (year, month, day) = civil_from_days(days);
+            if !has(self.months, month) {
+                // Midnight on the first of next month.
+                let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                minute = days_from_civil(year, month, 1) * 24 * 60;
+            } else if !self.day_matches(days, day) {
+                minute = (days + 1) * 24 * 60;
+            } else if !has(self.hours, in_day / 60) {
+                minute = (minute / 60 + 1) * 60;
+            } else if !has(self.minutes, in_day % 60) {
+                minute += 1;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute as u64 * 60));
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, days: i64, day: i64) -> bool {
+        // 1970-01-01 was a Thursday.
+        let weekday = (days + 4).rem_euclid(7);
+        let by_day = has(self.days, day);
+        let by_weekday = has(self.weekdays, weekday);
+        match (self.any_day, self.any_weekday) {
+            (false, false) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        }
+    }
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(s: &str) -> Result<Cron, CronError> {
+        Cron::parse(s)
+    }
+}
+
+fn has(set: u64, value: i64) -> bool {
+    set & (1 << value) != 0
+}
+
+fn parse_field(field: &'static str, text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, CronError> {
+    let error = |message: String| CronError { field, message };
+    let value = |part: &str| -> Result<u32, CronError> {
+        if let Some(index) = names.iter().position(|name| name.eq_ignore_ascii_case(part)) {
+            return Ok(index as u32 + min);
+        }
+        let n: u32 = part.parse().map_err(|_| error(format!("'{part}' is not a number")))?;
+        if n < min || n > max {
+            return Err(error(format!("{n} is outside {min}-{max}")));
+        }
+        Ok(n)
+    };
+
+    let mut set = 0u64;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(error(format!("bad step '{step}'"))),
+            },
+            None => (item, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // "5/15" means from 5 to the end, every 15.
+                None if item.contains('/') => (value(range)?, max),
+                None => {
+                    let n = value(range)?;
+                    (n, n)
+                }
+            },
+        };
+        if first > last {
+            return Err(error(format!("range {first}-{last} runs backwards")));
+        }
+        for n in (first..=last).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+// Days since 1970-01-01 to (year, month, day), and back; Howard Hinnant's
+// algorithms for the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+pub(crate) fn utc(year: i64, month: i64, day: i64, hour: u64, minute: u64) -> SystemTime {
+    let days = days_from_civil(year, month, day) as u64;
+    UNIX_EPOCH + Duration::from_secs(days * 86_400 + hour * 3600 + minute * 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(expression: &str, after: SystemTime) -> SystemTime {
+        Cron::parse(expression).unwrap().next_after(after).unwrap()
+    }
+
+    #[test]
+    fn converts_between_days_and_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28), 2);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn steps_lists_and_ranges() {
+        let start = utc(2026, 10, 19, 10, 2);
+        assert_eq!(next("*/5 * * * *", start), utc(2026, 10, 19, 10, 5));
+        assert_eq!(next("*/5 * * * *", utc(2026, 10, 19, 10, 5)), utc(2026, 10, 19, 10, 10));
+        assert_eq!(next("0,30 9-17 * * *", start), utc(2026, 10, 19, 10, 30));
+        assert_eq!(next("0 9-17/4 * * *", start), utc(2026, 10, 19, 13, 0));
+        assert_eq!(next("10/20 * * * *", start), utc(2026, 10, 19, 10, 10));
+        assert_eq!(next("* * * * *", start), utc(2026, 10, 19, 10, 3));
+    }
+
+    #[test]
+    fn rolls_over_days_months_and_years() {
+        assert_eq!(next("30 2 * * *", utc(2026, 10, 19, 10, 2)), utc(2026, 10, 20, 2, 30));
+        assert_eq!(next("0 0 1 * *", utc(2026, 12, 15, 0, 0)), utc(2027, 1, 1, 0, 0));
+        assert_eq!(next("0 0 29 feb *", utc(2025, 1, 1, 0, 0)), utc(2028, 2, 29, 0, 0));
+        assert_eq!(next("0 0 31 * *", utc(2026, 4, 1, 0, 0)), utc(2026, 5, 31, 0, 0));
+    }
+
+    #[test]
+    fn weekdays_and_the_either_day_rule() {
+        // 2026-10-19 is a Monday.
+        assert_eq!(next("0 3 * * sun", utc(2026, 10, 19, 10, 2)), utc(2026, 10, 25, 3, 0));
+        assert_eq!(next("0 3 * * 7", utc(2026, 10, 19, 10, 2)), utc(2026, 10, 25, 3, 0));
+        assert_eq!(next("0 3 * * MON-FRI", utc(2026, 10, 23, 10, 0)), utc(2026, 10, 26, 3, 0));
+        // The 1st of the month or any Friday, whichever comes first.
+        assert_eq!(next("0 0 1 * 5", utc(2026, 10, 19, 10, 2)), utc(2026, 10, 23, 0, 0));
+        assert_eq!(next("0 0 1 * 5", utc(2026, 10, 30, 10, 2)), utc(2026, 11, 1, 0, 0));
+        // A day field starting with '*' leaves the weekday in charge: Mondays only.
+        assert_eq!(next("*/1 * * * 1", utc(2026, 10, 19, 10, 2)), utc(2026, 10, 19, 10, 3));
+        assert_eq!(next("*/1 * * * 1", utc(2026, 10, 20, 10, 2)), utc(2026, 10, 26, 0, 0));
+        assert_eq!(next("0 0 */1 * 1", utc(2026, 10, 19, 10, 2)), utc(2026, 10, 26, 0, 0));
+    }
+
+    #[test]
+    fn impossible_dates_never_come() {
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(utc(2026, 1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        let field = |expression: &str| Cron::parse(expression).unwrap_err().field;
+        assert_eq!(field("* * * *"), "expression");
+        assert_eq!(field("60 * * * *"), "minute");
+        assert_eq!(field("* 24 * * *"), "hour");
+        assert_eq!(field("* * 0 * *"), "day-of-month");
+        assert_eq!(field("* * * 13 *"), "month");
+        assert_eq!(field("* * * * 8"), "day-of-week");
+        assert_eq!(field("*/0 * * * *"), "minute");
+        assert_eq!(field("5-1 * * * *"), "minute");
+        assert_eq!(field("* * * jun-may *"), "month");
+        assert_eq!(field("x * * * *"), "minute");
+        assert_eq!("*/5 * * * *".parse::<Cron>().unwrap(), Cron::parse("0-59/5 * * * *").unwrap());
+    }
+}
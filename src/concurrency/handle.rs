@@ -0,0 +1,176 @@
+// What ThreadPool::submit hands back: the job's return value, or the reason
+// there isn't one, delivered over a one-shot channel once the job finishes.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+
+use super::cancel::CancelToken;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobError {
+    // The job panicked; this is its panic message.
+    Panicked(String),
+    // The job was dropped without running (the pool went away first), or its
+    // result has already been taken from this handle.
+    Lost,
+    // The job's cancel token was cancelled before it started.
+    Cancelled,
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::Panicked(message) => write!(f, "job panicked: {message}"),
+            JobError::Lost => write!(f, "job result lost"),
+            JobError::Cancelled => write!(f, "job cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+pub struct JobHandle<T> {
+    receiver: Receiver<Result<T, JobError>>,
+    // The worker may not have dropped its sender yet when we take the
+    // result, so a second take can't rely on seeing the channel closed.
+    taken: bool,
+}
+
+impl<T> JobHandle<T> {
+    // Blocks until the job has finished.
+    pub fn join(self) -> Result<T, JobError> {
+        self.receiver.recv().unwrap_or(Err(JobError::Lost))
+    }
+
+    // None while the job is queued or running.
+    pub fn try_get(&mut self) -> Option<Result<T, JobError>> {
+        if self.taken {
+            return Some(Err(JobError::Lost));
+        }
+        match self.receiver.try_recv() {
+            Ok(result) => self.take(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(JobError::Lost)),
+        }
+    }
+
+    // Like join, but gives up after 'timeout' and returns None; the handle
+    // can be waited on again.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        if self.taken {
+            return Some(Err(JobError::Lost));
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => self.take(result),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Err(JobError::Lost)),
+        }
+    }
+
+    fn take(&mut self, result: Result<T, JobError>) -> Option<Result<T, JobError>> {
+        self.taken = true;
+        Some(result)
+    }
+}
+
+// Wraps 'job' so that running it sends its result, or its panic, to the
+// returned handle. The panic stops here, so the worker thread lives on.
+pub(crate) fn wrap<T, F>(job: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let run = move || {
+        // Nobody may be waiting any more; that's fine.
+        let _ = sender.send(catch(job));
+    };
+    (run, JobHandle { receiver, taken: false })
+}
+
+// Like wrap, but skips the job if 'token' is cancelled before it starts.
+pub(crate) fn wrap_cancellable<T, F>(token: CancelToken, job: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    T: Send + 'static,
+    F: FnOnce(&CancelToken) -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let run = move || {
+        let result = if token.is_cancelled() { Err(JobError::Cancelled) } else { catch(|| job(&token)) };
+        let _ = sender.send(result);
+    };
+    (run, JobHandle { receiver, taken: false })
+}
+
+fn catch<T>(job: impl FnOnce() -> T) -> Result<T, JobError> {
+    panic::catch_unwind(AssertUnwindSafe(job)).map_err(|payload| JobError::Panicked(panic_message(&*payload)))
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::ThreadPool;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn join_returns_the_result() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<_> = (0..8u64).map(|i| pool.submit(move || i * i)).collect();
+        let squares: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(squares, [0, 1, 4, 9, 16, 25, 36, 49]);
+        pool.end();
+    }
+
+    #[test]
+    fn try_get_and_join_timeout_wait_for_the_job() {
+        let pool = ThreadPool::new(1);
+        let gate = Arc::new(Barrier::new(2));
+        let in_job = Arc::clone(&gate);
+        let mut handle = pool.submit(move || {
+            in_job.wait();
+            "done"
+        });
+
+        assert_eq!(handle.try_get(), None);
+        assert_eq!(handle.join_timeout(Duration::from_millis(20)), None);
+        gate.wait();
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)), Some(Ok("done")));
+        // The result can only be taken once.
+        assert_eq!(handle.try_get(), Some(Err(JobError::Lost)));
+        pool.end();
+    }
+
+    #[test]
+    fn panics_are_reported_and_the_worker_survives() {
+        let pool = ThreadPool::new(1);
+        let failed = pool.submit(|| -> u32 { panic!("job {} failed", 7) });
+        assert_eq!(failed.join(), Err(JobError::Panicked("job 7 failed".to_string())));
+        let static_message = pool.submit(|| -> u32 { panic!("boom") });
+        assert_eq!(static_message.join(), Err(JobError::Panicked("boom".to_string())));
+        // Same single worker, still there.
+        assert_eq!(pool.submit(|| 42).join(), Ok(42));
+        pool.end();
+    }
+
+    #[test]
+    fn handles_outlive_the_pool() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.submit(|| thread::current().id());
+        pool.end();
+        assert_ne!(handle.join().unwrap(), thread::current().id());
+    }
+}
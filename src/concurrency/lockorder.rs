@@ -0,0 +1,471 @@
+// A Mutex that checks lock order. Debug builds remember, for every pair of
+// locks one thread has held at once, which was taken first and where. As
+// soon as two threads (or one, at different times) take the same locks in
+// opposite orders, that's reported as a potential deadlock, with the places
+// each order was taken, whether or not the threads ever actually collided.
+// Release builds keep none of this; the wrapper is then just std's Mutex.
+//
+// Locks are named after where they were created. Reports go to stderr unless
+// set_reporter says otherwise; each pair of locks is reported once.
+//
+// The guards don't work with std's Condvar; use the Condvar from here, which
+// stops counting the lock as held while it waits.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{self, LazyLock, LockResult, PoisonError, TryLockError, TryLockResult, WaitTimeoutResult};
+use std::time::Duration;
+
+pub struct Mutex<T: ?Sized> {
+    tracked: Tracked,
+    inner: sync::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    // Before 'inner', so the lock stops counting as held before it's let go.
+    held: Held<'a>,
+    inner: sync::MutexGuard<'a, T>,
+}
+
+struct Held<'a>(&'a Tracked);
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        self.0.released();
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    fn new(tracked: &'a Tracked, inner: sync::MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        MutexGuard { held: Held(tracked), inner }
+    }
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex { tracked: Tracked::new(), inner: sync::Mutex::new(value) }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[track_caller]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        self.tracked.locking(Location::caller());
+        tracked_result(&self.tracked, self.inner.lock())
+    }
+
+    // Can't wait, so can't deadlock: held locks get no edge to this one.
+    #[track_caller]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let tracked = &self.tracked;
+        let guard = match self.inner.try_lock() {
+            Ok(inner) => Ok(MutexGuard::new(tracked, inner)),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                Err(TryLockError::Poisoned(PoisonError::new(MutexGuard::new(tracked, poisoned.into_inner()))))
+            }
+            Err(TryLockError::WouldBlock) => return Err(TryLockError::WouldBlock),
+        };
+        tracked.held(Location::caller());
+        guard
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+fn tracked_result<'a, T: ?Sized>(
+    tracked: &'a Tracked,
+    result: LockResult<sync::MutexGuard<'a, T>>,
+) -> LockResult<MutexGuard<'a, T>> {
+    match result {
+        Ok(inner) => Ok(MutexGuard::new(tracked, inner)),
+        Err(poisoned) => Err(PoisonError::new(MutexGuard::new(tracked, poisoned.into_inner()))),
+    }
+}
+
+// std's Condvar, for the guards above. Waiting lets go of the lock, and
+// taking it back on waking is checked like any other lock().
+#[derive(Debug, Default)]
+pub struct Condvar {
+    inner: sync::Condvar,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { inner: sync::Condvar::new() }
+    }
+
+    #[track_caller]
+    pub fn wait_while<'a, T, F>(&self, guard: MutexGuard<'a, T>, condition: F) -> LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let (tracked, inner) = guard.release();
+        let result = self.inner.wait_while(inner, condition);
+        tracked.locking(Location::caller());
+        tracked_result(tracked, result)
+    }
+
+    #[track_caller]
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let (tracked, inner) = guard.release();
+        let result = self.inner.wait_timeout(inner, timeout);
+        tracked.locking(Location::caller());
+        match result {
+            Ok((inner, timed_out)) => Ok((MutexGuard::new(tracked, inner), timed_out)),
+            Err(poisoned) => {
+                let (inner, timed_out) = poisoned.into_inner();
+                Err(PoisonError::new((MutexGuard::new(tracked, inner), timed_out)))
+            }
+        }
+    }
+
+    pub fn notify_all(&self) {
+        self.inner.notify_all();
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    // For a Condvar to wait on: the lock no longer counts as held.
+    fn release(self) -> (&'a Tracked, sync::MutexGuard<'a, T>) {
+        let MutexGuard { held, inner } = self;
+        let tracked = held.0;
+        drop(held);
+        (tracked, inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Edge {
+    // Where the locks were created, which is how we name them.
+    pub held: &'static Location<'static>,
+    pub acquired: &'static Location<'static>,
+    // Where each was locked.
+    pub held_at: &'static Location<'static>,
+    pub acquired_at: &'static Location<'static>,
+}
+
+// A cycle in the lock graph: each edge's 'acquired' lock is the next one's
+// 'held'. The last edge is the acquisition that closed it.
+#[derive(Debug, Clone)]
+pub struct PotentialDeadlock {
+    pub cycle: Vec<Edge>,
+}
+
+impl Display for PotentialDeadlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "potential deadlock: locks taken in inconsistent order")?;
+        for edge in &self.cycle {
+            writeln!(
+                f,
+                "  lock created at {} was held (locked at {}) while locking lock created at {} (at {})",
+                edge.held, edge.held_at, edge.acquired, edge.acquired_at
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type Reporter = Box<dyn Fn(&PotentialDeadlock) + Send + Sync>;
+
+static REPORTER: LazyLock<sync::RwLock<Reporter>> = LazyLock::new(|| sync::RwLock::new(Box::new(|report| eprint!("{report}"))));
+
+// Replaces printing to stderr, e.g. to panic or log instead.
+pub fn set_reporter(reporter: impl Fn(&PotentialDeadlock) + Send + Sync + 'static) {
+    *REPORTER.write().unwrap() = Box::new(reporter);
+}
+
+#[cfg(not(debug_assertions))]
+struct Tracked;
+
+#[cfg(not(debug_assertions))]
+impl Tracked {
+    fn new() -> Tracked {
+        Tracked
+    }
+
+    fn locking(&self, _: &'static Location<'static>) {}
+
+    fn held(&self, _: &'static Location<'static>) {}
+
+    fn released(&self) {}
+}
+
+#[cfg(debug_assertions)]
+struct Tracked {
+    id: usize,
+    created: &'static Location<'static>,
+}
+
+#[cfg(debug_assertions)]
+#[derive(Default)]
+struct Graph {
+    // edges[a][b]: b was locked while a was held, and where each was taken.
+    edges: HashMap<usize, HashMap<usize, Edge>>,
+    reported: HashSet<(usize, usize)>,
+}
+
+#[cfg(debug_assertions)]
+static GRAPH: LazyLock<sync::Mutex<Graph>> = LazyLock::new(Default::default);
+
+#[cfg(debug_assertions)]
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(debug_assertions)]
+thread_local! {
+    // The locks this thread holds, in the order it took them.
+    static HELD: RefCell<Vec<(usize, &'static Location<'static>, &'static Location<'static>)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(debug_assertions)]
+impl Tracked {
+    #[track_caller]
+    fn new() -> Tracked {
+        Tracked { id: NEXT_ID.fetch_add(1, Ordering::Relaxed), created: Location::caller() }
+    }
+
+    // Before blocking on the lock: records an edge from every lock we hold,
+    // and reports any that closes a cycle.
+    fn locking(&self, at: &'static Location<'static>) {
+        let held = HELD.with(|held| held.borrow().clone());
+        if !held.is_empty() {
+            let mut reports = Vec::new();
+            {
+                let mut graph = GRAPH.lock().unwrap_or_else(PoisonError::into_inner);
+                for &(id, created, held_at) in &held {
+                    let edge = Edge { held: created, acquired: self.created, held_at, acquired_at: at };
+                    if graph.edges.get(&id).is_some_and(|out| out.contains_key(&self.id)) {
+                        continue;
+                    }
+                    if let Some(mut cycle) = graph.path(self.id, id) {
+                        let pair = (id.min(self.id), id.max(self.id));
+                        if graph.reported.insert(pair) {
+                            cycle.push(edge.clone());
+                            reports.push(PotentialDeadlock { cycle });
+                        }
+                    }
+                    graph.edges.entry(id).or_default().insert(self.id, edge);
+                }
+            }
+            // Outside our own lock, in case the reporter takes tracked locks.
+            let reporter = REPORTER.read().unwrap_or_else(PoisonError::into_inner);
+            for report in &reports {
+                reporter(report);
+            }
+        }
+        self.held(at);
+    }
+
+    fn held(&self, at: &'static Location<'static>) {
+        HELD.with(|held| held.borrow_mut().push((self.id, self.created, at)));
+    }
+
+    fn released(&self) {
+        // Guards needn't be dropped in the order they were taken.
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(i) = held.iter().rposition(|&(id, _, _)| id == self.id) {
+                held.remove(i);
+            }
+        });
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut graph = GRAPH.lock().unwrap_or_else(PoisonError::into_inner);
+        graph.edges.remove(&self.id);
+        for out in graph.edges.values_mut() {
+            out.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Graph {
+    // The edges of some path from 'from' to 'to', or to itself if they're
+    // the same lock (taking a lock we already hold).
+    fn path(&self, from: usize, to: usize) -> Option<Vec<Edge>> {
+        if from == to {
+            return Some(Vec::new());
+        }
+        let mut came_from: HashMap<usize, usize> = HashMap::new();
+        let mut stack = vec![from];
+        while let Some(node) = stack.pop() {
+            for &next in self.edges.get(&node).into_iter().flat_map(|out| out.keys()) {
+                if next == from || came_from.contains_key(&next) {
+                    continue;
+                }
+                came_from.insert(next, node);
+                if next == to {
+                    let mut path = vec![];
+                    let mut at = to;
+                    while at != from {
+                        let prev = came_from[&at];
+                        path.push(self.edges[&prev][&at].clone());
+                        at = prev;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                stack.push(next);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Once};
+    use std::thread;
+
+    static REPORTS: sync::Mutex<Vec<PotentialDeadlock>> = sync::Mutex::new(Vec::new());
+
+    // Reports about locks created on 'line' of this file; other tests' locks
+    // live on other lines.
+    fn reports_for(line: u32) -> Vec<PotentialDeadlock> {
+        static COLLECT: Once = Once::new();
+        COLLECT.call_once(|| set_reporter(|report| REPORTS.lock().unwrap().push(report.clone())));
+        let reports = REPORTS.lock().unwrap();
+        reports.iter().filter(|r| r.cycle.iter().any(|e| e.held.file() == file!() && e.held.line() == line)).cloned().collect()
+    }
+
+    #[test]
+    fn consistent_order_is_fine() {
+        reports_for(0);
+        let (a, b) = (Mutex::new(1), Mutex::new(2));
+        let line = line!() - 1;
+        for _ in 0..3 {
+            let _a = a.lock().unwrap();
+            let _b = b.lock().unwrap();
+        }
+        // Nesting the other way round, but only via try_lock, can't block.
+        let _b = b.lock().unwrap();
+        let _a = a.try_lock().unwrap();
+        assert!(reports_for(line).is_empty());
+    }
+
+    #[test]
+    fn opposite_orders_on_two_threads_are_reported_with_both_sites() {
+        reports_for(0);
+        let a = Arc::new(Mutex::new("a"));
+        let b = Arc::new(Mutex::new("b"));
+        let line = line!() - 2;
+        {
+            let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+            // Run one after the other: no actual deadlock, just the risk.
+            thread::spawn(move || {
+                let _a = a.lock().unwrap();
+                let _b = b.lock().unwrap();
+            })
+            .join()
+            .unwrap();
+        }
+        let first_site = line + 8;
+        let _b = b.lock().unwrap();
+        let _a = a.lock().unwrap();
+        let second_site = line!() - 1;
+
+        let reports = reports_for(line);
+        assert_eq!(reports.len(), 1);
+        let cycle = &reports[0].cycle;
+        assert_eq!(cycle.len(), 2);
+        assert_eq!((cycle[0].held_at.line(), cycle[0].acquired_at.line()), (first_site - 1, first_site));
+        assert_eq!((cycle[1].held_at.line(), cycle[1].acquired_at.line()), (second_site - 1, second_site));
+        assert!(reports[0].to_string().contains(&format!("{}:{}", file!(), second_site)));
+    }
+
+    #[test]
+    fn longer_cycles_and_single_reports() {
+        reports_for(0);
+        let (a, b, c) = (Mutex::new(()), Mutex::new(()), Mutex::new(()));
+        let line = line!() - 1;
+        let lock_pair = |x: &Mutex<()>, y: &Mutex<()>| {
+            let _x = x.lock().unwrap();
+            let _y = y.lock().unwrap();
+        };
+        lock_pair(&a, &b);
+        lock_pair(&b, &c);
+        assert!(reports_for(line).is_empty());
+        lock_pair(&c, &a);
+        lock_pair(&c, &a);
+        let reports = reports_for(line);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].cycle.len(), 3);
+    }
+
+    #[test]
+    fn guards_released_out_of_order_and_dropped_locks() {
+        reports_for(0);
+        let (a, b) = (Mutex::new(0), Mutex::new(0));
+        let line = line!() - 1;
+        let ga = a.lock().unwrap();
+        let mut gb = b.lock().unwrap();
+        drop(ga);
+        *gb += 1;
+        drop(gb);
+        // Neither is held now, so this adds no edges at all.
+        let _a = a.lock().unwrap();
+        assert_eq!(a.try_lock().err().map(|e| matches!(e, TryLockError::WouldBlock)), Some(true));
+        drop(_a);
+        assert!(reports_for(line).is_empty());
+        assert_eq!(b.into_inner().unwrap(), 1);
+    }
+
+    #[test]
+    fn condvar_waits_give_the_lock_back_and_take_it_again() {
+        reports_for(0);
+        let (ready, b, changed) = (Arc::new(Mutex::new(false)), Mutex::new(()), Arc::new(Condvar::new()));
+        let line = line!() - 1;
+        {
+            let (ready, changed) = (Arc::clone(&ready), Arc::clone(&changed));
+            thread::spawn(move || {
+                *ready.lock().unwrap() = true;
+                changed.notify_all();
+            });
+        }
+        let guard = changed.wait_while(ready.lock().unwrap(), |ready| !*ready).unwrap();
+        let (guard, timeout) = changed.wait_timeout(guard, Duration::from_millis(10)).unwrap();
+        assert!(timeout.timed_out());
+        // The guard the wait handed back is held like any other.
+        let _b = b.lock().unwrap();
+        drop(guard);
+        assert!(reports_for(line).is_empty());
+        let _ready = ready.lock().unwrap();
+        assert_eq!(reports_for(line).len(), 1);
+    }
+}
@@ -0,0 +1,436 @@
+// A small model checker for lock-free code, in the spirit of loom. check(f)
+// runs f again and again, once for every way the threads it starts with
+// model::spawn can interleave at atomic operations, and fails with the
+// schedule if a run panics, deadlocks or spins forever. Code under test
+// uses the atomics from here in test builds; outside check() they behave
+// just like std's.
+//
+// Only one model thread runs at a time, and it hands over to the next at
+// every atomic operation, so unlike loom this only explores sequentially
+// consistent executions: it finds races between operations, not missing
+// fences or too-weak orderings.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::handle::panic_message;
+
+// A run taking more switches than this is reported as looping.
+const MAX_SWITCHES: usize = 10_000;
+
+struct Execution {
+    state: Mutex<State>,
+    turn: Condvar,
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+struct State {
+    // The model thread allowed to run.
+    running: usize,
+    threads: Vec<Status>,
+    // Choices to make again, from the previous run; after them we always
+    // take the first option.
+    replay: Vec<usize>,
+    // (chosen, out of) at every switch that had more than one option.
+    choices: Vec<(usize, usize)>,
+    switches: usize,
+    // Per thread: operations done other than spinning, and how much the
+    // others had done when it last spun with nobody else able to run.
+    work: Vec<usize>,
+    stalled: Vec<Option<usize>>,
+    failure: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Runnable,
+    // Can't get anywhere until some other thread does something.
+    Spinning,
+    Joining(usize),
+    Finished,
+}
+
+// Unwinds the other model threads once one has failed.
+struct Aborted;
+
+thread_local! {
+    static CURRENT: RefCell<Option<(Arc<Execution>, usize)>> = const { RefCell::new(None) };
+}
+
+fn current() -> Option<(Arc<Execution>, usize)> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// Runs 'f' under every interleaving of its model threads; returns how many
+// there were.
+pub fn check(f: impl Fn()) -> usize {
+    let mut replay = Vec::new();
+    let mut runs = 0;
+    loop {
+        runs += 1;
+        let choices = run_once(&f, replay);
+        // Next: the last choice that still has options left, moved on by one.
+        let mut choices = choices;
+        while let Some(&(chosen, options)) = choices.last() {
+            if chosen + 1 < options {
+                break;
+            }
+            choices.pop();
+        }
+        let Some(last) = choices.last_mut() else {
+            return runs;
+        };
+        last.0 += 1;
+        replay = choices.iter().map(|&(chosen, _)| chosen).collect();
+    }
+}
+
+fn run_once(f: &impl Fn(), replay: Vec<usize>) -> Vec<(usize, usize)> {
+    let execution = Arc::new(Execution {
+        state: Mutex::new(State {
+            running: 0,
+            threads: vec![Status::Runnable],
+            replay: replay.clone(),
+            choices: Vec::new(),
+            switches: 0,
+            work: vec![0],
+            stalled: vec![None],
+            failure: None,
+        }),
+        turn: Condvar::new(),
+        threads: Mutex::new(Vec::new()),
+    });
+    CURRENT.with(|current| *current.borrow_mut() = Some((Arc::clone(&execution), 0)));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    execution.finished(0, result.err());
+    // Let whatever's left finish, or give up along with everyone else.
+    {
+        let mut state = execution.state.lock().unwrap();
+        while state.failure.is_none() && state.threads.iter().any(|&s| s != Status::Finished) {
+            state = execution.turn.wait(state).unwrap();
+        }
+    }
+    for thread in execution.threads.lock().unwrap().drain(..) {
+        let _ = thread.join();
+    }
+    CURRENT.with(|current| *current.borrow_mut() = None);
+
+    let state = execution.state.lock().unwrap();
+    if let Some(failure) = &state.failure {
+        panic!("model check failed: {failure}\n  schedule: {:?}", state.choices.iter().map(|c| c.0).collect::<Vec<_>>());
+    }
+    state.choices.clone()
+}
+
+impl Execution {
+    // Marks 'me' as 'status' and lets the scheduler pick who runs next,
+    // then waits until that's us again (unless we've finished).
+    fn switch(&self, me: usize, status: Status) {
+        let mut state = self.state.lock().unwrap();
+        if state.failure.is_some() {
+            drop(state);
+            return abort();
+        }
+        state.threads[me] = status;
+        if status != Status::Spinning {
+            state.work[me] += 1;
+            // Someone did something; spinners may get somewhere now.
+            for s in state.threads.iter_mut().filter(|s| **s == Status::Spinning) {
+                *s = Status::Runnable;
+            }
+        }
+        let mut candidates: Vec<usize> = (0..state.threads.len())
+            .filter(|&t| match state.threads[t] {
+                Status::Runnable => true,
+                Status::Joining(other) => state.threads[other] == Status::Finished,
+                _ => false,
+            })
+            .collect();
+        if candidates.is_empty() && status == Status::Spinning {
+            // The others may have moved on after we last looked, so we get
+            // one more look; spinning again with nothing new is for good.
+            let others: usize = state.work.iter().sum::<usize>() - state.work[me];
+            if state.stalled[me] != Some(others) {
+                state.stalled[me] = Some(others);
+                candidates.push(me);
+            }
+        }
+
+        if candidates.is_empty() {
+            if state.threads.iter().any(|&s| s != Status::Finished) {
+                let stuck: Vec<_> = state.threads.iter().enumerate().filter(|(_, s)| **s != Status::Finished).collect();
+                state.failure = Some(format!("no thread can make progress: {stuck:?}"));
+            }
+            self.turn.notify_all();
+            drop(state);
+            if status != Status::Finished {
+                abort();
+            }
+            return;
+        }
+
+        state.switches += 1;
+        if state.switches > MAX_SWITCHES {
+            state.failure = Some(format!("still running after {MAX_SWITCHES} switches"));
+            self.turn.notify_all();
+            drop(state);
+            return abort();
+        }
+        let choice = if candidates.len() == 1 {
+            0
+        } else {
+            let step = state.choices.len();
+            let choice = state.replay.get(step).copied().unwrap_or(0);
+            assert!(choice < candidates.len(), "the model ran differently when replayed; is it deterministic?");
+            state.choices.push((choice, candidates.len()));
+            choice
+        };
+        let next = candidates[choice];
+        state.threads[next] = Status::Runnable;
+        state.running = next;
+        self.turn.notify_all();
+        if status != Status::Finished {
+            self.wait_turn(state, me);
+        }
+    }
+
+    fn wait_turn(&self, mut state: std::sync::MutexGuard<'_, State>, me: usize) {
+        while state.running != me && state.failure.is_none() {
+            state = self.turn.wait(state).unwrap();
+        }
+        if state.failure.is_some() {
+            drop(state);
+            abort();
+        }
+    }
+
+    fn finished(&self, me: usize, panic: Option<Box<dyn Any + Send>>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(payload) = panic
+                && !payload.is::<Aborted>()
+            {
+                state.failure.get_or_insert_with(|| format!("thread {me} panicked: {}", panic_message(&*payload)));
+            }
+            if state.failure.is_some() {
+                state.threads[me] = Status::Finished;
+                self.turn.notify_all();
+                return;
+            }
+        }
+        self.switch(me, Status::Finished);
+    }
+}
+
+fn abort() {
+    // Already unwinding (a Drop touching an atomic): let that carry on.
+    if !thread::panicking() {
+        panic::resume_unwind(Box::new(Aborted));
+    }
+}
+
+fn switch(status: Status) {
+    if thread::panicking() {
+        return;
+    }
+    if let Some((execution, me)) = current() {
+        execution.switch(me, status);
+    }
+}
+
+// Marks a spin-wait: in a model run the scheduler won't pick this thread
+// again until another one has done something.
+pub fn spin() {
+    match current() {
+        Some(_) => switch(Status::Spinning),
+        None => thread::yield_now(),
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: usize,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+// Starts a model thread; only for use inside check().
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (execution, me) = current().expect("model::spawn outside model::check");
+    let id = {
+        let mut state = execution.state.lock().unwrap();
+        state.threads.push(Status::Runnable);
+        state.work.push(0);
+        state.stalled.push(None);
+        state.threads.len() - 1
+    };
+    let result = Arc::new(Mutex::new(None));
+    let slot = Arc::clone(&result);
+    let inner = Arc::clone(&execution);
+    let thread = thread::spawn(move || {
+        CURRENT.with(|current| *current.borrow_mut() = Some((Arc::clone(&inner), id)));
+        let started = panic::catch_unwind(AssertUnwindSafe(|| {
+            inner.wait_turn(inner.state.lock().unwrap(), id);
+            *slot.lock().unwrap() = Some(f());
+        }));
+        inner.finished(id, started.err());
+    });
+    execution.threads.lock().unwrap().push(thread);
+    execution.switch(me, Status::Runnable);
+    JoinHandle { id, result }
+}
+
+impl<T> JoinHandle<T> {
+    pub fn join(self) -> T {
+        switch(Status::Joining(self.id));
+        self.result.lock().unwrap().take().expect("model thread finished without a result")
+    }
+}
+
+// Atomics that hand over to another model thread before every operation.
+// The orderings are passed on, but runs are sequentially consistent anyway.
+
+// Only the queue's model tests use this, and only with --cfg model.
+#[cfg_attr(not(model), allow(dead_code))]
+#[derive(Debug, Default)]
+pub struct AtomicPtr<T>(atomic::AtomicPtr<T>);
+
+#[cfg_attr(not(model), allow(dead_code))]
+impl<T> AtomicPtr<T> {
+    pub const fn new(p: *mut T) -> AtomicPtr<T> {
+        AtomicPtr(atomic::AtomicPtr::new(p))
+    }
+
+    pub fn load(&self, order: Ordering) -> *mut T {
+        switch(Status::Runnable);
+        self.0.load(order)
+    }
+
+    pub fn store(&self, p: *mut T, order: Ordering) {
+        switch(Status::Runnable);
+        self.0.store(p, order)
+    }
+
+    pub fn swap(&self, p: *mut T, order: Ordering) -> *mut T {
+        switch(Status::Runnable);
+        self.0.swap(p, order)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AtomicUsize(atomic::AtomicUsize);
+
+impl AtomicUsize {
+    pub const fn new(n: usize) -> AtomicUsize {
+        AtomicUsize(atomic::AtomicUsize::new(n))
+    }
+
+    pub fn load(&self, order: Ordering) -> usize {
+        switch(Status::Runnable);
+        self.0.load(order)
+    }
+
+    pub fn store(&self, n: usize, order: Ordering) {
+        switch(Status::Runnable);
+        self.0.store(n, order)
+    }
+
+    pub fn fetch_add(&self, n: usize, order: Ordering) -> usize {
+        switch(Status::Runnable);
+        self.0.fetch_add(n, order)
+    }
+
+    pub fn compare_exchange(&self, current: usize, new: usize, success: Ordering, failure: Ordering) -> Result<usize, usize> {
+        switch(Status::Runnable);
+        self.0.compare_exchange(current, new, success, failure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(f: impl Fn()) -> String {
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| check(f))).unwrap_err();
+        panic_message(&*payload)
+    }
+
+    #[test]
+    fn explores_every_interleaving() {
+        // Two threads, each storing then loading. Every outcome an
+        // interleaving allows must turn up; (2, 1) can't, as each would
+        // have had to store after the other.
+        let seen = Arc::new(Mutex::new(std::collections::HashSet::new()));
+        let record = Arc::clone(&seen);
+        let runs = check(move || {
+            let x = Arc::new(AtomicUsize::new(0));
+            let (a, b) = (Arc::clone(&x), Arc::clone(&x));
+            let first = spawn(move || {
+                a.store(1, Ordering::SeqCst);
+                a.load(Ordering::SeqCst)
+            });
+            let second = spawn(move || {
+                b.store(2, Ordering::SeqCst);
+                b.load(Ordering::SeqCst)
+            });
+            record.lock().unwrap().insert((first.join(), second.join()));
+        });
+        assert!(runs > 1);
+        let mut seen: Vec<_> = seen.lock().unwrap().iter().copied().collect();
+        seen.sort();
+        assert_eq!(seen, [(1, 1), (1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn finds_lost_updates() {
+        // Outside the model this rarely goes wrong; inside, some schedule
+        // always has both threads load before either stores.
+        let message = failure(|| {
+            let counter = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let counter = Arc::clone(&counter);
+                    spawn(move || {
+                        let n = counter.load(Ordering::SeqCst);
+                        counter.store(n + 1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            threads.into_iter().for_each(JoinHandle::join);
+            assert_eq!(counter.load(Ordering::SeqCst), 2, "lost an update");
+        });
+        assert!(message.contains("lost an update"), "{message}");
+        assert!(message.contains("schedule"), "{message}");
+    }
+
+    #[test]
+    fn finds_threads_that_spin_forever() {
+        let message = failure(|| {
+            let flag = Arc::new(AtomicUsize::new(0));
+            let waiter = Arc::clone(&flag);
+            let handle = spawn(move || {
+                while waiter.load(Ordering::SeqCst) == 0 {
+                    spin();
+                }
+            });
+            // Forgot to set the flag.
+            handle.join();
+        });
+        assert!(message.contains("no thread can make progress"), "{message}");
+    }
+
+    #[test]
+    fn atomics_work_outside_the_model() {
+        let x = AtomicUsize::new(1);
+        assert_eq!(x.fetch_add(1, Ordering::SeqCst), 1);
+        assert_eq!(x.compare_exchange(2, 5, Ordering::SeqCst, Ordering::SeqCst), Ok(2));
+        spin();
+        assert_eq!(x.load(Ordering::SeqCst), 5);
+    }
+}
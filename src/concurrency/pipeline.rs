@@ -0,0 +1,573 @@
+// Staged pipelines over bounded channels (channel.rs), for the jobs that
+// used to be chains of thread::spawn and mpsc by hand:
+//
+//     let totals = Pipeline::from_iter(paths)
+//         .flat_map(read_lines).workers(4)
+//         .try_map(parse_record).workers(8).buffer(64).ordered()
+//         .batch(100)
+//         .map(store_batch)
+//         .collect()?;
+//
+// Each stage runs on threads of its own and hands its items to the next
+// through a bounded channel, so a slow stage holds back the ones before it
+// instead of letting work pile up. workers(), buffer() and ordered() set up
+// the stage added last: with several workers a stage fans its input out
+// over them and back in again, and ordered() has it pass its results on in
+// the order the inputs came. Pipeline::merge fans several pipelines in.
+//
+// Nothing runs until collect or for_each. The first error or panic in any
+// stage, or a cancelled token (cancel_on), stops every stage and is what
+// collect and for_each return. Stages notice within POLL; a source
+// iterator stuck waiting inside next() keeps its thread until it returns.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::cancel::CancelToken;
+use super::channel::{self, Receiver};
+use super::handle::panic_message;
+use super::sync::Semaphore;
+
+// How often a waiting stage looks for a reason to stop.
+const POLL: Duration = Duration::from_millis(20);
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    // A try_map stage returned this error.
+    Failed(BoxError),
+    // A stage panicked; this is its panic message.
+    Panicked(String),
+    // A token given to cancel_on was cancelled.
+    Cancelled,
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::Failed(e) => write!(f, "pipeline stage failed: {e}"),
+            PipelineError::Panicked(message) => write!(f, "pipeline stage panicked: {message}"),
+            PipelineError::Cancelled => write!(f, "pipeline cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PipelineError::Failed(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    workers: usize,
+    buffer: usize,
+    ordered: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings { workers: 1, buffer: 16, ordered: false }
+    }
+}
+
+// What the threads of one run share.
+struct Shared {
+    // Cancelled on the first failure.
+    stop: CancelToken,
+    tokens: Vec<CancelToken>,
+    error: Mutex<Option<PipelineError>>,
+    // Stage threads still running.
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl Shared {
+    fn stopped(&self) -> bool {
+        self.stop.is_cancelled() || self.tokens.iter().any(CancelToken::is_cancelled)
+    }
+
+    // The first failure is the one reported.
+    fn fail(&self, error: PipelineError) {
+        self.error.lock().unwrap().get_or_insert(error);
+        self.stop.cancel();
+    }
+
+    // None once the sending stage is done or the pipeline is stopping.
+    fn recv<T>(&self, input: &Receiver<T>) -> Option<T> {
+        loop {
+            if self.stopped() {
+                return None;
+            }
+            match input.recv_timeout(POLL) {
+                Ok(item) => return Some(item),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    // Stage threads are left to finish by themselves: once the pipeline
+    // stops they run out of input or of anyone to send to.
+    fn spawn(self: &Arc<Self>, name: String, body: impl FnOnce(&Shared) + Send + 'static) {
+        let shared = Arc::clone(self);
+        *self.running.lock().unwrap() += 1;
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| body(&shared))) {
+                    shared.fail(PipelineError::Panicked(panic_message(&*payload)));
+                }
+                *shared.running.lock().unwrap() -= 1;
+                shared.finished.notify_all();
+            })
+            .expect("can't spawn a pipeline thread");
+    }
+
+    fn outcome(&self) -> Result<(), PipelineError> {
+        if !self.stopped() {
+            // The output ran dry, so every stage is done or about to be; but
+            // a panicking one lets go of its channels while unwinding, before
+            // it gets to report the panic.
+            let running = self.running.lock().unwrap();
+            drop(self.finished.wait_while(running, |running| *running > 0).unwrap());
+        }
+        match self.error.lock().unwrap().take() {
+            Some(error) => Err(error),
+            None if self.stopped() => Err(PipelineError::Cancelled),
+            None => Ok(()),
+        }
+    }
+}
+
+// Items travel with their position in the stream, which ordered stages
+// put them back in.
+type Build<T> = Box<dyn FnOnce(&Arc<Shared>, Settings) -> Receiver<(u64, T)>>;
+
+pub struct Pipeline<T> {
+    build: Build<T>,
+    // For the stage added last.
+    settings: Settings,
+    tokens: Vec<CancelToken>,
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    pub fn from_iter<I>(source: I) -> Pipeline<T>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        let source = source.into_iter();
+        let build: Build<T> = Box::new(move |shared, settings| {
+            let (output, receiver) = channel::bounded(settings.buffer);
+            shared.spawn("pipeline-source".to_string(), move |shared| {
+                for item in (0..).zip(source) {
+                    if shared.stopped() || output.send(item).is_err() {
+                        return;
+                    }
+                }
+            });
+            receiver
+        });
+        Pipeline { build, settings: Settings::default(), tokens: Vec::new() }
+    }
+
+    // Fan-in: the items of every pipeline, in the order they come.
+    pub fn merge(pipelines: impl IntoIterator<Item = Pipeline<T>>) -> Pipeline<T> {
+        let pipelines: Vec<_> = pipelines.into_iter().collect();
+        let tokens = pipelines.iter().flat_map(|p| p.tokens.iter().cloned()).collect();
+        let build: Build<T> = Box::new(move |shared, settings| {
+            let (output, receiver) = channel::bounded(settings.buffer);
+            // Numbered as they go in, under the lock, so the numbers stay in
+            // step with the channel.
+            let output = Arc::new(Mutex::new((output, 0)));
+            for (i, pipeline) in pipelines.into_iter().enumerate() {
+                let input = (pipeline.build)(shared, pipeline.settings);
+                let output = Arc::clone(&output);
+                shared.spawn(format!("pipeline-merge-{i}"), move |shared| {
+                    while let Some((_, item)) = shared.recv(&input) {
+                        let mut output = output.lock().unwrap();
+                        let (sender, next) = &mut *output;
+                        if sender.send((*next, item)).is_err() {
+                            return;
+                        }
+                        *next += 1;
+                    }
+                });
+            }
+            receiver
+        });
+        Pipeline { build, settings: Settings::default(), tokens }
+    }
+
+    // Threads for the last stage; batch and window always use one.
+    pub fn workers(mut self, workers: usize) -> Pipeline<T> {
+        assert!(workers > 0, "a stage needs at least one worker");
+        self.settings.workers = workers;
+        self
+    }
+
+    // Room for this many items between the last stage and the next.
+    pub fn buffer(mut self, items: usize) -> Pipeline<T> {
+        assert!(items > 0, "a stage needs room for at least one item");
+        self.settings.buffer = items;
+        self
+    }
+
+    // The last stage passes its results on in input order, holding back
+    // the ones that finish early. It lets at most workers + buffer inputs
+    // in at a time, so one slow item can't make it hold back everything.
+    pub fn ordered(mut self) -> Pipeline<T> {
+        self.settings.ordered = true;
+        self
+    }
+
+    pub fn cancel_on(mut self, token: &CancelToken) -> Pipeline<T> {
+        self.tokens.push(token.clone());
+        self
+    }
+
+    pub fn map<U, F>(self, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: Fn(T) -> U + Send + Sync + 'static,
+    {
+        self.stage("map", move |item, out| {
+            out.push(f(item));
+            Ok(())
+        })
+    }
+
+    // A map that can fail; the first error stops the pipeline.
+    pub fn try_map<U, E, F>(self, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        E: Into<BoxError>,
+        F: Fn(T) -> Result<U, E> + Send + Sync + 'static,
+    {
+        self.stage("try-map", move |item, out| {
+            out.push(f(item).map_err(Into::into)?);
+            Ok(())
+        })
+    }
+
+    pub fn filter<F>(self, f: F) -> Pipeline<T>
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.stage("filter", move |item, out| {
+            if f(&item) {
+                out.push(item);
+            }
+            Ok(())
+        })
+    }
+
+    pub fn flat_map<U, I, F>(self, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        I: IntoIterator<Item = U>,
+        F: Fn(T) -> I + Send + Sync + 'static,
+    {
+        self.stage("flat-map", move |item, out| {
+            out.extend(f(item));
+            Ok(())
+        })
+    }
+
+    // Groups of 'size' items, the last one possibly smaller.
+    pub fn batch(self, size: usize) -> Pipeline<Vec<T>> {
+        assert!(size > 0, "batches need at least one item");
+        self.group("batch", size, None)
+    }
+
+    // What arrives within 'period' of a group's first item; never empty.
+    pub fn window(self, period: Duration) -> Pipeline<Vec<T>> {
+        self.group("window", usize::MAX, Some(period))
+    }
+
+    // Runs the pipeline and hands every item that comes out of it to 'f',
+    // on this thread.
+    pub fn for_each(self, mut f: impl FnMut(T)) -> Result<(), PipelineError> {
+        let shared = Arc::new(Shared {
+            stop: CancelToken::new(),
+            tokens: self.tokens,
+            error: Mutex::new(None),
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        });
+        let output = (self.build)(&shared, self.settings);
+        while let Some((_, item)) = shared.recv(&output) {
+            f(item);
+        }
+        shared.outcome()
+    }
+
+    pub fn collect(self) -> Result<Vec<T>, PipelineError> {
+        let mut items = Vec::new();
+        self.for_each(|item| items.push(item))?;
+        Ok(items)
+    }
+
+    // Adds a stage where 'work' turns each input into any number of
+    // outputs. Workers send what they make to a sequencer thread, which
+    // numbers it for the next stage, in input order if asked to.
+    fn stage<U, F>(self, name: &'static str, work: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: Fn(T, &mut Vec<U>) -> Result<(), BoxError> + Send + Sync + 'static,
+    {
+        let Pipeline { build, settings: upstream, tokens } = self;
+        let build: Build<U> = Box::new(move |shared, settings| {
+            let input = build(shared, upstream);
+            let work = Arc::new(work);
+            let (done, results) = channel::bounded::<(u64, Vec<U>)>(settings.buffer);
+            let (output, receiver) = channel::bounded(settings.buffer);
+            let window = (settings.ordered && settings.workers > 1)
+                .then(|| Arc::new(Semaphore::new(settings.workers + settings.buffer)));
+
+            for i in 0..settings.workers {
+                let (input, done, work, window) = (input.clone(), done.clone(), Arc::clone(&work), window.clone());
+                shared.spawn(format!("pipeline-{name}-{i}"), move |shared| loop {
+                    // Returned by the sequencer once it has passed the results on.
+                    let permit = match &window {
+                        Some(window) => match acquire(window, shared) {
+                            Some(permit) => Some(permit),
+                            None => return,
+                        },
+                        None => None,
+                    };
+                    let Some((seq, item)) = shared.recv(&input) else { return };
+                    let mut out = Vec::new();
+                    if let Err(e) = work(item, &mut out) {
+                        shared.fail(PipelineError::Failed(e));
+                        return;
+                    }
+                    if let Some(permit) = permit {
+                        permit.forget();
+                    }
+                    if done.send((seq, out)).is_err() {
+                        return;
+                    }
+                });
+            }
+            drop(done);
+
+            shared.spawn(format!("pipeline-{name}-out"), move |shared| {
+                let mut held = BTreeMap::new();
+                let (mut next_in, mut next_out) = (0, 0);
+                while let Some((seq, out)) = shared.recv(&results) {
+                    // Unordered results go on as they come.
+                    held.insert(if settings.ordered { seq } else { next_in }, out);
+                    while let Some(out) = held.remove(&next_in) {
+                        next_in += 1;
+                        if let Some(window) = &window {
+                            window.add_permits(1);
+                        }
+                        for item in out {
+                            if output.send((next_out, item)).is_err() {
+                                return;
+                            }
+                            next_out += 1;
+                        }
+                    }
+                }
+            });
+            receiver
+        });
+        Pipeline { build, settings: Settings::default(), tokens }
+    }
+
+    // A one-thread stage that groups items by count or by time.
+    fn group(self, name: &'static str, size: usize, period: Option<Duration>) -> Pipeline<Vec<T>> {
+        let Pipeline { build, settings: upstream, tokens } = self;
+        let build: Build<Vec<T>> = Box::new(move |shared, settings| {
+            let input = build(shared, upstream);
+            let (output, receiver) = channel::bounded(settings.buffer);
+            shared.spawn(format!("pipeline-{name}"), move |shared| {
+                let mut group = Vec::new();
+                let mut due = None;
+                let mut next = 0;
+                let mut flush = |group: &mut Vec<T>| {
+                    let sent = output.send((next, std::mem::take(group))).is_ok();
+                    next += 1;
+                    sent
+                };
+                while !shared.stopped() {
+                    let now = Instant::now();
+                    if due.is_some_and(|due| due <= now) {
+                        due = None;
+                        if !flush(&mut group) {
+                            return;
+                        }
+                    }
+                    let wait = due.map_or(POLL, |due: Instant| due.saturating_duration_since(now).min(POLL));
+                    match input.recv_timeout(wait) {
+                        Ok((_, item)) => {
+                            if group.is_empty() {
+                                due = period.map(|period| now + period);
+                            }
+                            group.push(item);
+                            if group.len() == size {
+                                due = None;
+                                if !flush(&mut group) {
+                                    return;
+                                }
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => {
+                            if !group.is_empty() {
+                                flush(&mut group);
+                            }
+                            return;
+                        }
+                    }
+                }
+            });
+            receiver
+        });
+        Pipeline { build, settings: Settings::default(), tokens }
+    }
+}
+
+fn acquire<'a>(window: &'a Semaphore, shared: &Shared) -> Option<super::sync::Permit<'a>> {
+    while !shared.stopped() {
+        if let Some(permit) = window.acquire_timeout(POLL) {
+            return Some(permit);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn chains_stages_in_order() {
+        let words = Pipeline::from_iter(1..=20)
+            .filter(|n| n % 2 == 0)
+            .map(|n| n * 10)
+            .workers(4)
+            .ordered()
+            .flat_map(|n| [n, n + 1])
+            .workers(3)
+            .buffer(2)
+            .ordered()
+            .map(|n| n.to_string())
+            .collect()
+            .unwrap();
+        let expected: Vec<String> = (1..=10).flat_map(|n| [n * 20, n * 20 + 1]).map(|n| n.to_string()).collect();
+        assert_eq!(words, expected);
+    }
+
+    #[test]
+    fn workers_share_a_stage() {
+        let started = Instant::now();
+        let mut squares = Pipeline::from_iter(0..20u64)
+            .map(|n| {
+                thread::sleep(Duration::from_millis(20));
+                n * n
+            })
+            .workers(5)
+            .collect()
+            .unwrap();
+        // Five at a time: about 80ms rather than 400.
+        assert!(started.elapsed() < Duration::from_millis(300), "{:?}", started.elapsed());
+        squares.sort();
+        assert_eq!(squares, (0..20).map(|n| n * n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ordered_stages_wait_for_slow_items() {
+        let items = Pipeline::from_iter(0..30)
+            .map(|n| {
+                // The first item of every ten is slow.
+                thread::sleep(Duration::from_millis(if n % 10 == 0 { 30 } else { 1 }));
+                n
+            })
+            .workers(4)
+            .buffer(2)
+            .ordered()
+            .collect()
+            .unwrap();
+        assert_eq!(items, (0..30).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn batches_and_windows() {
+        let batches = Pipeline::from_iter(1..=7).batch(3).collect().unwrap();
+        assert_eq!(batches, [vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+
+        // Two bursts, far enough apart to land in separate windows.
+        let windows = Pipeline::from_iter(0..6)
+            .map(|n| {
+                if n == 3 {
+                    thread::sleep(Duration::from_millis(150));
+                }
+                n
+            })
+            .window(Duration::from_millis(50))
+            .collect()
+            .unwrap();
+        assert_eq!(windows, [vec![0, 1, 2], vec![3, 4, 5]]);
+    }
+
+    #[test]
+    fn merges_pipelines() {
+        let evens = Pipeline::from_iter((0..50).map(|n| n * 2));
+        let odds = Pipeline::from_iter(0..100).filter(|n| n % 2 == 1);
+        let mut all = Pipeline::merge([evens, odds]).map(|n| n + 1).workers(2).collect().unwrap();
+        all.sort();
+        assert_eq!(all, (1..=100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_first_error_stops_everything() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&pulled);
+        // An endless source: only stopping the pipeline ends this test.
+        let result = Pipeline::from_iter((0..).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }))
+        .try_map(|n: u64| if n == 100 { Err(format!("bad record {n}")) } else { Ok(n) })
+        .workers(2)
+        .collect();
+        match result {
+            Err(PipelineError::Failed(e)) => assert_eq!(e.to_string(), "bad record 100"),
+            other => panic!("{other:?}"),
+        }
+        thread::sleep(POLL * 3);
+        let after_stop = pulled.load(Ordering::SeqCst);
+        thread::sleep(POLL * 3);
+        assert_eq!(pulled.load(Ordering::SeqCst), after_stop);
+    }
+
+    #[test]
+    fn panics_and_cancellation_are_reported() {
+        let result = Pipeline::from_iter(0..10).map(|n| if n == 5 { panic!("stage {n} broke") } else { n }).collect();
+        assert!(matches!(result, Err(PipelineError::Panicked(ref m)) if m == "stage 5 broke"), "{result:?}");
+
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let mut seen = 0;
+        let result = Pipeline::from_iter(0..).cancel_on(&token).for_each(|_: u64| {
+            seen += 1;
+            if seen == 50 {
+                canceller.cancel();
+            }
+        });
+        assert!(matches!(result, Err(PipelineError::Cancelled)), "{result:?}");
+    }
+}
@@ -0,0 +1,734 @@
+// A work-stealing thread pool. Every worker has its own deque; jobs added
+// from outside the pool go to a shared injector queue, jobs added by a job
+// running on a worker go to that worker's deque. A worker takes from the
+// back of its own deque (the newest job, whose data is likely still in
+// cache), then from the injector, then steals from the front of the others'
+// deques. Each queue has its own lock, so workers mostly take uncontended
+// locks instead of all queueing up on one.
+//
+// A panicking job doesn't take its worker down: the panic is caught and
+// passed to the pool's panic hook. Should a worker die anyway (the hook
+// itself panicking, say), a new one takes its place.
+//
+// A bounded pool holds at most 'capacity' jobs that haven't started yet;
+// add() waits for room, try_add() and add_timeout() give the job back.
+//
+// This is the crate's one pool: the tcp server runs its connections on it
+// too. ThreadPool::builder() sets everything; new() and bounded() are
+// shorthands for the common cases.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::handle::{self, panic_message, JobHandle};
+
+// How many times an idle worker looks for work again before it sleeps.
+const SPINS: usize = 16;
+
+pub(super) type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Called with the worker's index and the panic message.
+type PanicHook = Arc<dyn Fn(usize, &str) + Send + Sync>;
+
+pub struct ThreadPool {
+    pub(super) shared: Arc<Shared>,
+}
+
+pub(super) struct Shared {
+    injector: Mutex<VecDeque<Job>>,
+    locals: Vec<Mutex<VecDeque<Job>>>,
+    // Workers about to wait for a job. Adding a job only takes 'wakeups'
+    // when this is non-zero, so a busy pool never touches it.
+    sleepers: AtomicUsize,
+    // Wakeups handed out but not yet taken, never more than there are
+    // sleepers, so a burst of jobs doesn't signal the same sleeper over and
+    // over.
+    wakeups: Mutex<usize>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+    panic_hook: PanicHook,
+    // One per worker; a replacement worker puts its handle where the dead
+    // one's was.
+    handles: Mutex<Vec<Option<thread::JoinHandle<()>>>>,
+    respawned: AtomicUsize,
+    capacity: Option<usize>,
+    // Jobs waiting to start; only kept for bounded pools.
+    queued: Mutex<usize>,
+    space: Condvar,
+    rejected: AtomicUsize,
+    // For spawning replacements the same way as the originals.
+    name: String,
+    stack_size: Option<usize>,
+}
+
+// Defaults: a worker per CPU, named "pool-worker-N", the standard stack
+// size, no bound, and a panic hook that does nothing more than the
+// standard panic message already did.
+pub struct Builder {
+    size: usize,
+    name: String,
+    stack_size: Option<usize>,
+    capacity: Option<usize>,
+    panic_hook: PanicHook,
+}
+
+thread_local! {
+    // The pool this thread works for, and its index there.
+    static WORKER: Cell<Option<(*const Shared, usize)>> = const { Cell::new(None) };
+}
+
+impl Builder {
+    pub fn size(mut self, size: usize) -> Builder {
+        assert!(size > 0);
+        self.size = size;
+        self
+    }
+
+    // Workers are called "{name}-{index}".
+    pub fn thread_name(mut self, name: impl Into<String>) -> Builder {
+        self.name = name.into();
+        self
+    }
+
+    pub fn stack_size(mut self, bytes: usize) -> Builder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    // At most 'capacity' jobs can wait to start. Jobs added by the pool's
+    // own jobs don't wait for room: if every worker blocked on a full
+    // queue, nothing would ever empty it.
+    pub fn bounded(mut self, capacity: usize) -> Builder {
+        assert!(capacity > 0);
+        self.capacity = Some(capacity);
+        self
+    }
+
+    // Jobs added with add() that panic are reported here, with the worker's
+    // index. Those run through submit() or scope() report their panics to
+    // the caller instead.
+    pub fn on_panic(mut self, hook: impl Fn(usize, &str) + Send + Sync + 'static) -> Builder {
+        self.panic_hook = Arc::new(hook);
+        self
+    }
+
+    pub fn build(self) -> ThreadPool {
+        let n = self.size;
+        let shared = Arc::new(Shared {
+            injector: Mutex::new(VecDeque::new()),
+            locals: (0..n).map(|_| Mutex::new(VecDeque::new())).collect(),
+            sleepers: AtomicUsize::new(0),
+            wakeups: Mutex::new(0),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            panic_hook: self.panic_hook,
+            handles: Mutex::new((0..n).map(|_| None).collect()),
+            respawned: AtomicUsize::new(0),
+            capacity: self.capacity,
+            queued: Mutex::new(0),
+            space: Condvar::new(),
+            rejected: AtomicUsize::new(0),
+            name: self.name,
+            stack_size: self.stack_size,
+        });
+        for index in 0..n {
+            spawn_worker(&shared, index);
+        }
+        ThreadPool { shared }
+    }
+}
+
+impl ThreadPool {
+    pub fn builder() -> Builder {
+        Builder {
+            size: thread::available_parallelism().map_or(1, |n| n.get()),
+            name: "pool-worker".to_string(),
+            stack_size: None,
+            capacity: None,
+            panic_hook: Arc::new(|_, _| {}),
+        }
+    }
+
+    pub fn new(n: usize) -> Self {
+        ThreadPool::builder().size(n).build()
+    }
+
+    pub fn bounded(n: usize, capacity: usize) -> Self {
+        ThreadPool::builder().size(n).bounded(capacity).build()
+    }
+
+    pub fn size(&self) -> usize {
+        self.shared.locals.len()
+    }
+
+    // How many workers have died and been replaced.
+    pub fn respawned(&self) -> usize {
+        self.shared.respawned.load(Ordering::SeqCst)
+    }
+
+    // Waits for room in a bounded pool that is full.
+    pub fn add<F: FnOnce() + 'static + Send>(&self, job: F) {
+        self.shared.push(Box::new(job));
+    }
+
+    // Hands the job back if the pool is full.
+    pub fn try_add<F: FnOnce() + 'static + Send>(&self, job: F) -> Result<(), F> {
+        self.add_timeout(job, Duration::ZERO)
+    }
+
+    // Hands the job back if the pool is still full after 'timeout'.
+    pub fn add_timeout<F: FnOnce() + 'static + Send>(&self, job: F, timeout: Duration) -> Result<(), F> {
+        if !self.shared.reserve(Some(timeout)) {
+            return Err(job);
+        }
+        self.shared.enqueue(Box::new(job));
+        Ok(())
+    }
+
+    // Jobs try_add() and add_timeout() have handed back.
+    pub fn rejected(&self) -> usize {
+        self.shared.rejected.load(Ordering::SeqCst)
+    }
+
+    // Runs 'job' like add, and hands back its result (or its panic) through
+    // the returned handle.
+    pub fn submit<T, F>(&self, job: F) -> JobHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (job, handle) = handle::wrap(job);
+        self.add(job);
+        handle
+    }
+
+    // Runs everything already added, then stops the workers.
+    pub fn end(self) {
+        self.shared.stop();
+        for index in 0..self.shared.locals.len() {
+            // A worker dying right now leaves a replacement to wait for too.
+            loop {
+                let handle = self.shared.handles.lock().unwrap()[index].take();
+                match handle {
+                    Some(h) => {
+                        let _ = h.join();
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+// Without end(), the workers still finish the queued jobs, but nobody waits
+// for them.
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+fn spawn_worker(shared: &Arc<Shared>, index: usize) {
+    // Held until the handle is stored, so a replacement for this worker
+    // can't store its own first and then be overwritten.
+    let mut handles = shared.handles.lock().unwrap();
+    let sentinel = Sentinel { shared: Arc::clone(shared), index };
+    let mut builder = thread::Builder::new().name(format!("{}-{index}", shared.name));
+    if let Some(bytes) = shared.stack_size {
+        builder = builder.stack_size(bytes);
+    }
+    let handle = builder.spawn(move || sentinel.shared.work(sentinel.index)).expect("failed to spawn a pool worker");
+    handles[index] = Some(handle);
+}
+
+// Lives on a worker's stack; if the worker unwinds, its Drop starts the
+// replacement.
+struct Sentinel {
+    shared: Arc<Shared>,
+    index: usize,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.shared.respawned.fetch_add(1, Ordering::SeqCst);
+            spawn_worker(&self.shared, self.index);
+        }
+    }
+}
+
+impl Shared {
+    pub(super) fn push(&self, job: Job) {
+        self.reserve(None);
+        self.enqueue(job);
+    }
+
+    // Counts a job against the capacity, waiting up to 'wait' (or for as
+    // long as it takes) for room. False if there was none.
+    fn reserve(&self, wait: Option<Duration>) -> bool {
+        let Some(capacity) = self.capacity else { return true };
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        let mut queued = self.queued.lock().unwrap();
+        if self.local_index().is_none() {
+            while *queued >= capacity {
+                queued = match deadline {
+                    None => self.space.wait(queued).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            self.rejected.fetch_add(1, Ordering::SeqCst);
+                            return false;
+                        }
+                        self.space.wait_timeout(queued, deadline - now).unwrap().0
+                    }
+                };
+            }
+        }
+        *queued += 1;
+        true
+    }
+
+    // A job has left the queue to run.
+    fn release(&self) {
+        if self.capacity.is_some() {
+            *self.queued.lock().unwrap() -= 1;
+            self.space.notify_one();
+        }
+    }
+
+    fn enqueue(&self, job: Job) {
+        match self.local_index() {
+            Some(index) => self.locals[index].lock().unwrap().push_back(job),
+            None => self.injector.lock().unwrap().push_back(job),
+        }
+        // Pairs with the increment in park(): either the sleeper sees the
+        // job when it looks again, or we see the sleeper here.
+        let sleepers = self.sleepers.load(Ordering::SeqCst);
+        if sleepers > 0 {
+            let mut wakeups = self.wakeups.lock().unwrap();
+            if *wakeups < sleepers {
+                *wakeups += 1;
+                self.wake.notify_one();
+            }
+        }
+    }
+
+    // Our index if the current thread is one of this pool's workers.
+    pub(super) fn local_index(&self) -> Option<usize> {
+        match WORKER.get() {
+            Some((pool, index)) if std::ptr::eq(pool, self) => Some(index),
+            _ => None,
+        }
+    }
+
+    fn work(&self, index: usize) {
+        WORKER.set(Some((self as *const Shared, index)));
+        let mut idle = 0;
+        loop {
+            match self.find(index) {
+                Some(job) => {
+                    idle = 0;
+                    self.run(index, job);
+                }
+                None if self.shutdown.load(Ordering::SeqCst) => break,
+                // Jobs tend to come in bursts. Giving the thread adding them
+                // a few chances to add more is much cheaper than going to
+                // sleep and being woken for every one of them.
+                None if idle < SPINS => {
+                    idle += 1;
+                    thread::yield_now();
+                }
+                None => {
+                    idle = 0;
+                    self.park();
+                }
+            }
+        }
+        WORKER.set(None);
+    }
+
+    pub(super) fn run(&self, index: usize, job: Job) {
+        self.release();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+            (self.panic_hook)(index, &panic_message(&*payload));
+        }
+    }
+
+    pub(super) fn find(&self, index: usize) -> Option<Job> {
+        if let Some(job) = self.locals[index].lock().unwrap().pop_back() {
+            return Some(job);
+        }
+        if let Some(job) = self.take_injected(index) {
+            return Some(job);
+        }
+        self.steal(index)
+    }
+
+    // Takes one job from the injector, plus a fair share of the rest (up to
+    // BATCH) into our own deque, where others can still steal them. Fewer
+    // trips to the injector means less fighting over its lock when jobs
+    // come in from outside.
+    fn take_injected(&self, index: usize) -> Option<Job> {
+        const BATCH: usize = 32;
+        let mut injector = self.injector.lock().unwrap();
+        let job = injector.pop_front()?;
+        let share = (injector.len() / self.locals.len()).min(BATCH);
+        if share > 0 {
+            let mut local = self.locals[index].lock().unwrap();
+            // Oldest first out of pop_back would reverse the order they were
+            // added in; keep it.
+            local.extend(injector.drain(..share).rev());
+        }
+        Some(job)
+    }
+
+    // Visits the others starting after ourselves, so thieves spread out
+    // instead of all hitting worker 0.
+    fn steal(&self, index: usize) -> Option<Job> {
+        let n = self.locals.len();
+        (1..n).find_map(|offset| self.locals[(index + offset) % n].lock().unwrap().pop_front())
+    }
+
+    fn park(&self) {
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        // A job pushed before we counted ourselves didn't wake anyone. One
+        // pushed after this look leaves a wakeup for us to find below.
+        if !self.has_work() {
+            let mut wakeups = self.wakeups.lock().unwrap();
+            while *wakeups == 0 && !self.shutdown.load(Ordering::SeqCst) {
+                wakeups = self.wake.wait(wakeups).unwrap();
+            }
+            *wakeups = wakeups.saturating_sub(1);
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.lock().unwrap().is_empty() || self.locals.iter().any(|local| !local.lock().unwrap().is_empty())
+    }
+
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let _guard = self.wakeups.lock().unwrap();
+        self.wake.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn runs_every_job_before_ending() {
+        let pool = ThreadPool::new(4);
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10_000 {
+            let count = Arc::clone(&count);
+            pool.add(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        pool.end();
+        assert_eq!(count.load(Ordering::Relaxed), 10_000);
+    }
+
+    #[test]
+    fn jobs_added_from_a_worker_go_to_its_own_deque() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let inner = Arc::clone(&pool);
+        let (sender, receiver) = mpsc::channel();
+        pool.add(move || {
+            let index = inner.shared.local_index().expect("running on a worker");
+            inner.add(|| {});
+            let queued = inner.shared.locals[index].lock().unwrap().len();
+            sender.send((queued, inner.shared.injector.lock().unwrap().len())).unwrap();
+        });
+        // The child may already have been stolen, but it never went through
+        // the injector.
+        let (local, injected) = receiver.recv().unwrap();
+        assert!(local <= 1);
+        assert_eq!(injected, 0);
+        assert_eq!(pool.shared.local_index(), None);
+    }
+
+    #[test]
+    fn idle_workers_steal_from_a_busy_one() {
+        let pool = Arc::new(ThreadPool::new(4));
+        let inner = Arc::clone(&pool);
+        let (sender, receiver) = mpsc::channel();
+        pool.add(move || {
+            // All children land on this worker's deque; while it sleeps the
+            // others have to steal them.
+            for _ in 0..40 {
+                let sender = sender.clone();
+                inner.add(move || {
+                    thread::sleep(Duration::from_millis(2));
+                    sender.send(thread::current().id()).unwrap();
+                });
+            }
+            thread::sleep(Duration::from_millis(50));
+        });
+        let mut workers: Vec<_> = receiver.iter().take(40).collect();
+        workers.sort_by_key(|id| format!("{id:?}"));
+        workers.dedup();
+        assert!(workers.len() > 1, "nothing was stolen");
+    }
+
+    #[test]
+    fn wakes_sleeping_workers() {
+        let pool = ThreadPool::new(3);
+        for round in 0..50 {
+            // Long enough for every worker to have gone to sleep.
+            thread::sleep(Duration::from_millis(1));
+            assert_eq!(pool.submit(move || round).join(), Ok(round));
+        }
+        pool.end();
+    }
+
+    #[test]
+    fn panics_go_to_the_hook_and_the_worker_survives() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let hook_reported = Arc::clone(&reported);
+        let pool = ThreadPool::builder()
+            .size(1)
+            .on_panic(move |index, message| hook_reported.lock().unwrap().push((index, message.to_string())))
+            .build();
+        let before = pool.submit(|| thread::current().id()).join().unwrap();
+        pool.add(|| panic!("job {} failed", 1));
+        pool.add(|| panic!("job 2 failed"));
+        let after = pool.submit(|| thread::current().id()).join().unwrap();
+
+        assert_eq!(before, after);
+        assert_eq!(*reported.lock().unwrap(), [(0, "job 1 failed".to_string()), (0, "job 2 failed".to_string())]);
+        assert_eq!(pool.respawned(), 0);
+        pool.end();
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let pool = ThreadPool::builder().size(2).on_panic(|_, _| panic!("hook failed too")).build();
+        for _ in 0..3 {
+            pool.add(|| panic!("job failed"));
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.respawned() < 3 {
+            assert!(Instant::now() < deadline, "workers weren't replaced");
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Both slots are working again: one job blocks until the other has run.
+        let (sender, receiver) = mpsc::channel();
+        let waiting = pool.submit(move || receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        pool.add(move || sender.send(()).unwrap());
+        assert_eq!(waiting.join(), Ok(true));
+        pool.end();
+    }
+
+    #[test]
+    fn builder_names_threads_and_sets_their_stack() {
+        let pool = ThreadPool::builder().size(3).thread_name("resizer").build();
+        assert_eq!(pool.size(), 3);
+        let name = pool.submit(|| thread::current().name().map(str::to_string)).join().unwrap();
+        assert!(name.is_some_and(|name| name.starts_with("resizer-")));
+        pool.end();
+
+        // A job that needs more than the 2 MiB a thread gets by default.
+        let pool = ThreadPool::builder().size(1).stack_size(32 << 20).build();
+        let big = pool.submit(|| {
+            let buffer = std::hint::black_box([1u8; 4 << 20]);
+            buffer.iter().map(|&b| b as usize).sum::<usize>()
+        });
+        assert_eq!(big.join(), Ok(4 << 20));
+        pool.end();
+    }
+
+    // Occupies the pool's only worker until the returned sender is dropped.
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (started, is_started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.add(move || {
+            started.send(()).unwrap();
+            let _ = released.recv();
+        });
+        is_started.recv().unwrap();
+        release
+    }
+
+    #[test]
+    fn bounded_pools_hand_jobs_back_when_full() {
+        let pool = ThreadPool::bounded(1, 2);
+        let release = block_worker(&pool);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let job = |ran: &Arc<AtomicUsize>| {
+            let ran = Arc::clone(ran);
+            move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+
+        assert!(pool.try_add(job(&ran)).is_ok());
+        assert!(pool.try_add(job(&ran)).is_ok());
+        let returned = pool.try_add(job(&ran)).unwrap_err();
+        assert_eq!(pool.rejected(), 1);
+        let started = Instant::now();
+        assert!(pool.add_timeout(job(&ran), Duration::from_millis(20)).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(pool.rejected(), 2);
+
+        // Handed back intact.
+        returned();
+        drop(release);
+        pool.end();
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn add_waits_for_room() {
+        let pool = Arc::new(ThreadPool::bounded(1, 1));
+        let release = block_worker(&pool);
+        pool.add(|| {});
+        let added = Arc::new(AtomicBool::new(false));
+        let producer = {
+            let (pool, added) = (Arc::clone(&pool), Arc::clone(&added));
+            thread::spawn(move || {
+                pool.add(|| {});
+                added.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!added.load(Ordering::SeqCst));
+        drop(release);
+        producer.join().unwrap();
+        assert!(added.load(Ordering::SeqCst));
+        assert_eq!(pool.rejected(), 0);
+    }
+
+    #[test]
+    fn jobs_added_by_jobs_skip_the_bound() {
+        let pool = Arc::new(ThreadPool::bounded(1, 1));
+        let inner = Arc::clone(&pool);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&ran);
+        let mut parent = pool.submit(move || {
+            for _ in 0..5 {
+                let counted = Arc::clone(&counted);
+                inner.add(move || {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(parent.join_timeout(Duration::from_secs(5)), Some(Ok(())));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ran.load(Ordering::SeqCst) < 5 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // The pool before work stealing: one channel behind one mutex, which
+    // every idle worker waits on. Kept to compare against.
+    struct ChannelPool {
+        ch: mpsc::Sender<Job>,
+    }
+
+    impl ChannelPool {
+        fn new(n: usize) -> Self {
+            let (cs, cr) = mpsc::channel::<Job>();
+            let amap = Arc::new(Mutex::new(cr));
+            // The workers return once 'ch' is dropped.
+            for _ in 0..n {
+                let amp = amap.clone();
+                thread::spawn(move || {
+                    loop {
+                        let job = match amp.lock().unwrap().recv() {
+                            Ok(j) => j,
+                            _ => return,
+                        };
+                        job();
+                    }
+                });
+            }
+            ChannelPool { ch: cs }
+        }
+
+        fn add<F: FnOnce() + 'static + Send>(&self, job: F) {
+            self.ch.send(Box::new(job)).unwrap();
+        }
+    }
+
+    // Counts finished jobs and signals when the last one is done.
+    struct Countdown {
+        left: AtomicUsize,
+        done: Mutex<Option<mpsc::Sender<()>>>,
+    }
+
+    impl Countdown {
+        fn new(jobs: usize) -> (Arc<Countdown>, mpsc::Receiver<()>) {
+            let (sender, receiver) = mpsc::channel();
+            (Arc::new(Countdown { left: AtomicUsize::new(jobs), done: Mutex::new(Some(sender)) }), receiver)
+        }
+
+        fn tick(&self) {
+            if self.left.fetch_sub(1, Ordering::AcqRel) == 1 {
+                let _ = self.done.lock().unwrap().take().unwrap().send(());
+            }
+        }
+    }
+
+    // Fine-grained jobs: flat (every job added from outside) and nested (a
+    // few jobs from outside, each adding many tiny ones from inside).
+    // cargo test --release bench_work_stealing -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_work_stealing() {
+        const THREADS: usize = 4;
+        const PARENTS: usize = 1_000;
+        const CHILDREN: usize = 200;
+        const JOBS: usize = PARENTS * CHILDREN;
+
+        fn report(name: &str, shape: &str, elapsed: Duration) {
+            println!("{name:>14} {shape:>6}: {JOBS} jobs in {elapsed:?} ({:.0} jobs/s)", JOBS as f64 / elapsed.as_secs_f64());
+        }
+
+        // Works with any pool that can add jobs from anywhere.
+        fn run<P: Send + Sync + 'static>(name: &str, pool: Arc<P>, add: fn(&P, Job)) {
+            let (countdown, done) = Countdown::new(JOBS);
+            let started = Instant::now();
+            for _ in 0..JOBS {
+                let countdown = Arc::clone(&countdown);
+                add(&pool, Box::new(move || countdown.tick()));
+            }
+            done.recv().unwrap();
+            report(name, "flat", started.elapsed());
+
+            let (countdown, done) = Countdown::new(JOBS);
+            let started = Instant::now();
+            for _ in 0..PARENTS {
+                let countdown = Arc::clone(&countdown);
+                let inner = Arc::clone(&pool);
+                add(
+                    &pool,
+                    Box::new(move || {
+                        for _ in 0..CHILDREN {
+                            let countdown = Arc::clone(&countdown);
+                            add(&inner, Box::new(move || countdown.tick()));
+                        }
+                    }),
+                );
+            }
+            done.recv().unwrap();
+            report(name, "nested", started.elapsed());
+        }
+
+        run("channel pool", Arc::new(ChannelPool::new(THREADS)), |pool, job| pool.add(job));
+        run("work stealing", Arc::new(ThreadPool::new(THREADS)), |pool, job| pool.add(job));
+        let rayon = rayon::ThreadPoolBuilder::new().num_threads(THREADS).build().unwrap();
+        run("rayon", Arc::new(rayon), |pool, job| pool.spawn(job));
+    }
+}
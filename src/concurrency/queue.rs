@@ -0,0 +1,309 @@
+// A lock-free multi-producer single-consumer queue: Dmitry Vyukov's MPSC
+// node queue, built from raw pointers and AtomicPtr. Unlike his intrusive
+// original, where callers embed the link in their own structs, each push
+// here boxes a node around the value, so any T can be queued at the cost
+// of an allocation per push.
+//
+// The queue is a singly linked list of nodes, oldest first, that always
+// starts with one node whose value has already been taken (at first, an
+// empty stub). Producers own 'head', the newest node: push swaps in its new
+// node with one atomic swap and then links the old head to it. The consumer
+// owns 'tail', the spent node at the front: pop follows tail.next, takes
+// the value and frees the old tail. Between a producer's swap and its link
+// the list is briefly broken; pop then waits for that producer rather than
+// report the queue empty while a value is on its way.
+//
+// Built with --cfg model, the tests use the model checker's atomics
+// (model.rs) and check every interleaving of small cases:
+//
+//     RUSTFLAGS="--cfg model" cargo test queue::tests::model
+//
+// Every other build, benchmarks included, uses std's.
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+#[cfg(not(all(test, model)))]
+use std::sync::atomic::AtomicPtr;
+#[cfg(not(all(test, model)))]
+use std::thread::yield_now as spin;
+
+#[cfg(all(test, model))]
+use super::model::{spin, AtomicPtr};
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    // None only in the spent node at the front.
+    value: Option<T>,
+}
+
+struct Queue<T> {
+    head: AtomicPtr<Node<T>>,
+    // Only the consumer touches this.
+    tail: UnsafeCell<*mut Node<T>>,
+}
+
+// SAFETY: values move between threads through the queue, and the one field
+// that isn't atomic, 'tail', is only used by the single Consumer.
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+pub struct Producer<T> {
+    queue: Arc<Queue<T>>,
+}
+
+pub struct Consumer<T> {
+    queue: Arc<Queue<T>>,
+    // Not Sync or Clone: there's only ever one thread popping.
+    _single: PhantomData<Cell<()>>,
+}
+
+pub fn queue<T: Send>() -> (Producer<T>, Consumer<T>) {
+    let stub = Node::new(None);
+    let queue = Arc::new(Queue { head: AtomicPtr::new(stub), tail: UnsafeCell::new(stub) });
+    (Producer { queue: Arc::clone(&queue) }, Consumer { queue, _single: PhantomData })
+}
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node { next: AtomicPtr::new(ptr::null_mut()), value }))
+    }
+}
+
+impl<T> Producer<T> {
+    pub fn push(&self, value: T) {
+        let node = Node::new(Some(value));
+        // AcqRel: publishes our node to the next producer, and makes the
+        // previous producer's node safe for us to link from.
+        let previous = self.queue.head.swap(node, Ordering::AcqRel);
+        // SAFETY: 'previous' can't be freed yet: the consumer only frees a
+        // node after following its 'next', which we're about to set.
+        unsafe { (*previous).next.store(node, Ordering::Release) };
+    }
+}
+
+impl<T> Clone for Producer<T> {
+    fn clone(&self) -> Producer<T> {
+        Producer { queue: Arc::clone(&self.queue) }
+    }
+}
+
+impl<T> Consumer<T> {
+    // The oldest value, or None if nothing has been pushed that isn't
+    // popped yet.
+    pub fn pop(&self) -> Option<T> {
+        // SAFETY: only we use 'tail', and it always points to a live node.
+        unsafe {
+            let tail = *self.queue.tail.get();
+            loop {
+                let next = (*tail).next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    *self.queue.tail.get() = next;
+                    let value = (*next).value.take();
+                    drop(Box::from_raw(tail));
+                    return value;
+                }
+                if self.queue.head.load(Ordering::Acquire) == tail {
+                    return None;
+                }
+                // A producer has swapped 'head' but not linked its node yet.
+                spin();
+            }
+        }
+    }
+
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.pop())
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // Nobody else is left, so the list is whole.
+        let mut node = *self.tail.get_mut();
+        while !node.is_null() {
+            // SAFETY: every node was made by Box::into_raw and is freed once.
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(model)]
+    use crate::concurrency::model;
+    use crate::concurrency::ThreadPool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Instant;
+
+    // Pops until 'n' values have arrived, spinning while there are none.
+    fn pop_n<T>(consumer: &Consumer<T>, n: usize) -> Vec<T> {
+        let mut values = Vec::new();
+        while values.len() < n {
+            match consumer.pop() {
+                Some(value) => values.push(value),
+                None => thread::yield_now(),
+            }
+        }
+        values
+    }
+
+    // The model tests pop a fixed number of times while the producers run,
+    // then take the rest once they're done; waiting in a loop for every
+    // value would multiply the interleavings to check for little gain.
+    #[cfg(model)]
+    fn pop_during_and_after<T>(consumer: &Consumer<T>, pops: usize, producers: Vec<model::JoinHandle<()>>) -> Vec<T> {
+        let mut values: Vec<T> = (0..pops).filter_map(|_| consumer.pop()).collect();
+        producers.into_iter().for_each(model::JoinHandle::join);
+        values.extend(consumer.try_iter());
+        values
+    }
+
+    #[cfg(model)]
+    #[test]
+    fn model_two_producers() {
+        let runs = model::check(|| {
+            let (producer, consumer) = queue();
+            let other = producer.clone();
+            let a = model::spawn(move || producer.push(1));
+            let b = model::spawn(move || other.push(2));
+            let mut values = pop_during_and_after(&consumer, 1, vec![a, b]);
+            values.sort();
+            assert_eq!(values, [1, 2]);
+            assert_eq!(consumer.pop(), None);
+        });
+        assert!(runs > 10, "only {runs} interleavings");
+    }
+
+    #[cfg(model)]
+    #[test]
+    fn model_pops_in_push_order() {
+        model::check(|| {
+            let (producer, consumer) = queue();
+            let a = model::spawn(move || {
+                producer.push(1);
+                producer.push(2);
+            });
+            assert_eq!(pop_during_and_after(&consumer, 2, vec![a]), [1, 2]);
+        });
+    }
+
+    #[cfg(model)]
+    #[test]
+    fn model_dropping_with_values_left_frees_them() {
+        let value = Arc::new(());
+        let shared = Arc::clone(&value);
+        model::check(move || {
+            let (producer, consumer) = queue();
+            let other = producer.clone();
+            let (first, second) = (Arc::clone(&shared), Arc::clone(&shared));
+            let a = model::spawn(move || producer.push(first));
+            let b = model::spawn(move || other.push(second));
+            // Maybe pop one, maybe none, depending on the schedule.
+            drop(consumer.pop());
+            a.join();
+            b.join();
+        });
+        // check() has dropped its closure, and with it 'shared'.
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn many_producers_on_real_threads() {
+        const PRODUCERS: usize = 4;
+        const EACH: usize = 10_000;
+        let (producer, consumer) = queue();
+        let threads: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let producer = producer.clone();
+                thread::spawn(move || (0..EACH).for_each(|n| producer.push((p, n))))
+            })
+            .collect();
+        let mut next = [0; PRODUCERS];
+        for (p, n) in pop_n(&consumer, PRODUCERS * EACH) {
+            assert_eq!(n, next[p]);
+            next[p] += 1;
+        }
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(consumer.try_iter().count(), 0);
+    }
+
+    // Handing boxed jobs from several threads to one worker: std's mpsc,
+    // as the pool used to dispatch them, against this queue, and the pool's
+    // own path today (ThreadPool::add to its injector) for reference.
+    // cargo test --release bench_job_dispatch -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_job_dispatch() {
+        const PRODUCERS: usize = 4;
+        const JOBS: usize = 250_000;
+        type Job = Box<dyn FnOnce() + Send>;
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        let job = |ran: &Arc<AtomicUsize>| -> Job {
+            let ran = Arc::clone(ran);
+            Box::new(move || {
+                ran.fetch_add(1, Ordering::Relaxed);
+            })
+        };
+        let report = |name: &str, started: Instant| {
+            let elapsed = started.elapsed();
+            let jobs = PRODUCERS * JOBS;
+            assert_eq!(ran.swap(0, Ordering::Relaxed), jobs);
+            println!("{name:>12}: {jobs} jobs in {elapsed:?} ({:.0} jobs/s)", jobs as f64 / elapsed.as_secs_f64());
+        };
+
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let started = Instant::now();
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|_| {
+                let (sender, ran) = (sender.clone(), Arc::clone(&ran));
+                thread::spawn(move || (0..JOBS).for_each(|_| sender.send(job(&ran)).unwrap()))
+            })
+            .collect();
+        drop(sender);
+        receiver.iter().for_each(|job| job());
+        producers.into_iter().for_each(|t| t.join().unwrap());
+        report("mpsc", started);
+
+        let (producer, consumer) = queue::<Job>();
+        let started = Instant::now();
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|_| {
+                let (producer, ran) = (producer.clone(), Arc::clone(&ran));
+                thread::spawn(move || (0..JOBS).for_each(|_| producer.push(job(&ran))))
+            })
+            .collect();
+        let mut done = 0;
+        while done < PRODUCERS * JOBS {
+            match consumer.pop() {
+                Some(job) => {
+                    job();
+                    done += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        producers.into_iter().for_each(|t| t.join().unwrap());
+        report("lock-free", started);
+
+        let pool = Arc::new(ThreadPool::new(1));
+        let started = Instant::now();
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|_| {
+                let (pool, ran) = (Arc::clone(&pool), Arc::clone(&ran));
+                thread::spawn(move || (0..JOBS).for_each(|_| pool.add(job(&ran))))
+            })
+            .collect();
+        producers.into_iter().for_each(|t| t.join().unwrap());
+        Arc::into_inner(pool).expect("the producers are done").end();
+        report("pool", started);
+    }
+}
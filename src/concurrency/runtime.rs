@@ -0,0 +1,464 @@
+// A small single-threaded async runtime, to see what the big ones do.
+// block_on runs a future to completion on the calling thread, along with
+// every task it spawns. A task that can't go on leaves a waker with
+// whatever it waits for and isn't polled again until that's woken. All the
+// waiting happens in one place: when no task is ready, the thread sleeps in
+// the readiness poller (tcp::poll) until a socket some task waits on is
+// ready (net.rs), the next timer is due (timer.rs), or another thread wakes
+// a task.
+//
+// Everything stays on one thread, so futures needn't be Send, but a task
+// that blocks (thread::sleep, blocking IO) stalls all the others. A panic
+// in any task unwinds out of block_on.
+
+pub mod net;
+mod timer;
+
+pub use timer::{sleep, timeout};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use crate::tcp::poll::{Event, Interest, Poller};
+use timer::Wheel;
+
+// The task id block_on's own future is woken with.
+const MAIN: usize = usize::MAX;
+// The poller token of the socket other threads wake us through.
+const WAKE_TOKEN: u64 = u64::MAX;
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Waker,
+}
+
+// The part wakers use, from any thread.
+struct Shared {
+    ready: Mutex<VecDeque<usize>>,
+    // Set while the runtime sleeps in the poller; a waker then writes a
+    // byte to 'wake' to get it out.
+    parked: AtomicBool,
+    wake: UnixStream,
+}
+
+struct TaskWaker {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.shared.ready.lock().unwrap().push_back(self.id);
+        if self.shared.parked.load(Ordering::SeqCst) {
+            // WouldBlock just means a wake-up byte is already waiting.
+            let _ = (&self.shared.wake).write(&[1]);
+        }
+    }
+}
+
+struct Core {
+    shared: Arc<Shared>,
+    tasks: RefCell<HashMap<usize, Task>>,
+    next_id: Cell<usize>,
+    timers: RefCell<Wheel>,
+    reactor: RefCell<Reactor>,
+}
+
+thread_local! {
+    static CORE: RefCell<Option<Rc<Core>>> = const { RefCell::new(None) };
+}
+
+fn with_core<T>(f: impl FnOnce(&Core) -> T) -> T {
+    try_with_core(f).expect("not inside runtime::block_on")
+}
+
+// For destructors, which may run after the runtime has gone.
+fn try_with_core<T>(f: impl FnOnce(&Core) -> T) -> Option<T> {
+    let core = CORE.with(|core| core.borrow().clone())?;
+    Some(f(&core))
+}
+
+// Runs 'future' and the tasks it spawns until 'future' is done; tasks
+// still unfinished then are dropped.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let core = Rc::new(Core::new().expect("can't set up the runtime's poller"));
+    CORE.with(|current| {
+        let mut current = current.borrow_mut();
+        assert!(current.is_none(), "block_on inside block_on");
+        *current = Some(Rc::clone(&core));
+    });
+    // Declared before 'future', so it runs after 'future' is dropped: the
+    // futures' destructors may still need the runtime.
+    let _exit = Exit;
+    let mut future = pin!(future);
+    let waker = core.waker(MAIN);
+    core.shared.ready.lock().unwrap().push_back(MAIN);
+    loop {
+        let ready = std::mem::take(&mut *core.shared.ready.lock().unwrap());
+        for id in ready {
+            if id != MAIN {
+                core.run(id);
+            } else if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                return output;
+            }
+        }
+        core.turn().expect("the runtime's poller failed");
+    }
+}
+
+struct Exit;
+
+impl Drop for Exit {
+    fn drop(&mut self) {
+        if let Some(tasks) = try_with_core(|core| std::mem::take(&mut *core.tasks.borrow_mut())) {
+            drop(tasks);
+        }
+        CORE.with(|core| core.borrow_mut().take());
+    }
+}
+
+impl Core {
+    fn new() -> io::Result<Core> {
+        let (wake, woken) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        woken.set_nonblocking(true)?;
+        let poller = Poller::new()?;
+        poller.add(woken.as_raw_fd(), WAKE_TOKEN, Interest::Readable)?;
+        Ok(Core {
+            shared: Arc::new(Shared { ready: Mutex::new(VecDeque::new()), parked: AtomicBool::new(false), wake }),
+            tasks: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0),
+            timers: RefCell::new(Wheel::new(Instant::now())),
+            reactor: RefCell::new(Reactor {
+                poller,
+                sources: HashMap::new(),
+                next_token: 0,
+                events: Vec::new(),
+                woken,
+            }),
+        })
+    }
+
+    fn waker(&self, id: usize) -> Waker {
+        Waker::from(Arc::new(TaskWaker { id, shared: Arc::clone(&self.shared) }))
+    }
+
+    fn run(&self, id: usize) {
+        // Out of the map while it runs, so it can spawn. A task woken twice
+        // is polled twice, and one woken after finishing isn't found.
+        let Some(mut task) = self.tasks.borrow_mut().remove(&id) else { return };
+        if task.future.as_mut().poll(&mut Context::from_waker(&task.waker)).is_pending() {
+            self.tasks.borrow_mut().insert(id, task);
+        }
+    }
+
+    // Fires due timers and collects socket readiness, sleeping in the
+    // poller if nothing is ready to run.
+    fn turn(&self) -> io::Result<()> {
+        self.timers.borrow_mut().fire(Instant::now());
+        self.shared.parked.store(true, Ordering::SeqCst);
+        // Checked after 'parked' is set: a waker either got in before this
+        // or will write to the wake socket.
+        let timeout = if !self.shared.ready.lock().unwrap().is_empty() {
+            Some(Duration::ZERO)
+        } else {
+            self.timers.borrow().next_deadline().map(|at| at.saturating_duration_since(Instant::now()))
+        };
+        let result = self.reactor.borrow_mut().poll(timeout);
+        self.shared.parked.store(false, Ordering::SeqCst);
+        self.timers.borrow_mut().fire(Instant::now());
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Read,
+    Write,
+}
+
+// Sockets tasks are waiting on. A socket is only in the poller while a
+// task waits on it; the poller is level-triggered, and an idle writable
+// socket would otherwise keep waking us.
+struct Reactor {
+    poller: Poller,
+    sources: HashMap<u64, Source>,
+    next_token: u64,
+    events: Vec<Event>,
+    woken: UnixStream,
+}
+
+struct Source {
+    fd: RawFd,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    registered: Option<Interest>,
+}
+
+impl Reactor {
+    fn register(&mut self, fd: RawFd) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.sources.insert(token, Source { fd, reader: None, writer: None, registered: None });
+        token
+    }
+
+    fn deregister(&mut self, token: u64) {
+        if let Some(source) = self.sources.remove(&token)
+            && source.registered.is_some()
+        {
+            let _ = self.poller.delete(source.fd);
+        }
+    }
+
+    fn wait_for(&mut self, token: u64, direction: Direction, waker: &Waker) -> io::Result<()> {
+        let source = self.sources.get_mut(&token).expect("socket not registered with the runtime");
+        let slot = match direction {
+            Direction::Read => &mut source.reader,
+            Direction::Write => &mut source.writer,
+        };
+        match slot {
+            Some(old) if old.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+        self.update(token)
+    }
+
+    // Brings the poller in line with the wakers the source holds.
+    fn update(&mut self, token: u64) -> io::Result<()> {
+        let source = self.sources.get_mut(&token).expect("token came from the map");
+        let wanted = match (source.reader.is_some(), source.writer.is_some()) {
+            (false, false) => None,
+            (true, false) => Some(Interest::Readable),
+            (false, true) => Some(Interest::Writable),
+            (true, true) => Some(Interest::Both),
+        };
+        match (source.registered, wanted) {
+            (None, Some(interest)) => self.poller.add(source.fd, token, interest)?,
+            (Some(old), Some(interest)) if old != interest => self.poller.modify(source.fd, token, interest)?,
+            (Some(_), None) => self.poller.delete(source.fd)?,
+            _ => {}
+        }
+        source.registered = wanted;
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut events = std::mem::take(&mut self.events);
+        self.poller.wait(&mut events, timeout)?;
+        for event in &events {
+            if event.token == WAKE_TOKEN {
+                let mut sink = [0u8; 64];
+                while matches!((&self.woken).read(&mut sink), Ok(n) if n > 0) {}
+                continue;
+            }
+            let Some(source) = self.sources.get_mut(&event.token) else { continue };
+            // A closed socket wakes both sides, so they find out.
+            if (event.readable || event.closed)
+                && let Some(waker) = source.reader.take()
+            {
+                waker.wake();
+            }
+            if (event.writable || event.closed)
+                && let Some(waker) = source.writer.take()
+            {
+                waker.wake();
+            }
+            self.update(event.token)?;
+        }
+        self.events = events;
+        Ok(())
+    }
+}
+
+// Runs 'future' as a task of its own; its output comes back through the
+// handle. Dropping the handle leaves the task running.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let state = Rc::new(RefCell::new(JoinState { output: None, finished: false, waiter: None }));
+    let done = Rc::clone(&state);
+    let task = async move {
+        let output = future.await;
+        let mut done = done.borrow_mut();
+        done.output = Some(output);
+        done.finished = true;
+        if let Some(waiter) = done.waiter.take() {
+            waiter.wake();
+        }
+    };
+    with_core(|core| {
+        let id = core.next_id.get();
+        core.next_id.set(id + 1);
+        core.tasks.borrow_mut().insert(id, Task { future: Box::pin(task), waker: core.waker(id) });
+        core.shared.ready.lock().unwrap().push_back(id);
+    });
+    JoinHandle { state }
+}
+
+pub struct JoinHandle<T> {
+    state: Rc<RefCell<JoinState<T>>>,
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    waiter: Option<Waker>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn is_finished(&self) -> bool {
+        self.state.borrow().finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.borrow_mut();
+        if let Some(output) = state.output.take() {
+            return Poll::Ready(output);
+        }
+        assert!(!state.finished, "JoinHandle polled after it returned the output");
+        state.waiter = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Lets the other ready tasks run before carrying on.
+pub async fn yield_now() {
+    let mut yielded = false;
+    std::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn runs_spawned_tasks_and_returns_their_output() {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&order);
+        let total = block_on(async move {
+            let handles: Vec<_> = (1..=3)
+                .map(|n| {
+                    let log = Rc::clone(&log);
+                    spawn(async move {
+                        log.borrow_mut().push(n);
+                        yield_now().await;
+                        log.borrow_mut().push(n * 10);
+                        n
+                    })
+                })
+                .collect();
+            let mut total = 0;
+            for handle in handles {
+                total += handle.await;
+            }
+            total
+        });
+        assert_eq!(total, 6);
+        // Each task gave way once, so they took turns.
+        assert_eq!(*order.borrow(), [1, 2, 3, 10, 20, 30]);
+    }
+
+    #[test]
+    fn sleeping_tasks_run_concurrently() {
+        let started = Instant::now();
+        let finished = block_on(async {
+            let slow = spawn(async {
+                sleep(Duration::from_millis(100)).await;
+                "slow"
+            });
+            let fast = spawn(async {
+                sleep(Duration::from_millis(20)).await;
+                "fast"
+            });
+            let first = fast.await;
+            assert!(!slow.is_finished());
+            [first, slow.await]
+        });
+        assert_eq!(finished, ["fast", "slow"]);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(180), "{elapsed:?}");
+    }
+
+    #[test]
+    fn wakers_work_from_other_threads() {
+        let value = block_on(async {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let mut waiting = false;
+            std::future::poll_fn(|cx| {
+                if let Ok(value) = receiver.try_recv() {
+                    return Poll::Ready(value);
+                }
+                if !waiting {
+                    waiting = true;
+                    let (waker, sender) = (cx.waker().clone(), sender.clone());
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(20));
+                        sender.send(42).unwrap();
+                        waker.wake();
+                    });
+                }
+                Poll::Pending
+            })
+            .await
+        });
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn unfinished_tasks_are_dropped_with_the_runtime() {
+        struct Flag(Rc<Cell<bool>>);
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+        let dropped = Rc::new(Cell::new(false));
+        let flag = Flag(Rc::clone(&dropped));
+        block_on(async move {
+            spawn(async move {
+                let _flag = flag;
+                sleep(Duration::from_secs(60)).await;
+            });
+            yield_now().await;
+        });
+        assert!(dropped.get());
+        // And the thread can start another runtime.
+        assert_eq!(block_on(async { 7 }), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "not inside runtime::block_on")]
+    fn spawning_outside_the_runtime_panics() {
+        spawn(async {});
+    }
+}
@@ -0,0 +1,211 @@
+// Non-blocking TCP sockets for the runtime. Every operation first just
+// tries the socket; only when it would block does the task leave a waker
+// with the reactor and wait for the poller to say the socket is ready.
+
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::task::Poll;
+
+use super::{try_with_core, with_core, Direction};
+
+pub struct TcpListener {
+    inner: net::TcpListener,
+    token: u64,
+}
+
+pub struct TcpStream {
+    inner: net::TcpStream,
+    token: u64,
+}
+
+// Runs 'op' until it stops saying WouldBlock, waiting on the socket in
+// between.
+async fn when_ready<T>(token: u64, direction: Direction, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    poll_fn(|cx| loop {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return match with_core(|core| core.reactor.borrow_mut().wait_for(token, direction, cx.waker())) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e)),
+                };
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return Poll::Ready(result),
+        }
+    })
+    .await
+}
+
+fn register(fd: &impl AsRawFd) -> u64 {
+    with_core(|core| core.reactor.borrow_mut().register(fd.as_raw_fd()))
+}
+
+fn deregister(token: u64) {
+    try_with_core(|core| core.reactor.borrow_mut().deregister(token));
+}
+
+impl TcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        TcpListener::from_std(net::TcpListener::bind(addr)?)
+    }
+
+    pub fn from_std(listener: net::TcpListener) -> io::Result<TcpListener> {
+        listener.set_nonblocking(true)?;
+        let token = register(&listener);
+        Ok(TcpListener { inner: listener, token })
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = when_ready(self.token, Direction::Read, || self.inner.accept()).await?;
+        Ok((TcpStream::from_std(stream)?, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        deregister(self.token);
+    }
+}
+
+impl TcpStream {
+    // std can't start a connection without waiting for it, so this blocks
+    // the runtime until connected; fine for nearby servers and tests.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        TcpStream::from_std(net::TcpStream::connect(addr)?)
+    }
+
+    pub fn from_std(stream: net::TcpStream) -> io::Result<TcpStream> {
+        stream.set_nonblocking(true)?;
+        let token = register(&stream);
+        Ok(TcpStream { inner: stream, token })
+    }
+
+    // Ok(0) once the peer has closed its side.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        when_ready(self.token, Direction::Read, || inner.read(buf)).await
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        when_ready(self.token, Direction::Write, || inner.write(buf)).await
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn read_to_end(&mut self, out: &mut Vec<u8>) -> io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let before = out.len();
+        loop {
+            match self.read(&mut chunk).await? {
+                0 => return Ok(out.len() - before),
+                n => out.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    // Back to a blocking std stream, say to hand it to a thread.
+    pub fn into_std(self) -> io::Result<net::TcpStream> {
+        let stream = self.inner.try_clone()?;
+        drop(self);
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        deregister(self.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::runtime::{block_on, sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn echoes_between_tasks() {
+        let reply = block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 64];
+                loop {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        n => stream.write_all(&buf[..n]).await.unwrap(),
+                    }
+                }
+            });
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"hello, ").await.unwrap();
+            // Give the server a chance to echo the first part on its own.
+            sleep(Duration::from_millis(10)).await;
+            client.write_all(b"async").await.unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            server.await;
+            reply
+        });
+        assert_eq!(reply, b"hello, async");
+    }
+
+    #[test]
+    fn large_writes_wait_for_the_reader() {
+        const SIZE: usize = 8 * 1024 * 1024;
+        let received = block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            // More than the socket buffers hold, so write_all has to wait.
+            let writer = spawn(async move {
+                server.write_all(&vec![7u8; SIZE]).await.unwrap();
+            });
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            writer.await;
+            received
+        });
+        assert_eq!(received.len(), SIZE);
+        assert!(received.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn a_waiting_reader_does_not_hold_up_others() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut idle, _) = listener.accept().await.unwrap();
+            let stuck = spawn(async move {
+                let mut buf = [0u8; 1];
+                idle.read(&mut buf).await
+            });
+            let started = Instant::now();
+            sleep(Duration::from_millis(20)).await;
+            assert!(started.elapsed() < Duration::from_millis(200));
+            assert!(!stuck.is_finished());
+        });
+    }
+}
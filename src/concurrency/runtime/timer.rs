@@ -0,0 +1,286 @@
+// Timers for the runtime: a hashed timing wheel. Each timer goes in the
+// slot for the millisecond it's due in, modulo the number of slots, so
+// adding and cancelling one is cheap however many there are; timers due
+// more than one turn of the wheel ahead share slots with nearer ones and
+// are passed over until their turn comes.
+
+use std::fmt::{Display, Formatter};
+use std::future::{poll_fn, Future};
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use super::{try_with_core, with_core};
+
+const SLOTS: usize = 256;
+const TICK: Duration = Duration::from_millis(1);
+
+pub(super) struct Wheel {
+    start: Instant,
+    slots: Vec<Vec<Timer>>,
+    // The first tick not yet fired.
+    next_tick: u64,
+    len: usize,
+    next_id: u64,
+}
+
+struct Timer {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct TimerKey {
+    slot: usize,
+    id: u64,
+}
+
+impl Wheel {
+    pub(super) fn new(start: Instant) -> Wheel {
+        Wheel { start, slots: (0..SLOTS).map(|_| Vec::new()).collect(), next_tick: 0, len: 0, next_id: 0 }
+    }
+
+    // The tick an instant falls in, rounded up: a timer is never early.
+    fn tick(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.start).as_nanos().div_ceil(TICK.as_nanos()) as u64
+    }
+
+    pub(super) fn insert(&mut self, deadline: Instant, waker: Waker) -> TimerKey {
+        let tick = self.tick(deadline);
+        let slot = (tick % SLOTS as u64) as usize;
+        let id = self.next_id;
+        self.next_id += 1;
+        if tick < self.next_tick {
+            // Its tick has been swept already, so it's due: no need to wait.
+            waker.wake();
+            return TimerKey { slot, id };
+        }
+        self.slots[slot].push(Timer { id, deadline, waker });
+        self.len += 1;
+        TimerKey { slot, id }
+    }
+
+    pub(super) fn update(&mut self, key: TimerKey, waker: &Waker) {
+        if let Some(timer) = self.slots[key.slot].iter_mut().find(|t| t.id == key.id)
+            && !timer.waker.will_wake(waker)
+        {
+            timer.waker = waker.clone();
+        }
+    }
+
+    // Does nothing if the timer has already fired.
+    pub(super) fn cancel(&mut self, key: TimerKey) {
+        let slot = &mut self.slots[key.slot];
+        if let Some(i) = slot.iter().position(|t| t.id == key.id) {
+            slot.swap_remove(i);
+            self.len -= 1;
+        }
+    }
+
+    // Wakes and forgets every timer due by 'now'.
+    pub(super) fn fire(&mut self, now: Instant) {
+        let now_tick = self.tick(now);
+        if now_tick < self.next_tick || self.len == 0 {
+            return;
+        }
+        // After a long gap one turn covers every slot.
+        let ticks = (now_tick - self.next_tick + 1).min(SLOTS as u64);
+        for tick in self.next_tick..self.next_tick + ticks {
+            let slot = &mut self.slots[(tick % SLOTS as u64) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].deadline <= now {
+                    slot.swap_remove(i).waker.wake();
+                    self.len -= 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        // A tick 'now' is partway through gets swept again next time.
+        let passed = now.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos();
+        self.next_tick = passed as u64 + 1;
+    }
+
+    pub(super) fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        // The first slot holding a timer due in this turn of the wheel...
+        for tick in self.next_tick..self.next_tick + SLOTS as u64 {
+            let slot = &self.slots[(tick % SLOTS as u64) as usize];
+            let due = slot.iter().filter(|t| self.tick(t.deadline) <= tick).map(|t| t.deadline).min();
+            if due.is_some() {
+                return due;
+            }
+        }
+        // ...or, if they're all further off, the nearest of them.
+        self.slots.iter().flatten().map(|t| t.deadline).min()
+    }
+}
+
+// Completes at 'deadline'.
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerKey>,
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, timer: None }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            if let Some(key) = self.timer.take() {
+                with_core(|core| core.timers.borrow_mut().cancel(key));
+            }
+            return Poll::Ready(());
+        }
+        let (deadline, timer) = (self.deadline, self.timer);
+        let key = with_core(|core| {
+            let mut timers = core.timers.borrow_mut();
+            match timer {
+                Some(key) => {
+                    timers.update(key, cx.waker());
+                    key
+                }
+                None => timers.insert(deadline, cx.waker().clone()),
+            }
+        });
+        self.timer = Some(key);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.timer.take() {
+            try_with_core(|core| core.timers.borrow_mut().cancel(key));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+// 'future''s output, unless 'duration' passes first; it's then dropped.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut sleep = sleep(duration);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut sleep).poll(cx).map(|_| Err(Elapsed))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::runtime::{block_on, spawn};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counter() -> (Arc<Count>, Waker) {
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        (Arc::clone(&count), Waker::from(count))
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn fires_timers_when_due_and_not_before() {
+        let start = Instant::now();
+        let mut wheel = Wheel::new(start);
+        let (count, waker) = counter();
+        wheel.insert(start + ms(5), waker.clone());
+        wheel.insert(start + ms(5), waker.clone());
+        wheel.insert(start + Duration::from_micros(9_500), waker);
+        assert_eq!(wheel.next_deadline(), Some(start + ms(5)));
+
+        wheel.fire(start + Duration::from_micros(4_999));
+        assert_eq!(count.0.load(Ordering::SeqCst), 0);
+        wheel.fire(start + ms(5));
+        assert_eq!(count.0.load(Ordering::SeqCst), 2);
+        assert_eq!(wheel.next_deadline(), Some(start + Duration::from_micros(9_500)));
+        // Not early, even within the same tick.
+        wheel.fire(start + Duration::from_micros(9_200));
+        assert_eq!(count.0.load(Ordering::SeqCst), 2);
+        wheel.fire(start + ms(10));
+        assert_eq!(count.0.load(Ordering::SeqCst), 3);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn timers_beyond_one_turn_wait_for_theirs() {
+        let start = Instant::now();
+        let mut wheel = Wheel::new(start);
+        let (count, waker) = counter();
+        // Same slot, one turn of the wheel apart.
+        wheel.insert(start + ms(SLOTS as u64 + 3), waker.clone());
+        wheel.insert(start + ms(3), waker.clone());
+        assert_eq!(wheel.next_deadline(), Some(start + ms(3)));
+        wheel.fire(start + ms(3));
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert_eq!(wheel.next_deadline(), Some(start + ms(SLOTS as u64 + 3)));
+        // A long gap: one sweep over every slot.
+        wheel.fire(start + ms(10 * SLOTS as u64));
+        assert_eq!(count.0.load(Ordering::SeqCst), 2);
+        // Already due when added: woken at once.
+        wheel.insert(start, waker);
+        assert_eq!(count.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let start = Instant::now();
+        let mut wheel = Wheel::new(start);
+        let (count, waker) = counter();
+        let key = wheel.insert(start + ms(2), waker.clone());
+        wheel.insert(start + ms(4), waker);
+        wheel.cancel(key);
+        wheel.cancel(key);
+        assert_eq!(wheel.next_deadline(), Some(start + ms(4)));
+        wheel.fire(start + ms(4));
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn timeout_gives_up_on_slow_futures() {
+        block_on(async {
+            assert_eq!(timeout(ms(50), async { 1 }).await, Ok(1));
+            assert_eq!(timeout(ms(10), sleep(Duration::from_secs(60))).await, Err(Elapsed));
+            let slow = spawn(timeout(ms(100), sleep(ms(20))));
+            assert_eq!(slow.await, Ok(()));
+        });
+    }
+}
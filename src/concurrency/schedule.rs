@@ -0,0 +1,567 @@
+// Runs jobs on a ThreadPool later: once after a delay, periodically, or on a
+// cron schedule. One scheduler thread keeps the jobs ordered by when they're
+// due and hands each to the pool when its time comes; it never runs a job
+// itself, so a slow job can't hold up the others.
+//
+// Periodic jobs never overlap with themselves. A fixed-rate job that is still
+// running when its next run is due runs again as soon as it finishes (once,
+// however many runs were missed); a fixed-delay job waits 'delay' after each
+// run finishes.
+//
+// Time comes from a Clock. SystemClock is the real one; ManualClock only
+// moves when told to, which makes tests of schedules deterministic.
+
+use std::collections::{BTreeSet, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::cron::{Cron, CronError};
+use super::ThreadPool;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    // Called once by each scheduler using the clock. A clock that can jump
+    // (like ManualClock) calls 'wake' whenever it does, so the scheduler
+    // looks at what's due again instead of sleeping on.
+    fn on_change(&self, _wake: Box<dyn Fn() + Send + Sync>) {}
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+    wakers: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> ManualClock {
+        ManualClock { now: Mutex::new(start), wakers: Mutex::new(Vec::new()) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn on_change(&self, wake: Box<dyn Fn() + Send + Sync>) {
+        self.wakers.lock().unwrap().push(wake);
+    }
+}
+
+// The real clock can be set while we sleep; don't sleep so long that we'd
+// notice late.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    pool: Arc<ThreadPool>,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    // (due, id), soonest first.
+    queue: BTreeSet<(SystemTime, u64)>,
+    entries: HashMap<u64, Entry>,
+    next_id: u64,
+    shutdown: bool,
+}
+
+struct Entry {
+    kind: Kind,
+    task: Task,
+    // When it's in 'queue', the time it's queued under.
+    due: Option<SystemTime>,
+    running: bool,
+    // Came due while running; run again when the current run finishes.
+    missed: bool,
+}
+
+enum Kind {
+    Once,
+    FixedRate(Duration),
+    FixedDelay(Duration),
+    Cron(Cron),
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+enum Task {
+    Once(Option<Job>),
+    Repeat(Arc<dyn Fn() + Send + Sync>),
+}
+
+// Cancels the job it was returned for. Dropping it doesn't.
+pub struct ScheduleHandle {
+    id: u64,
+    shared: Weak<Shared>,
+}
+
+impl Scheduler {
+    pub fn new(pool: Arc<ThreadPool>) -> Scheduler {
+        Scheduler::with_clock(pool, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(pool: Arc<ThreadPool>, clock: Arc<dyn Clock>) -> Scheduler {
+        let shared = Arc::new(Shared {
+            pool,
+            clock: Arc::clone(&clock),
+            state: Mutex::new(State { queue: BTreeSet::new(), entries: HashMap::new(), next_id: 0, shutdown: false }),
+            changed: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&shared);
+        clock.on_change(Box::new(move || {
+            if let Some(shared) = weak.upgrade() {
+                let _guard = shared.state.lock().unwrap();
+                shared.changed.notify_all();
+            }
+        }));
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("scheduler".to_string())
+                .spawn(move || shared.run())
+                .expect("failed to spawn the scheduler thread")
+        };
+        Scheduler { shared, thread: Some(thread) }
+    }
+
+    pub fn after(&self, delay: Duration, job: impl FnOnce() + Send + 'static) -> ScheduleHandle {
+        let due = self.shared.clock.now() + delay;
+        self.shared.add(Kind::Once, Task::Once(Some(Box::new(job))), Some(due))
+    }
+
+    // Runs at 'initial_delay', then every 'period' after that, measured from
+    // when each run was due rather than when it ran, so it doesn't drift.
+    pub fn at_fixed_rate(
+        &self,
+        initial_delay: Duration,
+        period: Duration,
+        job: impl Fn() + Send + Sync + 'static,
+    ) -> ScheduleHandle {
+        assert!(!period.is_zero());
+        let due = self.shared.clock.now() + initial_delay;
+        self.shared.add(Kind::FixedRate(period), Task::Repeat(Arc::new(job)), Some(due))
+    }
+
+    // Runs at 'initial_delay', then 'delay' after each run finishes.
+    pub fn with_fixed_delay(
+        &self,
+        initial_delay: Duration,
+        delay: Duration,
+        job: impl Fn() + Send + Sync + 'static,
+    ) -> ScheduleHandle {
+        let due = self.shared.clock.now() + initial_delay;
+        self.shared.add(Kind::FixedDelay(delay), Task::Repeat(Arc::new(job)), Some(due))
+    }
+
+    // Runs at every time 'expression' matches (see cron.rs).
+    pub fn cron(&self, expression: &str, job: impl Fn() + Send + Sync + 'static) -> Result<ScheduleHandle, CronError> {
+        let cron = Cron::parse(expression)?;
+        let due = cron.next_after(self.shared.clock.now());
+        Ok(self.shared.add(Kind::Cron(cron), Task::Repeat(Arc::new(job)), due))
+    }
+
+    // Jobs scheduled and not yet finished for good.
+    pub fn scheduled(&self) -> usize {
+        self.shared.state.lock().unwrap().entries.len()
+    }
+
+    // Stops scheduling. Runs already handed to the pool still finish;
+    // nothing else will run.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl ScheduleHandle {
+    // False if the job had already finished for good or been cancelled. A
+    // run in progress isn't interrupted, but nothing runs after it.
+    pub fn cancel(&self) -> bool {
+        let Some(shared) = self.shared.upgrade() else { return false };
+        let mut state = shared.state.lock().unwrap();
+        match state.entries.remove(&self.id) {
+            Some(entry) => {
+                if let Some(due) = entry.due {
+                    state.queue.remove(&(due, self.id));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.shared.upgrade().is_some_and(|shared| shared.state.lock().unwrap().entries.contains_key(&self.id))
+    }
+}
+
+impl Shared {
+    fn add(self: &Arc<Self>, kind: Kind, task: Task, due: Option<SystemTime>) -> ScheduleHandle {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        // A cron expression that never matches again has nothing to run.
+        if let Some(due) = due {
+            state.entries.insert(id, Entry { kind, task, due: Some(due), running: false, missed: false });
+            state.queue.insert((due, id));
+            self.changed.notify_all();
+        }
+        ScheduleHandle { id, shared: Arc::downgrade(self) }
+    }
+
+    fn run(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while !state.shutdown {
+            let now = self.clock.now();
+            let mut jobs = Vec::new();
+            while let Some(&(due, id)) = state.queue.first()
+                && due <= now
+            {
+                state.queue.pop_first();
+                jobs.extend(self.fire(&mut state, id, due, now));
+            }
+            if !jobs.is_empty() {
+                // A bounded pool can make add wait, for a worker that may
+                // itself be waiting for the lock in finished.
+                drop(state);
+                for job in jobs {
+                    self.pool.add(job);
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            let wait = match state.queue.first() {
+                Some(&(due, _)) => due.duration_since(now).unwrap_or_default().min(MAX_WAIT),
+                None => MAX_WAIT,
+            };
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    // 'id' is due: queue its next run, if it has one, and return this one
+    // for the pool unless the last is still going.
+    fn fire(self: &Arc<Self>, state: &mut State, id: u64, due: SystemTime, now: SystemTime) -> Option<Job> {
+        let entry = state.entries.get_mut(&id)?;
+        entry.due = match &entry.kind {
+            Kind::Once | Kind::FixedDelay(_) => None,
+            Kind::FixedRate(period) => {
+                // Skip the runs we're too late for.
+                let mut next = due + *period;
+                while next <= now {
+                    next += *period;
+                }
+                Some(next)
+            }
+            Kind::Cron(cron) => cron.next_after(now),
+        };
+        if let Some(next) = entry.due {
+            state.queue.insert((next, id));
+        }
+        if entry.running {
+            entry.missed = true;
+            None
+        } else {
+            self.start(state, id)
+        }
+    }
+
+    // The job for the next run of 'id'; it's up to the caller to hand it to
+    // the pool, once it has let go of the state.
+    fn start(self: &Arc<Self>, state: &mut State, id: u64) -> Option<Job> {
+        let entry = state.entries.get_mut(&id)?;
+        let job: Job = match &mut entry.task {
+            Task::Once(job) => {
+                let job = job.take().expect("one-shot jobs start once");
+                state.entries.remove(&id);
+                job
+            }
+            Task::Repeat(job) => {
+                entry.running = true;
+                let job = Arc::clone(job);
+                let shared = Arc::clone(self);
+                Box::new(move || {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| job()));
+                    shared.finished(id);
+                    // Let the pool's panic hook hear about it.
+                    if let Err(payload) = result {
+                        panic::resume_unwind(payload);
+                    }
+                })
+            }
+        };
+        Some(job)
+    }
+
+    // A run of a periodic job is over.
+    fn finished(self: &Arc<Self>, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let now = self.clock.now();
+        if state.shutdown {
+            return;
+        }
+        let Some(entry) = state.entries.get_mut(&id) else { return };
+        entry.running = false;
+        if let Kind::FixedDelay(delay) = entry.kind {
+            let due = now + delay;
+            entry.due = Some(due);
+            state.queue.insert((due, id));
+            self.changed.notify_all();
+        } else if entry.missed {
+            entry.missed = false;
+            if let Some(job) = self.start(&mut state, id) {
+                drop(state);
+                self.pool.add(job);
+            }
+        } else if entry.due.is_none() {
+            // A cron schedule that has run out.
+            state.entries.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::cron::utc;
+    use std::sync::mpsc;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn scheduler() -> (Scheduler, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(utc(2026, 10, 19, 10, 2)));
+        let scheduler = Scheduler::with_clock(Arc::new(ThreadPool::new(2)), clock.clone());
+        (scheduler, clock)
+    }
+
+    // Sends the clock's time each time the job runs.
+    fn recorder(clock: &Arc<ManualClock>) -> (impl Fn() + Send + Sync + 'static, mpsc::Receiver<SystemTime>) {
+        let (sender, receiver) = mpsc::channel();
+        let clock = Arc::clone(clock);
+        let sender = Mutex::new(sender);
+        (move || sender.lock().unwrap().send(clock.now()).unwrap(), receiver)
+    }
+
+    fn expect_run(receiver: &mpsc::Receiver<SystemTime>) -> SystemTime {
+        receiver.recv_timeout(Duration::from_secs(5)).expect("the job should have run")
+    }
+
+    fn expect_quiet(receiver: &mpsc::Receiver<SystemTime>) {
+        assert!(receiver.recv_timeout(Duration::from_millis(30)).is_err(), "the job ran too soon");
+    }
+
+    #[test]
+    fn runs_a_job_once_after_its_delay() {
+        let (scheduler, clock) = scheduler();
+        let (job, runs) = recorder(&clock);
+        let start = clock.now();
+        let handle = scheduler.after(10 * SECOND, job);
+
+        clock.advance(9 * SECOND);
+        expect_quiet(&runs);
+        clock.advance(SECOND);
+        assert_eq!(expect_run(&runs), start + 10 * SECOND);
+        clock.advance(60 * SECOND);
+        expect_quiet(&runs);
+        assert!(!handle.is_scheduled());
+        assert_eq!(scheduler.scheduled(), 0);
+    }
+
+    #[test]
+    fn fixed_rate_keeps_to_its_timetable() {
+        let (scheduler, clock) = scheduler();
+        let (job, runs) = recorder(&clock);
+        let start = clock.now();
+        scheduler.at_fixed_rate(5 * SECOND, 10 * SECOND, job);
+
+        clock.advance(5 * SECOND);
+        assert_eq!(expect_run(&runs), start + 5 * SECOND);
+        clock.advance(10 * SECOND);
+        assert_eq!(expect_run(&runs), start + 15 * SECOND);
+        // Three periods at once: one run, then back on the timetable.
+        clock.advance(30 * SECOND);
+        assert_eq!(expect_run(&runs), start + 45 * SECOND);
+        expect_quiet(&runs);
+        clock.advance(10 * SECOND);
+        assert_eq!(expect_run(&runs), start + 55 * SECOND);
+    }
+
+    #[test]
+    fn fixed_rate_runs_never_overlap() {
+        let (scheduler, clock) = scheduler();
+        let (started, starts) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (started, released) = (Mutex::new(started), Mutex::new(released));
+        scheduler.at_fixed_rate(Duration::ZERO, SECOND, move || {
+            started.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+        });
+
+        starts.recv_timeout(Duration::from_secs(5)).unwrap();
+        clock.advance(SECOND);
+        clock.advance(SECOND);
+        assert!(starts.recv_timeout(Duration::from_millis(30)).is_err());
+        // Two runs came due meanwhile; they make up one more.
+        release.send(()).unwrap();
+        starts.recv_timeout(Duration::from_secs(5)).unwrap();
+        release.send(()).unwrap();
+        assert!(starts.recv_timeout(Duration::from_millis(30)).is_err());
+        clock.advance(SECOND);
+        starts.recv_timeout(Duration::from_secs(5)).unwrap();
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn fixed_delay_counts_from_the_end_of_each_run() {
+        let (scheduler, clock) = scheduler();
+        let start = clock.now();
+        let (sender, runs) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let job_clock = Arc::clone(&clock);
+        // Each run takes 3 seconds of clock time.
+        let handle = scheduler.with_fixed_delay(SECOND, 10 * SECOND, move || {
+            sender.lock().unwrap().send(job_clock.now()).unwrap();
+            job_clock.advance(3 * SECOND);
+        });
+
+        clock.advance(SECOND);
+        assert_eq!(expect_run(&runs), start + SECOND);
+        // Wait for the run to end and the next one to be queued.
+        let queued = || scheduler.shared.state.lock().unwrap().entries[&handle.id].due;
+        while queued().is_none() {
+            thread::yield_now();
+        }
+        assert_eq!(queued(), Some(start + 14 * SECOND));
+        clock.advance(9 * SECOND);
+        expect_quiet(&runs);
+        clock.advance(SECOND);
+        assert_eq!(expect_run(&runs), start + 14 * SECOND);
+    }
+
+    #[test]
+    fn cron_jobs_run_when_the_expression_matches() {
+        let (scheduler, clock) = scheduler();
+        let (job, runs) = recorder(&clock);
+        scheduler.cron("*/5 * * * *", job).unwrap();
+
+        clock.advance(2 * 60 * SECOND);
+        expect_quiet(&runs);
+        clock.advance(60 * SECOND);
+        assert_eq!(expect_run(&runs), utc(2026, 10, 19, 10, 5));
+        clock.advance(5 * 60 * SECOND);
+        assert_eq!(expect_run(&runs), utc(2026, 10, 19, 10, 10));
+        assert!(scheduler.cron("*/5 * *", || {}).is_err());
+    }
+
+    #[test]
+    fn cancelled_jobs_stop_running() {
+        let (scheduler, clock) = scheduler();
+        let (job, runs) = recorder(&clock);
+        let periodic = scheduler.at_fixed_rate(SECOND, SECOND, job);
+        let (job, once_runs) = recorder(&clock);
+        let once = scheduler.after(SECOND, job);
+
+        assert!(once.cancel());
+        assert!(!once.cancel());
+        clock.advance(SECOND);
+        expect_run(&runs);
+        assert!(periodic.cancel());
+        clock.advance(5 * SECOND);
+        expect_quiet(&runs);
+        expect_quiet(&once_runs);
+        assert_eq!(scheduler.scheduled(), 0);
+    }
+
+    #[test]
+    fn a_panicking_run_doesnt_stop_the_schedule() {
+        let (scheduler, clock) = scheduler();
+        let (job, runs) = recorder(&clock);
+        scheduler.at_fixed_rate(SECOND, SECOND, move || {
+            job();
+            panic!("periodic job failed");
+        });
+        clock.advance(SECOND);
+        expect_run(&runs);
+        clock.advance(SECOND);
+        expect_run(&runs);
+    }
+
+    #[test]
+    fn works_with_a_bounded_pool() {
+        let clock = Arc::new(ManualClock::new(utc(2026, 10, 19, 10, 2)));
+        let scheduler = Scheduler::with_clock(Arc::new(ThreadPool::bounded(1, 1)), clock.clone());
+        let (started, starts) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (started, released) = (Mutex::new(started), Mutex::new(released));
+        let periodic = scheduler.at_fixed_rate(Duration::ZERO, SECOND, move || {
+            started.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+        });
+        starts.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The one worker is busy and the first job fills the queue, so the
+        // scheduler has to wait for room to add the second.
+        let (job, runs) = recorder(&clock);
+        scheduler.after(SECOND, job);
+        let (job, more_runs) = recorder(&clock);
+        scheduler.after(SECOND, job);
+        clock.advance(SECOND);
+        thread::sleep(Duration::from_millis(30));
+
+        assert!(periodic.cancel());
+        release.send(()).unwrap();
+        expect_run(&runs);
+        expect_run(&more_runs);
+        assert_eq!(scheduler.scheduled(), 0);
+    }
+
+    #[test]
+    fn runs_on_the_real_clock() {
+        let scheduler = Scheduler::new(Arc::new(ThreadPool::new(1)));
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        scheduler.at_fixed_rate(Duration::from_millis(5), Duration::from_millis(5), move || {
+            let _ = sender.lock().unwrap().send(());
+        });
+        for _ in 0..3 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        scheduler.shutdown();
+    }
+}
@@ -0,0 +1,215 @@
+// Scoped jobs: pool.scope(|s| { s.spawn(|_| ...); }) runs jobs on the pool
+// that may borrow from the caller's stack, because scope() doesn't return
+// until every job spawned in it, directly or from another job, has finished.
+// A panic in any of them is re-raised from scope() once they're all done.
+
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::pool::{Job, Shared, ThreadPool};
+
+// 'scope is how long jobs may run, 'env what they may borrow; the same
+// split as std::thread::scope.
+pub struct Scope<'scope, 'env: 'scope> {
+    shared: Arc<Shared>,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    finished: Condvar,
+    // The first panic; later ones are dropped.
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ThreadPool {
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            shared: Arc::clone(&self.shared),
+            state: Arc::new(ScopeState { pending: Mutex::new(0), finished: Condvar::new(), panic: Mutex::new(None) }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Even when f panicked: its jobs may still hold borrows.
+        scope.wait();
+        let job_panic = scope.state.panic.lock().unwrap().take();
+        match (result, job_panic) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(value), None) => value,
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub fn spawn<F>(&'scope self, job: F)
+    where
+        F: FnOnce(&'scope Scope<'scope, 'env>) + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+        let state = Arc::clone(&self.state);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| job(self))) {
+                state.panic.lock().unwrap().get_or_insert(payload);
+            }
+            // 'self' may be gone once this runs; only 'state' is ours.
+            let mut pending = state.pending.lock().unwrap();
+            *pending -= 1;
+            if *pending == 0 {
+                state.finished.notify_all();
+            }
+        });
+        // SAFETY: the job borrows nothing that lives shorter than 'scope, and
+        // scope() doesn't return (or unwind) before 'pending' says the job has
+        // run, so the borrows stay valid for as long as the pool holds it.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.shared.push(job);
+    }
+
+    // On one of the pool's own workers, blocking would take a worker away
+    // from the very jobs we wait for (with one worker, forever), so we run
+    // jobs while we wait instead.
+    fn wait(&self) {
+        let worker = self.shared.local_index();
+        let mut pending = self.state.pending.lock().unwrap();
+        while *pending > 0 {
+            match worker {
+                Some(index) => {
+                    drop(pending);
+                    match self.shared.find(index) {
+                        Some(job) => self.shared.run(index, job),
+                        None => {
+                            let pending = self.state.pending.lock().unwrap();
+                            if *pending > 0 {
+                                // Our jobs are running elsewhere; look again
+                                // soon in case one of them spawns more.
+                                let _ = self.state.finished.wait_timeout(pending, Duration::from_millis(1)).unwrap();
+                            }
+                        }
+                    }
+                    pending = self.state.pending.lock().unwrap();
+                }
+                None => pending = self.state.finished.wait(pending).unwrap(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::handle::panic_message;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let numbers: Vec<u64> = (1..=1000).collect();
+        let mut sums = [0u64; 10];
+        pool.scope(|s| {
+            for (chunk, sum) in numbers.chunks(100).zip(sums.iter_mut()) {
+                s.spawn(move |_| *sum = chunk.iter().sum());
+            }
+        });
+        assert_eq!(sums.iter().sum::<u64>(), 500_500);
+        assert_eq!(sums[0], 5050);
+    }
+
+    #[test]
+    fn waits_for_jobs_spawned_by_jobs() {
+        let pool = ThreadPool::new(3);
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|s| {
+                    for _ in 0..10 {
+                        s.spawn(|_| {
+                            std::thread::sleep(Duration::from_millis(1));
+                            count.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn returns_the_closures_value() {
+        let pool = ThreadPool::new(2);
+        let words = ["scoped", "jobs"];
+        let total = pool.scope(|s| {
+            s.spawn(|_| assert_eq!(words.len(), 2));
+            words.iter().map(|w| w.len()).sum::<usize>()
+        });
+        assert_eq!(total, 10);
+    }
+
+    #[test]
+    fn nested_scopes_on_a_single_worker_finish() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let inner = Arc::clone(&pool);
+        let handle = pool.submit(move || {
+            let data = [1, 2, 3];
+            let sum = AtomicUsize::new(0);
+            // Runs on the only worker, so that worker has to run these itself.
+            inner.scope(|s| {
+                for n in &data {
+                    let sum = &sum;
+                    s.spawn(move |_| {
+                        sum.fetch_add(*n, Ordering::SeqCst);
+                    });
+                }
+            });
+            sum.into_inner()
+        });
+        assert_eq!(handle.join(), Ok(6));
+    }
+
+    #[test]
+    fn a_panicking_job_panics_the_scope_after_the_others_finish() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|_| panic!("job failed"));
+                for _ in 0..5 {
+                    s.spawn(|_| {
+                        std::thread::sleep(Duration::from_millis(5));
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            })
+        }));
+        assert_eq!(panic_message(&*result.unwrap_err()), "job failed");
+        assert_eq!(finished.load(Ordering::SeqCst), 5);
+        // The pool is still usable.
+        assert_eq!(pool.submit(|| 1).join(), Ok(1));
+    }
+
+    #[test]
+    fn a_panicking_closure_still_waits_for_its_jobs() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|_| {
+                    std::thread::sleep(Duration::from_millis(20));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+                panic!("closure failed");
+            })
+        }));
+        assert_eq!(panic_message(&*result.unwrap_err()), "closure failed");
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,462 @@
+// Divide-and-conquer building blocks for slices: merge sort, quicksort, a
+// merge of two sorted slices and a join of two slices sorted by key. Each
+// splits its input, works on the parts in parallel through a Join (rayon's
+// pool, or ours), and falls back to plain sequential code for parts below
+// a cutoff, where handing work to another thread costs more than it saves.
+//
+// The merge and the join find their split points by binary search instead
+// of walking the input, so those steps are parallel too. Quicksort's
+// partition isn't: each level scans its part on one thread.
+
+use std::cmp::Ordering;
+
+use super::ThreadPool;
+
+// Parts this small are sorted with slice::sort / sort_unstable.
+pub const SORT_CUTOFF: usize = 4 * 1024;
+// Merges and joins of this many elements in all are done in one go.
+pub const MERGE_CUTOFF: usize = 8 * 1024;
+
+// Runs two closures, potentially in parallel, and returns both results.
+pub trait Join: Sync {
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send;
+}
+
+// rayon::join, on rayon's global pool.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rayon;
+
+impl Join for Rayon {
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        rayon::join(a, b)
+    }
+}
+
+impl Join for ThreadPool {
+    fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        ThreadPool::join(self, a, b)
+    }
+}
+
+impl ThreadPool {
+    // Runs 'b' on the pool and 'a' here. Waiting for 'b' on one of the
+    // pool's workers runs other jobs meanwhile (see scope), so jobs can
+    // join all the way down without running out of workers.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        let mut rb = None;
+        let slot = &mut rb;
+        let ra = self.scope(|s| {
+            s.spawn(move |_| *slot = Some(b()));
+            a()
+        });
+        (ra, rb.expect("scope waits for its jobs"))
+    }
+}
+
+// Stable, like slice::sort; needs a scratch copy of 'v'.
+pub fn merge_sort<T>(v: &mut [T], on: &impl Join)
+where
+    T: Ord + Clone + Send + Sync,
+{
+    let mut scratch = v.to_vec();
+    sort_runs(v, &mut scratch, false, on);
+}
+
+// Sorts 'v', leaving the result in 'v', or in 'scratch' if 'into_scratch'.
+// The halves are sorted into the other buffer and merged back, so the
+// result never needs copying.
+fn sort_runs<T>(v: &mut [T], scratch: &mut [T], into_scratch: bool, on: &impl Join)
+where
+    T: Ord + Clone + Send + Sync,
+{
+    if v.len() <= SORT_CUTOFF {
+        v.sort();
+        if into_scratch {
+            scratch.clone_from_slice(v);
+        }
+        return;
+    }
+    let mid = v.len() / 2;
+    {
+        let (v_left, v_right) = v.split_at_mut(mid);
+        let (s_left, s_right) = scratch.split_at_mut(mid);
+        on.join(
+            || sort_runs(v_left, s_left, !into_scratch, on),
+            || sort_runs(v_right, s_right, !into_scratch, on),
+        );
+    }
+    if into_scratch {
+        let (left, right) = v.split_at(mid);
+        merge(left, right, scratch, on);
+    } else {
+        let (left, right) = scratch.split_at(mid);
+        merge(left, right, v, on);
+    }
+}
+
+// Merges the sorted slices 'a' and 'b' into 'out', which must be exactly
+// as long as both. Stable: of equal elements, those from 'a' come first.
+pub fn merge<T>(a: &[T], b: &[T], out: &mut [T], on: &impl Join)
+where
+    T: Ord + Clone + Send + Sync,
+{
+    assert_eq!(a.len() + b.len(), out.len(), "merge needs room for exactly both slices");
+    if out.len() <= MERGE_CUTOFF {
+        merge_sequential(a, b, out);
+        return;
+    }
+    // Split the longer slice in the middle and the other where its middle
+    // element would go: everything in both left parts then sorts before
+    // everything in both right parts. Ties go left of b's pivot and right
+    // of a's, which keeps a's elements first.
+    let (a_mid, b_mid) = if a.len() >= b.len() {
+        let a_mid = a.len() / 2;
+        (a_mid, b.partition_point(|x| x < &a[a_mid]))
+    } else {
+        let b_mid = b.len() / 2;
+        (a.partition_point(|x| x <= &b[b_mid]), b_mid)
+    };
+    let (out_left, out_right) = out.split_at_mut(a_mid + b_mid);
+    on.join(
+        || merge(&a[..a_mid], &b[..b_mid], out_left, on),
+        || merge(&a[a_mid..], &b[b_mid..], out_right, on),
+    );
+}
+
+fn merge_sequential<T: Ord + Clone>(a: &[T], b: &[T], out: &mut [T]) {
+    let (mut i, mut j) = (0, 0);
+    for slot in out {
+        if j == b.len() || (i < a.len() && a[i] <= b[j]) {
+            slot.clone_from(&a[i]);
+            i += 1;
+        } else {
+            slot.clone_from(&b[j]);
+            j += 1;
+        }
+    }
+}
+
+// In place and unstable, like sort_unstable. Like it, too, it gives up on
+// splitting after too many lopsided partitions and sorts what's left
+// sequentially, so sorted or adversarial input can't make it quadratic.
+pub fn quicksort<T: Ord + Send>(v: &mut [T], on: &impl Join) {
+    let depth = 2 * (usize::BITS - v.len().leading_zeros());
+    quicksort_to(v, depth, on);
+}
+
+fn quicksort_to<T: Ord + Send>(v: &mut [T], depth: u32, on: &impl Join) {
+    if v.len() <= SORT_CUTOFF || depth == 0 {
+        v.sort_unstable();
+        return;
+    }
+    let (less, greater) = partition(v);
+    let (left, rest) = v.split_at_mut(less);
+    let right = &mut rest[greater - less..];
+    on.join(|| quicksort_to(left, depth - 1, on), || quicksort_to(right, depth - 1, on));
+}
+
+// Three-way partition around the median of the first, middle and last
+// elements, so runs of equal elements are done with in one go. Afterwards
+// v[..less] < pivot, v[less..greater] == pivot and v[greater..] > pivot.
+fn partition<T: Ord>(v: &mut [T]) -> (usize, usize) {
+    let (first, mid, last) = (0, v.len() / 2, v.len() - 1);
+    let median = if v[first] <= v[mid] {
+        if v[mid] <= v[last] { mid } else if v[first] <= v[last] { last } else { first }
+    } else if v[first] <= v[last] {
+        first
+    } else if v[mid] <= v[last] {
+        last
+    } else {
+        mid
+    };
+    v.swap(0, median);
+
+    // The pivot waits at v[0] while the rest is split up behind it.
+    let (pivot, rest) = v.split_first_mut().expect("partitioned slices aren't empty");
+    let (mut lt, mut i, mut gt) = (0, 0, rest.len());
+    while i < gt {
+        match rest[i].cmp(pivot) {
+            Ordering::Less => {
+                rest.swap(lt, i);
+                lt += 1;
+                i += 1;
+            }
+            Ordering::Equal => i += 1,
+            Ordering::Greater => {
+                gt -= 1;
+                rest.swap(i, gt);
+            }
+        }
+    }
+    // Into place, swapping with the last smaller element (or itself).
+    v.swap(0, lt);
+    (lt, gt + 1)
+}
+
+// An inner join of two slices sorted by key: every pair of elements with
+// equal keys, in key order, and for each key in the order of 'left', then
+// 'right'. Like a database merge join, but the slices are split at a key
+// found by binary search, and the parts joined in parallel.
+pub fn join_sorted<'a, L, R, K, FL, FR>(
+    left: &'a [L],
+    right: &'a [R],
+    left_key: FL,
+    right_key: FR,
+    on: &impl Join,
+) -> Vec<(&'a L, &'a R)>
+where
+    L: Sync,
+    R: Sync,
+    K: Ord,
+    FL: Fn(&L) -> K + Sync,
+    FR: Fn(&R) -> K + Sync,
+{
+    join_parts(left, right, &left_key, &right_key, on)
+}
+
+fn join_parts<'a, L, R, K, FL, FR>(
+    left: &'a [L],
+    right: &'a [R],
+    left_key: &FL,
+    right_key: &FR,
+    on: &impl Join,
+) -> Vec<(&'a L, &'a R)>
+where
+    L: Sync,
+    R: Sync,
+    K: Ord,
+    FL: Fn(&L) -> K + Sync,
+    FR: Fn(&R) -> K + Sync,
+{
+    if left.is_empty() || right.is_empty() {
+        return Vec::new();
+    }
+    if left.len() + right.len() <= MERGE_CUTOFF {
+        return join_sequential(left, right, left_key, right_key);
+    }
+    // The run of left's middle key, and the same key's run in right; what
+    // comes before both runs can only match before, and after after. Left's
+    // run isn't empty, so both parts are smaller than what we started with.
+    let key = left_key(&left[left.len() / 2]);
+    let left_run = run(left, &key, left_key);
+    let right_run = run(right, &key, right_key);
+    let (mut pairs, after) = on.join(
+        || join_parts(&left[..left_run.0], &right[..right_run.0], left_key, right_key, on),
+        || join_parts(&left[left_run.1..], &right[right_run.1..], left_key, right_key, on),
+    );
+    for l in &left[left_run.0..left_run.1] {
+        pairs.extend(right[right_run.0..right_run.1].iter().map(|r| (l, r)));
+    }
+    pairs.extend(after);
+    pairs
+}
+
+// Where the elements with 'key' start and end.
+fn run<T, K: Ord>(v: &[T], key: &K, key_of: impl Fn(&T) -> K) -> (usize, usize) {
+    (v.partition_point(|x| key_of(x) < *key), v.partition_point(|x| key_of(x) <= *key))
+}
+
+fn join_sequential<'a, L, R, K: Ord>(
+    left: &'a [L],
+    right: &'a [R],
+    left_key: impl Fn(&L) -> K,
+    right_key: impl Fn(&R) -> K,
+) -> Vec<(&'a L, &'a R)> {
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        let key = left_key(&left[i]);
+        match key.cmp(&right_key(&right[j])) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                let left_end = i + left[i..].iter().take_while(|l| left_key(l) == key).count();
+                let right_end = j + right[j..].iter().take_while(|r| right_key(r) == key).count();
+                for l in &left[i..left_end] {
+                    pairs.extend(right[j..right_end].iter().map(|r| (l, r)));
+                }
+                (i, j) = (left_end, right_end);
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rng, Rng};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn random(n: usize, range: u64) -> Vec<u64> {
+        let mut rng = rng();
+        (0..n).map(|_| rng.random_range(0..range)).collect()
+    }
+
+    // Sizes on both sides of the cutoffs, and inputs that trouble naive
+    // sorts: sorted, reversed, all equal, few distinct values.
+    fn inputs() -> Vec<Vec<u64>> {
+        let big = 5 * SORT_CUTOFF + 17;
+        vec![
+            Vec::new(),
+            vec![3],
+            random(1000, 1_000_000),
+            random(big, u64::MAX),
+            random(big, 4),
+            (0..big as u64).collect(),
+            (0..big as u64).rev().collect(),
+            vec![7; big],
+        ]
+    }
+
+    type Sort<'a> = dyn Fn(&mut [u64]) + 'a;
+
+    #[test]
+    fn sorts_match_slice_sort() {
+        let pool = ThreadPool::new(3);
+        for input in inputs() {
+            let mut expected = input.clone();
+            expected.sort();
+            let sorts: [(&str, &Sort); 4] = [
+                ("merge_sort on rayon", &|v| merge_sort(v, &Rayon)),
+                ("merge_sort on the pool", &|v| merge_sort(v, &pool)),
+                ("quicksort on rayon", &|v| quicksort(v, &Rayon)),
+                ("quicksort on the pool", &|v| quicksort(v, &pool)),
+            ];
+            for (name, sort) in sorts {
+                let mut v = input.clone();
+                sort(&mut v);
+                assert_eq!(v, expected, "{name}, {} elements", input.len());
+            }
+        }
+    }
+
+    // Compares by key only, so the order among equals shows.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Record {
+        key: u64,
+        id: usize,
+    }
+
+    impl Ord for Record {
+        fn cmp(&self, other: &Record) -> Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    impl PartialOrd for Record {
+        fn partial_cmp(&self, other: &Record) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    #[test]
+    fn merge_sort_is_stable() {
+        let records: Vec<_> = random(4 * MERGE_CUTOFF, 50).into_iter().enumerate().map(|(id, key)| Record { key, id }).collect();
+        let mut v = records.clone();
+        merge_sort(&mut v, &Rayon);
+        let mut expected = records;
+        expected.sort_by_key(|r| (r.key, r.id));
+        assert!(v.iter().zip(&expected).all(|(a, b)| a.id == b.id));
+    }
+
+    #[test]
+    fn merges_sorted_slices() {
+        let pool = ThreadPool::new(2);
+        for (a_len, b_len) in [(0, 10), (10, 0), (3 * MERGE_CUTOFF, 5), (7, 2 * MERGE_CUTOFF), (MERGE_CUTOFF, MERGE_CUTOFF + 1)] {
+            let mut a = random(a_len, 1000);
+            let mut b = random(b_len, 1000);
+            a.sort();
+            b.sort();
+            let mut out = vec![0; a_len + b_len];
+            merge(&a, &b, &mut out, &pool);
+            let mut expected = [a, b].concat();
+            expected.sort();
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn joins_by_key() {
+        // Few keys, so runs of equal keys cross the split points.
+        let mut orders: Vec<(u64, usize)> = random(3 * MERGE_CUTOFF, 300).into_iter().zip(0..).collect();
+        let mut customers: Vec<(u64, &str)> = random(MERGE_CUTOFF, 400).into_iter().map(|id| (id, "name")).collect();
+        orders.sort();
+        customers.sort();
+        let expected: Vec<_> = orders
+            .iter()
+            .flat_map(|o| customers.iter().filter(move |c| c.0 == o.0).map(move |c| (o, c)))
+            .collect();
+        let pool = ThreadPool::new(2);
+        assert_eq!(join_sorted(&orders, &customers, |o| o.0, |c| c.0, &pool), expected);
+        assert_eq!(join_sorted(&orders, &customers, |o| o.0, |c| c.0, &Rayon), expected);
+        assert!(join_sorted(&orders, &customers[..0], |o| o.0, |c| c.0, &Rayon).is_empty());
+    }
+
+    #[test]
+    fn pool_join_nests_on_one_worker() {
+        fn sum(v: &[u64], pool: &ThreadPool) -> u64 {
+            if v.len() <= 2 {
+                return v.iter().sum();
+            }
+            let (left, right) = v.split_at(v.len() / 2);
+            let (a, b) = pool.join(|| sum(left, pool), || sum(right, pool));
+            a + b
+        }
+        let pool = Arc::new(ThreadPool::new(1));
+        let v: Vec<u64> = (1..=1000).collect();
+        assert_eq!(sum(&v, &pool), 500_500);
+        // From the only worker itself, which has to run the other halves too.
+        let inner = Arc::clone(&pool);
+        assert_eq!(pool.submit(move || sum(&v, &inner)).join(), Ok(500_500));
+    }
+
+    // The parallel sorts against slice::sort and sort_unstable.
+    // cargo test --release bench_against_slice_sort -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_against_slice_sort() {
+        const N: usize = 4_000_000;
+        fn time(name: &str, input: &[u64], sort: impl Fn(&mut [u64])) {
+            let mut v = input.to_vec();
+            let started = Instant::now();
+            sort(&mut v);
+            let elapsed = started.elapsed();
+            assert!(v.is_sorted());
+            println!("{name:>20}: {N} elements in {elapsed:?}");
+        }
+
+        let input = random(N, u64::MAX);
+        let pool = ThreadPool::builder().build();
+        time("slice::sort", &input, |v| v.sort());
+        time("sort_unstable", &input, |v| v.sort_unstable());
+        time("merge_sort (rayon)", &input, |v| merge_sort(v, &Rayon));
+        time("merge_sort (pool)", &input, |v| merge_sort(v, &pool));
+        time("quicksort (rayon)", &input, |v| quicksort(v, &Rayon));
+        time("quicksort (pool)", &input, |v| quicksort(v, &pool));
+    }
+}
@@ -0,0 +1,509 @@
+// Blocking primitives built from a Mutex and Condvars: a counting Semaphore
+// handing out permits that give themselves back when dropped, a reusable
+// Barrier, a one-shot countdown Latch, and an RwLock that lets waiting
+// writers in ahead of new readers, so a steady stream of readers can't
+// starve them. Each blocking call has a _timeout variant.
+//
+// None of them are poisoned by a panic: the Mutex only guards their own
+// bookkeeping, never user code.
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Waits on 'condvar' while 'blocked' holds, until 'deadline' if there is one.
+// Returns the guard and whether we stopped waiting because time ran out.
+fn wait_while<'a, T>(
+    condvar: &Condvar,
+    mut guard: MutexGuard<'a, T>,
+    deadline: Option<Instant>,
+    mut blocked: impl FnMut(&mut T) -> bool,
+) -> (MutexGuard<'a, T>, bool) {
+    while blocked(&mut guard) {
+        match deadline {
+            None => guard = condvar.wait(guard).unwrap(),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return (guard, true);
+                }
+                guard = condvar.wait_timeout(guard, deadline - now).unwrap().0;
+            }
+        }
+    }
+    (guard, false)
+}
+
+fn deadline(timeout: Duration) -> Option<Instant> {
+    Some(Instant::now() + timeout)
+}
+
+// Semaphore
+
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+// Returns its permit to the semaphore when dropped.
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore { permits: Mutex::new(permits), released: Condvar::new() }
+    }
+
+    pub fn acquire(&self) -> Permit<'_> {
+        self.acquire_until(None).unwrap()
+    }
+
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        self.acquire_timeout(Duration::ZERO)
+    }
+
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<Permit<'_>> {
+        self.acquire_until(deadline(timeout))
+    }
+
+    fn acquire_until(&self, deadline: Option<Instant>) -> Option<Permit<'_>> {
+        let permits = self.permits.lock().unwrap();
+        let (mut permits, timed_out) = wait_while(&self.released, permits, deadline, |p| *p == 0);
+        if timed_out {
+            return None;
+        }
+        *permits -= 1;
+        Some(Permit { semaphore: self })
+    }
+
+    pub fn available(&self) -> usize {
+        *self.permits.lock().unwrap()
+    }
+
+    // Adds permits that weren't handed out by acquire.
+    pub fn add_permits(&self, n: usize) {
+        *self.permits.lock().unwrap() += n;
+        self.released.notify_all();
+    }
+}
+
+impl Permit<'_> {
+    // Keeps the permit out of the semaphore for good.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+// Barrier
+
+// Lets 'parties' threads wait for each other, then resets for the next
+// round.
+pub struct Barrier {
+    parties: usize,
+    state: Mutex<BarrierState>,
+    released: Condvar,
+}
+
+struct BarrierState {
+    arrived: usize,
+    // Which round we're in; waiters from an earlier one know they're free.
+    generation: u64,
+}
+
+impl Barrier {
+    pub fn new(parties: usize) -> Barrier {
+        assert!(parties > 0, "a barrier needs at least one party");
+        Barrier { parties, state: Mutex::new(BarrierState { arrived: 0, generation: 0 }), released: Condvar::new() }
+    }
+
+    // Returns true in exactly one thread per round: the one that arrived
+    // last.
+    pub fn wait(&self) -> bool {
+        self.wait_until(None).unwrap()
+    }
+
+    // None if the round didn't fill up in time; we're then no longer
+    // counted as waiting in it.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<bool> {
+        self.wait_until(deadline(timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Option<bool> {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        state.arrived += 1;
+        if state.arrived == self.parties {
+            state.arrived = 0;
+            state.generation += 1;
+            self.released.notify_all();
+            return Some(true);
+        }
+        let (mut state, timed_out) = wait_while(&self.released, state, deadline, |s| s.generation == generation);
+        if timed_out {
+            state.arrived -= 1;
+            return None;
+        }
+        Some(false)
+    }
+}
+
+// Latch
+
+// Opens once count_down() has been called 'count' times, and stays open.
+pub struct Latch {
+    count: Mutex<usize>,
+    opened: Condvar,
+}
+
+impl Latch {
+    pub fn new(count: usize) -> Latch {
+        Latch { count: Mutex::new(count), opened: Condvar::new() }
+    }
+
+    pub fn count_down(&self) {
+        let mut count = self.count.lock().unwrap();
+        if *count > 0 {
+            *count -= 1;
+            if *count == 0 {
+                self.opened.notify_all();
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.lock().unwrap()
+    }
+
+    pub fn wait(&self) {
+        self.wait_until(None);
+    }
+
+    // Returns whether the latch opened in time.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(deadline(timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> bool {
+        let count = self.count.lock().unwrap();
+        !wait_while(&self.opened, count, deadline, |c| *c > 0).1
+    }
+}
+
+// RwLock
+
+pub struct RwLock<T: ?Sized> {
+    state: Mutex<RwState>,
+    readable: Condvar,
+    writable: Condvar,
+    data: UnsafeCell<T>,
+}
+
+#[derive(Default)]
+struct RwState {
+    readers: usize,
+    writer: bool,
+    // New readers queue behind these.
+    waiting_writers: usize,
+}
+
+// The guards hand out &T to many threads at once and &mut T to one, like
+// std's RwLock.
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct ReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct WriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock { state: Mutex::new(RwState::default()), readable: Condvar::new(), writable: Condvar::new(), data: UnsafeCell::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        self.read_until(None).unwrap()
+    }
+
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        self.read_timeout(Duration::ZERO)
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Option<ReadGuard<'_, T>> {
+        self.read_until(deadline(timeout))
+    }
+
+    fn read_until(&self, deadline: Option<Instant>) -> Option<ReadGuard<'_, T>> {
+        let state = self.state.lock().unwrap();
+        let (mut state, timed_out) = wait_while(&self.readable, state, deadline, |s| s.writer || s.waiting_writers > 0);
+        if timed_out {
+            return None;
+        }
+        state.readers += 1;
+        Some(ReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.write_until(None).unwrap()
+    }
+
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        self.write_timeout(Duration::ZERO)
+    }
+
+    pub fn write_timeout(&self, timeout: Duration) -> Option<WriteGuard<'_, T>> {
+        self.write_until(deadline(timeout))
+    }
+
+    fn write_until(&self, deadline: Option<Instant>) -> Option<WriteGuard<'_, T>> {
+        let mut state = self.state.lock().unwrap();
+        state.waiting_writers += 1;
+        let (mut state, timed_out) = wait_while(&self.writable, state, deadline, |s| s.writer || s.readers > 0);
+        state.waiting_writers -= 1;
+        if timed_out {
+            // Readers held back for our sake can go now.
+            if state.waiting_writers == 0 && !state.writer {
+                self.readable.notify_all();
+            }
+            return None;
+        }
+        state.writer = true;
+        Some(WriteGuard { lock: self })
+    }
+}
+
+impl<T: ?Sized> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: no writer can get in while we count as a reader.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().unwrap();
+        state.readers -= 1;
+        if state.readers == 0 && state.waiting_writers > 0 {
+            self.lock.writable.notify_one();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: we're the only writer and there are no readers.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: as above.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock().unwrap();
+        state.writer = false;
+        if state.waiting_writers > 0 {
+            self.lock.writable.notify_one();
+        } else {
+            self.lock.readable.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 16;
+
+    #[test]
+    fn semaphore_never_lets_more_in_than_it_has_permits() {
+        let semaphore = Semaphore::new(3);
+        let inside = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        let _permit = semaphore.acquire();
+                        let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        thread::yield_now();
+                        inside.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(most.load(Ordering::SeqCst) <= 3);
+        assert_eq!(semaphore.available(), 3);
+    }
+
+    #[test]
+    fn semaphore_timeouts_and_forgotten_permits() {
+        let semaphore = Semaphore::new(1);
+        let permit = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        assert!(semaphore.acquire_timeout(Duration::from_millis(10)).is_none());
+        drop(permit);
+        semaphore.acquire().forget();
+        assert_eq!(semaphore.available(), 0);
+        semaphore.add_permits(2);
+        assert_eq!(semaphore.available(), 2);
+    }
+
+    #[test]
+    fn barrier_holds_every_round_together() {
+        let barrier = Barrier::new(THREADS);
+        let arrivals = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for round in 0..50 {
+                        arrivals.fetch_add(1, Ordering::SeqCst);
+                        if barrier.wait() {
+                            leaders.fetch_add(1, Ordering::SeqCst);
+                        }
+                        // Everyone arrived before anyone left.
+                        assert!(arrivals.load(Ordering::SeqCst) >= (round + 1) * THREADS);
+                        barrier.wait();
+                    }
+                });
+            }
+        });
+        assert_eq!(leaders.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn a_timed_out_barrier_waiter_no_longer_counts() {
+        let barrier = Barrier::new(2);
+        assert_eq!(barrier.wait_timeout(Duration::from_millis(10)), None);
+        // Had the first waiter still counted, this would release at once.
+        assert_eq!(barrier.wait_timeout(Duration::from_millis(10)), None);
+        thread::scope(|s| {
+            let other = s.spawn(|| barrier.wait_timeout(Duration::from_secs(10)));
+            let mine = barrier.wait_timeout(Duration::from_secs(10));
+            let mut results = [mine, other.join().unwrap()];
+            results.sort();
+            assert_eq!(results, [Some(false), Some(true)]);
+        });
+    }
+
+    #[test]
+    fn latch_opens_after_the_last_count_down() {
+        let latch = Latch::new(THREADS);
+        let done = AtomicUsize::new(0);
+        assert!(!latch.wait_timeout(Duration::from_millis(5)));
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    done.fetch_add(1, Ordering::SeqCst);
+                    latch.count_down();
+                });
+            }
+            latch.wait();
+            assert_eq!(done.load(Ordering::SeqCst), THREADS);
+        });
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        assert!(latch.wait_timeout(Duration::ZERO));
+    }
+
+    #[test]
+    fn rwlock_readers_share_and_writers_exclude() {
+        let lock = RwLock::new(0usize);
+        let readers = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for i in 0..THREADS {
+                let (lock, readers) = (&lock, &readers);
+                s.spawn(move || {
+                    for _ in 0..200 {
+                        if i % 4 == 0 {
+                            let mut value = lock.write();
+                            assert_eq!(readers.load(Ordering::SeqCst), 0);
+                            *value += 1;
+                        } else {
+                            let value = lock.read();
+                            readers.fetch_add(1, Ordering::SeqCst);
+                            assert!(*value <= 4 * 200);
+                            readers.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.into_inner(), 4 * 200);
+    }
+
+    #[test]
+    fn rwlock_prefers_waiting_writers() {
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let reading = lock.read();
+
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || lock.write().push("writer"))
+        };
+        while lock.state.lock().unwrap().waiting_writers == 0 {
+            thread::yield_now();
+        }
+        // A new reader has to wait behind the writer, even though only
+        // readers hold the lock right now.
+        assert!(lock.try_read().is_none());
+        let reader = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || lock.read().len())
+        };
+        drop(reading);
+        writer.join().unwrap();
+        assert_eq!(reader.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn rwlock_timeouts() {
+        let lock = RwLock::new(1);
+        let writing = lock.write();
+        assert!(lock.try_read().is_none());
+        assert!(lock.read_timeout(Duration::from_millis(5)).is_none());
+        assert!(lock.write_timeout(Duration::from_millis(5)).is_none());
+        drop(writing);
+
+        let reading = lock.read();
+        assert!(lock.write_timeout(Duration::from_millis(5)).is_none());
+        // The writer that gave up no longer holds readers back.
+        assert_eq!(*lock.try_read().unwrap(), 1);
+        drop(reading);
+        *lock.try_write().unwrap() += 1;
+        assert_eq!(*lock.read(), 2);
+    }
+}
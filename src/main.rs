@@ -82,6 +82,17 @@ fn hex_or_die_trying(maybe_string: Option<String>) -> Result<u32, String>
 
 fn main()
 {
+    // cargo run -- serve [options]: run the tcp server instead of the examples
+    if std::env::args().nth(1).as_deref() == Some("serve")
+    {
+        if let Err(e) = tcp::serve_command(std::env::args().skip(2))
+        {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let p = Point(rng().random_range(10..20), 23);
     println!("({}, {})", p.0, p.1);
     println!("{}", size_of::<usize>());
@@ -245,6 +256,7 @@ fn main()
     concurrency::move_closures();
     concurrency::channels();
     concurrency::mutexes();
+    concurrency::lock_ordering();
     
     let mut o = IncapsulatedCollection::new();
     o.add(1);
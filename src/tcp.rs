@@ -1,80 +1,771 @@
-use std::{fs, thread};
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::time::Duration;
+use std::{env, fs, io, thread};
+use std::io::{BufRead, BufReader, Read};
+use std::net::{Shutdown, TcpListener};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+pub mod asynchronous;
+pub mod cookie;
+pub mod form;
+pub mod h2;
+pub mod http;
+pub mod limits;
+pub mod listener;
+pub mod multipart;
+#[cfg(target_os = "linux")]
+pub mod nonblocking;
+#[cfg(target_os = "linux")]
+pub mod poll;
+pub mod router;
+pub mod session;
+pub mod sse;
+pub mod template;
+pub mod vhost;
+
+use crate::concurrency::ThreadPool;
+#[cfg(target_os = "linux")]
+use crate::concurrency::runtime;
+#[cfg(target_os = "linux")]
+use asynchronous::AsyncServer;
+use cookie::{SameSite, SetCookie};
+use http::{Request, Response};
+use limits::{DeadlineReader, Limits, RequestError};
+use listener::{Listener, Stream};
+use multipart::{MultipartError, Upload, UploadLimits};
+use session::SessionStore;
+use sse::{Event, Hub, StreamSlots, DEFAULT_HEARTBEAT};
+use template::Context;
+use vhost::{Site, VirtualHosts};
+
+// The site that answers when the Host header matches nothing else:
+// hello.html on "/" and "/sleep", greeting ?name= if there is one; an upload
+// that reports what it got on "/upload"; a login kept in a session on
+// "/login" and "/logout", announced to whoever watches "/events"; 404.html
+// for everything else.
+pub fn default_site() -> Site {
+    let sessions = Arc::new(SessionStore::new(Duration::from_secs(30 * 60)));
+    let logins = Arc::new(Hub::new(20, DEFAULT_HEARTBEAT));
+    Site::new(".")
+        .route("GET", "/", hello)
+        .route("GET", "/sleep", |request, site| {
+            thread::sleep(Duration::from_secs(5));
+            hello(request, site)
+        })
+        .upload("/upload", env::temp_dir(), UploadLimits::default(), |_, multipart, _| {
+            let mut report = String::new();
+            for (name, value) in &multipart.fields {
+                report += &format!("{name}: {value}\n");
+            }
+            // Nothing keeps them; this is only a demo.
+            for file in &multipart.files {
+                let content_type = file.content_type.as_deref().unwrap_or("no type");
+                report += &format!("{}: {} ({content_type}, {} bytes)\n", file.field, file.filename, file.size);
+                let _ = fs::remove_file(&file.path);
+            }
+            Response::text(200, report)
+        })
+        .route("GET", "/login", {
+            let sessions = Arc::clone(&sessions);
+            move |request, _| {
+                // Only logging in starts a session; looking doesn't save one.
+                match sessions.load(request).data.get("user") {
+                    Some(user) => Response::text(200, format!("logged in as {user}\n")),
+                    None => Response::text(200, "not logged in\n"),
+                }
+            }
+        })
+        .route("POST", "/login", {
+            let (sessions, logins) = (Arc::clone(&sessions), Arc::clone(&logins));
+            move |request, _| {
+                let form = request.form().unwrap_or_default();
+                let Some(user) = form.get("user").filter(|user| !user.is_empty()) else {
+                    return Response::text(400, "a form with a user field, please\n");
+                };
+                let mut session = sessions.load(request);
+                session.data.insert("user".to_string(), user.to_string());
+                logins.publish(Event::new(format!("{user} logged in")).event("login"));
+                sessions.save(session, Response::text(200, format!("logged in as {user}\n")))
+            }
+        })
+        .route("POST", "/logout", {
+            let (sessions, logins) = (Arc::clone(&sessions), Arc::clone(&logins));
+            move |request, _| {
+                let session = sessions.load(request);
+                if let Some(user) = session.data.get("user") {
+                    logins.publish(Event::new(format!("{user} logged out")).event("logout"));
+                }
+                sessions.destroy(session, Response::text(200, "logged out\n"))
+            }
+        })
+        .route("GET", "/events", move |request, _| {
+            // Logins are rare; there's no hurry to reconnect.
+            Response::events(logins.subscribe(request.last_event_id()).retry(Duration::from_secs(10)))
+        })
+        .error_page(404, "404.html")
+}
+
+// Greets ?name=, or else the name remembered from last time, or else Rust.
+fn hello(request: &Request, site: &Site) -> Response {
+    let query = request.query_form();
+    let asked = query.get("name").filter(|name| !name.is_empty());
+    let name = asked.or(request.cookie("name")).unwrap_or("Rust");
+    let response = site.render("hello.html", &Context::new().with("name", name));
+    // A cookie value can't hold just anything; plain names are enough here.
+    match asked.filter(|name| name.bytes().all(|b| b.is_ascii_alphanumeric())) {
+        Some(name) => {
+            let cookie = SetCookie::new("name", name)
+                .path("/")
+                .max_age(Duration::from_secs(365 * 24 * 60 * 60))
+                .same_site(SameSite::Strict);
+            response.with_cookie(&cookie)
+        }
+        None => response,
+    }
+}
 
 #[allow(unused)]
-pub struct ThreadPool {
-    workers: Vec<Worker>,
+pub fn establish_connection() {
+    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    Server::new(VirtualHosts::new(default_site())).serve(listener);
 }
+
+// The same demo on the async runtime: "/sleep" waits on a timer instead of
+// a worker thread, so nobody else waits with it.
+#[cfg(target_os = "linux")]
 #[allow(unused)]
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id))
+pub fn establish_async_connection() {
+    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    async_demo(Duration::from_secs(5)).serve(listener).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+fn async_demo(pause: Duration) -> AsyncServer {
+    AsyncServer::new(VirtualHosts::new(default_site())).route("GET", "/sleep", move |request, hosts| async move {
+        runtime::sleep(pause).await;
+        hello(&request, hosts.site_for(&request))
+    })
+}
+
+// How long a closing connection keeps swallowing client input, see 'linger_close'.
+const LINGER: Duration = Duration::from_millis(500);
+// The pause after a failed accept.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Everything a server loop needs apart from the listener.
+pub struct Server {
+    hosts: Arc<VirtualHosts>,
+    limits: Limits,
+    workers: usize,
+    queue: Option<usize>,
+}
+
+impl Server {
+    pub fn new(hosts: VirtualHosts) -> Server {
+        Server { hosts: Arc::new(hosts), limits: Limits::default(), workers: 4, queue: None }
+    }
+
+    // Thread pool size for 'serve'; the non-blocking mode always uses one thread.
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.workers = workers;
+        self
+    }
+
+    // Connections accepted but not yet picked up by a worker. Past this the
+    // accept loop waits, and new clients queue in the listen backlog
+    // instead of in our memory.
+    pub fn queue(mut self, connections: usize) -> Server {
+        self.queue = Some(connections);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+
+    // One listener for every site; the Host header of each request picks the site.
+    pub fn serve(self, listener: impl Into<Listener>) {
+        self.serve_all(vec![listener.into()]);
+    }
+
+    // Several listeners, say a TCP port and a Unix socket, feeding one pool.
+    pub fn serve_all(self, listeners: Vec<Listener>) {
+        let mut pool = ThreadPool::builder().size(self.workers).thread_name("http-worker");
+        if let Some(connections) = self.queue {
+            pool = pool.bounded(connections);
+        }
+        let pool = pool.build();
+        let streams = StreamSlots::new(self.limits.max_streams);
+        thread::scope(|scope| {
+            for listener in &listeners {
+                scope.spawn(|| self.accept_loop(listener, &pool, &streams));
+            }
+        });
+    }
+
+    fn accept_loop(&self, listener: &Listener, pool: &ThreadPool, streams: &StreamSlots) {
+        loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // Out of file descriptors and the like: retrying at once
+                    // would only spin. Give open connections a moment to close.
+                    eprintln!("accept failed: {e}");
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            println!("Connection established!");
+
+            let hosts = Arc::clone(&self.hosts);
+            let limits = self.limits;
+            let streams = streams.clone();
+            pool.add(move || {
+                if let Err(e) = handle_connection(stream, &hosts, &limits, &streams) {
+                    eprintln!("connection error: {e}");
+                }
+            });
         }
-        ThreadPool { workers }
     }
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        thread::spawn(f);
+
+    #[cfg(all(test, target_os = "linux"))]
+    pub fn serve_nonblocking(self, listener: impl Into<Listener>) -> io::Result<()> {
+        self.serve_all_nonblocking(vec![listener.into()])
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn serve_all_nonblocking(self, listeners: Vec<Listener>) -> io::Result<()> {
+        nonblocking::run(listeners, self.hosts, self.limits)
     }
 }
 
-#[allow(unused)]
-struct Worker {
-    id: usize,
-    thread: thread::JoinHandle<()>,
+// cargo run -- serve [--addr 127.0.0.1:7878] [--unix PATH [--unix-mode 660]] [--workers N [--queue N]]
+//                    [--host NAME=DIR]... [LIMITS] [--nonblocking | --async]
+// A target for the loadgen binary. With --unix alone there is no TCP listener;
+// give --addr as well to get both. Each --host serves DIR to requests for NAME,
+// everything else gets the demo site. --async serves the async demo, TCP only.
+// LIMITS are any of --idle-timeout, --header-timeout and --body-timeout in
+// seconds, and --max-request-line, --max-headers, --max-header-size,
+// --max-body and --max-streams; see 'Limits'.
+pub fn serve_command(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut addr = None;
+    let mut unix = None;
+    let mut unix_mode = 0o660;
+    let mut workers = 4;
+    let mut queue = None;
+    let mut nonblocking = false;
+    let mut asynchronous = false;
+    let mut sites = Vec::new();
+    let mut limits = Limits::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => addr = Some(args.next().ok_or("--addr needs a value")?),
+            "--unix" => unix = Some(args.next().ok_or("--unix needs a path")?),
+            "--unix-mode" => {
+                unix_mode = args
+                    .next()
+                    .and_then(|mode| u32::from_str_radix(&mode, 8).ok())
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or("--unix-mode needs octal permissions like 660")?;
+            }
+            "--workers" => {
+                workers = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or("--workers needs a positive number")?;
+            }
+            "--queue" => {
+                queue = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or("--queue needs a positive number")?,
+                );
+            }
+            "--host" => {
+                let host = args.next().ok_or("--host needs NAME=DIR")?;
+                let (name, dir) = host.split_once('=').ok_or("--host needs NAME=DIR")?;
+                sites.push((name.to_string(), static_site(dir)));
+            }
+            "--nonblocking" => nonblocking = true,
+            "--async" => asynchronous = true,
+            _ if set_limit(&mut limits, &arg, &mut args)? => {}
+            _ => return Err(format!("unknown option {arg}")),
+        }
+    }
+
+    if asynchronous {
+        if nonblocking || unix.is_some() || !sites.is_empty() {
+            return Err("--async serves one TCP address and nothing else".to_string());
+        }
+        let addr = addr.unwrap_or_else(|| String::from("127.0.0.1:7878"));
+        let listener = TcpListener::bind(&addr).map_err(|e| format!("can't listen on {addr}: {e}"))?;
+        println!("Serving {addr} (async)");
+        #[cfg(target_os = "linux")]
+        return async_demo(Duration::from_secs(5)).limits(limits).serve(listener).map_err(|e| e.to_string());
+        #[cfg(not(target_os = "linux"))]
+        return Err("the async mode needs epoll".to_string());
+    }
+
+    let mut listeners = Vec::new();
+    if addr.is_some() || unix.is_none() {
+        let addr = addr.unwrap_or_else(|| String::from("127.0.0.1:7878"));
+        listeners.push(Listener::bind_tcp(&addr).map_err(|e| format!("can't listen on {addr}: {e}"))?);
+    }
+    if let Some(path) = unix {
+        #[cfg(unix)]
+        listeners.push(Listener::bind_unix(&path, unix_mode).map_err(|e| format!("can't listen on {path}: {e}"))?);
+        #[cfg(not(unix))]
+        return Err("Unix domain sockets need a Unix".to_string());
+    }
+
+    let hosts = sites
+        .into_iter()
+        .fold(VirtualHosts::new(default_site()), |hosts, (name, site)| hosts.host(&name, site));
+    let mut server = Server::new(hosts).workers(workers).limits(limits);
+    if let Some(connections) = queue {
+        server = server.queue(connections);
+    }
+    if nonblocking {
+        listeners.iter().for_each(|l| println!("Serving {l} (non-blocking)"));
+        #[cfg(target_os = "linux")]
+        return server.serve_all_nonblocking(listeners).map_err(|e| e.to_string());
+        #[cfg(not(target_os = "linux"))]
+        return Err("the non-blocking mode needs epoll".to_string());
+    }
+    listeners.iter().for_each(|l| println!("Serving {l} ({workers} workers)"));
+    server.serve_all(listeners);
+    Ok(())
 }
-impl Worker {
-    fn new(id: usize) -> Worker {
-        let thread = thread::spawn(|| {});
-        Worker { id, thread, }
+
+// One of the LIMITS flags of 'serve', with its value taken from 'args'.
+// Ok(false) means 'flag' isn't one of them.
+fn set_limit(limits: &mut Limits, flag: &str, args: &mut impl Iterator<Item = String>) -> Result<bool, String> {
+    let seconds = |args: &mut dyn Iterator<Item = String>| {
+        args.next()
+            .and_then(|secs| secs.parse().ok())
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .filter(|timeout| !timeout.is_zero())
+            .ok_or(format!("{flag} needs a positive number of seconds"))
+    };
+    let size = |args: &mut dyn Iterator<Item = String>| {
+        args.next()
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n > 0)
+            .ok_or(format!("{flag} needs a positive number"))
+    };
+    match flag {
+        "--idle-timeout" => limits.idle_timeout = seconds(args)?,
+        "--header-timeout" => limits.header_timeout = seconds(args)?,
+        "--body-timeout" => limits.body_timeout = seconds(args)?,
+        "--max-request-line" => limits.max_request_line = size(args)?,
+        "--max-headers" => limits.max_headers = size(args)?,
+        "--max-header-size" => limits.max_header_size = size(args)?,
+        "--max-body" => limits.max_body_size = size(args)?,
+        "--max-streams" => limits.max_streams = size(args)?,
+        _ => return Ok(false),
     }
+    Ok(true)
 }
-#[allow(unused)]
-pub fn establish_connection() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-    
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        println!("Connection established!");
-
-        pool.execute(move || {
-            handle_connection(stream);
-        });
+
+// A site of plain files for 'serve --host': DIR/index.html on "/",
+// DIR/404.html for everything else.
+fn static_site(dir: &str) -> Site {
+    Site::new(dir)
+        .route("GET", "/", |_, site| site.file("index.html"))
+        .error_page(404, "404.html")
+}
+
+// Serves requests on one connection until the client closes it, asks to,
+// or breaks one of the limits.
+fn handle_connection(mut stream: Stream, hosts: &VirtualHosts, limits: &Limits, streams: &StreamSlots) -> io::Result<()> {
+    let mut buf_reader = BufReader::new(DeadlineReader::new(stream.try_clone()?));
+    loop {
+        // An idle kept-alive connection is closed without a word.
+        buf_reader.get_mut().set_deadline(Some(Instant::now() + limits.idle_timeout));
+        match buf_reader.fill_buf() {
+            Ok([]) => return Ok(()), // the client went away without asking anything
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let request = match read_request(&mut buf_reader, hosts, limits) {
+            Ok(request) => request,
+            Err(RequestError::Io(e)) => return Err(e),
+            Err(e) => {
+                if let Some(response) = e.response() {
+                    response.write_to(&mut stream)?;
+                }
+                linger_close(&mut stream);
+                return Ok(());
+            }
+        };
+
+        if h2::is_preface(&request) {
+            return h2::server::serve_prior_knowledge(buf_reader, stream, hosts, limits);
+        }
+        if h2::wants_upgrade(&request) {
+            return h2::server::serve_upgrade(buf_reader, stream, hosts, limits, request);
+        }
+
+        let site = hosts.site_for(&request);
+        let (response, keep_alive) = match site.upload_for(&request) {
+            Some(upload) => match stream_upload(&mut buf_reader, &request, upload, site) {
+                Ok(answer) => answer,
+                Err(e) => {
+                    site.with_error_page(e.response()).write_to(&mut stream)?;
+                    linger_close(&mut stream);
+                    return Ok(());
+                }
+            },
+            None => respond(hosts, &request),
+        };
+        if response.events.is_some() {
+            // Runs until the events end or the client goes away, so it gets
+            // a thread of its own rather than holding on to a worker.
+            let Some(slot) = streams.try_acquire() else {
+                return StreamSlots::unavailable().write_to(&mut stream);
+            };
+            thread::spawn(move || {
+                let _slot = slot;
+                sse::write_response(&mut stream, response)
+            });
+            return Ok(());
+        }
+        response.write_to(&mut stream)?;
+        if !keep_alive {
+            return Ok(());
+        }
     }
 }
 
-#[allow(unused)]
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&mut stream);
-    let request_line = buf_reader
-        .lines()
-        .next()
-        .unwrap()// takes care of the Option and stops the program if the iterator has no items
-        .unwrap(); // handles the Result
-
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "hello.html")
+// The header and body deadlines run from when each part starts arriving.
+// An upload's body is left to stream_upload.
+fn read_request(reader: &mut BufReader<DeadlineReader>, hosts: &VirtualHosts, limits: &Limits) -> Result<Request, RequestError> {
+    reader.get_mut().set_deadline(Some(Instant::now() + limits.header_timeout));
+    let mut request = Request::read_head(reader, limits)?
+        .ok_or(RequestError::Malformed("connection closed mid-request"))?;
+    reader.get_mut().set_deadline(Some(Instant::now() + limits.body_timeout));
+    if hosts.site_for(&request).upload_for(&request).is_none() {
+        request.read_body(reader, limits)?;
+    }
+    Ok(request)
+}
+
+// Hands the upload its body straight off the connection, so files go to
+// disk as they arrive. After an error the body may be partly unread, and
+// the connection can't be used again.
+fn stream_upload(
+    reader: &mut BufReader<DeadlineReader>,
+    request: &Request,
+    upload: &Upload,
+    site: &Site,
+) -> Result<(Response, bool), MultipartError> {
+    let length = request.content_length().map_err(|_| MultipartError::Malformed("Content-Length"))?;
+    if length as u64 > upload.limits().max_total_size {
+        return Err(MultipartError::TooLarge("request body"));
+    }
+    let mut body = reader.take(length as u64);
+    let response = upload.handle(request, &mut body, site)?;
+    // Whatever follows the closing boundary.
+    io::copy(&mut body, &mut io::sink())?;
+    if body.limit() > 0 {
+        return Err(MultipartError::Malformed("body ended early"));
+    }
+    Ok(finish(request, site.with_error_page(response)))
+}
+
+// Closing a socket with unread input makes the kernel answer with a reset,
+// which can destroy the error response before the client has read it.
+// Stop writing, then swallow whatever the client is still sending for a moment.
+fn linger_close(stream: &mut Stream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let deadline = Instant::now() + LINGER;
+    let mut sink = [0u8; 4096];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+            return;
+        }
+        match stream.read(&mut sink) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+// Answers one request and decides whether the connection stays open.
+// Shared by every server mode so they behave the same on the wire.
+// An event stream only ends with its connection.
+pub(crate) fn respond(hosts: &VirtualHosts, request: &Request) -> (Response, bool) {
+    finish(request, hosts.handle(request))
+}
+
+// The Connection header part of 'respond', for responses made elsewhere.
+pub(crate) fn finish(request: &Request, response: Response) -> (Response, bool) {
+    let keep_alive = request.keep_alive() && response.events.is_none();
+    let response = response.with_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    (response, keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+
+    pub fn start(hosts: VirtualHosts) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || Server::new(hosts).serve(listener));
+        addr
+    }
+
+    pub fn get(addr: SocketAddr, raw: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn one_listener_serves_several_sites() {
+        let internal = Site::new(".")
+            .route("GET", "/", |_, _| Response::text(200, "internal"))
+            .error_page(404, "layout.html");
+        let addr = start(VirtualHosts::new(default_site()).host("internal.local", internal));
+
+        let hello = get(addr, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(hello.starts_with("HTTP/1.1 200 OK"));
+        assert!(hello.contains("Hi from Rust"));
+
+        let internal_root = get(addr, "GET / HTTP/1.1\r\nHost: internal.local:7878\r\nConnection: close\r\n\r\n");
+        assert!(internal_root.ends_with("\r\n\r\ninternal"));
+
+        let default_404 = get(addr, "GET /nope HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        assert!(default_404.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(default_404.contains("Oops!"));
+
+        let internal_404 = get(addr, "GET /nope HTTP/1.1\r\nHost: internal.local\r\nConnection: close\r\n\r\n");
+        assert!(internal_404.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(internal_404.contains("<title>Hello!</title>"));
+        assert!(!internal_404.contains("Oops!"));
+    }
+
+    #[test]
+    fn static_sites_serve_their_own_files() {
+        let dir = env::temp_dir().join(format!("rustbox-static-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), "<p>static</p>").unwrap();
+        fs::write(dir.join("404.html"), "<p>no {{ status }} here</p>").unwrap();
+        let site = static_site(dir.to_str().unwrap());
+        let addr = start(VirtualHosts::new(default_site()).host("static.local", site));
+
+        let index = get(addr, "GET / HTTP/1.1\r\nHost: static.local\r\nConnection: close\r\n\r\n");
+        assert!(index.ends_with("\r\n\r\n<p>static</p>"), "{index}");
+        let missing = get(addr, "GET /nope HTTP/1.1\r\nHost: static.local\r\nConnection: close\r\n\r\n");
+        assert!(missing.starts_with("HTTP/1.1 404") && missing.ends_with("<p>no 404 here</p>"), "{missing}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn serve_takes_every_limit() {
+        let flags = "--idle-timeout 5 --header-timeout 0.5 --body-timeout 2 --max-request-line 100 \
+                     --max-headers 10 --max-header-size 1000 --max-body 5000 --max-streams 20";
+        let mut args = flags.split_whitespace().map(String::from);
+        let mut limits = Limits::default();
+        while let Some(flag) = args.next() {
+            assert!(set_limit(&mut limits, &flag, &mut args).unwrap(), "{flag}");
         }
-        _ =>("HTTP/1.1 404 NOT FOUND", "404.html"),
+        let expected = Limits {
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_millis(500),
+            body_timeout: Duration::from_secs(2),
+            max_request_line: 100,
+            max_headers: 10,
+            max_header_size: 1000,
+            max_body_size: 5000,
+            max_streams: 20,
         };
+        assert_eq!(limits, expected);
+
+        let mut none = std::iter::empty();
+        assert!(!set_limit(&mut limits, "--workers", &mut none).unwrap());
+        for bad in ["0", "-1", "soon"] {
+            let mut value = std::iter::once(bad.to_string());
+            assert!(set_limit(&mut limits, "--body-timeout", &mut value).is_err());
+        }
+        assert!(set_limit(&mut limits, "--max-body", &mut none).is_err());
+    }
+
+    #[test]
+    fn malformed_request_is_400() {
+        let addr = start(VirtualHosts::new(default_site()));
+        assert!(get(addr, "nonsense\r\n\r\n").starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[test]
+    fn keeps_http11_connections_open() {
+        let addr = start(VirtualHosts::new(default_site()));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        for _ in 0..3 {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let response = read_response(&mut reader);
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.contains("Connection: keep-alive"));
+        }
+    }
+
+    #[test]
+    fn connections_run_on_the_shared_pool() {
+        let site = Site::new(".").route("GET", "/worker", |_, _| {
+            Response::text(200, thread::current().name().unwrap_or("unnamed").to_string())
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(VirtualHosts::new(site)).workers(1).queue(1);
+        thread::spawn(move || server.serve(listener));
+
+        // One worker: the second connection waits for the first to close.
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.write_all(b"GET /worker HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut BufReader::new(&first)).ends_with("http-worker-0"));
+        let second = thread::spawn(move || get(addr, "GET /worker HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!second.is_finished());
+        drop(first);
+        assert!(second.join().unwrap().ends_with("http-worker-0"));
+    }
+
+    fn upload_request(file: &[u8]) -> Vec<u8> {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nbig one\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"data\"; filename=\"big.bin\"\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(file);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+        let mut request = format!(
+            "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+        request
+    }
+
+    #[test]
+    fn uploads_stream_past_the_body_limit() {
+        let addr = start(VirtualHosts::new(default_site()));
+        let file = vec![b'u'; 3 * Limits::default().max_body_size];
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        stream.write_all(&upload_request(&file)).unwrap();
+        let response = read_response(&mut reader);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("title: big one\n"));
+        assert!(response.contains(&format!("data: big.bin (no type, {} bytes)\n", file.len())));
+        assert!(response.contains("Connection: keep-alive"));
+
+        // Other bodies are still held to the limit.
+        let too_big = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", file.len());
+        stream.write_all(too_big.as_bytes()).unwrap();
+        assert!(read_response(&mut reader).starts_with("HTTP/1.1 413"));
+    }
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
-    let response = format!(
-        "{status_line}\r\n\
-        Content-Length: {length}\r\n\r\n\
-        {contents}"
-        );
-    stream.write_all(response.as_bytes()).unwrap();
-}
\ No newline at end of file
+    #[test]
+    fn broken_uploads_are_refused() {
+        let addr = start(VirtualHosts::new(default_site()));
+        let mut truncated = upload_request(b"data");
+        truncated.truncate(truncated.len() - 10);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&truncated).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{response}");
+
+        let plain = "POST /upload HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi";
+        assert!(get(addr, plain).starts_with("HTTP/1.1 415"));
+        let wrong_method = get(addr, "GET /upload HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(wrong_method.starts_with("HTTP/1.1 405"));
+        assert!(wrong_method.contains("Allow: POST\r\n"), "{wrong_method}");
+    }
+
+    #[test]
+    fn hello_remembers_names_in_a_cookie() {
+        let addr = start(VirtualHosts::new(default_site()));
+        let asked = get(addr, "GET /?name=Ann HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(asked.contains("Hi from Ann"));
+        assert!(asked.contains("Set-Cookie: name=Ann; Path=/; Max-Age=31536000; SameSite=Strict\r\n"), "{asked}");
+
+        let remembered = get(addr, "GET / HTTP/1.1\r\nCookie: name=Ann\r\nConnection: close\r\n\r\n");
+        assert!(remembered.contains("Hi from Ann"));
+        assert!(!remembered.contains("Set-Cookie"));
+
+        let escaped = get(addr, "GET /?name=%3Cb%3E+Bo HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(escaped.contains("Hi from &lt;b&gt; Bo"));
+        assert!(!escaped.contains("Set-Cookie"));
+    }
+
+    #[test]
+    fn logins_live_in_a_session_and_are_announced() {
+        let addr = start(VirtualHosts::new(default_site()));
+        let mut watcher = TcpStream::connect(addr).unwrap();
+        watcher.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        watcher.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let mut events = BufReader::new(watcher);
+        assert!(read_response(&mut events).contains("text/event-stream"));
+
+        let form = "POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+            Content-Length: 8\r\nConnection: close\r\n\r\nuser=ann";
+        let login = get(addr, form);
+        assert!(login.ends_with("logged in as ann\n"), "{login}");
+        let at = login.find("Set-Cookie: ").unwrap() + "Set-Cookie: ".len();
+        let cookie = login[at..].split(';').next().unwrap();
+
+        let whoami = format!("GET /login HTTP/1.1\r\nCookie: {cookie}\r\nConnection: close\r\n\r\n");
+        assert!(get(addr, &whoami).ends_with("logged in as ann\n"));
+        let logout = format!("POST /logout HTTP/1.1\r\nCookie: {cookie}\r\nConnection: close\r\n\r\n");
+        assert!(get(addr, &logout).contains("Set-Cookie: rustbox_session=; Path=/; Max-Age=0\r\n"));
+        assert!(get(addr, &whoami).ends_with("not logged in\n"));
+
+        let mut announced = Vec::new();
+        while announced.len() < 2 {
+            let mut line = String::new();
+            events.read_line(&mut line).unwrap();
+            if let Some(data) = line.strip_prefix("data: ") {
+                announced.push(data.trim_end().to_string());
+            }
+        }
+        assert_eq!(announced, ["ann logged in", "ann logged out"]);
+
+        let no_form = "POST /login HTTP/1.1\r\nContent-Length: 8\r\nConnection: close\r\n\r\nuser=ann";
+        assert!(get(addr, no_form).starts_with("HTTP/1.1 400"));
+    }
+
+    // Reads exactly one response off a kept-alive connection.
+    pub fn read_response(reader: &mut impl BufRead) -> String {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            head.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+        let length = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .map_or(0, |n| n.trim().parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        head + &String::from_utf8_lossy(&body)
+    }
+}
@@ -0,0 +1,310 @@
+// Server mode on the async runtime (concurrency::runtime): one thread, one
+// task per connection. Reading, writing and the timeouts are awaited, so a
+// waiting connection costs nothing but its task, and a route that has to
+// wait for something can do it without stalling the others, which the
+// non-blocking mode's handlers can't.
+//
+// Sites stay as they are. Async routes are looked up first, for every
+// host, and anything they don't cover goes to the sites as usual; sync
+// handlers run on the loop thread, so a slow one still holds everyone up.
+
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::io;
+use std::net::Shutdown;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::task::Poll;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::http::{Request, Response};
+use super::limits::{Limits, RequestError};
+use super::listener::{Listener, Stream};
+use super::sse::StreamSlots;
+use super::vhost::VirtualHosts;
+use super::{finish, h2, respond, sse, LINGER};
+use crate::concurrency::runtime::net::{TcpListener, TcpStream};
+use crate::concurrency::runtime::{self, timeout};
+
+const READ_CHUNK: usize = 8 * 1024;
+
+type Handler = Box<dyn Fn(Request, Rc<VirtualHosts>) -> Pin<Box<dyn Future<Output = Response>>>>;
+
+pub struct AsyncServer {
+    hosts: Rc<VirtualHosts>,
+    limits: Limits,
+    routes: HashMap<(String, String), Handler>,
+}
+
+impl AsyncServer {
+    pub fn new(hosts: VirtualHosts) -> AsyncServer {
+        AsyncServer { hosts: Rc::new(hosts), limits: Limits::default(), routes: HashMap::new() }
+    }
+
+    pub fn limits(mut self, limits: Limits) -> AsyncServer {
+        self.limits = limits;
+        self
+    }
+
+    // An exact method and path, served by an async handler. Its response
+    // gets the site's error pages like any other.
+    pub fn route<F, Fut>(mut self, method: &str, path: &str, handler: F) -> AsyncServer
+    where
+        F: Fn(Request, Rc<VirtualHosts>) -> Fut + 'static,
+        Fut: Future<Output = Response> + 'static,
+    {
+        let handler: Handler = Box::new(move |request, hosts| Box::pin(handler(request, hosts)));
+        self.routes.insert((method.to_string(), path.to_string()), handler);
+        self
+    }
+
+    // Runs until the listener fails; TCP only, the runtime has no Unix sockets.
+    pub fn serve(self, listener: impl Into<Listener>) -> io::Result<()> {
+        let Listener::Tcp(listener) = listener.into() else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "the async server only listens on TCP"));
+        };
+        let streams = StreamSlots::new(self.limits.max_streams);
+        let server = Rc::new(self);
+        runtime::block_on(async move {
+            let listener = TcpListener::from_std(listener)?;
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let server = Rc::clone(&server);
+                        let streams = streams.clone();
+                        runtime::spawn(async move {
+                            if let Err(e) = server.handle_connection(stream, &streams).await {
+                                eprintln!("connection error: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        // Out of file descriptors and the like: give the
+                        // open connections a moment to finish.
+                        eprintln!("accept failed: {e}");
+                        runtime::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        })
+    }
+
+    // The same loop as the blocking mode's, with the reads awaited.
+    async fn handle_connection(&self, mut stream: TcpStream, streams: &StreamSlots) -> io::Result<()> {
+        let mut input = Vec::new();
+        loop {
+            let request = match self.read_request(&mut stream, &mut input).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(RequestError::Io(e)) => return Err(e),
+                Err(e) => {
+                    if let Some(response) = e.response() {
+                        stream.write_all(&response.to_bytes()).await?;
+                    }
+                    linger_close(&mut stream).await;
+                    return Ok(());
+                }
+            };
+
+            // h2c runs on threads (see h2::server); this mode only speaks HTTP/1.x.
+            let (response, keep_alive) = if h2::is_preface(&request) {
+                let response = Response::text(505, "505 HTTP Version Not Supported");
+                (response.with_header("Connection", "close"), false)
+            } else {
+                // A panicking handler, sync or async, costs its connection
+                // rather than every task on the runtime.
+                catch_unwind(self.respond(request)).await.unwrap_or_else(|_| {
+                    let response = Response::text(500, "500 Internal Server Error");
+                    (response.with_header("Connection", "close"), false)
+                })
+            };
+            if response.events.is_some() {
+                // Runs until the events end or the client goes away, so it
+                // gets a thread of its own.
+                let Some(slot) = streams.try_acquire() else {
+                    return stream.write_all(&StreamSlots::unavailable().to_bytes()).await;
+                };
+                let mut stream = Stream::Tcp(stream.into_std()?);
+                thread::spawn(move || {
+                    let _slot = slot;
+                    sse::write_response(&mut stream, response)
+                });
+                return Ok(());
+            }
+            stream.write_all(&response.to_bytes()).await?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn respond(&self, mut request: Request) -> (Response, bool) {
+        let key = (request.method.clone(), request.path.clone());
+        let Some(handler) = self.routes.get(&key) else {
+            return respond(&self.hosts, &request);
+        };
+        // The handler owns the request; the head is still needed afterwards.
+        let body = std::mem::take(&mut request.body);
+        let head = request.clone();
+        request.body = body;
+        let response = handler(request, Rc::clone(&self.hosts)).await;
+        let response = self.hosts.site_for(&head).with_error_page(response);
+        finish(&head, response)
+    }
+
+    // Ok(None) when the client closes or idles out between requests. The
+    // header and body deadlines run from when each part starts arriving,
+    // as in the other modes.
+    async fn read_request(&self, stream: &mut TcpStream, input: &mut Vec<u8>) -> Result<Option<Request>, RequestError> {
+        let limits = &self.limits;
+        let mut started = None;
+        let mut head_done = None;
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            // Pipelined requests may already be buffered.
+            if let Some((request, used)) = Request::parse_buffered(input, limits)? {
+                input.drain(..used);
+                return Ok(Some(request));
+            }
+            let now = Instant::now();
+            if !input.is_empty() {
+                started.get_or_insert(now);
+            }
+            if head_done.is_none() && input.windows(4).any(|w| w == b"\r\n\r\n") {
+                head_done = Some(now);
+            }
+            let deadline = match (started, head_done) {
+                (_, Some(head_done)) => head_done + limits.body_timeout,
+                (Some(started), None) => started + limits.header_timeout,
+                (None, None) => now + limits.idle_timeout,
+            };
+            match timeout(deadline.saturating_duration_since(now), stream.read(&mut chunk)).await {
+                Ok(Ok(0)) if started.is_none() => return Ok(None),
+                Ok(Ok(0)) => return Err(RequestError::Malformed("connection closed mid-request")),
+                Ok(Ok(n)) => input.extend_from_slice(&chunk[..n]),
+                Ok(Err(e)) => return Err(RequestError::Io(e)),
+                // An idle connection is closed without a word.
+                Err(_) if started.is_none() => return Ok(None),
+                Err(_) => return Err(RequestError::Timeout),
+            }
+        }
+    }
+}
+
+// catch_unwind for a future: each poll is caught, not just the first.
+async fn catch_unwind<F: Future>(future: F) -> thread::Result<F::Output> {
+    let mut future = pin!(future);
+    future::poll_fn(|cx| match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+        Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+        Ok(Poll::Pending) => Poll::Pending,
+        Err(payload) => Poll::Ready(Err(payload)),
+    })
+    .await
+}
+
+// See tcp::linger_close.
+async fn linger_close(stream: &mut TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = timeout(LINGER, async {
+        let mut sink = [0u8; 4096];
+        while matches!(stream.read(&mut sink).await, Ok(n) if n > 0) {}
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::tests::read_response;
+    use crate::tcp::{async_demo, default_site};
+    use std::io::{BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn start(server: impl FnOnce() -> AsyncServer + Send + 'static) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server().serve(listener));
+        addr
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn sleeping_requests_do_not_hold_up_others() {
+        let addr = start(|| async_demo(Duration::from_millis(500)));
+        let sleeper = thread::spawn(move || get(addr, "/sleep"));
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let hello = get(addr, "/");
+        assert!(started.elapsed() < Duration::from_millis(300), "{:?}", started.elapsed());
+        assert!(hello.starts_with("HTTP/1.1 200 OK"), "{hello}");
+
+        let slept = sleeper.join().unwrap();
+        assert!(slept.starts_with("HTTP/1.1 200 OK"), "{slept}");
+        assert!(slept.contains("Hi from Rust"));
+    }
+
+    #[test]
+    fn serves_keep_alive_and_pipelined_requests() {
+        let addr = start(|| async_demo(Duration::ZERO));
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\nGET /sle").unwrap();
+        assert!(read_response(&mut reader).starts_with("HTTP/1.1 200 OK"));
+        assert!(read_response(&mut reader).starts_with("HTTP/1.1 404 Not Found"));
+
+        thread::sleep(Duration::from_millis(20));
+        client.write_all(b"ep HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let last = read_response(&mut reader);
+        assert!(last.starts_with("HTTP/1.1 200 OK"), "{last}");
+        assert!(last.contains("Connection: close"));
+    }
+
+    #[test]
+    fn async_routes_get_error_pages() {
+        let addr = start(|| {
+            AsyncServer::new(VirtualHosts::new(default_site()))
+                .route("GET", "/gone", |_, _| async { Response::new(404) })
+        });
+        let response = get(addr, "/gone");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(response.contains("Oops!"));
+    }
+
+    #[test]
+    fn panicking_handlers_get_500() {
+        let addr = start(|| {
+            let site = default_site().route("GET", "/sync", |_, _| panic!("handler bug"));
+            AsyncServer::new(VirtualHosts::new(site)).route("GET", "/async", |_, _| async {
+                runtime::sleep(Duration::from_millis(10)).await;
+                panic!("handler bug")
+            })
+        });
+        for path in ["/sync", "/async"] {
+            let response = get(addr, path);
+            assert!(response.starts_with("HTTP/1.1 500"), "{path}: {response}");
+        }
+        assert!(get(addr, "/").starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn slow_clients_get_408() {
+        let limits = Limits { header_timeout: Duration::from_millis(100), ..Limits::default() };
+        let addr = start(move || async_demo(Duration::ZERO).limits(limits));
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"), "{response}");
+    }
+}
@@ -0,0 +1,141 @@
+// The Cookie request header and Set-Cookie response values.
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use super::http::{Request, Response};
+
+impl Request {
+    // "a=1; b=2" -> [("a", "1"), ("b", "2")]. Pairs without '=' are skipped.
+    pub fn cookies(&self) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim().trim_matches('"')))
+            .collect()
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies().into_iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+}
+
+impl Response {
+    pub fn with_cookie(self, cookie: &SetCookie) -> Response {
+        self.with_header("Set-Cookie", cookie.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<Duration>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> SetCookie {
+        SetCookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    // A cookie that tells the browser to forget 'name' right away.
+    pub fn removal(name: impl Into<String>) -> SetCookie {
+        SetCookie::new(name, "").max_age(Duration::ZERO)
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> SetCookie {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> SetCookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> SetCookie {
+        self.http_only = http_only;
+        self
+    }
+
+    // Browsers reject SameSite=None without Secure, so it implies Secure.
+    pub fn same_site(mut self, same_site: SameSite) -> SetCookie {
+        self.same_site = Some(same_site);
+        self.secure |= same_site == SameSite::None;
+        self
+    }
+}
+
+impl Display for SetCookie {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cookie_header() {
+        let mut request = Request::from_request_line("GET / HTTP/1.1").unwrap();
+        request.headers.push(("Cookie".into(), "theme=dark; sid=\"abc\"; junk; empty=".into()));
+        assert_eq!(request.cookie("theme"), Some("dark"));
+        assert_eq!(request.cookie("sid"), Some("abc"));
+        assert_eq!(request.cookie("empty"), Some(""));
+        assert_eq!(request.cookie("junk"), None);
+    }
+
+    #[test]
+    fn builds_set_cookie_with_attributes() {
+        let cookie = SetCookie::new("sid", "42")
+            .path("/")
+            .max_age(Duration::from_secs(3600))
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(), "sid=42; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax");
+
+        let none = SetCookie::new("a", "b").same_site(SameSite::None);
+        assert_eq!(none.to_string(), "a=b; Secure; SameSite=None");
+        assert_eq!(SetCookie::removal("sid").to_string(), "sid=; Max-Age=0");
+    }
+}
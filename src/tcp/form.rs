@@ -0,0 +1,100 @@
+// application/x-www-form-urlencoded bodies and query strings.
+
+use super::http::Request;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    pub fn parse(encoded: &str) -> Form {
+        let fields = encoded
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+        Form { fields }
+    }
+
+    // The first value sent for 'name'.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+impl Request {
+    pub fn query_form(&self) -> Form {
+        Form::parse(&self.query)
+    }
+
+    // The body of a urlencoded form post, None for any other content type.
+    pub fn form(&self) -> Option<Form> {
+        let content_type = self.header("Content-Type")?;
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if !mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return None;
+        }
+        Some(Form::parse(&String::from_utf8_lossy(&self.body)))
+    }
+}
+
+// '+' is a space and %XX an escaped byte. Broken escapes are kept as they are
+// rather than rejecting the whole form; invalid UTF-8 becomes U+FFFD.
+pub fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push(high << 4 | low);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_fields() {
+        let form = Form::parse("name=Jos%C3%A9+Smith&tag=a&tag=b&empty=&flag&bad=100%+sure");
+        assert_eq!(form.get("name"), Some("José Smith"));
+        assert_eq!(form.get("tag"), Some("a"));
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("flag"), Some(""));
+        assert_eq!(form.get("bad"), Some("100% sure"));
+        assert_eq!(form.get("missing"), None);
+    }
+
+    #[test]
+    fn only_urlencoded_bodies_are_forms() {
+        let mut request = Request::from_request_line("POST /login?next=%2Fhome HTTP/1.1").unwrap();
+        request.body = b"user=ann&pass=x%26y".to_vec();
+        assert_eq!(request.form(), None);
+
+        request.headers.push(("Content-Type".into(), "application/x-www-form-urlencoded; charset=utf-8".into()));
+        let form = request.form().unwrap();
+        assert_eq!(form.get("pass"), Some("x&y"));
+        assert_eq!(request.query_form().get("next"), Some("/home"));
+    }
+}
@@ -0,0 +1,132 @@
+// HTTP/2 over cleartext TCP (h2c), either with prior knowledge (the client
+// opens with the connection preface) or by upgrading an HTTP/1.1 request
+// that carries 'Upgrade: h2c'. Requests end up in the same VirtualHosts and
+// handlers as HTTP/1.1 ones; only the framing differs.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use http::Request;
+
+use super::http;
+
+#[cfg(test)]
+pub mod client;
+pub mod frame;
+pub mod hpack;
+mod huffman;
+pub mod server;
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Error codes for RST_STREAM and GOAWAY (RFC 9113 section 7).
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+
+#[derive(Debug)]
+pub enum H2Error {
+    // The whole connection is unusable: GOAWAY with this code, then close.
+    Connection(u32, &'static str),
+    // Only this stream is: RST_STREAM with this code, then carry on.
+    Stream(u32, u32),
+    Io(io::Error),
+}
+
+impl Display for H2Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            H2Error::Connection(code, what) => write!(f, "connection error {code:#x}: {what}"),
+            H2Error::Stream(stream, code) => write!(f, "stream {stream} reset with {code:#x}"),
+            H2Error::Io(e) => write!(f, "i/o error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for H2Error {}
+
+impl From<io::Error> for H2Error {
+    fn from(e: io::Error) -> H2Error {
+        H2Error::Io(e)
+    }
+}
+
+impl From<hpack::HpackError> for H2Error {
+    fn from(e: hpack::HpackError) -> H2Error {
+        H2Error::Connection(COMPRESSION_ERROR, e.0)
+    }
+}
+
+// What the HTTP/1.1 parser makes of the first line of the preface.
+pub fn is_preface(request: &Request) -> bool {
+    request.method == "PRI" && request.target == "*" && request.version == "HTTP/2.0"
+}
+
+// An HTTP/1.1 request asking to continue as h2c (RFC 7540 section 3.2).
+pub fn wants_upgrade(request: &Request) -> bool {
+    let has_token = |header: &str, token: &str| {
+        request
+            .header(header)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    has_token("Upgrade", "h2c")
+        && has_token("Connection", "Upgrade")
+        && request.header("HTTP2-Settings").is_some()
+}
+
+// HTTP2-Settings is base64url without padding.
+pub fn decode_base64url(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Only the test client sends HTTP2-Settings.
+#[cfg(test)]
+pub fn encode_base64url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().fold(0u32, |n, b| n << 8 | *b as u32) << (8 * (3 - chunk.len()));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64url_round_trips() {
+        for len in 0..8 {
+            let bytes: Vec<u8> = (0..len).map(|i: u32| (i * 37 + 250) as u8).collect();
+            assert_eq!(decode_base64url(&encode_base64url(&bytes)).unwrap(), bytes);
+        }
+        assert_eq!(encode_base64url(&[0, 3, 0, 0, 0, 100]), "AAMAAABk");
+        assert!(decode_base64url("not base64!").is_none());
+    }
+}
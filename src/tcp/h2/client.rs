@@ -0,0 +1,298 @@
+// A small blocking HTTP/2 client, enough to test the server with. Requests
+// go out on new streams and responses are collected as their frames come in,
+// in whatever order the server interleaves them. Request bodies are sent
+// without looking at the send window, so keep them under 64K.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use super::frame::*;
+use super::hpack::{Decoder, Encoder, HeaderField, DEFAULT_TABLE_SIZE};
+use super::*;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Client {
+    socket: TcpStream,
+    reader: BufReader<TcpStream>,
+    encoder: Encoder,
+    decoder: Decoder,
+    next_stream: u32,
+    max_frame_size: usize,
+    // A header block waiting for CONTINUATION frames: stream, block, end of stream.
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    partial: HashMap<u32, ClientResponse>,
+    done: HashMap<u32, ClientResponse>,
+    resets: HashMap<u32, u32>,
+    // Streams in the order their responses completed.
+    pub completed: Vec<u32>,
+    // Hand back window for every DATA frame; tests of flow control turn it off.
+    pub auto_window_update: bool,
+    pub authority: String,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Client> {
+        Client::connect_with(addr, &[])
+    }
+
+    // Prior knowledge, with these SETTINGS in the preface.
+    pub fn connect_with(addr: impl ToSocketAddrs, settings: &[(u16, u32)]) -> io::Result<Client> {
+        let mut client = Client::new(TcpStream::connect(addr)?)?;
+        client.socket.write_all(PREFACE)?;
+        client.send(&Frame::Settings { ack: false, params: settings.to_vec() })?;
+        Ok(client)
+    }
+
+    // Starts out as HTTP/1.1 and asks to switch; the response to that first
+    // request arrives as stream 1.
+    pub fn upgrade(addr: impl ToSocketAddrs, host: &str, path: &str) -> Result<(Client, ClientResponse), H2Error> {
+        let mut client = Client::new(TcpStream::connect(addr)?)?;
+        let settings = encode_base64url(&[0, ENABLE_PUSH as u8, 0, 0, 0, 0]);
+        write!(
+            client.socket,
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+             Upgrade: h2c\r\nHTTP2-Settings: {settings}\r\n\r\n"
+        )?;
+
+        let mut status_line = String::new();
+        client.reader.read_line(&mut status_line)?;
+        if !status_line.starts_with("HTTP/1.1 101") {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "upgrade refused"));
+        }
+        loop {
+            let mut line = String::new();
+            if client.reader.read_line(&mut line)? == 0 || line == "\r\n" {
+                break;
+            }
+        }
+
+        client.socket.write_all(PREFACE)?;
+        client.send(&Frame::Settings { ack: false, params: Vec::new() })?;
+        client.authority = host.to_string();
+        client.next_stream = 3;
+        let response = client.wait(1)?;
+        Ok((client, response))
+    }
+
+    fn new(socket: TcpStream) -> io::Result<Client> {
+        socket.set_nodelay(true)?;
+        Ok(Client {
+            reader: BufReader::new(socket.try_clone()?),
+            socket,
+            encoder: Encoder::new(),
+            decoder: Decoder::new(DEFAULT_TABLE_SIZE),
+            next_stream: 1,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE as usize,
+            continuation: None,
+            partial: HashMap::new(),
+            done: HashMap::new(),
+            resets: HashMap::new(),
+            completed: Vec::new(),
+            auto_window_update: true,
+            authority: "localhost".to_string(),
+        })
+    }
+
+    pub fn send(&mut self, frame: &Frame) -> io::Result<()> {
+        frame.write_to(&mut self.socket)
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.socket.set_read_timeout(timeout).expect("zero timeouts aren't used");
+    }
+
+    // Opens a stream without waiting for the answer.
+    pub fn send_request(&mut self, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<u32> {
+        let stream = self.next_stream;
+        self.next_stream += 2;
+
+        let pseudo = [(":method", method), (":scheme", "http"), (":path", path), (":authority", &self.authority)];
+        let block = self.encoder.encode(pseudo.into_iter().chain(headers.iter().copied()));
+        let frame = Frame::Headers { stream, block, end_stream: body.is_empty(), end_headers: true };
+        let mut bytes = frame.encode();
+        let mut chunks = body.chunks(self.max_frame_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let end_stream = chunks.peek().is_none();
+            bytes.extend(Frame::Data { stream, data: chunk.to_vec(), end_stream, padding: 0 }.encode());
+        }
+        self.socket.write_all(&bytes)?;
+        Ok(stream)
+    }
+
+    pub fn get(&mut self, path: &str) -> Result<ClientResponse, H2Error> {
+        let stream = self.send_request("GET", path, &[], b"")?;
+        self.wait(stream)
+    }
+
+    // Reads frames until 'stream' is answered or reset.
+    pub fn wait(&mut self, stream: u32) -> Result<ClientResponse, H2Error> {
+        loop {
+            if let Some(response) = self.done.remove(&stream) {
+                return Ok(response);
+            }
+            if let Some(code) = self.resets.remove(&stream) {
+                return Err(H2Error::Stream(stream, code));
+            }
+            if let Frame::GoAway { code, .. } = self.step()? {
+                return Err(H2Error::Connection(code, "server sent GOAWAY"));
+            }
+        }
+    }
+
+    pub fn ping(&mut self, data: [u8; 8]) -> Result<(), H2Error> {
+        self.send(&Frame::Ping { ack: false, data })?;
+        loop {
+            if let Frame::Ping { ack: true, data: echoed } = self.step()?
+                && echoed == data
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    // Body bytes received so far on a stream that hasn't finished.
+    pub fn received(&self, stream: u32) -> usize {
+        self.partial.get(&stream).map_or(0, |r| r.body.len())
+    }
+
+    // Reads one frame, does the bookkeeping for it and hands it back.
+    pub fn step(&mut self) -> Result<Frame, H2Error> {
+        let frame = Frame::read_from(&mut self.reader, MAX_MAX_FRAME_SIZE)?
+            .ok_or_else(|| H2Error::Io(io::ErrorKind::UnexpectedEof.into()))?;
+        // Nothing may come between HEADERS and the end of its block, not
+        // even on another stream.
+        if let Some((pending, _, _)) = &self.continuation
+            && !matches!(frame, Frame::Continuation { stream, .. } if stream == *pending)
+        {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "expected CONTINUATION"));
+        }
+        match &frame {
+            Frame::Settings { ack: false, params } => {
+                for &(id, value) in params {
+                    match id {
+                        HEADER_TABLE_SIZE => self.encoder.set_max_table_size((value as usize).min(DEFAULT_TABLE_SIZE)),
+                        MAX_FRAME_SIZE => self.max_frame_size = value as usize,
+                        _ => {}
+                    }
+                }
+                self.send(&Frame::Settings { ack: true, params: Vec::new() })?;
+            }
+            Frame::Ping { ack: false, data } => self.send(&Frame::Ping { ack: true, data: *data })?,
+            Frame::Headers { stream, block, end_stream, end_headers: true } => {
+                self.on_headers(*stream, block, *end_stream)?;
+            }
+            Frame::Headers { stream, block, end_stream, end_headers: false } => {
+                self.continuation = Some((*stream, block.clone(), *end_stream));
+            }
+            Frame::Continuation { block, end_headers, .. } => {
+                let Some((_, pending, _)) = &mut self.continuation else {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+                };
+                pending.extend_from_slice(block);
+                if *end_headers {
+                    let (stream, block, end_stream) = self.continuation.take().expect("checked above");
+                    self.on_headers(stream, &block, end_stream)?;
+                }
+            }
+            Frame::Data { stream, data, end_stream, padding } => {
+                let flow = data.len() as u32 + padding;
+                if self.auto_window_update && flow > 0 {
+                    self.send(&Frame::WindowUpdate { stream: 0, increment: flow })?;
+                    if !end_stream {
+                        self.send(&Frame::WindowUpdate { stream: *stream, increment: flow })?;
+                    }
+                }
+                let response = self.partial.get_mut(stream).ok_or(H2Error::Stream(*stream, STREAM_CLOSED))?;
+                response.body.extend_from_slice(data);
+                if *end_stream {
+                    self.complete(*stream);
+                }
+            }
+            Frame::RstStream { stream, code } => {
+                self.partial.remove(stream);
+                self.resets.insert(*stream, *code);
+            }
+            _ => {}
+        }
+        Ok(frame)
+    }
+
+    fn on_headers(&mut self, stream: u32, block: &[u8], end_stream: bool) -> Result<(), H2Error> {
+        let fields = self.decoder.decode(block)?;
+        // A second block on a stream is trailers.
+        if let Entry::Vacant(slot) = self.partial.entry(stream) {
+            let status = fields
+                .iter()
+                .find(|(n, _)| n == ":status")
+                .and_then(|(_, v)| v.parse().ok())
+                .ok_or(H2Error::Stream(stream, PROTOCOL_ERROR))?;
+            let headers = fields.into_iter().filter(|(n, _)| !n.starts_with(':')).collect();
+            slot.insert(ClientResponse { status, headers, body: Vec::new() });
+        }
+        if end_stream {
+            self.complete(stream);
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self, stream: u32) {
+        if let Some(response) = self.partial.remove(&stream) {
+            self.done.insert(stream, response);
+            self.completed.push(stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A client talking to a fake server that sends 'frames' and hangs up.
+    fn receiving(frames: Vec<Frame>) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            frames.iter().for_each(|frame| frame.write_to(&mut socket).unwrap());
+        });
+        Client::new(TcpStream::connect(addr).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn header_blocks_continue_on_their_own_stream() {
+        // :status 200, split over two frames.
+        let open = Frame::Headers { stream: 1, block: vec![], end_stream: true, end_headers: false };
+        let mut client = receiving(vec![open.clone(), Frame::Continuation { stream: 1, block: vec![0x88], end_headers: true }]);
+        client.step().unwrap();
+        client.step().unwrap();
+        assert_eq!(client.wait(1).unwrap().status, 200);
+
+        let interruptions = [
+            Frame::Continuation { stream: 3, block: vec![0x88], end_headers: true },
+            Frame::Ping { ack: false, data: [0; 8] },
+        ];
+        for next in interruptions {
+            let mut client = receiving(vec![open.clone(), next]);
+            client.step().unwrap();
+            assert!(matches!(client.step(), Err(H2Error::Connection(PROTOCOL_ERROR, _))));
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// The HTTP/2 frame layer (RFC 9113 section 4 and 6): a 9-byte header of
+// length, type, flags and stream id, then a type-specific payload.
+
+use std::io;
+use std::io::{Read, Write};
+
+use super::{H2Error, FRAME_SIZE_ERROR, PROTOCOL_ERROR};
+
+pub const HEADER_LEN: usize = 9;
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+pub const MAX_MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+pub const DEFAULT_WINDOW: u32 = 65_535;
+pub const MAX_WINDOW: u32 = (1 << 31) - 1;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// SETTINGS parameters.
+pub const HEADER_TABLE_SIZE: u16 = 0x1;
+pub const ENABLE_PUSH: u16 = 0x2;
+pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const MAX_FRAME_SIZE: u16 = 0x5;
+pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    // 'padding' counts the pad length byte too, as flow control does.
+    Data { stream: u32, data: Vec<u8>, end_stream: bool, padding: u32 },
+    // Priority information is parsed and dropped; nothing here schedules by it.
+    Headers { stream: u32, block: Vec<u8>, end_stream: bool, end_headers: bool },
+    Priority { stream: u32 },
+    RstStream { stream: u32, code: u32 },
+    Settings { ack: bool, params: Vec<(u16, u32)> },
+    PushPromise { stream: u32, promised: u32, block: Vec<u8>, end_headers: bool },
+    Ping { ack: bool, data: [u8; 8] },
+    GoAway { last_stream: u32, code: u32, debug: Vec<u8> },
+    WindowUpdate { stream: u32, increment: u32 },
+    Continuation { stream: u32, block: Vec<u8>, end_headers: bool },
+    // Unknown frame types must be ignored.
+    Unknown { kind: u8, stream: u32 },
+}
+
+impl Frame {
+    // Reads one frame, refusing payloads over the SETTINGS_MAX_FRAME_SIZE we
+    // advertised. Ok(None) on a clean end of stream between frames.
+    pub fn read_from<R: Read>(reader: &mut R, max_frame_size: u32) -> Result<Option<Frame>, H2Error> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < HEADER_LEN {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]);
+        let (kind, flags) = (header[3], header[4]);
+        let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & MAX_WINDOW;
+        if length > max_frame_size {
+            return Err(H2Error::Connection(FRAME_SIZE_ERROR, "frame larger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        Frame::decode(kind, flags, stream, payload).map(Some)
+    }
+
+    fn decode(kind: u8, flags: u8, stream: u32, mut payload: Vec<u8>) -> Result<Frame, H2Error> {
+        let needs_stream = matches!(kind, DATA | HEADERS | PRIORITY | RST_STREAM | PUSH_PROMISE | CONTINUATION);
+        if needs_stream && stream == 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "frame needs a stream"));
+        }
+        if matches!(kind, SETTINGS | PING | GOAWAY) && stream != 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "connection frame on a stream"));
+        }
+
+        let frame = match kind {
+            DATA => {
+                let padding = unpad(flags, &mut payload)?;
+                Frame::Data { stream, data: payload, end_stream: flags & END_STREAM != 0, padding }
+            }
+            HEADERS => {
+                unpad(flags, &mut payload)?;
+                if flags & PRIORITY_FLAG != 0 {
+                    if payload.len() < 5 {
+                        return Err(H2Error::Connection(FRAME_SIZE_ERROR, "HEADERS too short for its priority"));
+                    }
+                    payload.drain(..5);
+                }
+                Frame::Headers {
+                    stream,
+                    block: payload,
+                    end_stream: flags & END_STREAM != 0,
+                    end_headers: flags & END_HEADERS != 0,
+                }
+            }
+            PRIORITY => {
+                if payload.len() != 5 {
+                    return Err(H2Error::Stream(stream, FRAME_SIZE_ERROR));
+                }
+                Frame::Priority { stream }
+            }
+            RST_STREAM => {
+                let code = word(&payload)?;
+                Frame::RstStream { stream, code }
+            }
+            SETTINGS => {
+                let ack = flags & ACK != 0;
+                if ack && !payload.is_empty() || !payload.len().is_multiple_of(6) {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR, "bad SETTINGS length"));
+                }
+                let params = payload
+                    .chunks(6)
+                    .map(|p| (u16::from_be_bytes([p[0], p[1]]), u32::from_be_bytes([p[2], p[3], p[4], p[5]])))
+                    .collect();
+                Frame::Settings { ack, params }
+            }
+            PUSH_PROMISE => {
+                unpad(flags, &mut payload)?;
+                if payload.len() < 4 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR, "PUSH_PROMISE too short"));
+                }
+                let promised = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & MAX_WINDOW;
+                payload.drain(..4);
+                Frame::PushPromise { stream, promised, block: payload, end_headers: flags & END_HEADERS != 0 }
+            }
+            PING => {
+                let data: [u8; 8] = payload
+                    .try_into()
+                    .map_err(|_| H2Error::Connection(FRAME_SIZE_ERROR, "PING must be 8 bytes"))?;
+                Frame::Ping { ack: flags & ACK != 0, data }
+            }
+            GOAWAY => {
+                if payload.len() < 8 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR, "GOAWAY too short"));
+                }
+                let last_stream = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & MAX_WINDOW;
+                let code = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+                Frame::GoAway { last_stream, code, debug: payload.split_off(8) }
+            }
+            WINDOW_UPDATE => {
+                let increment = word(&payload)? & MAX_WINDOW;
+                if increment == 0 {
+                    return Err(match stream {
+                        0 => H2Error::Connection(PROTOCOL_ERROR, "zero WINDOW_UPDATE"),
+                        _ => H2Error::Stream(stream, PROTOCOL_ERROR),
+                    });
+                }
+                Frame::WindowUpdate { stream, increment }
+            }
+            CONTINUATION => Frame::Continuation { stream, block: payload, end_headers: flags & END_HEADERS != 0 },
+            _ => Frame::Unknown { kind, stream },
+        };
+        Ok(frame)
+    }
+
+    // Padding is only written when a frame asks for it, which only tests do.
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, flags, stream, payload): (u8, u8, u32, Vec<u8>) = match self {
+            Frame::Data { stream, data, end_stream, padding } => {
+                let mut flags = flag(*end_stream, END_STREAM);
+                let mut payload = Vec::new();
+                if *padding > 0 {
+                    flags |= PADDED;
+                    payload.push((*padding - 1) as u8);
+                }
+                payload.extend_from_slice(data);
+                payload.resize(payload.len() + padding.saturating_sub(1) as usize, 0);
+                (DATA, flags, *stream, payload)
+            }
+            Frame::Headers { stream, block, end_stream, end_headers } => {
+                let flags = flag(*end_stream, END_STREAM) | flag(*end_headers, END_HEADERS);
+                (HEADERS, flags, *stream, block.clone())
+            }
+            Frame::Priority { stream } => (PRIORITY, 0, *stream, vec![0, 0, 0, 0, 15]),
+            Frame::RstStream { stream, code } => (RST_STREAM, 0, *stream, code.to_be_bytes().to_vec()),
+            Frame::Settings { ack, params } => {
+                let payload = params
+                    .iter()
+                    .flat_map(|(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+                    .collect();
+                (SETTINGS, flag(*ack, ACK), 0, payload)
+            }
+            Frame::PushPromise { stream, promised, block, end_headers } => {
+                let mut payload = promised.to_be_bytes().to_vec();
+                payload.extend_from_slice(block);
+                (PUSH_PROMISE, flag(*end_headers, END_HEADERS), *stream, payload)
+            }
+            Frame::Ping { ack, data } => (PING, flag(*ack, ACK), 0, data.to_vec()),
+            Frame::GoAway { last_stream, code, debug } => {
+                let mut payload = last_stream.to_be_bytes().to_vec();
+                payload.extend_from_slice(&code.to_be_bytes());
+                payload.extend_from_slice(debug);
+                (GOAWAY, 0, 0, payload)
+            }
+            Frame::WindowUpdate { stream, increment } => {
+                (WINDOW_UPDATE, 0, *stream, increment.to_be_bytes().to_vec())
+            }
+            Frame::Continuation { stream, block, end_headers } => {
+                (CONTINUATION, flag(*end_headers, END_HEADERS), *stream, block.clone())
+            }
+            Frame::Unknown { kind, stream } => (*kind, 0, *stream, Vec::new()),
+        };
+
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        out.push(kind);
+        out.push(flags);
+        out.extend_from_slice(&stream.to_be_bytes());
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.encode())
+    }
+}
+
+fn flag(set: bool, bit: u8) -> u8 {
+    if set { bit } else { 0 }
+}
+
+fn word(payload: &[u8]) -> Result<u32, H2Error> {
+    let bytes: [u8; 4] = payload
+        .try_into()
+        .map_err(|_| H2Error::Connection(FRAME_SIZE_ERROR, "fixed-size frame with the wrong length"))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+// Strips the pad length byte and the padding; returns how many bytes that was.
+fn unpad(flags: u8, payload: &mut Vec<u8>) -> Result<u32, H2Error> {
+    if flags & PADDED == 0 {
+        return Ok(0);
+    }
+    let Some(&pad) = payload.first() else {
+        return Err(H2Error::Connection(FRAME_SIZE_ERROR, "padded frame without a pad length"));
+    };
+    if pad as usize >= payload.len() {
+        return Err(H2Error::Connection(PROTOCOL_ERROR, "padding longer than the frame"));
+    }
+    payload.truncate(payload.len() - pad as usize);
+    payload.remove(0);
+    Ok(pad as u32 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: Frame) {
+        let bytes = frame.encode();
+        let read = Frame::read_from(&mut &bytes[..], DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(read, Some(frame));
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        round_trip(Frame::Data { stream: 1, data: b"hello".to_vec(), end_stream: true, padding: 0 });
+        round_trip(Frame::Data { stream: 3, data: b"padded".to_vec(), end_stream: false, padding: 4 });
+        round_trip(Frame::Headers { stream: 5, block: vec![0x82], end_stream: false, end_headers: true });
+        round_trip(Frame::RstStream { stream: 7, code: 8 });
+        round_trip(Frame::Settings { ack: false, params: vec![(INITIAL_WINDOW_SIZE, 100), (ENABLE_PUSH, 0)] });
+        round_trip(Frame::Settings { ack: true, params: vec![] });
+        round_trip(Frame::Ping { ack: true, data: *b"12345678" });
+        round_trip(Frame::GoAway { last_stream: 9, code: 0, debug: b"bye".to_vec() });
+        round_trip(Frame::WindowUpdate { stream: 0, increment: 1000 });
+        round_trip(Frame::Continuation { stream: 1, block: vec![1, 2, 3], end_headers: true });
+    }
+
+    #[test]
+    fn drops_priority_fields_from_headers() {
+        let bytes = [0, 0, 6, HEADERS, PRIORITY_FLAG | END_HEADERS, 0, 0, 0, 1, 0, 0, 0, 3, 15, 0x82];
+        let frame = Frame::read_from(&mut &bytes[..], DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(frame, Frame::Headers { stream: 1, block: vec![0x82], end_stream: false, end_headers: true });
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let read = |bytes: &[u8]| Frame::read_from(&mut &bytes[..], DEFAULT_MAX_FRAME_SIZE);
+        // Too large for what we advertised.
+        assert!(matches!(read(&[0, 0x40, 1, DATA, 0, 0, 0, 0, 1]), Err(H2Error::Connection(FRAME_SIZE_ERROR, _))));
+        // DATA on stream 0.
+        assert!(matches!(read(&[0, 0, 0, DATA, 0, 0, 0, 0, 0]), Err(H2Error::Connection(PROTOCOL_ERROR, _))));
+        // PING of the wrong size.
+        assert!(matches!(read(&[0, 0, 1, PING, 0, 0, 0, 0, 0, 0]), Err(H2Error::Connection(FRAME_SIZE_ERROR, _))));
+        // Padding as long as the frame.
+        assert!(matches!(read(&[0, 0, 1, DATA, PADDED, 0, 0, 0, 1, 1]), Err(H2Error::Connection(PROTOCOL_ERROR, _))));
+        // A zero window increment only breaks its stream.
+        assert!(matches!(read(&[0, 0, 4, WINDOW_UPDATE, 0, 0, 0, 0, 3, 0, 0, 0, 0]), Err(H2Error::Stream(3, _))));
+        // A truncated frame is an error, a clean end between frames is not.
+        assert!(read(&[0, 0, 4, PING]).is_err());
+        assert!(read(&[]).unwrap().is_none());
+        // Unknown types are passed up to be ignored.
+        assert_eq!(read(&[0, 0, 1, 0xfa, 0, 0, 0, 0, 0, 9]).unwrap(), Some(Frame::Unknown { kind: 0xfa, stream: 0 }));
+    }
+}
@@ -0,0 +1,486 @@
+// HPACK (RFC 7541): header compression with a static table, a per-direction
+// dynamic table and optional Huffman coding of string literals.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::huffman;
+
+#[derive(Debug, PartialEq)]
+pub struct HpackError(pub &'static str);
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HPACK: {}", self.0)
+    }
+}
+
+impl std::error::Error for HpackError {}
+
+pub type HeaderField = (String, String);
+
+// Indices 1..=61; the dynamic table continues at 62.
+static STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// Headers whose values should never end up in a table, where a later
+// request on the same connection could probe for them.
+const SENSITIVE: [&str; 3] = ["authorization", "cookie", "set-cookie"];
+
+// Newest entry first, as the indices count from it.
+#[derive(Debug)]
+struct DynamicTable {
+    entries: VecDeque<HeaderField>,
+    size: usize,
+    max_size: usize,
+}
+
+impl DynamicTable {
+    fn new(max_size: usize) -> DynamicTable {
+        DynamicTable { entries: VecDeque::new(), size: 0, max_size }
+    }
+
+    fn entry_size((name, value): &HeaderField) -> usize {
+        name.len() + value.len() + 32
+    }
+
+    fn insert(&mut self, field: HeaderField) {
+        let size = Self::entry_size(&field);
+        if size > self.max_size {
+            // Not an error: the table just ends up empty.
+            self.entries.clear();
+            self.size = 0;
+            return;
+        }
+        self.size += size;
+        self.entries.push_front(field);
+        self.evict();
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let field = self.entries.pop_back().expect("size counts the entries");
+            self.size -= Self::entry_size(&field);
+        }
+    }
+}
+
+fn lookup(table: &DynamicTable, index: usize) -> Result<(&str, &str), HpackError> {
+    match index {
+        0 => Err(HpackError("index 0")),
+        1..=61 => Ok(STATIC_TABLE[index - 1]),
+        _ => table
+            .entries
+            .get(index - 62)
+            .map(|(n, v)| (n.as_str(), v.as_str()))
+            .ok_or(HpackError("index past the dynamic table")),
+    }
+}
+
+pub fn encode_int(value: usize, prefix_bits: u8, first: u8, out: &mut Vec<u8>) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    let mut rest = value - max;
+    while rest >= 128 {
+        out.push((rest % 128) as u8 | 0x80);
+        rest /= 128;
+    }
+    out.push(rest as u8);
+}
+
+pub fn decode_int(buf: &[u8], pos: &mut usize, prefix_bits: u8) -> Result<usize, HpackError> {
+    let max = (1usize << prefix_bits) - 1;
+    let first = *buf.get(*pos).ok_or(HpackError("truncated integer"))? as usize & max;
+    *pos += 1;
+    if first < max {
+        return Ok(first);
+    }
+    let mut value = max;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos).ok_or(HpackError("truncated integer"))?;
+        *pos += 1;
+        // Anything wider than this is an attack, not a header.
+        if shift > 28 {
+            return Err(HpackError("integer overflow"));
+        }
+        value += (byte as usize & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_string(s: &str, out: &mut Vec<u8>) {
+    let huffman_len = huffman::encoded_len(s.as_bytes());
+    if huffman_len < s.len() {
+        encode_int(huffman_len, 7, 0x80, out);
+        huffman::encode(s.as_bytes(), out);
+    } else {
+        encode_int(s.len(), 7, 0, out);
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn decode_string(buf: &[u8], pos: &mut usize) -> Result<String, HpackError> {
+    let huffman = *buf.get(*pos).ok_or(HpackError("truncated string"))? & 0x80 != 0;
+    let len = decode_int(buf, pos, 7)?;
+    let raw = buf.get(*pos..*pos + len).ok_or(HpackError("truncated string"))?;
+    *pos += len;
+    let bytes = if huffman { huffman::decode(raw)? } else { raw.to_vec() };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// One per connection for the headers we receive.
+#[derive(Debug)]
+pub struct Decoder {
+    table: DynamicTable,
+    // SETTINGS_HEADER_TABLE_SIZE we advertised; the encoder on the other
+    // side may shrink its table below this but never grow past it.
+    max_allowed: usize,
+}
+
+impl Decoder {
+    pub fn new(max_allowed: usize) -> Decoder {
+        Decoder { table: DynamicTable::new(max_allowed), max_allowed }
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<HeaderField>, HpackError> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < block.len() {
+            let byte = block[pos];
+            if byte & 0x80 != 0 {
+                // Indexed header field.
+                let index = decode_int(block, &mut pos, 7)?;
+                let (name, value) = lookup(&self.table, index)?;
+                fields.push((name.to_string(), value.to_string()));
+            } else if byte & 0x40 != 0 {
+                // Literal with incremental indexing.
+                let field = self.literal(block, &mut pos, 6)?;
+                self.table.insert(field.clone());
+                fields.push(field);
+            } else if byte & 0x20 != 0 {
+                // Dynamic table size update, only allowed before any field.
+                if !fields.is_empty() {
+                    return Err(HpackError("table size update after a header field"));
+                }
+                let size = decode_int(block, &mut pos, 5)?;
+                if size > self.max_allowed {
+                    return Err(HpackError("table size update above the agreed maximum"));
+                }
+                self.table.set_max_size(size);
+            } else {
+                // Literal without indexing (0000) or never indexed (0001).
+                fields.push(self.literal(block, &mut pos, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix_bits: u8) -> Result<HeaderField, HpackError> {
+        let index = decode_int(block, pos, prefix_bits)?;
+        let name = if index == 0 {
+            decode_string(block, pos)?
+        } else {
+            lookup(&self.table, index)?.0.to_string()
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+}
+
+// One per connection for the headers we send.
+#[derive(Debug)]
+pub struct Encoder {
+    table: DynamicTable,
+    // The smallest size the peer allowed since the last header block, and
+    // the size it ended at; both have to be announced, in that order.
+    pending_update: Option<(usize, usize)>,
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder { table: DynamicTable::new(DEFAULT_TABLE_SIZE), pending_update: None }
+    }
+
+    // Follows the peer's SETTINGS_HEADER_TABLE_SIZE.
+    pub fn set_max_table_size(&mut self, size: usize) {
+        let smallest = match self.pending_update {
+            Some((smallest, _)) => smallest.min(size),
+            None => size,
+        };
+        self.pending_update = Some((smallest, size));
+        self.table.set_max_size(size);
+    }
+
+    // Names must already be lowercase, as HTTP/2 requires.
+    pub fn encode<'a>(&mut self, fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some((smallest, last)) = self.pending_update.take() {
+            if smallest < last {
+                encode_int(smallest, 5, 0x20, &mut out);
+            }
+            encode_int(last, 5, 0x20, &mut out);
+        }
+
+        for (name, value) in fields {
+            let (exact, name_index) = self.find(name, value);
+            if let Some(index) = exact {
+                encode_int(index, 7, 0x80, &mut out);
+                continue;
+            }
+            let sensitive = SENSITIVE.contains(&name);
+            let (prefix_bits, first) = if sensitive { (4, 0x10) } else { (6, 0x40) };
+            encode_int(name_index.unwrap_or(0), prefix_bits, first, &mut out);
+            if name_index.is_none() {
+                encode_string(name, &mut out);
+            }
+            encode_string(value, &mut out);
+            if !sensitive {
+                self.table.insert((name.to_string(), value.to_string()));
+            }
+        }
+        out
+    }
+
+    // The index of an exact match if there is one, and of a name match.
+    fn find(&self, name: &str, value: &str) -> (Option<usize>, Option<usize>) {
+        let dynamic = self.table.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        let mut name_index = None;
+        for (i, (n, v)) in STATIC_TABLE.iter().copied().chain(dynamic).enumerate() {
+            if n == name {
+                if v == value {
+                    return (Some(i + 1), Some(i + 1));
+                }
+                name_index.get_or_insert(i + 1);
+            }
+        }
+        (None, name_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<HeaderField> {
+        list.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        // RFC 7541 C.1: 1337 with a 5-bit prefix.
+        let mut out = Vec::new();
+        encode_int(1337, 5, 0, &mut out);
+        assert_eq!(out, [31, 154, 10]);
+        assert_eq!(decode_int(&out, &mut 0, 5), Ok(1337));
+        assert!(decode_int(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], &mut 0, 5).is_err());
+    }
+
+    // RFC 7541 C.3 and C.4: three requests on one connection, without and
+    // with Huffman coding.
+    const REQUESTS: [&[(&str, &str)]; 3] = [
+        &[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")],
+        &[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ],
+        &[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ],
+    ];
+
+    #[test]
+    fn decodes_requests_without_huffman() {
+        let blocks = [
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ];
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        for (block, expected) in blocks.iter().zip(REQUESTS) {
+            assert_eq!(decoder.decode(&hex(block)).unwrap(), fields(expected));
+        }
+        assert_eq!(decoder.table.size, 164);
+    }
+
+    #[test]
+    fn encodes_and_decodes_requests_with_huffman() {
+        let blocks = [
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ];
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        for (block, expected) in blocks.iter().zip(REQUESTS) {
+            assert_eq!(encoder.encode(expected.iter().copied()), hex(block));
+            assert_eq!(decoder.decode(&hex(block)).unwrap(), fields(expected));
+        }
+    }
+
+    // RFC 7541 C.6: responses with a 256-byte table, so entries get evicted.
+    #[test]
+    fn decodes_responses_with_eviction() {
+        let blocks = [
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6
+             2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+            "4883 640e ff c1 c0 bf",
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab
+             77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 0fb5 291f
+             9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        ];
+        let date1 = ("date", "Mon, 21 Oct 2013 20:13:21 GMT");
+        let date2 = ("date", "Mon, 21 Oct 2013 20:13:22 GMT");
+        let location = ("location", "https://www.example.com");
+        let cookie = (
+            "set-cookie",
+            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+        );
+        let expected: [&[(&str, &str)]; 3] = [
+            &[(":status", "302"), ("cache-control", "private"), date1, location],
+            &[(":status", "307"), ("cache-control", "private"), date1, location],
+            &[
+                (":status", "200"),
+                ("cache-control", "private"),
+                date2,
+                location,
+                ("content-encoding", "gzip"),
+                cookie,
+            ],
+        ];
+        let mut decoder = Decoder::new(256);
+        for (block, expected) in blocks.iter().zip(expected) {
+            assert_eq!(decoder.decode(&hex(block)).unwrap(), fields(expected));
+        }
+        assert_eq!(decoder.table.size, 215);
+        assert_eq!(decoder.table.entries.len(), 3);
+    }
+
+    #[test]
+    fn sensitive_headers_stay_out_of_the_table() {
+        let mut encoder = Encoder::new();
+        let block = encoder.encode([("authorization", "secret"), ("x-trace", "1")]);
+        assert_eq!(block[0] & 0xf0, 0x10);
+        assert_eq!(encoder.table.entries.len(), 1);
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[("authorization", "secret"), ("x-trace", "1")]));
+    }
+
+    #[test]
+    fn announces_table_size_changes() {
+        let mut encoder = Encoder::new();
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        decoder.decode(&encoder.encode([("x-a", "1")])).unwrap();
+        encoder.set_max_table_size(0);
+        encoder.set_max_table_size(100);
+        let block = encoder.encode([("x-a", "1")]);
+        // 0 first to flush the table, then 100.
+        assert_eq!(&block[..3], [0x20, 0x3f, 0x45]);
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[("x-a", "1")]));
+        assert_eq!(decoder.table.max_size, 100);
+
+        // Growing past what we advertised is a decoding error.
+        assert!(Decoder::new(100).decode(&[0x3f, 0xe2, 0x1f]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_indices() {
+        let mut decoder = Decoder::new(DEFAULT_TABLE_SIZE);
+        assert!(decoder.decode(&[0x80]).is_err());
+        assert!(decoder.decode(&[0xbe]).is_err());
+        assert!(decoder.decode(&[0x82, 0x20]).is_err());
+    }
+}
@@ -0,0 +1,964 @@
+// Serving one h2c connection. The calling thread reads and decodes frames,
+// every request runs on a thread of its own so a slow handler doesn't hold up
+// the other streams, and one writer thread owns the socket's write half, the
+// HPACK encoder and the send windows. Everything bound for the socket goes
+// through the writer's channel.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, Scope};
+use std::time::Instant;
+
+use super::frame::*;
+use super::hpack::{Decoder, Encoder, HeaderField, DEFAULT_TABLE_SIZE};
+use super::*;
+use crate::tcp::http::{Request, Response};
+use crate::tcp::limits::{DeadlineReader, Limits, RequestError};
+use crate::tcp::listener::Stream;
+use crate::tcp::vhost::VirtualHosts;
+
+const MAX_STREAMS: usize = 100;
+
+// Headers that only mean something to one HTTP/1.1 hop.
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+// The HTTP/1.1 parser has already eaten "PRI * HTTP/2.0\r\n\r\n"; the rest
+// of the preface must follow.
+pub(crate) fn serve_prior_knowledge(
+    reader: BufReader<DeadlineReader>,
+    socket: Stream,
+    hosts: &VirtualHosts,
+    limits: &Limits,
+) -> io::Result<()> {
+    serve(reader, socket, hosts, limits, &PREFACE[18..], None)
+}
+
+// Answers an 'Upgrade: h2c' request with 101, then serves its response as
+// stream 1 of the new connection.
+pub(crate) fn serve_upgrade(
+    reader: BufReader<DeadlineReader>,
+    mut socket: Stream,
+    hosts: &VirtualHosts,
+    limits: &Limits,
+    mut request: Request,
+) -> io::Result<()> {
+    let settings = request
+        .header("HTTP2-Settings")
+        .and_then(decode_base64url)
+        .filter(|payload| payload.len().is_multiple_of(6))
+        .map(|payload| {
+            payload
+                .chunks(6)
+                .map(|p| (u16::from_be_bytes([p[0], p[1]]), u32::from_be_bytes([p[2], p[3], p[4], p[5]])))
+                .collect::<Vec<_>>()
+        })
+        .filter(|params| check_settings(params).is_ok());
+    let Some(settings) = settings else {
+        let response = Response::text(400, "400 Bad Request").with_header("Connection", "close");
+        return response.write_to(&mut socket);
+    };
+
+    socket.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")?;
+    request.version = "HTTP/2.0".to_string();
+    serve(reader, socket, hosts, limits, PREFACE, Some((request, settings)))
+}
+
+fn serve(
+    reader: BufReader<DeadlineReader>,
+    socket: Stream,
+    hosts: &VirtualHosts,
+    limits: &Limits,
+    preface: &[u8],
+    upgrade: Option<(Request, Vec<(u16, u32)>)>,
+) -> io::Result<()> {
+    // Frames are small and written one at a time, Nagle would only delay them.
+    socket.set_nodelay(true)?;
+    let active = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    let writer = Writer::new(socket.try_clone()?, &active);
+
+    let result = thread::scope(|scope| {
+        let writer = scope.spawn(move || writer.run(rx));
+
+        let mut connection = Connection {
+            reader,
+            tx,
+            hosts,
+            limits,
+            active: &active,
+            decoder: Decoder::new(DEFAULT_TABLE_SIZE),
+            streams: HashMap::new(),
+            reset: HashSet::new(),
+            continuation: None,
+            last_stream: 0,
+            going_away: false,
+        };
+        connection.send(Out::Frame(Frame::Settings {
+            ack: false,
+            params: vec![
+                (MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+                (MAX_HEADER_LIST_SIZE, limits.max_header_size as u32),
+            ],
+        }));
+        if let Some((request, settings)) = upgrade {
+            // Settings from HTTP2-Settings count as acknowledged by the 101.
+            connection.send(Out::PeerSettings(settings));
+            connection.open(1);
+            connection.dispatch(scope, 1, request);
+        }
+
+        let result = connection.read_preface(preface).and_then(|ok| match ok {
+            true => connection.run(scope),
+            false => Ok(()),
+        });
+        // Dropping the connection drops its sender: the writer finishes once
+        // the last handler has handed in its response.
+        drop(connection);
+        let written = writer.join().unwrap_or(Ok(()));
+        result.and(written)
+    });
+    let _ = socket.shutdown(Shutdown::Both);
+    result
+}
+
+// A SETTINGS frame that is wrong as a whole is a connection error.
+fn check_settings(params: &[(u16, u32)]) -> Result<(), H2Error> {
+    for &(id, value) in params {
+        match id {
+            ENABLE_PUSH if value > 1 => return Err(H2Error::Connection(PROTOCOL_ERROR, "ENABLE_PUSH must be 0 or 1")),
+            INITIAL_WINDOW_SIZE if value > MAX_WINDOW => {
+                return Err(H2Error::Connection(FLOW_CONTROL_ERROR, "INITIAL_WINDOW_SIZE too large"));
+            }
+            MAX_FRAME_SIZE if !(DEFAULT_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(&value) => {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "MAX_FRAME_SIZE out of range"));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Turns a decoded header list into the Request the handlers know.
+fn to_request(fields: Vec<HeaderField>, limits: &Limits) -> Result<Request, RequestError> {
+    if fields.len() > limits.max_headers {
+        return Err(RequestError::TooManyHeaders);
+    }
+    if fields.iter().map(|(n, v)| n.len() + v.len()).sum::<usize>() > limits.max_header_size {
+        return Err(RequestError::HeadersTooLarge);
+    }
+
+    let (mut method, mut path, mut authority, mut scheme) = (None, None, None, None);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err(RequestError::Malformed("pseudo-header after a regular header"));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                "scheme" => &mut scheme,
+                _ => return Err(RequestError::Malformed("unknown pseudo-header")),
+            };
+            if slot.replace(value).is_some() {
+                return Err(RequestError::Malformed("repeated pseudo-header"));
+            }
+        } else if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err(RequestError::Malformed("uppercase header name"));
+        } else if CONNECTION_HEADERS.contains(&name.as_str()) || name == "te" && value != "trailers" {
+            return Err(RequestError::Malformed("connection-specific header"));
+        } else if name == "cookie" {
+            // HTTP/2 lets clients split cookies into several fields.
+            cookies.push(value);
+        } else {
+            headers.push((name, value));
+        }
+    }
+
+    let (Some(method), Some(path), Some(_)) = (method, path, scheme) else {
+        return Err(RequestError::Malformed("missing pseudo-header"));
+    };
+    let mut request = Request::from_request_line(&format!("{method} {path} HTTP/2.0"))?;
+    if let Some(authority) = authority.filter(|_| !headers.iter().any(|(n, _)| n == "host")) {
+        headers.insert(0, ("host".to_string(), authority));
+    }
+    if !cookies.is_empty() {
+        headers.push(("cookie".to_string(), cookies.join("; ")));
+    }
+    request.headers = headers;
+    Ok(request)
+}
+
+// Work for the writer thread.
+enum Out {
+    // Sent ahead of any waiting DATA.
+    Frame(Frame),
+    PeerSettings(Vec<(u16, u32)>),
+    // A stream the client opened; it now has a send window.
+    Open(u32),
+    // 'streaming' is set for event streams: the body follows as Data, and
+    // the flag drops once the stream is closed so the handler can stop.
+    Response { stream: u32, response: Response, reset_after: bool, streaming: Option<Arc<AtomicBool>> },
+    Data { stream: u32, data: Vec<u8>, end_stream: bool },
+    // The client granted more send window.
+    WindowUpdate { stream: u32, increment: u32 },
+    // Stop sending on a stream, telling the client with RST_STREAM if 'code' is set.
+    Reset { stream: u32, code: Option<u32> },
+}
+
+struct Connection<'a> {
+    reader: BufReader<DeadlineReader>,
+    tx: Sender<Out>,
+    hosts: &'a VirtualHosts,
+    limits: &'a Limits,
+    // Streams opened and not yet fully answered, counted against MAX_STREAMS.
+    active: &'a AtomicUsize,
+    decoder: Decoder,
+    // Streams whose request body is still arriving.
+    streams: HashMap<u32, Request>,
+    // Streams we gave up on while the client may still be sending DATA.
+    reset: HashSet<u32>,
+    // A header block waiting for CONTINUATION frames: stream, block, end of stream.
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    last_stream: u32,
+    going_away: bool,
+}
+
+impl<'a> Connection<'a> {
+    fn send(&self, out: Out) {
+        // The writer only goes away with the socket, and then so do we.
+        let _ = self.tx.send(out);
+    }
+
+    fn read_preface(&mut self, expected: &[u8]) -> io::Result<bool> {
+        self.reader.get_mut().set_deadline(Some(Instant::now() + self.limits.header_timeout));
+        let mut preface = vec![0; expected.len()];
+        match self.reader.read_exact(&mut preface) {
+            Ok(()) if preface == expected => Ok(true),
+            Ok(()) => {
+                self.go_away(PROTOCOL_ERROR, "bad connection preface");
+                Ok(false)
+            }
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn run<'scope>(&mut self, scope: &'scope Scope<'scope, 'a>) -> io::Result<()> {
+        loop {
+            let frame = match self.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(H2Error::Io(e)) if is_timeout(&e) || e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(H2Error::Io(e)) => return Err(e),
+                Err(e) => {
+                    if !self.fail(e) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            match self.on_frame(scope, frame) {
+                Ok(()) if self.going_away && self.active.load(Ordering::SeqCst) == 0 => return Ok(()),
+                Ok(()) => {}
+                Err(H2Error::Io(e)) => return Err(e),
+                Err(e) => {
+                    if !self.fail(e) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    // Resets the stream or ends the connection; false if it's the latter.
+    fn fail(&mut self, error: H2Error) -> bool {
+        match error {
+            H2Error::Stream(stream, code) => {
+                self.streams.remove(&stream);
+                self.reset.insert(stream);
+                self.send(Out::Reset { stream, code: Some(code) });
+                true
+            }
+            H2Error::Connection(code, why) => {
+                self.go_away(code, why);
+                false
+            }
+            H2Error::Io(_) => false,
+        }
+    }
+
+    fn go_away(&self, code: u32, why: &str) {
+        let debug = why.as_bytes().to_vec();
+        self.send(Out::Frame(Frame::GoAway { last_stream: self.last_stream, code, debug }));
+    }
+
+    // Waits out the idle timeout between frames, like the HTTP/1.1 path does
+    // between requests; a frame that has started must arrive in 'header_timeout'.
+    fn next_frame(&mut self) -> Result<Option<Frame>, H2Error> {
+        loop {
+            self.reader.get_mut().set_deadline(Some(Instant::now() + self.limits.idle_timeout));
+            match self.reader.fill_buf() {
+                Ok([]) => return Ok(None),
+                Ok(_) => break,
+                // Clients stay quiet while waiting on a slow handler.
+                Err(e) if is_timeout(&e) && self.active.load(Ordering::SeqCst) > 0 => {}
+                Err(e) if is_timeout(&e) => {
+                    self.go_away(NO_ERROR, "idle");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.reader.get_mut().set_deadline(Some(Instant::now() + self.limits.header_timeout));
+        Frame::read_from(&mut self.reader, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    fn on_frame<'scope>(&mut self, scope: &'scope Scope<'scope, 'a>, frame: Frame) -> Result<(), H2Error> {
+        if let Some((stream, block, _)) = &mut self.continuation {
+            let Frame::Continuation { stream: s, block: more, end_headers } = frame else {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "expected CONTINUATION"));
+            };
+            if s != *stream {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "CONTINUATION on another stream"));
+            }
+            block.extend_from_slice(&more);
+            if block.len() > 2 * self.limits.max_header_size {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "header block too large"));
+            }
+            if end_headers {
+                let (stream, block, end_stream) = self.continuation.take().expect("checked above");
+                return self.on_headers(scope, stream, &block, end_stream);
+            }
+            return Ok(());
+        }
+
+        match frame {
+            Frame::Data { stream, data, end_stream, padding } => {
+                // The window goes back as soon as a frame is read, so a
+                // client can never overrun it: frames are 16K, windows 64K.
+                let flow = data.len() as u32 + padding;
+                if flow > 0 {
+                    self.send(Out::Frame(Frame::WindowUpdate { stream: 0, increment: flow }));
+                }
+                let Some(request) = self.streams.get_mut(&stream) else {
+                    if stream > self.last_stream {
+                        return Err(H2Error::Connection(PROTOCOL_ERROR, "DATA on an idle stream"));
+                    }
+                    if self.reset.contains(&stream) {
+                        return Ok(());
+                    }
+                    return Err(H2Error::Stream(stream, STREAM_CLOSED));
+                };
+                if request.body.len() + data.len() > self.limits.max_body_size {
+                    return self.reject(stream, RequestError::BodyTooLarge, end_stream);
+                }
+                request.body.extend_from_slice(&data);
+                if end_stream {
+                    let request = self.streams.remove(&stream).expect("found above");
+                    self.finish_request(scope, stream, request)?;
+                } else if flow > 0 {
+                    self.send(Out::Frame(Frame::WindowUpdate { stream, increment: flow }));
+                }
+            }
+            Frame::Headers { stream, block, end_stream, end_headers } => {
+                if end_headers {
+                    self.on_headers(scope, stream, &block, end_stream)?;
+                } else {
+                    self.continuation = Some((stream, block, end_stream));
+                }
+            }
+            Frame::Continuation { .. } => {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+            }
+            Frame::RstStream { stream, .. } => {
+                if stream > self.last_stream {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+                self.streams.remove(&stream);
+                self.send(Out::Reset { stream, code: None });
+            }
+            Frame::Settings { ack: false, params } => {
+                check_settings(&params)?;
+                self.send(Out::PeerSettings(params));
+                self.send(Out::Frame(Frame::Settings { ack: true, params: Vec::new() }));
+            }
+            Frame::PushPromise { .. } => {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "clients can't push"));
+            }
+            Frame::Ping { ack: false, data } => self.send(Out::Frame(Frame::Ping { ack: true, data })),
+            Frame::GoAway { .. } => self.going_away = true,
+            Frame::WindowUpdate { stream, increment } => {
+                if stream > self.last_stream {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"));
+                }
+                self.send(Out::WindowUpdate { stream, increment });
+            }
+            Frame::Settings { ack: true, .. } | Frame::Ping { ack: true, .. } => {}
+            Frame::Priority { .. } | Frame::Unknown { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn on_headers<'scope>(
+        &mut self,
+        scope: &'scope Scope<'scope, 'a>,
+        stream: u32,
+        block: &[u8],
+        end_stream: bool,
+    ) -> Result<(), H2Error> {
+        // Decoded even for streams we refuse, or the tables drift apart.
+        let fields = self.decoder.decode(block)?;
+
+        if self.streams.contains_key(&stream) {
+            // Trailers: they end the request and are otherwise ignored.
+            if !end_stream {
+                return Err(H2Error::Stream(stream, PROTOCOL_ERROR));
+            }
+            let request = self.streams.remove(&stream).expect("checked above");
+            return self.finish_request(scope, stream, request);
+        }
+        if stream.is_multiple_of(2) {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "clients open odd streams"));
+        }
+        if stream <= self.last_stream {
+            return Err(H2Error::Connection(STREAM_CLOSED, "HEADERS on a closed stream"));
+        }
+        self.last_stream = stream;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.active.load(Ordering::SeqCst) >= MAX_STREAMS {
+            return Err(H2Error::Stream(stream, REFUSED_STREAM));
+        }
+        self.open(stream);
+
+        let request = match to_request(fields, self.limits) {
+            Ok(request) => request,
+            Err(e) => return self.reject(stream, e, end_stream),
+        };
+        match request.content_length() {
+            Ok(length) if length > self.limits.max_body_size => {
+                self.reject(stream, RequestError::BodyTooLarge, end_stream)
+            }
+            Ok(_) if end_stream => self.finish_request(scope, stream, request),
+            Ok(_) => {
+                self.streams.insert(stream, request);
+                Ok(())
+            }
+            Err(e) => self.reject(stream, e, end_stream),
+        }
+    }
+
+    fn open(&mut self, stream: u32) {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.last_stream = self.last_stream.max(stream);
+        self.send(Out::Open(stream));
+    }
+
+    // Answers a request we won't hand to a handler. Malformed requests get a
+    // reset, as HTTP/2 asks; limits get the same status as over HTTP/1.1.
+    fn reject(&mut self, stream: u32, error: RequestError, end_stream: bool) -> Result<(), H2Error> {
+        self.streams.remove(&stream);
+        let response = match error {
+            RequestError::Malformed(_) => None,
+            e => e.response(),
+        };
+        let Some(response) = response else {
+            return Err(H2Error::Stream(stream, PROTOCOL_ERROR));
+        };
+        if !end_stream {
+            self.reset.insert(stream);
+        }
+        self.send(Out::Response { stream, response, reset_after: !end_stream, streaming: None });
+        Ok(())
+    }
+
+    fn finish_request<'scope>(
+        &mut self,
+        scope: &'scope Scope<'scope, 'a>,
+        stream: u32,
+        request: Request,
+    ) -> Result<(), H2Error> {
+        if request.header("content-length").is_some_and(|_| request.content_length().ok() != Some(request.body.len())) {
+            return Err(H2Error::Stream(stream, PROTOCOL_ERROR));
+        }
+        self.dispatch(scope, stream, request);
+        Ok(())
+    }
+
+    fn dispatch<'scope>(&self, scope: &'scope Scope<'scope, 'a>, stream: u32, request: Request) {
+        let tx = self.tx.clone();
+        let hosts = self.hosts;
+        scope.spawn(move || {
+            // A panicking handler takes down its stream, not the connection.
+            let mut response = panic::catch_unwind(AssertUnwindSafe(|| hosts.handle(&request)))
+                .unwrap_or_else(|_| Response::text(500, "500 Internal Server Error"));
+            let Some(mut events) = response.events.take() else {
+                let _ = tx.send(Out::Response { stream, response, reset_after: false, streaming: None });
+                return;
+            };
+
+            // Server-sent events: this thread feeds the stream until the
+            // events end, the client resets the stream or the writer is gone.
+            let open = Arc::new(AtomicBool::new(true));
+            let streaming = Some(Arc::clone(&open));
+            let _ = tx.send(Out::Response { stream, response, reset_after: false, streaming });
+            while let Some(data) = events.next_chunk() {
+                if !open.load(Ordering::SeqCst) || tx.send(Out::Data { stream, data, end_stream: false }).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(Out::Data { stream, data: Vec::new(), end_stream: true });
+        });
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// A response body still being sent. Event streams grow theirs as events
+// come in and are only complete once the events end.
+struct Pending {
+    stream: u32,
+    body: Vec<u8>,
+    sent: usize,
+    complete: bool,
+    reset_after: bool,
+    open: Option<Arc<AtomicBool>>,
+}
+
+impl Pending {
+    fn remaining(&self) -> usize {
+        self.body.len() - self.sent
+    }
+}
+
+struct Writer<'a> {
+    socket: BufWriter<Stream>,
+    encoder: Encoder,
+    active: &'a AtomicUsize,
+    max_frame_size: usize,
+    connection_window: i64,
+    initial_window: i64,
+    // Send windows of the open streams; a stream is open until its last
+    // frame went out or it was reset.
+    windows: HashMap<u32, i64>,
+    // Round robin, so one large body doesn't starve the others.
+    pending: VecDeque<Pending>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(socket: Stream, active: &'a AtomicUsize) -> Writer<'a> {
+        Writer {
+            socket: BufWriter::new(socket),
+            encoder: Encoder::new(),
+            active,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE as usize,
+            connection_window: DEFAULT_WINDOW as i64,
+            initial_window: DEFAULT_WINDOW as i64,
+            windows: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn run(mut self, rx: Receiver<Out>) -> io::Result<()> {
+        loop {
+            // Messages first, as they can open windows or end the connection;
+            // DATA when there is nothing else to do.
+            let out = if self.can_send() {
+                rx.try_recv().ok()
+            } else {
+                self.socket.flush()?;
+                match rx.recv() {
+                    Ok(out) => Some(out),
+                    Err(_) => return Ok(()),
+                }
+            };
+            match out {
+                Some(out) => {
+                    if !self.handle(out)? {
+                        return self.socket.flush();
+                    }
+                }
+                None => self.send_data()?,
+            }
+        }
+    }
+
+    // False once the connection is over.
+    fn handle(&mut self, out: Out) -> io::Result<bool> {
+        match out {
+            Out::Frame(frame) => {
+                frame.write_to(&mut self.socket)?;
+                if let Frame::GoAway { code, .. } = frame {
+                    return Ok(code == NO_ERROR);
+                }
+            }
+            Out::PeerSettings(params) => self.apply_settings(&params),
+            Out::Open(stream) => {
+                self.windows.insert(stream, self.initial_window);
+            }
+            Out::Response { stream, response, reset_after, streaming } => {
+                if self.windows.contains_key(&stream) {
+                    self.start_response(stream, response, reset_after, streaming)?;
+                } else if let Some(open) = streaming {
+                    // Reset by the client while its handler ran.
+                    open.store(false, Ordering::SeqCst);
+                }
+            }
+            Out::Data { stream, data, end_stream } => {
+                if let Some(pending) = self.pending.iter_mut().find(|p| p.stream == stream) {
+                    pending.body.extend_from_slice(&data);
+                    pending.complete = end_stream;
+                }
+            }
+            Out::WindowUpdate { stream: 0, increment } => {
+                self.connection_window += increment as i64;
+                if self.connection_window > MAX_WINDOW as i64 {
+                    let debug = b"connection window overflow".to_vec();
+                    Frame::GoAway { last_stream: 0, code: FLOW_CONTROL_ERROR, debug }.write_to(&mut self.socket)?;
+                    return Ok(false);
+                }
+            }
+            Out::WindowUpdate { stream, increment } => {
+                if let Some(window) = self.windows.get_mut(&stream) {
+                    *window += increment as i64;
+                    if *window > MAX_WINDOW as i64 {
+                        self.close(stream, Some(FLOW_CONTROL_ERROR))?;
+                    }
+                }
+            }
+            Out::Reset { stream, code } => self.close(stream, code)?,
+        }
+        Ok(true)
+    }
+
+    fn apply_settings(&mut self, params: &[(u16, u32)]) {
+        for &(id, value) in params {
+            match id {
+                // We may use less table than the client allows, never more.
+                HEADER_TABLE_SIZE => self.encoder.set_max_table_size((value as usize).min(DEFAULT_TABLE_SIZE)),
+                INITIAL_WINDOW_SIZE => {
+                    // Applies to the streams already open too.
+                    let delta = value as i64 - self.initial_window;
+                    self.windows.values_mut().for_each(|window| *window += delta);
+                    self.initial_window = value as i64;
+                }
+                MAX_FRAME_SIZE => self.max_frame_size = value as usize,
+                _ => {}
+            }
+        }
+    }
+
+    fn start_response(
+        &mut self,
+        stream: u32,
+        response: Response,
+        reset_after: bool,
+        streaming: Option<Arc<AtomicBool>>,
+    ) -> io::Result<()> {
+        let status = response.status.to_string();
+        // An event stream's length isn't known up front.
+        let length = streaming.is_none().then(|| response.body.len().to_string());
+        let names: Vec<String> = response.headers.iter().map(|(n, _)| n.to_ascii_lowercase()).collect();
+        let fields = std::iter::once((":status", status.as_str()))
+            .chain(
+                names
+                    .iter()
+                    .zip(&response.headers)
+                    .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()) && *name != "content-length")
+                    .map(|(name, (_, value))| (name.as_str(), value.as_str())),
+            )
+            .chain(length.as_deref().map(|length| ("content-length", length)));
+        let block = self.encoder.encode(fields);
+
+        let end_stream = response.body.is_empty() && streaming.is_none();
+        let mut chunks = block.chunks(self.max_frame_size);
+        let first = chunks.next().unwrap_or_default().to_vec();
+        let more = chunks.len();
+        Frame::Headers { stream, block: first, end_stream, end_headers: more == 0 }.write_to(&mut self.socket)?;
+        for (i, chunk) in chunks.enumerate() {
+            let end_headers = i + 1 == more;
+            Frame::Continuation { stream, block: chunk.to_vec(), end_headers }.write_to(&mut self.socket)?;
+        }
+
+        if end_stream {
+            return self.close(stream, reset_after.then_some(NO_ERROR));
+        }
+        let complete = streaming.is_none();
+        let body = response.body;
+        self.pending.push_back(Pending { stream, body, sent: 0, complete, reset_after, open: streaming });
+        Ok(())
+    }
+
+    // Bytes the windows let through, or an empty frame that ends a stream.
+    fn sendable(&self, pending: &Pending) -> bool {
+        let open_window = self.connection_window > 0 && self.windows[&pending.stream] > 0;
+        pending.remaining() > 0 && open_window || pending.remaining() == 0 && pending.complete
+    }
+
+    fn can_send(&self) -> bool {
+        self.pending.iter().any(|p| self.sendable(p))
+    }
+
+    // One DATA frame for the first stream that can take one.
+    fn send_data(&mut self) -> io::Result<()> {
+        let Some(index) = self.pending.iter().position(|p| self.sendable(p)) else {
+            return Ok(());
+        };
+        let mut pending = self.pending.remove(index).expect("found above");
+        let window = self.windows.get_mut(&pending.stream).expect("pending streams are open");
+        let size = pending
+            .remaining()
+            .min((*window).max(0) as usize)
+            .min(self.connection_window as usize)
+            .min(self.max_frame_size);
+        *window -= size as i64;
+        self.connection_window -= size as i64;
+
+        let data = pending.body[pending.sent..pending.sent + size].to_vec();
+        pending.sent += size;
+        let end_stream = pending.complete && pending.remaining() == 0;
+        Frame::Data { stream: pending.stream, data, end_stream, padding: 0 }.write_to(&mut self.socket)?;
+        if end_stream {
+            return self.close(pending.stream, pending.reset_after.then_some(NO_ERROR));
+        }
+        if pending.remaining() == 0 {
+            // An event stream caught up; start its buffer over.
+            pending.body.clear();
+            pending.sent = 0;
+        }
+        self.pending.push_back(pending);
+        Ok(())
+    }
+
+    // Forgets a stream, once.
+    fn close(&mut self, stream: u32, reset: Option<u32>) -> io::Result<()> {
+        if let Some(code) = reset {
+            Frame::RstStream { stream, code }.write_to(&mut self.socket)?;
+        }
+        for pending in self.pending.iter().filter(|p| p.stream == stream) {
+            if let Some(open) = &pending.open {
+                open.store(false, Ordering::SeqCst);
+            }
+        }
+        self.pending.retain(|p| p.stream != stream);
+        if self.windows.remove(&stream).is_some() {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::h2::client::Client;
+    use crate::tcp::sse::{Event, EventStream};
+    use crate::tcp::tests::start;
+    use crate::tcp::vhost::Site;
+    use crate::tcp::{default_site, Server};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn test_site() -> Site {
+        Site::new(".")
+            .route("GET", "/fast", |_, _| Response::text(200, "fast"))
+            .route("GET", "/slow", |_, _| {
+                thread::sleep(Duration::from_millis(300));
+                Response::text(200, "slow")
+            })
+            .route("GET", "/big", |_, _| Response::new(200).with_body(big_body()))
+            .route("POST", "/echo", |request, _| {
+                let name = request.header("x-name").unwrap_or("?");
+                let cookie = request.header("cookie").unwrap_or("");
+                Response::text(200, format!("{name} {cookie} {}", String::from_utf8_lossy(&request.body)))
+            })
+            .route("GET", "/panic", |_, _| panic!("handler bug"))
+            .route("GET", "/events", |_, _| {
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    for n in 1..=3 {
+                        let _ = tx.send(Event::new(format!("tick {n}")));
+                    }
+                });
+                Response::events(EventStream::new(rx))
+            })
+    }
+
+    fn big_body() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn start_h2() -> SocketAddr {
+        start(VirtualHosts::new(default_site()).host("test.local", test_site()))
+    }
+
+    fn connect(addr: SocketAddr) -> Client {
+        let mut client = Client::connect(addr).unwrap();
+        client.authority = "test.local".to_string();
+        client
+    }
+
+    #[test]
+    fn serves_requests_with_prior_knowledge() {
+        let addr = start_h2();
+        let mut client = Client::connect(addr).unwrap();
+
+        let hello = client.get("/").unwrap();
+        assert_eq!(hello.status, 200);
+        assert_eq!(hello.header("content-type"), Some("text/html; charset=utf-8"));
+        assert!(String::from_utf8_lossy(&hello.body).contains("Hi from Rust"));
+        assert!(hello.header("connection").is_none());
+
+        // Same connection, the dynamic tables now have entries on both sides.
+        let missing = client.get("/nope").unwrap();
+        assert_eq!(missing.status, 404);
+        assert!(String::from_utf8_lossy(&missing.body).contains("Oops!"));
+        assert_eq!(client.get("/").unwrap().body, hello.body);
+    }
+
+    #[test]
+    fn passes_headers_bodies_and_authority_to_handlers() {
+        let addr = start_h2();
+        let mut client = connect(addr);
+        let headers = [("x-name", "ferris"), ("cookie", "a=1"), ("cookie", "b=2")];
+        let stream = client.send_request("POST", "/echo", &headers, b"some body").unwrap();
+        let response = client.wait(stream).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"ferris a=1; b=2 some body");
+
+        let panicked = client.get("/panic").unwrap();
+        assert_eq!(panicked.status, 500);
+        assert_eq!(client.get("/fast").unwrap().body, b"fast");
+    }
+
+    #[test]
+    fn multiplexes_streams_on_one_connection() {
+        let addr = start_h2();
+        let mut client = connect(addr);
+        let started = Instant::now();
+        let slow = client.send_request("GET", "/slow", &[], b"").unwrap();
+        let others: Vec<u32> = (0..5).map(|_| client.send_request("GET", "/fast", &[], b"").unwrap()).collect();
+
+        for stream in &others {
+            assert_eq!(client.wait(*stream).unwrap().body, b"fast");
+        }
+        assert!(started.elapsed() < Duration::from_millis(250), "fast streams waited for the slow one");
+        assert_eq!(client.wait(slow).unwrap().body, b"slow");
+        assert_eq!(client.completed.last(), Some(&slow));
+    }
+
+    #[test]
+    fn respects_the_client_flow_control_window() {
+        let addr = start_h2();
+        let mut client = Client::connect_with(addr, &[(INITIAL_WINDOW_SIZE, 1000)]).unwrap();
+        client.authority = "test.local".to_string();
+        client.auto_window_update = false;
+
+        let stream = client.send_request("GET", "/big", &[], b"").unwrap();
+        while client.received(stream) < 1000 {
+            client.step().unwrap();
+        }
+        assert_eq!(client.received(stream), 1000);
+
+        // Nothing more until the window opens.
+        client.set_read_timeout(Some(Duration::from_millis(200)));
+        assert!(matches!(client.step(), Err(H2Error::Io(e)) if is_timeout(&e)));
+        client.set_read_timeout(None);
+
+        client.auto_window_update = true;
+        client.send(&Frame::WindowUpdate { stream, increment: 200_000 }).unwrap();
+        client.send(&Frame::WindowUpdate { stream: 0, increment: 200_000 }).unwrap();
+        let response = client.wait(stream).unwrap();
+        assert_eq!(response.body, big_body());
+    }
+
+    #[test]
+    fn answers_ping_and_finishes_streams_after_goaway() {
+        let addr = start_h2();
+        let mut client = connect(addr);
+        client.ping(*b"rustbox!").unwrap();
+
+        let slow = client.send_request("GET", "/slow", &[], b"").unwrap();
+        client.send(&Frame::GoAway { last_stream: 0, code: NO_ERROR, debug: Vec::new() }).unwrap();
+        assert_eq!(client.wait(slow).unwrap().body, b"slow");
+    }
+
+    #[test]
+    fn protocol_errors_end_the_connection_with_goaway() {
+        let addr = start_h2();
+        let mut client = connect(addr);
+        assert_eq!(client.get("/fast").unwrap().status, 200);
+        // Even stream ids belong to the server.
+        client.send(&Frame::Headers { stream: 2, block: vec![0x82], end_stream: true, end_headers: true }).unwrap();
+        loop {
+            match client.step() {
+                Ok(Frame::GoAway { last_stream, code, .. }) => {
+                    assert_eq!((last_stream, code), (1, PROTOCOL_ERROR));
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => panic!("no GOAWAY: {e}"),
+            }
+        }
+        assert!(client.step().is_err());
+    }
+
+    #[test]
+    fn resets_malformed_and_oversized_requests() {
+        let addr = start_h2();
+        let mut client = connect(addr);
+        let stream = client.send_request("GET", "/fast", &[("connection", "close")], b"").unwrap();
+        assert!(matches!(client.wait(stream), Err(H2Error::Stream(_, PROTOCOL_ERROR))));
+
+        let big = vec![b'x'; Limits::default().max_body_size + 1];
+        let stream = client.send_request("POST", "/echo", &[("content-length", &big.len().to_string())], b"").unwrap();
+        assert_eq!(client.wait(stream).unwrap().status, 413);
+        assert_eq!(client.get("/fast").unwrap().status, 200);
+    }
+
+    #[test]
+    fn streams_server_sent_events() {
+        let addr = start_h2();
+        let mut client = connect(addr);
+        let events = client.get("/events").unwrap();
+        assert_eq!(events.header("content-type"), Some("text/event-stream"));
+        assert!(events.header("content-length").is_none());
+        assert_eq!(events.body, b"data: tick 1\n\ndata: tick 2\n\ndata: tick 3\n\n");
+        // The stream ended, the connection didn't.
+        assert_eq!(client.get("/fast").unwrap().body, b"fast");
+    }
+
+    #[test]
+    fn upgrades_http11_connections() {
+        let addr = start_h2();
+        let (mut client, response) = Client::upgrade(addr, "localhost", "/").unwrap();
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8_lossy(&response.body).contains("Hi from Rust"));
+        assert_eq!(client.get("/nope").unwrap().status, 404);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn nonblocking_mode_turns_h2c_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || Server::new(VirtualHosts::new(default_site())).serve_nonblocking(listener));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(PREFACE).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported"));
+    }
+}
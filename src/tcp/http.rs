@@ -0,0 +1,394 @@
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+use super::limits::{Limits, RequestError};
+use super::sse::EventStream;
+
+// A parsed HTTP/1.1 request. Header names keep the case the client sent,
+// lookups through 'header' are case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    // Reads one request from the stream. The servers read the head and body
+    // separately; this is the two together.
+    // Ok(None) means the client closed the connection before sending anything.
+    #[cfg(test)]
+    pub fn read_from<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Request>, RequestError> {
+        let Some(mut request) = Request::read_head(reader, limits)? else { return Ok(None) };
+        request.read_body(reader, limits)?;
+        Ok(Some(request))
+    }
+
+    // Request line and headers, up to and including the empty line.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Request>, RequestError> {
+        let Some(line) = read_line(reader, limits.max_request_line, || RequestError::RequestLineTooLong)? else {
+            return Ok(None);
+        };
+        let mut request = Request::from_request_line(&line)?;
+
+        let mut header_bytes = 0;
+        loop {
+            let budget = limits.max_header_size.saturating_sub(header_bytes);
+            let Some(header) = read_line(reader, budget, || RequestError::HeadersTooLarge)? else {
+                return Err(RequestError::Malformed("connection closed inside the header section"));
+            };
+            if header.is_empty() {
+                break;
+            }
+            if request.headers.len() == limits.max_headers {
+                return Err(RequestError::TooManyHeaders);
+            }
+            header_bytes += header.len() + 2;
+            request.headers.push(parse_header(&header)?);
+        }
+        // Without chunked decoding a Transfer-Encoding body can't be told
+        // apart from the next request, so it is refused, not guessed at.
+        if request.header("Transfer-Encoding").is_some() {
+            return Err(RequestError::NotImplemented("Transfer-Encoding"));
+        }
+        request.content_length()?;
+        Ok(Some(request))
+    }
+
+    // Checks Content-Length against the limit before reading a single byte of the body.
+    pub fn read_body<R: Read>(&mut self, reader: &mut R, limits: &Limits) -> Result<(), RequestError> {
+        let length = self.content_length()?;
+        if length > limits.max_body_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+        self.body = vec![0; length];
+        reader.read_exact(&mut self.body)?;
+        Ok(())
+    }
+
+    // For readers that collect bytes themselves, like the non-blocking server:
+    // Ok(None) until 'buffer' holds a whole request, then the request and
+    // how many bytes it took. Limits apply to incomplete requests too.
+    pub fn parse_buffered(buffer: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, RequestError> {
+        let Some(line_end) = find(buffer, b"\r\n") else {
+            if buffer.len() > limits.max_request_line {
+                return Err(RequestError::RequestLineTooLong);
+            }
+            return Ok(None);
+        };
+        let Some(head_end) = find(buffer, b"\r\n\r\n") else {
+            if buffer.len() - line_end > limits.max_header_size + 2 {
+                return Err(RequestError::HeadersTooLarge);
+            }
+            return Ok(None);
+        };
+        let head_end = head_end + 4;
+        let Some(mut request) = Request::read_head(&mut &buffer[..head_end], limits)? else {
+            return Ok(None);
+        };
+        let length = request.content_length()?;
+        if length > limits.max_body_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+        let end = head_end + length;
+        if buffer.len() < end {
+            return Ok(None);
+        }
+        request.body = buffer[head_end..end].to_vec();
+        Ok(Some((request, end)))
+    }
+
+    // "GET /path?query HTTP/1.1"
+    pub fn from_request_line(line: &str) -> Result<Request, RequestError> {
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(RequestError::Malformed("request line"));
+        };
+        if method.is_empty() || !target.starts_with('/') && target != "*" || !version.starts_with("HTTP/") {
+            return Err(RequestError::Malformed("request line"));
+        }
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Ok(Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // The Host header without the port, lowercased, since host names are case-insensitive.
+    pub fn host(&self) -> Option<String> {
+        let host = self.header("Host")?.trim();
+        let name = match host.strip_prefix('[') {
+            // IPv6 literal: "[::1]:7878"
+            Some(rest) => rest.split(']').next().unwrap_or(rest),
+            None => host.split(':').next().unwrap_or(host),
+        };
+        Some(name.to_ascii_lowercase())
+    }
+
+    // HTTP/1.1 connections stay open unless the client says otherwise,
+    // HTTP/1.0 ones only if the client asks.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has = |token: &str| connection.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+        if self.version == "HTTP/1.1" { !has("close") } else { has("keep-alive") }
+    }
+
+    // Exactly one Content-Length of plain digits. Repeated ones are refused
+    // even when they agree: a proxy that reads a different one than we do
+    // sees a different request boundary.
+    pub fn content_length(&self) -> Result<usize, RequestError> {
+        let mut values = self.headers.iter().filter(|(n, _)| n.eq_ignore_ascii_case("Content-Length"));
+        let value = match (values.next(), values.next()) {
+            (None, _) => return Ok(0),
+            (Some((_, value)), None) => value.trim(),
+            (Some(_), Some(_)) => return Err(RequestError::Malformed("repeated Content-Length")),
+        };
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RequestError::Malformed("Content-Length"));
+        }
+        value.parse().map_err(|_| RequestError::Malformed("Content-Length"))
+    }
+}
+
+pub fn parse_header(line: &str) -> Result<(String, String), RequestError> {
+    let (name, value) = line.split_once(':').ok_or(RequestError::Malformed("header"))?;
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(RequestError::Malformed("header"));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+// One CRLF-terminated line of at most 'max' bytes without the line ending.
+// Unlike BufRead::read_line it stops reading as soon as the line is too long.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    max: usize,
+    too_long: fn() -> RequestError,
+) -> Result<Option<String>, RequestError> {
+    let mut line = Vec::new();
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(RequestError::Malformed("connection closed mid-line"));
+        }
+        let (used, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(newline) => (newline + 1, true),
+            None => (available.len(), false),
+        };
+        if line.len() + used > max + 2 {
+            return Err(too_long());
+        }
+        line.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if done {
+            break;
+        }
+    }
+    while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| RequestError::Malformed("not UTF-8"))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Set for server-sent events, which are streamed after the head instead
+    // of a body (see sse.rs).
+    pub events: Option<EventStream>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response { status, headers: Vec::new(), body: Vec::new(), events: None }
+    }
+
+    pub fn html(status: u16, contents: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(contents.into())
+    }
+
+    pub fn text(status: u16, contents: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(contents.into())
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    #[cfg(test)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn status_line(&self) -> String {
+        format!("HTTP/1.1 {} {}", self.status, reason_phrase(self.status))
+    }
+
+    // Content-Length is always derived from the body, so handlers can't get it wrong.
+    // Head and body go out in one write: two small writes on a kept-alive
+    // connection run into Nagle's algorithm and the peer's delayed ACK.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())?;
+        writer.flush()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{}\r\n", self.status_line());
+        for (name, value) in &self.headers {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_request_with_headers_and_body() {
+        let raw = "POST /submit?x=1 HTTP/1.1\r\nHost: Example.COM:7878\r\nContent-Length: 5\r\n\r\nhello";
+        let request = Request::read_from(&mut raw.as_bytes(), &Limits::default()).unwrap().unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/submit");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(request.header("content-length"), Some("5"));
+        assert_eq!(request.host().as_deref(), Some("example.com"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn parses_only_complete_buffers() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET / HTTP/1.1\r\n";
+        let limits = Limits::default();
+        assert!(Request::parse_buffered(&raw[..20], &limits).unwrap().is_none());
+        assert!(Request::parse_buffered(&raw[..40], &limits).unwrap().is_none());
+
+        let (request, used) = Request::parse_buffered(raw, &limits).unwrap().unwrap();
+        assert_eq!(request.body, b"abc");
+        assert_eq!(&raw[used..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version() {
+        let mut request = Request::from_request_line("GET / HTTP/1.1").unwrap();
+        assert!(request.keep_alive());
+        request.headers.push(("Connection".to_string(), "close".to_string()));
+        assert!(!request.keep_alive());
+
+        let mut old = Request::from_request_line("GET / HTTP/1.0").unwrap();
+        assert!(!old.keep_alive());
+        old.headers.push(("Connection".to_string(), "Keep-Alive".to_string()));
+        assert!(old.keep_alive());
+    }
+
+    #[test]
+    fn rejects_malformed_request_line() {
+        let limits = Limits::default();
+        assert!(Request::read_from(&mut "GARBAGE\r\n\r\n".as_bytes(), &limits).is_err());
+        assert!(Request::read_from(&mut "".as_bytes(), &limits).unwrap().is_none());
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let limits = Limits { max_request_line: 20, max_headers: 2, max_header_size: 30, max_body_size: 4, ..Limits::default() };
+        let read = |raw: &str| Request::read_from(&mut raw.as_bytes(), &limits);
+        let buffered = |raw: &str| Request::parse_buffered(raw.as_bytes(), &limits);
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(20));
+        assert!(matches!(read(&long_line), Err(RequestError::RequestLineTooLong)));
+        assert!(matches!(buffered(&long_line[..25]), Err(RequestError::RequestLineTooLong)));
+
+        let many = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(read(many), Err(RequestError::TooManyHeaders)));
+        assert!(matches!(buffered(many), Err(RequestError::TooManyHeaders)));
+
+        let big = format!("GET / HTTP/1.1\r\nA: {}\r\n", "x".repeat(40));
+        assert!(matches!(read(&big), Err(RequestError::HeadersTooLarge)));
+        assert!(matches!(buffered(&big), Err(RequestError::HeadersTooLarge)));
+
+        let body = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+        assert!(matches!(read(body), Err(RequestError::BodyTooLarge)));
+        assert!(matches!(buffered(body), Err(RequestError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn writes_content_length() {
+        let bytes = Response::text(404, "nope").to_bytes();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(text.contains("Content-Length: 4\r\n"));
+        assert!(text.ends_with("\r\n\r\nnope"));
+    }
+}
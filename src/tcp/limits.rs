@@ -0,0 +1,312 @@
+// Limits that keep one client from holding a worker forever (slowloris) or
+// from making the server buffer gigabytes of headers or body.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Read;
+use super::listener::Stream;
+use std::time::{Duration, Instant};
+
+use super::http::Response;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    // How long a kept-alive connection may sit without starting a request.
+    pub idle_timeout: Duration,
+    // From the first byte of a request to the end of its headers.
+    pub header_timeout: Duration,
+    // From the end of the headers to the end of the body.
+    pub body_timeout: Duration,
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    // All header lines together, not counting the request line.
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    // Event streams open at once; over HTTP/1.x each holds a thread until
+    // it ends, so past this subscribers get 503.
+    pub max_streams: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            idle_timeout: Duration::from_secs(60),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+            max_streams: 256,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Malformed(&'static str),
+    Timeout,
+    RequestLineTooLong,
+    TooManyHeaders,
+    HeadersTooLarge,
+    BodyTooLarge,
+    // Something we recognise but don't do, like a chunked body.
+    NotImplemented(&'static str),
+    Io(io::Error),
+}
+
+impl RequestError {
+    // The response owed to the client, or None when the connection is
+    // already broken and there is nobody left to answer.
+    pub fn response(&self) -> Option<Response> {
+        let status = match self {
+            RequestError::Malformed(_) => 400,
+            RequestError::Timeout => 408,
+            RequestError::BodyTooLarge => 413,
+            RequestError::NotImplemented(_) => 501,
+            RequestError::RequestLineTooLong
+            | RequestError::TooManyHeaders
+            | RequestError::HeadersTooLarge => 431,
+            RequestError::Io(_) => return None,
+        };
+        let reason = super::http::reason_phrase(status);
+        Some(Response::text(status, format!("{status} {reason}")).with_header("Connection", "close"))
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Malformed(what) => write!(f, "malformed request: {what}"),
+            RequestError::Timeout => write!(f, "client too slow"),
+            RequestError::RequestLineTooLong => write!(f, "request line too long"),
+            RequestError::TooManyHeaders => write!(f, "too many headers"),
+            RequestError::HeadersTooLarge => write!(f, "header section too large"),
+            RequestError::BodyTooLarge => write!(f, "body too large"),
+            RequestError::NotImplemented(what) => write!(f, "not implemented: {what}"),
+            RequestError::Io(e) => write!(f, "i/o error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> RequestError {
+        match e.kind() {
+            // What a socket read timeout looks like, depending on the platform.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => RequestError::Timeout,
+            io::ErrorKind::UnexpectedEof => RequestError::Malformed("connection closed mid-request"),
+            _ => RequestError::Io(e),
+        }
+    }
+}
+
+// A read side that gives up at a deadline, not after a quiet period: a client
+// trickling one byte every few seconds never trips a plain read timeout.
+pub struct DeadlineReader {
+    stream: Stream,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineReader {
+    pub fn new(stream: Stream) -> DeadlineReader {
+        DeadlineReader { stream, deadline: None }
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(left))?;
+        } else {
+            self.stream.set_read_timeout(None)?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::vhost::VirtualHosts;
+    #[cfg(target_os = "linux")]
+    use crate::tcp::asynchronous::AsyncServer;
+    use crate::tcp::tests::read_response;
+    use crate::tcp::{default_site, Server};
+    use std::io::{BufReader, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    fn tight() -> Limits {
+        Limits {
+            idle_timeout: Duration::from_millis(300),
+            header_timeout: Duration::from_millis(300),
+            body_timeout: Duration::from_millis(300),
+            max_request_line: 256,
+            max_headers: 8,
+            max_header_size: 512,
+            max_body_size: 1024,
+            max_streams: 1,
+        }
+    }
+
+    // The same limits on a thread-pool server and, on Linux, a non-blocking
+    // and an async one.
+    fn servers() -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        addrs.push(listener.local_addr().unwrap());
+        thread::spawn(move || Server::new(VirtualHosts::new(default_site())).limits(tight()).serve(listener));
+
+        #[cfg(target_os = "linux")]
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            thread::spawn(move || {
+                Server::new(VirtualHosts::new(default_site())).limits(tight()).serve_nonblocking(listener)
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            thread::spawn(move || {
+                AsyncServer::new(VirtualHosts::new(default_site())).limits(tight()).serve(listener)
+            });
+        }
+        addrs
+    }
+
+    // Sends 'head' at once, then 'trickle' one byte every 50ms from another
+    // thread, and returns whatever the server answers before closing.
+    fn exchange(addr: SocketAddr, head: &[u8], trickle: &'static [u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(head).unwrap();
+
+        let mut writer = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            for byte in trickle {
+                thread::sleep(Duration::from_millis(50));
+                if writer.write_all(&[*byte]).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    fn status(response: &str) -> &str {
+        response.get(9..12).unwrap_or(response)
+    }
+
+    #[test]
+    fn slowloris_headers_get_408() {
+        for addr in servers() {
+            let started = Instant::now();
+            // Every byte arrives well inside any per-read timeout, the whole
+            // header section doesn't.
+            let response = exchange(addr, b"GET / HTTP/1.1\r\nX-Slow: ", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n");
+            assert_eq!(status(&response), "408", "{addr}: {response}");
+            assert!(started.elapsed() < Duration::from_millis(1200), "{:?}", started.elapsed());
+        }
+    }
+
+    #[test]
+    fn slow_body_gets_408() {
+        for addr in servers() {
+            let response = exchange(addr, b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\nabc", b"");
+            assert_eq!(status(&response), "408", "{addr}: {response}");
+        }
+    }
+
+    #[test]
+    fn oversized_heads_get_431() {
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(2000));
+        let many_headers: String = (0..20).map(|i| format!("X-{i}: {i}\r\n")).collect();
+        let many_headers = format!("GET / HTTP/1.1\r\n{many_headers}\r\n");
+        let huge_header = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "b".repeat(100_000));
+
+        for addr in servers() {
+            for head in [&long_line, &many_headers, &huge_header] {
+                let response = exchange(addr, head.as_bytes(), b"");
+                assert_eq!(status(&response), "431", "{addr}: {response}");
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_body_gets_413_before_it_is_sent() {
+        for addr in servers() {
+            let response = exchange(addr, b"POST / HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n", b"");
+            assert_eq!(status(&response), "413", "{addr}: {response}");
+        }
+    }
+
+    #[test]
+    fn ambiguous_body_lengths_are_refused() {
+        // The chunked body hides a second request that a server ignoring
+        // Transfer-Encoding would answer.
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1b\r\nGET /smuggled HTTP/1.1\r\n\r\n\r\n0\r\n\r\n";
+        let both = "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let lengths = [
+            "Content-Length: 5\r\nContent-Length: 5",
+            "Content-Length: 5\r\nContent-Length: 50",
+            "Content-Length: 5, 5",
+            "Content-Length: +5",
+        ];
+        for addr in servers() {
+            for raw in [chunked, both] {
+                let response = exchange(addr, raw.as_bytes(), b"");
+                assert_eq!(status(&response), "501", "{addr}: {response}");
+                assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{addr}: {response}");
+            }
+            for length in lengths {
+                let raw = format!("POST / HTTP/1.1\r\n{length}\r\n\r\nhello");
+                let response = exchange(addr, raw.as_bytes(), b"");
+                assert_eq!(status(&response), "400", "{addr}: {length}: {response}");
+            }
+        }
+    }
+
+    #[test]
+    fn event_streams_past_the_limit_get_503() {
+        for addr in servers() {
+            let mut first = TcpStream::connect(addr).unwrap();
+            first.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+            let head = read_response(&mut BufReader::new(&first));
+            assert!(head.contains("text/event-stream"), "{addr}: {head}");
+
+            let response = exchange(addr, b"GET /events HTTP/1.1\r\n\r\n", b"");
+            assert_eq!(status(&response), "503", "{addr}: {response}");
+        }
+    }
+
+    #[test]
+    fn idle_connections_are_closed_quietly() {
+        for addr in servers() {
+            let started = Instant::now();
+            let response = exchange(addr, b"", b"");
+            assert_eq!(response, "");
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn requests_within_limits_still_work() {
+        for addr in servers() {
+            let response = exchange(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n", b"");
+            assert_eq!(status(&response), "200", "{addr}: {response}");
+        }
+    }
+}
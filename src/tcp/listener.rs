@@ -0,0 +1,298 @@
+// Where the server takes connections from: a TCP address, a Unix domain
+// socket path, or both at once. Past accept() the two look the same, so
+// every server mode, HTTP/1.1 and h2c alike, works over either.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+// A bound socket file, removed again when the listener goes away.
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    // Device and inode of the file we created, so we never delete a socket
+    // some later server has put in its place.
+    id: (u64, u64),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: impl ToSocketAddrs) -> io::Result<Listener> {
+        TcpListener::bind(addr).map(Listener::Tcp)
+    }
+
+    // Binds 'path' and gives the socket file 'mode' (e.g. 0o660 so a proxy in
+    // the same group can connect). A socket file left behind by a server that
+    // died is replaced; a live one, or anything that isn't a socket, is not.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>, mode: u32) -> io::Result<Listener> {
+        let path = path.as_ref();
+        remove_stale(path)?;
+        let listener = UnixListener::bind(path)?;
+        // Until this runs the file has the umask's permissions; changing the
+        // umask instead would race with every other thread creating files.
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        let metadata = fs::metadata(path)?;
+        let id = (metadata.dev(), metadata.ino());
+        Ok(Listener::Unix(UnixSocket { listener, path: path.to_path_buf(), id }))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(socket) => socket.listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(socket) => socket.listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{addr}"),
+                Err(_) => write!(f, "http://(unknown address)"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(socket) => write!(f, "unix:{}", socket.path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(socket) => socket.listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let ours = fs::symlink_metadata(&self.path).is_ok_and(|m| (m.dev(), m.ino()) == self.id);
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Nobody answering on an existing socket file means its server is gone.
+#[cfg(unix)]
+fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        let message = format!("{} exists and is not a socket", path.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            let message = format!("another server is listening on {}", path.display());
+            Err(io::Error::new(io::ErrorKind::AddrInUse, message))
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+// One accepted connection.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Unix sockets have no Nagle to turn off.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tcp::tests::read_response;
+    use crate::tcp::vhost::VirtualHosts;
+    use crate::tcp::{default_site, Server};
+    use std::env;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn socket_path(test: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rustbox-{test}-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn get_over_unix(path: &Path) -> String {
+        let stream = UnixStream::connect(path).unwrap();
+        (&stream).write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        read_response(&mut BufReader::new(&stream))
+    }
+
+    #[test]
+    fn serves_over_tcp_and_a_unix_socket_at_once() {
+        let path = socket_path("both");
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let unix = Listener::bind_unix(&path, 0o600).unwrap();
+        thread::spawn(move || Server::new(VirtualHosts::new(default_site())).serve_all(vec![tcp.into(), unix]));
+
+        assert!(get_over_unix(&path).contains("Hi from Rust"));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read_response(&mut BufReader::new(&stream)).contains("Hi from Rust"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn nonblocking_mode_serves_a_unix_socket() {
+        let path = socket_path("nonblocking");
+        let unix = Listener::bind_unix(&path, 0o600).unwrap();
+        thread::spawn(move || Server::new(VirtualHosts::new(default_site())).serve_nonblocking(unix));
+        assert!(get_over_unix(&path).contains("Hi from Rust"));
+    }
+
+    #[test]
+    fn sets_the_socket_file_mode() {
+        let path = socket_path("mode");
+        let _listener = Listener::bind_unix(&path, 0o640).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn replaces_stale_sockets_but_not_live_ones_or_other_files() {
+        let path = socket_path("stale");
+        // A std listener leaves its file behind, like a crashed server would.
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = Listener::bind_unix(&path, 0o600).unwrap();
+
+        let err = Listener::bind_unix(&path, 0o600).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        assert!(!path.exists(), "the socket file outlived its listener");
+
+        fs::write(&path, "not a socket").unwrap();
+        let err = Listener::bind_unix(&path, 0o600).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+// multipart/form-data bodies. The parser reads from any 'Read' in fixed-size
+// chunks: text fields are collected in memory up to a limit, file parts are
+// written straight to disk as they arrive, so an upload never has to fit in
+// memory at once.
+//
+// Site::upload routes posts to a handler that gets the parsed parts. The
+// thread-pool server streams those bodies straight from the connection, so
+// they are held to UploadLimits rather than Limits::max_body_size; the other
+// modes buffer every body and parse it from memory.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use rand::{rng, Rng};
+
+use super::http::{Request, Response};
+use super::vhost::Site;
+
+const CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimits {
+    pub max_field_size: usize,
+    pub max_file_size: u64,
+    pub max_total_size: u64,
+    pub max_parts: usize,
+    pub max_part_header_size: usize,
+}
+
+impl Default for UploadLimits {
+    fn default() -> UploadLimits {
+        UploadLimits {
+            max_field_size: 64 * 1024,
+            max_file_size: 10 * 1024 * 1024,
+            max_total_size: 50 * 1024 * 1024,
+            max_parts: 100,
+            max_part_header_size: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MultipartError {
+    NotMultipart,
+    Malformed(&'static str),
+    TooLarge(&'static str),
+    Io(io::Error),
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "not a multipart/form-data request"),
+            MultipartError::Malformed(what) => write!(f, "malformed multipart body: {what}"),
+            MultipartError::TooLarge(what) => write!(f, "{what} is over the size limit"),
+            MultipartError::Io(e) => write!(f, "i/o error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+impl MultipartError {
+    // What the client is told. The body may have been half read, so the
+    // connection closes after it.
+    pub fn response(&self) -> Response {
+        let status = match self {
+            MultipartError::NotMultipart => 415,
+            MultipartError::Malformed(_) => 400,
+            MultipartError::TooLarge(_) => 413,
+            MultipartError::Io(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => 408,
+                // Failing to store the upload is our fault.
+                _ => 500,
+            },
+        };
+        Response::new(status).with_header("Connection", "close")
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> MultipartError {
+        MultipartError::Io(e)
+    }
+}
+
+// A file part that has been written to the upload directory. The file is the
+// caller's to move or delete; 'filename' is what the browser claimed and is
+// only safe to show, never to use as a path.
+#[derive(Debug)]
+pub struct UploadedFile {
+    pub field: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Multipart {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Multipart {
+    // 'body' is the request's body: the connection itself, or 'request.body'
+    // where the server has already read it.
+    pub fn from_request<R: Read>(request: &Request, body: R, dir: &Path, limits: &UploadLimits) -> Result<Multipart, MultipartError> {
+        let boundary = request
+            .header("Content-Type")
+            .and_then(boundary)
+            .ok_or(MultipartError::NotMultipart)?;
+        Multipart::parse(body, &boundary, dir, limits)
+    }
+
+    // Files written before a failure are removed again.
+    pub fn parse<R: Read>(reader: R, boundary: &str, dir: &Path, limits: &UploadLimits) -> Result<Multipart, MultipartError> {
+        let mut multipart = Multipart::default();
+        let result = parse_parts(reader, boundary, dir, limits, &mut multipart);
+        match result {
+            Ok(()) => Ok(multipart),
+            Err(e) => {
+                for file in &multipart.files {
+                    let _ = fs::remove_file(&file.path);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+pub type UploadHandler = Box<dyn Fn(&Request, Multipart, &Site) -> Response + Send + Sync + 'static>;
+
+// A POST route whose body is multipart/form-data, see Site::upload. Files
+// go to 'dir' and are the handler's to keep or delete.
+pub struct Upload {
+    dir: PathBuf,
+    limits: UploadLimits,
+    handler: UploadHandler,
+}
+
+impl Upload {
+    pub fn new(dir: PathBuf, limits: UploadLimits, handler: UploadHandler) -> Upload {
+        Upload { dir, limits, handler }
+    }
+
+    pub fn limits(&self) -> &UploadLimits {
+        &self.limits
+    }
+
+    pub fn handle<R: Read>(&self, request: &Request, body: R, site: &Site) -> Result<Response, MultipartError> {
+        let multipart = Multipart::from_request(request, body, &self.dir, &self.limits)?;
+        Ok((self.handler)(request, multipart, site))
+    }
+}
+
+// "multipart/form-data; boundary=----xyz" -> "----xyz"
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+}
+
+fn parse_parts<R: Read>(
+    reader: R,
+    boundary: &str,
+    dir: &Path,
+    limits: &UploadLimits,
+    multipart: &mut Multipart,
+) -> Result<(), MultipartError> {
+    let delimiter = format!("\r\n--{boundary}").into_bytes();
+    // Starting with CRLF makes the first boundary look like all the others.
+    let mut stream = Stream { reader, buf: b"\r\n".to_vec(), read: 0, limit: limits.max_total_size };
+
+    // The preamble before the first boundary is ignored.
+    stream.copy_until(&delimiter, &mut |_| Ok(()))?;
+
+    for part in 0.. {
+        match &stream.take(2)?[..] {
+            b"--" => return Ok(()),
+            b"\r\n" => {}
+            _ => return Err(MultipartError::Malformed("boundary not followed by CRLF")),
+        }
+        if part == limits.max_parts {
+            return Err(MultipartError::TooLarge("number of parts"));
+        }
+
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = None;
+        let mut header_bytes = 0;
+        loop {
+            let line = stream.line(limits.max_part_header_size)?;
+            header_bytes += line.len() + 2;
+            if header_bytes > limits.max_part_header_size {
+                return Err(MultipartError::TooLarge("part header"));
+            }
+            if line.is_empty() {
+                break;
+            }
+            let Some((header, value)) = line.split_once(':') else {
+                return Err(MultipartError::Malformed("part header without ':'"));
+            };
+            if header.trim().eq_ignore_ascii_case("Content-Disposition") {
+                for param in value.split(';').skip(1) {
+                    if let Some((key, v)) = param.trim().split_once('=') {
+                        let v = v.trim().trim_matches('"').to_string();
+                        match key.trim() {
+                            "name" => name = Some(v),
+                            "filename" => filename = Some(v),
+                            _ => {}
+                        }
+                    }
+                }
+            } else if header.trim().eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+        let name = name.ok_or(MultipartError::Malformed("part without a name"))?;
+
+        match filename {
+            Some(filename) => {
+                let path = dir.join(upload_name());
+                let mut file = File::create_new(&path)?;
+                let mut upload = UploadedFile { field: name, filename: base_name(&filename), content_type, path, size: 0 };
+                let written = stream.copy_until(&delimiter, &mut |chunk| {
+                    upload.size += chunk.len() as u64;
+                    if upload.size > limits.max_file_size {
+                        return Err(MultipartError::TooLarge("uploaded file"));
+                    }
+                    file.write_all(chunk).map_err(MultipartError::Io)
+                });
+                // Registered even on failure so Multipart::parse cleans it up.
+                multipart.files.push(upload);
+                written?;
+            }
+            None => {
+                let mut value = Vec::new();
+                stream.copy_until(&delimiter, &mut |chunk| {
+                    if value.len() + chunk.len() > limits.max_field_size {
+                        return Err(MultipartError::TooLarge("form field"));
+                    }
+                    value.extend_from_slice(chunk);
+                    Ok(())
+                })?;
+                multipart.fields.push((name, String::from_utf8_lossy(&value).into_owned()));
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Stream<R> {
+    reader: R,
+    buf: Vec<u8>,
+    read: u64,
+    limit: u64,
+}
+
+impl<R: Read> Stream<R> {
+    fn fill(&mut self) -> Result<(), MultipartError> {
+        let mut chunk = [0u8; CHUNK];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if n == 0 {
+            return Err(MultipartError::Malformed("body ended before the closing boundary"));
+        }
+        self.read += n as u64;
+        if self.read > self.limit {
+            return Err(MultipartError::TooLarge("request body"));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    // Feeds everything up to 'delimiter' to 'sink' and consumes the delimiter.
+    fn copy_until(
+        &mut self,
+        delimiter: &[u8],
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), MultipartError>,
+    ) -> Result<(), MultipartError> {
+        loop {
+            if let Some(at) = find(&self.buf, delimiter) {
+                sink(&self.buf[..at])?;
+                self.buf.drain(..at + delimiter.len());
+                return Ok(());
+            }
+            // The tail could be the beginning of a delimiter split across reads.
+            let safe = self.buf.len().saturating_sub(delimiter.len() - 1);
+            if safe > 0 {
+                sink(&self.buf[..safe])?;
+                self.buf.drain(..safe);
+            }
+            self.fill()?;
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<Vec<u8>, MultipartError> {
+        while self.buf.len() < n {
+            self.fill()?;
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    fn line(&mut self, max: usize) -> Result<String, MultipartError> {
+        loop {
+            if let Some(at) = find(&self.buf, b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..at]).into_owned();
+                self.buf.drain(..at + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > max {
+                return Err(MultipartError::TooLarge("part header"));
+            }
+            self.fill()?;
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Old browsers send the full client-side path.
+fn base_name(filename: &str) -> String {
+    filename.rsplit(['/', '\\']).next().unwrap_or("").to_string()
+}
+
+fn upload_name() -> String {
+    let bytes: [u8; 16] = rng().random();
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("upload-{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Hands out at most 'step' bytes per read, so delimiters get split.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn upload_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustbox-multipart-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn body(file_contents: &[u8]) -> Vec<u8> {
+        let mut body = b"preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Holiday --XyZ photos\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\\\pics\\\\beach.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(file_contents);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+        body
+    }
+
+    #[test]
+    fn streams_files_to_disk() {
+        let dir = upload_dir("streams");
+        let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let body = body(&contents);
+
+        for step in [1, 7, CHUNK] {
+            let reader = Trickle { data: &body, step };
+            let multipart = Multipart::parse(reader, "XyZ", &dir, &UploadLimits::default()).unwrap();
+            assert_eq!(multipart.fields, [("title".to_string(), "Holiday --XyZ photos".to_string())]);
+
+            let [photo] = &multipart.files[..] else { panic!("{:?}", multipart.files) };
+            assert_eq!(photo.field, "photo");
+            assert_eq!(photo.filename, "beach.jpg");
+            assert_eq!(photo.content_type.as_deref(), Some("image/jpeg"));
+            assert_eq!(photo.size, contents.len() as u64);
+            assert_eq!(fs::read(&photo.path).unwrap(), contents);
+            assert!(photo.path.starts_with(&dir));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn enforces_limits_and_cleans_up() {
+        let dir = upload_dir("limits");
+        let body = body(&[b'x'; 5000]);
+        let small_files = UploadLimits { max_file_size: 4096, ..UploadLimits::default() };
+        let error = Multipart::parse(&body[..], "XyZ", &dir, &small_files).unwrap_err();
+        assert!(matches!(error, MultipartError::TooLarge("uploaded file")));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let small_body = UploadLimits { max_total_size: 1000, ..UploadLimits::default() };
+        assert!(matches!(
+            Multipart::parse(&body[..], "XyZ", &dir, &small_body),
+            Err(MultipartError::TooLarge("request body"))
+        ));
+
+        let truncated = &body[..body.len() - 10];
+        assert!(matches!(
+            Multipart::parse(truncated, "XyZ", &dir, &UploadLimits::default()),
+            Err(MultipartError::Malformed(_))
+        ));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_boundary_from_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(boundary("Multipart/Form-Data;charset=utf-8; boundary=xyz").as_deref(), Some("xyz"));
+        assert_eq!(boundary("text/plain; boundary=xyz"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+}
@@ -0,0 +1,489 @@
+// Readiness-based server mode. Every socket is non-blocking and a single
+// thread drives all connections from poller events, so an idle keep-alive
+// client costs a few buffers instead of a whole worker thread.
+//
+// Handlers still run on the loop thread: a slow one (like "/sleep") stalls
+// every other connection while it runs. Event streams never finish, so a
+// connection that gets one leaves the loop for a thread of its own.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::http::{Request, Response};
+use super::limits::{Limits, RequestError};
+use super::listener::{Listener, Stream};
+use super::poll::{Interest, Poller};
+use super::vhost::VirtualHosts;
+use super::sse::{StreamSlot, StreamSlots};
+use super::{h2, respond, sse, LINGER};
+
+const READ_CHUNK: usize = 8 * 1024;
+
+enum State {
+    // Collecting bytes until a whole request is buffered.
+    Reading,
+    // Flushing 'output'; afterwards either back to Reading or Draining.
+    Writing { keep_alive: bool },
+    // The last response is out and our side is shut. Input is thrown away
+    // until the client closes or LINGER runs out, so the kernel doesn't
+    // reset the connection while the response is still in flight.
+    Draining { until: Instant },
+}
+
+struct Connection {
+    stream: Stream,
+    state: State,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    idle_since: Instant,
+    request_started: Option<Instant>,
+    head_done: Option<Instant>,
+    // When the socket first refused part of the current response.
+    write_blocked: Option<Instant>,
+    // An event-stream response waiting to be handed off, see 'settle'.
+    handoff: Option<(Response, StreamSlot)>,
+}
+
+impl Connection {
+    fn new(stream: Stream) -> Connection {
+        Connection {
+            stream,
+            state: State::Reading,
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            idle_since: Instant::now(),
+            request_started: None,
+            head_done: None,
+            write_blocked: None,
+            handoff: None,
+        }
+    }
+
+    fn interest(&self) -> Interest {
+        match self.state {
+            State::Reading | State::Draining { .. } => Interest::Readable,
+            State::Writing { .. } => Interest::Writable,
+        }
+    }
+
+    // The same deadlines the blocking mode enforces per read. A client that
+    // stops reading gets body_timeout to take the rest of a response.
+    fn deadline(&self, limits: &Limits) -> Option<Instant> {
+        match self.state {
+            State::Reading => match (self.request_started, self.head_done) {
+                (_, Some(head_done)) => Some(head_done + limits.body_timeout),
+                (Some(started), None) => Some(started + limits.header_timeout),
+                (None, None) => Some(self.idle_since + limits.idle_timeout),
+            },
+            State::Writing { .. } => self.write_blocked.map(|blocked| blocked + limits.body_timeout),
+            State::Draining { until } => Some(until),
+        }
+    }
+
+    // A client that is half-way through a request gets a 408; an idle,
+    // draining or stalled connection is just closed.
+    fn expire(&mut self) -> io::Result<bool> {
+        match self.state {
+            State::Reading if self.request_started.is_some() => {
+                let response = RequestError::Timeout.response().expect("timeouts are answered");
+                self.start_writing(response, false);
+                self.flush()
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Drains the socket into 'input'. Returns false once the peer has closed.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn start_writing(&mut self, response: Response, keep_alive: bool) {
+        self.output = response.to_bytes();
+        self.written = 0;
+        self.write_blocked = None;
+        self.state = State::Writing { keep_alive };
+    }
+
+    // Writes as much of 'output' as the socket takes and moves on once it is all out.
+    // Returns false if the peer is gone.
+    fn flush(&mut self) -> io::Result<bool> {
+        let State::Writing { keep_alive } = self.state else { return Ok(true) };
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.write_blocked.get_or_insert_with(Instant::now);
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.output.clear();
+        if keep_alive {
+            // Pipelined requests may already be waiting in 'input'.
+            self.state = State::Reading;
+            self.idle_since = Instant::now();
+        } else {
+            self.stream.shutdown(Shutdown::Write)?;
+            self.state = State::Draining { until: Instant::now() + LINGER };
+        }
+        Ok(true)
+    }
+
+    // Runs the state machine as far as the buffered input and the socket allow.
+    // Returns false when the connection should be closed.
+    fn advance(&mut self, hosts: &VirtualHosts, limits: &Limits, streams: &StreamSlots) -> io::Result<bool> {
+        loop {
+            match self.state {
+                State::Reading => {
+                    if self.input.is_empty() {
+                        return Ok(true);
+                    }
+                    let now = Instant::now();
+                    self.request_started.get_or_insert(now);
+                    if self.head_done.is_none() && self.input.windows(4).any(|w| w == b"\r\n\r\n") {
+                        self.head_done = Some(now);
+                    }
+                    match Request::parse_buffered(&self.input, limits) {
+                        Ok(Some((request, used))) => {
+                            self.input.drain(..used);
+                            self.request_started = None;
+                            self.head_done = None;
+                            // h2c runs on threads (see h2::server); this loop only speaks HTTP/1.x.
+                            let (response, keep_alive) = if h2::is_preface(&request) {
+                                let response = Response::text(505, "505 HTTP Version Not Supported");
+                                (response.with_header("Connection", "close"), false)
+                            } else {
+                                // A panicking handler costs its connection, not the loop.
+                                panic::catch_unwind(AssertUnwindSafe(|| respond(hosts, &request))).unwrap_or_else(|_| {
+                                    let response = Response::text(500, "500 Internal Server Error");
+                                    (response.with_header("Connection", "close"), false)
+                                })
+                            };
+                            if response.events.is_some() {
+                                match streams.try_acquire() {
+                                    Some(slot) => {
+                                        self.handoff = Some((response, slot));
+                                        return Ok(false);
+                                    }
+                                    None => self.start_writing(StreamSlots::unavailable(), false),
+                                }
+                            } else {
+                                self.start_writing(response, keep_alive);
+                            }
+                        }
+                        Ok(None) => return Ok(true),
+                        Err(e) => match e.response() {
+                            Some(response) => self.start_writing(response, false),
+                            None => return Ok(false),
+                        },
+                    }
+                }
+                State::Writing { .. } => {
+                    if !self.flush()? {
+                        return Ok(false);
+                    }
+                    if matches!(self.state, State::Writing { .. }) {
+                        return Ok(true);
+                    }
+                }
+                State::Draining { .. } => {
+                    self.input.clear();
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+// Listeners get the tokens 0..listeners.len(), connections the ones after.
+pub(super) fn run(listeners: Vec<Listener>, hosts: Arc<VirtualHosts>, limits: Limits) -> io::Result<()> {
+    let mut poller = Poller::new()?;
+    for (token, listener) in listeners.iter().enumerate() {
+        listener.set_nonblocking(true)?;
+        poller.add(listener.as_raw_fd(), token as u64, Interest::Readable)?;
+    }
+
+    let mut connections: HashMap<u64, Connection> = HashMap::new();
+    let mut next_token = listeners.len() as u64;
+    let mut events = Vec::new();
+    let streams = StreamSlots::new(limits.max_streams);
+
+    // Deadlines are checked in sweeps rather than one timer per connection.
+    let tick = sweep_interval(&limits);
+    let mut next_sweep = Instant::now() + tick;
+
+    loop {
+        poller.wait(&mut events, Some(next_sweep.saturating_duration_since(Instant::now())))?;
+
+        for event in &events {
+            if let Some(listener) = listeners.get(event.token as usize) {
+                accept_all(listener, &poller, &mut connections, &mut next_token);
+                continue;
+            }
+            let Some(connection) = connections.get_mut(&event.token) else { continue };
+            let before = connection.interest();
+
+            let open = if event.readable {
+                // Whatever arrived before a hang-up still gets answered.
+                connection.fill().and_then(|open| Ok(connection.advance(&hosts, &limits, &streams)? && open))
+            } else if event.writable {
+                connection.advance(&hosts, &limits, &streams)
+            } else {
+                Ok(!event.closed)
+            };
+            settle(&poller, &mut connections, event.token, before, open)?;
+        }
+
+        let now = Instant::now();
+        if now >= next_sweep {
+            next_sweep = now + tick;
+            let expired: Vec<u64> = connections
+                .iter()
+                .filter(|(_, c)| c.deadline(&limits).is_some_and(|deadline| deadline <= now))
+                .map(|(token, _)| *token)
+                .collect();
+            for token in expired {
+                let connection = connections.get_mut(&token).expect("token came from the map");
+                let before = connection.interest();
+                let open = connection.expire();
+                settle(&poller, &mut connections, token, before, open)?;
+            }
+        }
+    }
+}
+
+// Re-registers a connection whose interest changed, or forgets a closed one.
+fn settle(
+    poller: &Poller,
+    connections: &mut HashMap<u64, Connection>,
+    token: u64,
+    before: Interest,
+    open: io::Result<bool>,
+) -> io::Result<()> {
+    let connection = &connections[&token];
+    match open {
+        Ok(true) => {
+            let after = connection.interest();
+            if after != before {
+                poller.modify(connection.stream.as_raw_fd(), token, after)?;
+            }
+        }
+        Ok(false) | Err(_) => {
+            poller.delete(connection.stream.as_raw_fd())?;
+            let connection = connections.remove(&token).expect("token came from the map");
+            if let Some((response, slot)) = connection.handoff {
+                let mut stream = connection.stream;
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if stream.set_nonblocking(false).is_ok() {
+                        let _ = sse::write_response(&mut stream, response);
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// A tenth of the shortest timeout, so deadlines are late by at most 10%.
+fn sweep_interval(limits: &Limits) -> Duration {
+    let shortest = limits.idle_timeout.min(limits.header_timeout).min(limits.body_timeout);
+    (shortest / 10).clamp(Duration::from_millis(10), Duration::from_secs(1))
+}
+
+fn accept_all(
+    listener: &Listener,
+    poller: &Poller,
+    connections: &mut HashMap<u64, Connection>,
+    next_token: &mut u64,
+) {
+    loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let registered = stream
+                    .set_nonblocking(true)
+                    .and_then(|_| poller.add(stream.as_raw_fd(), *next_token, Interest::Readable));
+                if registered.is_ok() {
+                    connections.insert(*next_token, Connection::new(stream));
+                    *next_token += 1;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // Out of file descriptors and the like: leave the rest in the
+                // backlog, the listener stays readable and we'll be back.
+                eprintln!("accept failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::tests::read_response;
+    use crate::tcp::{default_site, Server};
+    use std::io::BufReader;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn start() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || Server::new(VirtualHosts::new(default_site())).serve_nonblocking(listener));
+        addr
+    }
+
+    #[test]
+    fn serves_many_connections_from_one_thread() {
+        let addr = start();
+        let mut clients: Vec<_> = (0..50).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+        for round in 0..3 {
+            for client in &mut clients {
+                client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            }
+            for client in &clients {
+                let response = read_response(&mut BufReader::new(client));
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "round {round}: {response}");
+                assert!(response.contains("Hi from Rust"));
+            }
+        }
+    }
+
+    #[test]
+    fn answers_pipelined_and_split_requests() {
+        let addr = start();
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\nGET / HT").unwrap();
+        assert!(read_response(&mut reader).starts_with("HTTP/1.1 200 OK"));
+        assert!(read_response(&mut reader).starts_with("HTTP/1.1 404 Not Found"));
+
+        thread::sleep(Duration::from_millis(20));
+        client.write_all(b"TP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let last = read_response(&mut reader);
+        assert!(last.starts_with("HTTP/1.1 200 OK"));
+        assert!(last.contains("Connection: close"));
+    }
+
+    #[test]
+    fn survives_a_panicking_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let site = default_site().route("GET", "/panic", |_, _| panic!("handler bug"));
+        thread::spawn(move || Server::new(VirtualHosts::new(site)).serve_nonblocking(listener));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /panic HTTP/1.1\r\n\r\n").unwrap();
+        let response = read_response(&mut BufReader::new(&client));
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert!(response.contains("Connection: close"));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(&mut BufReader::new(&client)).starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn clients_that_stop_reading_are_cut_off() {
+        const BODY: usize = 64 * 1024 * 1024;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let site = default_site().route("GET", "/big", |_, _| Response::new(200).with_body(vec![b'x'; BODY]));
+        let limits = Limits { body_timeout: Duration::from_millis(200), ..Limits::default() };
+        thread::spawn(move || Server::new(VirtualHosts::new(site)).limits(limits).serve_nonblocking(listener));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = 0;
+        let mut chunk = [0u8; 64 * 1024];
+        while let Ok(n @ 1..) = client.read(&mut chunk) {
+            received += n;
+        }
+        assert!(received < BODY, "got all {received} bytes");
+    }
+
+    // Idle keep-alive clients plus a few busy ones, against both server modes.
+    // cargo test --release bench_against_thread_pool -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_against_thread_pool() {
+        const IDLE: usize = 2000;
+        const ACTIVE: usize = 8;
+        const REQUESTS: usize = 2000;
+
+        let threaded = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            // A kept-alive connection holds its worker until it closes, so
+            // the pool needs a thread per client, as it would in production.
+            let server = Server::new(VirtualHosts::new(default_site())).workers(IDLE + ACTIVE);
+            thread::spawn(move || server.serve(listener));
+            addr
+        };
+
+        for (mode, addr) in [("thread pool", threaded), ("non-blocking", start())] {
+            let idle: Vec<_> = (0..IDLE)
+                .map(|_| {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+                    read_response(&mut BufReader::new(&stream));
+                    stream
+                })
+                .collect();
+
+            let started = Instant::now();
+            let busy: Vec<_> = (0..ACTIVE)
+                .map(|_| {
+                    thread::spawn(move || {
+                        let mut stream = TcpStream::connect(addr).unwrap();
+                        let mut reader = BufReader::new(stream.try_clone().unwrap());
+                        for _ in 0..REQUESTS {
+                            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+                            read_response(&mut reader);
+                        }
+                    })
+                })
+                .collect();
+            busy.into_iter().for_each(|h| h.join().unwrap());
+            let elapsed = started.elapsed();
+
+            let total = ACTIVE * REQUESTS;
+            println!(
+                "{mode:>12}: {IDLE} idle, {total} requests in {elapsed:?} ({:.0} req/s)",
+                total as f64 / elapsed.as_secs_f64()
+            );
+            drop(idle);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use super::http::{Request, Response};
+use super::vhost::Site;
+
+// Handlers get the site that matched the Host header, so they can
+// load files relative to its document root.
+pub type Handler = Box<dyn Fn(&Request, &Site) -> Response + Send + Sync + 'static>;
+
+struct Route {
+    method: String,
+    path: String,
+    handler: Handler,
+}
+
+// Exact (method, path) routing. A path registered for another method
+// answers 405 instead of 404, with the methods it does take.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+pub enum Match<'a> {
+    Found(&'a Handler),
+    MethodNotAllowed(Vec<&'a str>),
+    NotFound,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn add<F>(&mut self, method: &str, path: &str, handler: F)
+    where
+        F: Fn(&Request, &Site) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            handler: Box::new(handler),
+        });
+    }
+
+    pub fn find(&self, method: &str, path: &str) -> Match<'_> {
+        let mut allowed = Vec::new();
+        for route in self.routes.iter().filter(|r| r.path == path) {
+            if route.method == method {
+                return Match::Found(&route.handler);
+            }
+            if !allowed.contains(&route.method.as_str()) {
+                allowed.push(route.method.as_str());
+            }
+        }
+        if allowed.is_empty() { Match::NotFound } else { Match::MethodNotAllowed(allowed) }
+    }
+}
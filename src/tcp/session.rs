@@ -0,0 +1,131 @@
+// Server-side sessions. The browser only holds a random id in a cookie;
+// the data stays in memory on the server.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::{rng, Rng};
+
+use super::cookie::{SameSite, SetCookie};
+use super::http::{Request, Response};
+
+pub const SESSION_COOKIE: &str = "rustbox_session";
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    id: String,
+    is_new: bool,
+    pub data: HashMap<String, String>,
+}
+
+struct Entry {
+    data: HashMap<String, String>,
+    last_seen: Instant,
+}
+
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Entry>>,
+    idle_timeout: Duration,
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration) -> SessionStore {
+        SessionStore { sessions: Mutex::new(HashMap::new()), idle_timeout }
+    }
+
+    // The session named by the request's cookie, or a fresh one if the cookie
+    // is missing, unknown or expired.
+    pub fn load(&self, request: &Request) -> Session {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, entry| now.duration_since(entry.last_seen) < self.idle_timeout);
+
+        if let Some(id) = request.cookie(SESSION_COOKIE)
+            && let Some(entry) = sessions.get_mut(id)
+        {
+            entry.last_seen = now;
+            return Session { id: id.to_string(), is_new: false, data: entry.data.clone() };
+        }
+        // Never reuse an id the client made up: that is how session fixation works.
+        let mut id = new_session_id();
+        while sessions.contains_key(&id) {
+            id = new_session_id();
+        }
+        Session { id, is_new: true, data: HashMap::new() }
+    }
+
+    // Stores the session and, the first time round, hands the browser its cookie.
+    pub fn save(&self, session: Session, response: Response) -> Response {
+        let entry = Entry { data: session.data, last_seen: Instant::now() };
+        self.sessions.lock().unwrap().insert(session.id.clone(), entry);
+        if session.is_new { response.with_cookie(&self.cookie(&session.id)) } else { response }
+    }
+
+    pub fn destroy(&self, session: Session, response: Response) -> Response {
+        self.sessions.lock().unwrap().remove(&session.id);
+        response.with_cookie(&SetCookie::removal(SESSION_COOKIE).path("/"))
+    }
+
+    fn cookie(&self, id: &str) -> SetCookie {
+        SetCookie::new(SESSION_COOKIE, id)
+            .path("/")
+            .max_age(self.idle_timeout)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+    }
+}
+
+// 128 bits from the thread-local CSPRNG, hex encoded.
+fn new_session_id() -> String {
+    let bytes: [u8; 16] = rng().random();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_cookie(cookie: Option<&str>) -> Request {
+        let mut request = Request::from_request_line("GET / HTTP/1.1").unwrap();
+        if let Some(cookie) = cookie {
+            request.headers.push(("Cookie".into(), cookie.into()));
+        }
+        request
+    }
+
+    #[test]
+    fn session_survives_between_requests() {
+        let store = SessionStore::new(Duration::from_secs(60));
+        let mut session = store.load(&request_with_cookie(None));
+        assert!(session.is_new);
+        session.data.insert("user".into(), "ann".into());
+        let response = store.save(session, Response::new(200));
+
+        let set_cookie = response.header("Set-Cookie").unwrap();
+        let pair = set_cookie.split(';').next().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+
+        let again = store.load(&request_with_cookie(Some(pair)));
+        assert!(!again.is_new);
+        assert_eq!(again.data.get("user").map(String::as_str), Some("ann"));
+        let response = store.save(again, Response::new(200));
+        assert_eq!(response.header("Set-Cookie"), None);
+    }
+
+    #[test]
+    fn unknown_and_expired_ids_get_a_new_session() {
+        let store = SessionStore::new(Duration::from_millis(20));
+        let forged = store.load(&request_with_cookie(Some("rustbox_session=chosen-by-attacker")));
+        assert!(forged.is_new);
+        assert_ne!(forged.id, "chosen-by-attacker");
+        assert_eq!(forged.id.len(), 32);
+
+        let id = forged.id.clone();
+        store.save(forged, Response::new(200));
+        std::thread::sleep(Duration::from_millis(40));
+        let cookie = format!("{SESSION_COOKIE}={id}");
+        assert!(store.load(&request_with_cookie(Some(&cookie))).is_new);
+        assert_eq!(store.sessions.lock().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,506 @@
+// Server-sent events: a response that stays open and carries a
+// text/event-stream of events, fed from an mpsc channel. A Hub fans one
+// publisher out to every subscriber and remembers recent events, so a client
+// reconnecting with Last-Event-ID gets what it missed.
+//
+// Over HTTP/1.1 the stream ends when the connection does, so the response
+// is sent with 'Connection: close'; over h2c it is one stream among others.
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::http::{Request, Response};
+
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+// Events a hub subscriber may fall behind by. One that lags further is
+// dropped, which ends its stream; the client reconnects with Last-Event-ID.
+const SUBSCRIBER_BUFFER: usize = 64;
+
+impl Request {
+    // Sent by browsers when they reconnect to an event stream.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.header("Last-Event-ID")
+    }
+}
+
+impl Response {
+    pub fn events(stream: EventStream) -> Response {
+        let mut response = Response::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache");
+        response.events = Some(stream);
+        response
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event { id: None, event: None, data: data.into() }
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(one_line(id.into()));
+        self
+    }
+
+    // The event type; clients listen for it with addEventListener(name).
+    pub fn event(mut self, name: impl Into<String>) -> Event {
+        self.event = Some(one_line(name.into()));
+        self
+    }
+
+    pub fn get_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
+// Field values other than data can't span lines; ids can't hold NUL either.
+fn one_line(value: String) -> String {
+    value.replace(['\r', '\n', '\0'], "")
+}
+
+// Multi-line data becomes one data field per line.
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {event}")?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {id}")?;
+        }
+        let data = self.data.replace("\r\n", "\n").replace('\r', "\n");
+        for line in data.split('\n') {
+            writeln!(f, "data: {line}")?;
+        }
+        writeln!(f)
+    }
+}
+
+// The body of an event-stream response: whatever is replayed first, then
+// whatever arrives on the channel, with a comment line whenever it has been
+// quiet for 'heartbeat' so proxies don't time the connection out. It ends
+// when every sender is gone.
+pub struct EventStream {
+    backlog: VecDeque<Event>,
+    receiver: Receiver<Event>,
+    heartbeat: Duration,
+    retry: Option<Duration>,
+}
+
+impl EventStream {
+    pub fn new(receiver: Receiver<Event>) -> EventStream {
+        EventStream { backlog: VecDeque::new(), receiver, heartbeat: DEFAULT_HEARTBEAT, retry: None }
+    }
+
+    pub fn heartbeat(mut self, every: Duration) -> EventStream {
+        self.heartbeat = every;
+        self
+    }
+
+    // Sent once, before anything else.
+    pub fn retry(mut self, after: Duration) -> EventStream {
+        self.retry = Some(after);
+        self
+    }
+
+    // The next bytes to send, or None once the stream is over.
+    pub fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        if let Some(retry) = self.retry.take() {
+            return Some(format!("retry: {}\n\n", retry.as_millis()).into_bytes());
+        }
+        if let Some(event) = self.backlog.pop_front() {
+            return Some(event.to_string().into_bytes());
+        }
+        match self.receiver.recv_timeout(self.heartbeat) {
+            Ok(event) => Some(event.to_string().into_bytes()),
+            Err(RecvTimeoutError::Timeout) => Some(b": heartbeat\n\n".to_vec()),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    // Writes until the stream ends or the client goes away.
+    pub fn write_to<W: Write>(mut self, writer: &mut W) -> io::Result<()> {
+        while let Some(chunk) = self.next_chunk() {
+            writer.write_all(&chunk)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for EventStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream")
+            .field("backlog", &self.backlog.len())
+            .field("heartbeat", &self.heartbeat)
+            .finish()
+    }
+}
+
+// An event-stream response over HTTP/1.x: the head without a Content-Length,
+// since the body runs until the connection closes, then the events.
+pub(crate) fn write_response<W: Write>(writer: &mut W, mut response: Response) -> io::Result<()> {
+    let mut head = format!("{}\r\n", response.status_line());
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())?;
+    writer.flush()?;
+    match response.events.take() {
+        Some(events) => events.write_to(writer),
+        None => Ok(()),
+    }
+}
+
+// Counts the event streams a server has running on threads of their own,
+// so subscribers can't make it spawn threads without end. Clones share
+// the count.
+#[derive(Clone)]
+pub(crate) struct StreamSlots {
+    running: Arc<AtomicUsize>,
+    max: usize,
+}
+
+// Held by a running stream; dropping it frees the slot.
+pub(crate) struct StreamSlot(Arc<AtomicUsize>);
+
+impl StreamSlots {
+    pub(crate) fn new(max: usize) -> StreamSlots {
+        StreamSlots { running: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    // None when 'max' streams are already running.
+    pub(crate) fn try_acquire(&self) -> Option<StreamSlot> {
+        self.running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < self.max).then_some(n + 1))
+            .ok()
+            .map(|_| StreamSlot(Arc::clone(&self.running)))
+    }
+
+    // The answer to a subscriber past the limit.
+    pub(crate) fn unavailable() -> Response {
+        Response::text(503, "503 Service Unavailable").with_header("Connection", "close")
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Publishes to every subscriber at once. Share it between handlers and
+// publishers with an Arc.
+pub struct Hub {
+    state: Mutex<HubState>,
+    heartbeat: Duration,
+}
+
+struct HubState {
+    subscribers: Vec<SyncSender<Event>>,
+    // The most recent events, for clients resuming with Last-Event-ID.
+    history: VecDeque<Event>,
+    history_len: usize,
+    next_id: u64,
+}
+
+impl Hub {
+    // Subscribers' streams get a heartbeat after 'heartbeat' without events.
+    pub fn new(history_len: usize, heartbeat: Duration) -> Hub {
+        Hub {
+            state: Mutex::new(HubState {
+                subscribers: Vec::new(),
+                history: VecDeque::new(),
+                history_len,
+                next_id: 1,
+            }),
+            heartbeat,
+        }
+    }
+
+    // Events without an id get the next number, so every event can be
+    // resumed from. Returns how many subscribers it reached.
+    pub fn publish(&self, event: Event) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let event = match event.id {
+            Some(_) => event,
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                event.id(id.to_string())
+            }
+        };
+        // A failed send means the subscriber's stream is gone or too far behind.
+        state.subscribers.retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+        if state.history_len > 0 {
+            if state.history.len() == state.history_len {
+                state.history.pop_front();
+            }
+            state.history.push_back(event);
+        }
+        state.subscribers.len()
+    }
+
+    // A stream of everything published from now on, preceded by the
+    // remembered events after 'last_event_id'. An id we no longer remember
+    // replays all we have: the client missed at least that much.
+    pub fn subscribe(&self, last_event_id: Option<&str>) -> EventStream {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.push(tx);
+
+        let mut stream = EventStream::new(rx).heartbeat(self.heartbeat);
+        if let Some(last) = last_event_id {
+            let start = state
+                .history
+                .iter()
+                .position(|e| e.get_id() == Some(last))
+                .map_or(0, |i| i + 1);
+            stream.backlog = state.history.iter().skip(start).cloned().collect();
+        }
+        stream
+    }
+
+    #[cfg(test)]
+    pub fn subscribers(&self) -> usize {
+        self.state.lock().unwrap().subscribers.len()
+    }
+
+    // Ends every subscriber's stream.
+    #[cfg(test)]
+    pub fn close(&self) {
+        self.state.lock().unwrap().subscribers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::tests::start;
+    use crate::tcp::vhost::{Site, VirtualHosts};
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn formats_events() {
+        let event = Event::new("one\ntwo").id("7").event("update");
+        assert_eq!(event.to_string(), "event: update\nid: 7\ndata: one\ndata: two\n\n");
+        assert_eq!(Event::new("").to_string(), "data: \n\n");
+        assert_eq!(Event::new("x").id("a\nb").to_string(), "id: ab\ndata: x\n\n");
+    }
+
+    #[test]
+    fn streams_send_heartbeats_and_end_with_their_senders() {
+        let (tx, rx) = mpsc::channel();
+        let mut stream = EventStream::new(rx).heartbeat(Duration::from_millis(20)).retry(Duration::from_secs(1));
+        assert_eq!(stream.next_chunk().unwrap(), b"retry: 1000\n\n");
+        assert_eq!(stream.next_chunk().unwrap(), b": heartbeat\n\n");
+        tx.send(Event::new("hi")).unwrap();
+        drop(tx);
+        assert_eq!(stream.next_chunk().unwrap(), b"data: hi\n\n");
+        assert!(stream.next_chunk().is_none());
+    }
+
+    #[test]
+    fn hub_numbers_events_and_replays_after_last_event_id() {
+        let hub = Hub::new(3, DEFAULT_HEARTBEAT);
+        for n in 1..=4 {
+            hub.publish(Event::new(format!("event {n}")));
+        }
+        // History holds 2, 3 and 4.
+        let mut resumed = hub.subscribe(Some("2"));
+        assert_eq!(resumed.next_chunk().unwrap(), b"id: 3\ndata: event 3\n\n");
+        assert_eq!(resumed.next_chunk().unwrap(), b"id: 4\ndata: event 4\n\n");
+
+        let forgotten = hub.subscribe(Some("1"));
+        assert_eq!(forgotten.backlog.len(), 3);
+        let fresh = hub.subscribe(None);
+        assert!(fresh.backlog.is_empty());
+
+        assert_eq!(hub.publish(Event::new("event 5")), 3);
+        assert_eq!(resumed.next_chunk().unwrap(), b"id: 5\ndata: event 5\n\n");
+        drop(forgotten);
+        assert_eq!(hub.publish(Event::new("event 6")), 2);
+        hub.close();
+        assert_eq!(hub.subscribers(), 0);
+    }
+
+    #[test]
+    fn hub_drops_subscribers_that_fall_behind() {
+        let hub = Hub::new(0, DEFAULT_HEARTBEAT);
+        let mut slow = hub.subscribe(None);
+        for n in 0..SUBSCRIBER_BUFFER {
+            assert_eq!(hub.publish(Event::new(n.to_string())), 1);
+        }
+        assert_eq!(hub.publish(Event::new("one too many")), 0);
+        // What was buffered still arrives, then the stream ends.
+        assert_eq!((0..SUBSCRIBER_BUFFER).filter(|_| slow.next_chunk().is_some()).count(), SUBSCRIBER_BUFFER);
+        assert!(slow.next_chunk().is_none());
+    }
+
+    fn events_site(hub: &Arc<Hub>) -> VirtualHosts {
+        let hub = Arc::clone(hub);
+        let site = Site::new(".").route("GET", "/events", move |request, _| {
+            Response::events(hub.subscribe(request.last_event_id()))
+        });
+        VirtualHosts::new(site)
+    }
+
+    fn start_events(hub: &Arc<Hub>) -> SocketAddr {
+        start(events_site(hub))
+    }
+
+    fn subscribe(addr: SocketAddr, last_event_id: Option<&str>) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let resume = last_event_id.map_or(String::new(), |id| format!("Last-Event-ID: {id}\r\n"));
+        write!(stream, "GET /events HTTP/1.1\r\nHost: localhost\r\n{resume}\r\n").unwrap();
+        BufReader::new(stream)
+    }
+
+    // Reads up to the blank line ending the next event (or the head).
+    fn next_block(reader: &mut BufReader<TcpStream>) -> String {
+        let mut block = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            block.push_str(&line);
+            if line.trim_end().is_empty() {
+                return block;
+            }
+        }
+    }
+
+    #[test]
+    fn serves_an_event_stream_to_several_clients() {
+        let hub = Arc::new(Hub::new(10, Duration::from_millis(50)));
+        let addr = start_events(&hub);
+        let mut clients = vec![subscribe(addr, None), subscribe(addr, None)];
+        for client in &mut clients {
+            let head = next_block(client);
+            assert!(head.starts_with("HTTP/1.1 200 OK"));
+            assert!(head.contains("Content-Type: text/event-stream"));
+            assert!(head.contains("Connection: close"));
+            assert!(!head.contains("Content-Length"));
+        }
+        while hub.subscribers() < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // Any thread can publish.
+        let publisher = Arc::clone(&hub);
+        thread::spawn(move || publisher.publish(Event::new("hello").event("greeting"))).join().unwrap();
+        for client in &mut clients {
+            let mut block = next_block(client);
+            while block.starts_with(": heartbeat") {
+                block = next_block(client);
+            }
+            assert_eq!(block, "event: greeting\nid: 1\ndata: hello\n\n");
+        }
+
+        // Quiet streams get heartbeats.
+        assert_eq!(next_block(&mut clients[0]), ": heartbeat\n\n");
+
+        // Closing the hub ends the responses and with them the connections.
+        hub.close();
+        for client in &mut clients {
+            let mut rest = String::new();
+            client.read_to_string(&mut rest).unwrap();
+        }
+    }
+
+    #[test]
+    fn resumes_from_last_event_id() {
+        let hub = Arc::new(Hub::new(10, Duration::from_millis(50)));
+        let addr = start_events(&hub);
+        for n in 1..=3 {
+            hub.publish(Event::new(format!("missed {n}")));
+        }
+        let mut client = subscribe(addr, Some("1"));
+        next_block(&mut client);
+        assert_eq!(next_block(&mut client), "id: 2\ndata: missed 2\n\n");
+        assert_eq!(next_block(&mut client), "id: 3\ndata: missed 3\n\n");
+    }
+
+    #[test]
+    fn streams_do_not_hold_on_to_pool_workers() {
+        let hub = Arc::new(Hub::new(0, Duration::from_millis(50)));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hosts = events_site(&hub);
+        thread::spawn(move || crate::tcp::Server::new(hosts).workers(1).serve(listener));
+
+        let mut clients: Vec<_> = (0..4).map(|_| subscribe(addr, None)).collect();
+        for client in &mut clients {
+            assert!(next_block(client).contains("text/event-stream"));
+        }
+        // The one worker is free for ordinary requests.
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        other.write_all(b"GET /nope HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = crate::tcp::tests::read_response(&mut BufReader::new(&other));
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn nonblocking_mode_hands_streams_to_a_thread() {
+        let hub = Arc::new(Hub::new(0, Duration::from_millis(50)));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hosts = events_site(&hub);
+        thread::spawn(move || crate::tcp::Server::new(hosts).serve_nonblocking(listener));
+
+        let mut client = subscribe(addr, None);
+        assert!(next_block(&mut client).contains("text/event-stream"));
+        while hub.subscribers() < 1 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        hub.publish(Event::new("from the loop"));
+        let mut block = next_block(&mut client);
+        while block.starts_with(": heartbeat") {
+            block = next_block(&mut client);
+        }
+        assert_eq!(block, "id: 1\ndata: from the loop\n\n");
+
+        // The loop itself is still free for ordinary requests.
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.write_all(b"GET /nope HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = crate::tcp::tests::read_response(&mut BufReader::new(&other));
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn drops_subscribers_that_disconnect() {
+        let hub = Arc::new(Hub::new(0, Duration::from_millis(50)));
+        let addr = start_events(&hub);
+        let mut client = subscribe(addr, None);
+        next_block(&mut client);
+        while hub.subscribers() < 1 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(client);
+        // The next heartbeat write fails, which drops the stream's receiver.
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while hub.publish(Event::new("anyone?")) > 0 {
+            assert!(std::time::Instant::now() < deadline, "subscriber never dropped");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
@@ -0,0 +1,603 @@
+// A small template engine for HTML responses.
+//
+//   {{ user.name }}                   HTML-escaped interpolation
+//   {% if admin %}..{% else %}..{% endif %}, {% if not admin %}
+//   {% for item in items %}..{% endfor %}
+//   {% include "header.html" %}
+//   {% extends "layout.html" %} with {% block body %}..{% endblock %}
+//   {# comments #}
+//
+// Templates are compiled on first use and cached; every error carries the
+// template name, line and column it refers to.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use super::vhost::safe_join;
+
+// Includes and layouts can refer to each other; this stops the cycles.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(s) => !s.is_empty(),
+            Value::Int(i) => *i != 0,
+            Value::Bool(b) => *b,
+            Value::List(l) => !l.is_empty(),
+            Value::Map(m) => !m.is_empty(),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+}
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Text(s)
+    }
+}
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context.0)
+    }
+}
+
+// The variables a handler hands to a template.
+#[derive(Debug, Clone, Default)]
+pub struct Context(HashMap<String, Value>);
+
+impl Context {
+    pub fn new() -> Context {
+        Context(HashMap::new())
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Context {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.0.insert(name.to_string(), value.into());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    const START: Position = Position { line: 1, column: 1 };
+
+    fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    pub template: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl TemplateError {
+    fn new(template: &str, at: Position, message: impl Into<String>) -> TemplateError {
+        TemplateError { template: template.to_string(), line: at.line, column: at.column, message: message.into() }
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.template, self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug)]
+enum Token<'s> {
+    Text(&'s str),
+    Var(&'s str, Position),
+    Tag(&'s str, Position),
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var(Vec<String>, Position),
+    If { negate: bool, path: Vec<String>, then: Vec<Node>, otherwise: Vec<Node> },
+    For { name: String, path: Vec<String>, at: Position, body: Vec<Node> },
+    Include(String, Position),
+    Block(String, Vec<Node>),
+}
+
+#[derive(Debug)]
+pub struct Template {
+    name: String,
+    extends: Option<(String, Position)>,
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn compile(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let tokens = tokenize(name, source)?;
+        let mut parser = Parser { name, tokens, next: 0, extends: None };
+        let (nodes, end) = parser.nodes(&[])?;
+        debug_assert!(end.is_none());
+        Ok(Template { name: name.to_string(), extends: parser.extends, nodes })
+    }
+}
+
+fn tokenize<'s>(name: &str, source: &'s str) -> Result<Vec<Token<'s>>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut at = Position::START;
+
+    while !rest.is_empty() {
+        let open = ["{{", "{%", "{#"].iter().filter_map(|o| rest.find(o)).min();
+        let Some(open) = open else {
+            tokens.push(Token::Text(rest));
+            break;
+        };
+        if open > 0 {
+            tokens.push(Token::Text(&rest[..open]));
+            at.advance(&rest[..open]);
+            rest = &rest[open..];
+        }
+
+        let close = match &rest[..2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        // Not in the opener itself, as in "{%}".
+        let Some(end) = rest[2..].find(close).map(|end| end + 2) else {
+            return Err(TemplateError::new(name, at, format!("'{}' is never closed", &rest[..2])));
+        };
+        let inner = rest[2..end].trim();
+        match close {
+            "}}" => tokens.push(Token::Var(inner, at)),
+            "%}" => tokens.push(Token::Tag(inner, at)),
+            _ => {}
+        }
+        at.advance(&rest[..end + 2]);
+        rest = &rest[end + 2..];
+    }
+    Ok(tokens)
+}
+
+// The closing tag that ended a nested block, e.g. "else" or "endif".
+type EndTag = (String, Position);
+
+struct Parser<'s, 'n> {
+    name: &'n str,
+    tokens: Vec<Token<'s>>,
+    next: usize,
+    extends: Option<(String, Position)>,
+}
+
+impl Parser<'_, '_> {
+    fn error(&self, at: Position, message: impl Into<String>) -> TemplateError {
+        TemplateError::new(self.name, at, message)
+    }
+
+    // Parses until one of the 'until' tags (returned with its position) or
+    // the end of the template, which is only fine when nothing is open.
+    fn nodes(&mut self, until: &[&str]) -> Result<(Vec<Node>, Option<EndTag>), TemplateError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.get(self.next) {
+            self.next += 1;
+            match *token {
+                Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+                Token::Var(expr, at) => nodes.push(Node::Var(self.path(expr, at)?, at)),
+                Token::Tag(tag, at) => {
+                    let keyword = tag.split_whitespace().next().unwrap_or("");
+                    if until.contains(&keyword) {
+                        return Ok((nodes, Some((keyword.to_string(), at))));
+                    }
+                    if let Some(node) = self.tag(tag, at)? {
+                        nodes.push(node);
+                    }
+                }
+            }
+        }
+        match until.first() {
+            Some(expected) => Err(self.error(self.end_position(), format!("missing {{% {expected} %}}"))),
+            None => Ok((nodes, None)),
+        }
+    }
+
+    fn end_position(&self) -> Position {
+        match self.tokens.iter().rev().find_map(|t| match t {
+            Token::Var(_, at) | Token::Tag(_, at) => Some(*at),
+            Token::Text(_) => None,
+        }) {
+            Some(at) => at,
+            None => Position::START,
+        }
+    }
+
+    fn tag(&mut self, tag: &str, at: Position) -> Result<Option<Node>, TemplateError> {
+        let words: Vec<&str> = tag.split_whitespace().collect();
+        match words.as_slice() {
+            ["if", "not", path] => self.if_block(true, path, at).map(Some),
+            ["if", path] => self.if_block(false, path, at).map(Some),
+            ["for", name, "in", path] => {
+                let path = self.path(path, at)?;
+                let name = self.identifier(name, at)?;
+                let (body, _) = self.nodes(&["endfor"])?;
+                Ok(Some(Node::For { name, path, at, body }))
+            }
+            ["include", name] => Ok(Some(Node::Include(self.quoted(name, at)?, at))),
+            ["extends", name] => {
+                if self.extends.is_some() {
+                    return Err(self.error(at, "a template can only extend one layout"));
+                }
+                self.extends = Some((self.quoted(name, at)?, at));
+                Ok(None)
+            }
+            ["block", name] => {
+                let name = self.identifier(name, at)?;
+                let (body, _) = self.nodes(&["endblock"])?;
+                Ok(Some(Node::Block(name, body)))
+            }
+            [keyword @ ("else" | "endif" | "endfor" | "endblock"), ..] => {
+                Err(self.error(at, format!("unexpected {{% {keyword} %}}")))
+            }
+            _ => Err(self.error(at, format!("unknown tag '{tag}'"))),
+        }
+    }
+
+    fn if_block(&mut self, negate: bool, path: &str, at: Position) -> Result<Node, TemplateError> {
+        let path = self.path(path, at)?;
+        let (then, end) = self.nodes(&["endif", "else"])?;
+        let otherwise = match end {
+            Some((keyword, _)) if keyword == "else" => self.nodes(&["endif"])?.0,
+            _ => Vec::new(),
+        };
+        Ok(Node::If { negate, path, then, otherwise })
+    }
+
+    fn path(&self, expr: &str, at: Position) -> Result<Vec<String>, TemplateError> {
+        expr.split('.').map(|part| self.identifier(part, at)).collect()
+    }
+
+    fn identifier(&self, word: &str, at: Position) -> Result<String, TemplateError> {
+        let valid = !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if valid { Ok(word.to_string()) } else { Err(self.error(at, format!("invalid name '{word}'"))) }
+    }
+
+    fn quoted(&self, word: &str, at: Position) -> Result<String, TemplateError> {
+        word.strip_prefix('"')
+            .and_then(|w| w.strip_suffix('"'))
+            .map(str::to_string)
+            .ok_or_else(|| self.error(at, format!("expected a quoted template name, got {word}")))
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Compiled templates loaded from one directory, shared between threads.
+pub struct Templates {
+    dir: PathBuf,
+    cache: RwLock<HashMap<String, Arc<Template>>>,
+}
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Templates {
+        Templates { dir: dir.into(), cache: RwLock::new(HashMap::new()) }
+    }
+
+    // Registers a template from a string instead of a file.
+    #[cfg(test)]
+    pub fn add(&self, name: &str, source: &str) -> Result<(), TemplateError> {
+        let template = Arc::new(Template::compile(name, source)?);
+        self.cache.write().unwrap().insert(name.to_string(), template);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        if let Some(template) = self.cache.read().unwrap().get(name) {
+            return Ok(Arc::clone(template));
+        }
+        let source = safe_join(&self.dir, name)
+            .and_then(|path| fs::read_to_string(path).ok())
+            .ok_or_else(|| TemplateError::new(name, Position::START, "template not found"))?;
+        let template = Arc::new(Template::compile(name, &source)?);
+
+        // Two threads may compile the same template at once; the first one in wins.
+        let mut cache = self.cache.write().unwrap();
+        Ok(Arc::clone(cache.entry(name.to_string()).or_insert(template)))
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope { context, locals: Vec::new() };
+        self.render_into(name, None, &mut scope, &mut out, 0)?;
+        Ok(out)
+    }
+
+    // 'from' is the template and position that asked for 'name', where a
+    // failure to load it gets reported.
+    fn render_into(
+        &self,
+        name: &str,
+        from: Option<(&str, Position)>,
+        scope: &mut Scope,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        let load = |name: &str, from: Option<(&str, Position)>, depth: usize| {
+            let error = |message: String| match from {
+                Some((template, at)) => TemplateError::new(template, at, message),
+                None => TemplateError::new(name, Position::START, message),
+            };
+            if depth > MAX_DEPTH {
+                return Err(error(format!("templates nested deeper than {MAX_DEPTH}")));
+            }
+            self.get(name).map_err(|e| if e.template == name && from.is_some() { error(e.message) } else { e })
+        };
+
+        // Walk up the layout chain: the child's blocks win over its parent's.
+        let mut chain = vec![load(name, from, depth)?];
+        loop {
+            let child = Arc::clone(&chain[chain.len() - 1]);
+            let Some((parent, at)) = &child.extends else { break };
+            chain.push(load(parent, Some((&child.name, *at)), depth + chain.len())?);
+        }
+        let mut blocks = HashMap::new();
+        for template in &chain {
+            collect_blocks(&template.name, &template.nodes, &mut blocks);
+        }
+
+        let root = &chain[chain.len() - 1];
+        let renderer = Renderer { templates: self, blocks: &blocks, depth: depth + chain.len() };
+        renderer.nodes(&root.name, &root.nodes, scope, out)
+    }
+}
+
+// Blocks can sit inside other blocks or inside if/for; all of them can be overridden.
+fn collect_blocks<'t>(template: &'t str, nodes: &'t [Node], blocks: &mut HashMap<&'t str, (&'t str, &'t [Node])>) {
+    for node in nodes {
+        match node {
+            Node::Block(name, body) => {
+                blocks.entry(name.as_str()).or_insert((template, body));
+                collect_blocks(template, body, blocks);
+            }
+            Node::If { then, otherwise, .. } => {
+                collect_blocks(template, then, blocks);
+                collect_blocks(template, otherwise, blocks);
+            }
+            Node::For { body, .. } => collect_blocks(template, body, blocks),
+            _ => {}
+        }
+    }
+}
+
+// Loop variables shadow the context, innermost first.
+struct Scope<'c> {
+    context: &'c Context,
+    locals: Vec<(String, Value)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = self
+            .locals
+            .iter()
+            .rev()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value)
+            .or_else(|| self.context.0.get(first))?;
+        for part in rest {
+            value = match value {
+                Value::Map(map) => map.get(part)?,
+                Value::List(list) => list.get(part.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+struct Renderer<'t> {
+    templates: &'t Templates,
+    blocks: &'t HashMap<&'t str, (&'t str, &'t [Node])>,
+    depth: usize,
+}
+
+impl Renderer<'_> {
+    fn nodes(&self, template: &str, nodes: &[Node], scope: &mut Scope, out: &mut String) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Var(path, at) => {
+                    let text = match scope.lookup(path) {
+                        Some(Value::Text(s)) => s.clone(),
+                        Some(Value::Int(i)) => i.to_string(),
+                        Some(Value::Bool(b)) => b.to_string(),
+                        Some(_) => {
+                            return Err(TemplateError::new(template, *at, format!("'{}' is not printable", path.join("."))));
+                        }
+                        None => {
+                            return Err(TemplateError::new(template, *at, format!("unknown variable '{}'", path.join("."))));
+                        }
+                    };
+                    out.push_str(&escape_html(&text));
+                }
+                // Unlike {{ }}, a missing variable in a condition just counts as false.
+                Node::If { negate, path, then, otherwise } => {
+                    let truthy = scope.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.nodes(template, branch, scope, out)?;
+                }
+                Node::For { name, path, at, body } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::List(items)) => items.clone(),
+                        Some(_) => return Err(TemplateError::new(template, *at, format!("'{}' is not a list", path.join(".")))),
+                        None => return Err(TemplateError::new(template, *at, format!("unknown variable '{}'", path.join(".")))),
+                    };
+                    for item in items {
+                        scope.locals.push((name.clone(), item));
+                        let result = self.nodes(template, body, scope, out);
+                        scope.locals.pop();
+                        result?;
+                    }
+                }
+                Node::Include(name, at) => {
+                    self.templates.render_into(name, Some((template, *at)), scope, out, self.depth + 1)?;
+                }
+                Node::Block(name, body) => match self.blocks.get(name.as_str()) {
+                    Some((owner, body)) => self.nodes(owner, body, scope, out)?,
+                    None => self.nodes(template, body, scope, out)?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(sources: &[(&str, &str)]) -> Templates {
+        let templates = Templates::new("/nonexistent");
+        for (name, source) in sources {
+            templates.add(name, source).unwrap();
+        }
+        templates
+    }
+
+    #[test]
+    fn interpolates_escaped_values() {
+        let t = templates(&[("page", "<p>{{ user.name }} ({{ age }})</p>")]);
+        let user = Context::new().with("name", "<Bob & \"Alice\">");
+        let context = Context::new().with("user", user).with("age", 42);
+        assert_eq!(
+            t.render("page", &context).unwrap(),
+            "<p>&lt;Bob &amp; &quot;Alice&quot;&gt; (42)</p>"
+        );
+    }
+
+    #[test]
+    fn if_and_for_blocks() {
+        let t = templates(&[(
+            "list",
+            "{% if items %}<ul>{% for i in items %}<li>{{ i }}</li>{% endfor %}</ul>{% else %}empty{% endif %}\
+             {% if not admin %}!{% endif %}",
+        )]);
+        let full = Context::new().with("items", vec!["a", "b"]);
+        assert_eq!(t.render("list", &full).unwrap(), "<ul><li>a</li><li>b</li></ul>!");
+        let empty = Context::new().with("items", Vec::<String>::new()).with("admin", true);
+        assert_eq!(t.render("list", &empty).unwrap(), "empty");
+    }
+
+    #[test]
+    fn layouts_and_includes() {
+        let t = templates(&[
+            ("layout", "<title>{% block title %}Default{% endblock %}</title>{% include \"nav\" %}{% block body %}{% endblock %}"),
+            ("nav", "<nav>{{ site }}</nav>"),
+            ("page", "{% extends \"layout\" %}ignored{% block body %}<main>{{ site }}</main>{% endblock %}"),
+        ]);
+        let context = Context::new().with("site", "Rustbox");
+        assert_eq!(
+            t.render("page", &context).unwrap(),
+            "<title>Default</title><nav>Rustbox</nav><main>Rustbox</main>"
+        );
+    }
+
+    #[test]
+    fn compiles_once() {
+        let t = Templates::new(".");
+        let first = t.get("hello.html").unwrap();
+        let second = t.get("hello.html").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn errors_report_template_line_and_column() {
+        let error = Template::compile("broken", "line one\n  {% if x %}never closed").unwrap_err();
+        assert_eq!((error.template.as_str(), error.line, error.column), ("broken", 2, 3));
+        assert!(error.message.contains("endif"), "{}", error.message);
+
+        let error = Template::compile("tag", "ok {% frobnicate %}").unwrap_err();
+        assert_eq!(error.to_string(), "tag:1:4: unknown tag 'frobnicate'");
+
+        let t = templates(&[("outer", "a\nb {% include \"inner\" %}"), ("inner", "\n\n   {{ nope }}")]);
+        let error = t.render("outer", &Context::new()).unwrap_err();
+        assert_eq!(error.to_string(), "inner:3:4: unknown variable 'nope'");
+
+        let error = t.render("missing", &Context::new()).unwrap_err();
+        assert_eq!(error.message, "template not found");
+
+        let t = templates(&[("loop", "x\n{% include \"loop\" %}")]);
+        let error = t.render("loop", &Context::new()).unwrap_err();
+        assert_eq!((error.line, error.column), (2, 1));
+    }
+
+    #[test]
+    fn openers_do_not_close_themselves() {
+        for (source, opener) in [("{%}", "{%"), ("a {#}", "{#"), ("{{}", "{{")] {
+            let error = Template::compile("t", source).unwrap_err();
+            assert_eq!(error.message, format!("'{opener}' is never closed"));
+        }
+        let t = templates(&[("comment", "{#}#}ok")]);
+        assert_eq!(t.render("comment", &Context::new()).unwrap(), "ok");
+    }
+}
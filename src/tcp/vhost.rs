@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use super::http::{Request, Response};
+use super::multipart::{Multipart, Upload, UploadLimits};
+use super::router::{Match, Router};
+use super::template::{Context, Templates};
+
+// One site served by the listener: where its files live, how its paths are
+// routed and which pages replace the bare error responses.
+pub struct Site {
+    root: PathBuf,
+    router: Router,
+    uploads: HashMap<String, Upload>,
+    error_pages: HashMap<u16, PathBuf>,
+    templates: Templates,
+}
+
+impl Site {
+    pub fn new(root: impl Into<PathBuf>) -> Site {
+        let root = root.into();
+        let templates = Templates::new(root.clone());
+        Site { root, router: Router::new(), uploads: HashMap::new(), error_pages: HashMap::new(), templates }
+    }
+
+    pub fn route<F>(mut self, method: &str, path: &str, handler: F) -> Site
+    where
+        F: Fn(&Request, &Site) -> Response + Send + Sync + 'static,
+    {
+        self.router.add(method, path, handler);
+        self
+    }
+
+    // POSTs to 'path' are multipart/form-data: file parts are written to
+    // 'dir' as they arrive and the handler gets the parsed parts. Malformed
+    // or oversized uploads never reach it.
+    pub fn upload<F>(mut self, path: &str, dir: impl Into<PathBuf>, limits: UploadLimits, handler: F) -> Site
+    where
+        F: Fn(&Request, Multipart, &Site) -> Response + Send + Sync + 'static,
+    {
+        self.uploads.insert(path.to_string(), Upload::new(dir.into(), limits, Box::new(handler)));
+        // For the servers that read the whole body first.
+        self.router.add("POST", path, |request, site| {
+            let upload = site.upload_for(request).expect("upload routes have an upload");
+            upload.handle(request, &request.body[..], site).unwrap_or_else(|e| e.response())
+        });
+        self
+    }
+
+    // The upload route 'request' is for, if any. Its body is best read by
+    // the upload as it arrives, not beforehand.
+    pub fn upload_for(&self, request: &Request) -> Option<&Upload> {
+        if request.method != "POST" {
+            return None;
+        }
+        self.uploads.get(&request.path)
+    }
+
+    // 'file' is relative to the document root, e.g. error_page(404, "404.html").
+    pub fn error_page(mut self, status: u16, file: impl Into<PathBuf>) -> Site {
+        self.error_pages.insert(status, file.into());
+        self
+    }
+
+    // A 200 response with the contents of a file under the document root,
+    // or a 404 if it can't be read.
+    pub fn file(&self, name: &str) -> Response {
+        match safe_join(&self.root, name).and_then(|path| fs::read_to_string(path).ok()) {
+            Some(contents) => Response::html(200, contents),
+            None => Response::new(404),
+        }
+    }
+
+    // Renders a template from the document root. A broken template is the
+    // site's fault, not the client's, so it turns into a 500.
+    pub fn render(&self, name: &str, context: &Context) -> Response {
+        match self.templates.render(name, context) {
+            Ok(html) => Response::html(200, html),
+            Err(e) => {
+                eprintln!("template error: {e}");
+                Response::new(500)
+            }
+        }
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let response = match self.router.find(&request.method, &request.path) {
+            Match::Found(handler) => handler(request, self),
+            Match::MethodNotAllowed(allowed) => Response::new(405).with_header("Allow", allowed.join(", ")),
+            Match::NotFound => Response::new(404),
+        };
+        self.with_error_page(response)
+    }
+
+    // Error responses without a body get the site's page for that status,
+    // or a plain-text line if the site has none.
+    pub fn with_error_page(&self, response: Response) -> Response {
+        if response.status < 400 || !response.body.is_empty() {
+            return response;
+        }
+        let page = self.error_pages.get(&response.status).and_then(|file| {
+            let context = Context::new()
+                .with("status", response.status as i64)
+                .with("reason", super::http::reason_phrase(response.status));
+            let name = file.to_string_lossy();
+            self.templates.render(&name, &context).map_err(|e| eprintln!("error page: {e}")).ok()
+        });
+
+        let status = response.status;
+        let mut headers = response.headers;
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+        let mut page = match page {
+            Some(contents) => Response::html(status, contents),
+            None => Response::text(status, format!("{} {}", status, super::http::reason_phrase(status))),
+        };
+        page.headers.extend(headers);
+        page
+    }
+}
+
+// Joins 'name' onto 'root' unless that would leave it: "../secret" and
+// absolute paths are refused.
+pub(crate) fn safe_join(root: &Path, name: &str) -> Option<PathBuf> {
+    let relative = Path::new(name.trim_start_matches('/'));
+    let escapes = relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes { None } else { Some(root.join(relative)) }
+}
+
+// Picks a site by the Host header. Requests without a Host header, or for a
+// name nobody registered, go to the default site.
+pub struct VirtualHosts {
+    default: Site,
+    sites: HashMap<String, Site>,
+}
+
+impl VirtualHosts {
+    pub fn new(default: Site) -> VirtualHosts {
+        VirtualHosts { default, sites: HashMap::new() }
+    }
+
+    pub fn host(mut self, name: &str, site: Site) -> VirtualHosts {
+        self.sites.insert(name.to_ascii_lowercase(), site);
+        self
+    }
+
+    pub fn site_for(&self, request: &Request) -> &Site {
+        request
+            .host()
+            .and_then(|host| self.sites.get(&host))
+            .unwrap_or(&self.default)
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        self.site_for(request).handle(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, host: Option<&str>) -> Request {
+        let mut request = Request::from_request_line(&format!("GET {path} HTTP/1.1")).unwrap();
+        if let Some(host) = host {
+            request.headers.push(("Host".to_string(), host.to_string()));
+        }
+        request
+    }
+
+    fn hosts() -> VirtualHosts {
+        let default = Site::new(".")
+            .route("GET", "/", |_, _| Response::text(200, "default"))
+            .error_page(404, "404.html");
+        let blog = Site::new(".")
+            .route("GET", "/", |_, _| Response::text(200, "blog"));
+        VirtualHosts::new(default).host("Blog.Local", blog)
+    }
+
+    #[test]
+    fn routes_by_host_header() {
+        let hosts = hosts();
+        assert_eq!(hosts.handle(&request("/", Some("blog.local:7878"))).body, b"blog");
+        assert_eq!(hosts.handle(&request("/", Some("unknown.local"))).body, b"default");
+        assert_eq!(hosts.handle(&request("/", None)).body, b"default");
+    }
+
+    #[test]
+    fn error_pages_are_per_host() {
+        let hosts = hosts();
+        let default_404 = hosts.handle(&request("/missing", None));
+        assert_eq!(default_404.status, 404);
+        assert!(String::from_utf8_lossy(&default_404.body).contains("Oops!"));
+
+        let blog_404 = hosts.handle(&request("/missing", Some("blog.local")));
+        assert_eq!(blog_404.status, 404);
+        assert_eq!(blog_404.body, b"404 Not Found");
+    }
+
+    #[test]
+    fn wrong_method_is_405() {
+        let hosts = hosts();
+        let mut post = request("/", None);
+        post.method = "POST".to_string();
+        let response = hosts.handle(&post);
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET"));
+
+        let site = Site::new(".")
+            .route("GET", "/item", |_, _| Response::text(200, "item"))
+            .route("delete", "/item", |_, _| Response::new(204));
+        let mut put = request("/item", None);
+        put.method = "PUT".to_string();
+        let response = VirtualHosts::new(site).handle(&put);
+        assert_eq!(response.header("Allow"), Some("GET, DELETE"));
+    }
+
+    #[test]
+    fn files_stay_inside_the_document_root() {
+        let site = Site::new("src");
+        assert_eq!(site.file("../Cargo.toml").status, 404);
+        assert_eq!(site.file("/main.rs").status, 200);
+    }
+}